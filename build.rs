@@ -0,0 +1,16 @@
+// Rebuild when a migration is added, since they are embedded by `sqlx::migrate!`.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    // The admin API's gRPC service, generated with the vendored protoc unless
+    // another is set, so building takes none installed.
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc");
+        // SAFETY: the build script sets it before starting any thread.
+        unsafe { std::env::set_var("PROTOC", protoc) };
+    }
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/admin.proto"], &["proto"])
+        .expect("failed to compile the admin protos");
+}
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS rate_limit_quotas (
+    key TEXT NOT NULL,
+    period_start DATE NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (key, period_start)
+);
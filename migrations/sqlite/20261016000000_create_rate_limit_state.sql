@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS rate_limit_state (
+    key TEXT PRIMARY KEY NOT NULL,
+    value BLOB NOT NULL,
+    expires_at INTEGER NOT NULL
+);
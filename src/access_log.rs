@@ -0,0 +1,170 @@
+//! Access log: a line per request, in the combined log format of web servers
+//! or as JSON, with what the rate limiter decided on it. It is written to a
+//! file of its own, rotated by time, so it can be kept and shipped apart from
+//! the diagnostic logs.
+
+use axum::{
+    body::{Body, HttpBody as _},
+    http::{HeaderMap, Request, Response, header},
+};
+use chrono::{DateTime, Utc};
+use std::{fmt::Write as _, io::Write as _, path::Path, sync::OnceLock, time::Duration};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{InitError, RollingFileAppender, Rotation},
+};
+
+use crate::client_ip::client_ip;
+use crate::config::{AccessLogConfig, AccessLogFormat, LogRotation};
+use crate::middleware::Checked;
+
+static LOG: OnceLock<AccessLog> = OnceLock::new();
+
+struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+    /// Flushes what is left to write when dropped, which statics never are;
+    /// lines still buffered when the process exits are lost.
+    _guard: WorkerGuard,
+}
+
+/// Opens the access log of `config`, so requests are logged from then on.
+pub fn init(config: &AccessLogConfig) -> Result<(), InitError> {
+    let path = Path::new(&config.path);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(match config.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        })
+        .filename_prefix(
+            path.file_name()
+                .map_or("access.log".into(), |name| name.to_string_lossy()),
+        );
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    let (writer, guard) = tracing_appender::non_blocking(builder.build(directory)?);
+    let _ = LOG.set(AccessLog {
+        format: config.format,
+        writer,
+        _guard: guard,
+    });
+    Ok(())
+}
+
+/// What is logged of a request, taken before it is handled.
+pub struct Entry {
+    time: DateTime<Utc>,
+    ip: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    /// The entry of `req`, none when there is no access log.
+    pub fn new(req: &Request<Body>) -> Option<Self> {
+        LOG.get()?;
+        let uri = req.uri();
+        Some(Self {
+            time: Utc::now(),
+            ip: client_ip(req),
+            method: req.method().to_string(),
+            target: uri
+                .path_and_query()
+                .map_or(uri.path(), |pq| pq.as_str())
+                .to_string(),
+            version: format!("{:?}", req.version()),
+            referer: header_value(req.headers(), header::REFERER),
+            user_agent: header_value(req.headers(), header::USER_AGENT),
+        })
+    }
+
+    /// Logs the request with its response, which started after `latency`.
+    pub fn write(self, id: &str, response: &Response<Body>, latency: Duration) {
+        let Some(log) = LOG.get() else { return };
+        let checked = response.extensions().get::<Checked>();
+        // Unknown for streamed bodies, which go out in chunks.
+        let bytes: Option<u64> = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .or_else(|| response.body().size_hint().exact());
+        let latency_ms = latency.as_micros() as f64 / 1000.0;
+        let mut line = match log.format {
+            AccessLogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+                    self.ip.as_deref().unwrap_or("-"),
+                    self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                    self.method,
+                    self.target,
+                    self.version,
+                    response.status().as_u16(),
+                    bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+                    quoted(self.referer.as_deref()),
+                    quoted(self.user_agent.as_deref()),
+                );
+                if let Some(checked) = checked {
+                    let _ = write!(
+                        line,
+                        " key=\"{}\" decision={} remaining={}",
+                        quoted(Some(&checked.key)),
+                        decision(checked),
+                        checked
+                            .remaining
+                            .map_or("-".to_string(), |remaining| remaining.to_string()),
+                    );
+                }
+                let _ = write!(line, " latency_ms={} request_id={}", latency_ms, id);
+                line
+            }
+            AccessLogFormat::Json => serde_json::json!({
+                "time": self.time.to_rfc3339(),
+                "request_id": id,
+                "ip": self.ip,
+                "method": self.method,
+                "target": self.target,
+                "version": self.version,
+                "status": response.status().as_u16(),
+                "bytes": bytes,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "key": checked.map(|checked| &checked.key),
+                "decision": checked.map(decision),
+                "remaining": checked.and_then(|checked| checked.remaining),
+                "latency_ms": latency_ms,
+            })
+            .to_string(),
+        };
+        line.push('\n');
+        // One write per line, so lines from concurrent requests do not mix.
+        let _ = log.writer.clone().write_all(line.as_bytes());
+    }
+}
+
+fn decision(checked: &Checked) -> &'static str {
+    if checked.allowed { "allow" } else { "deny" }
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// `value` to put between double quotes, `-` if missing.
+fn quoted(value: Option<&str>) -> String {
+    value.map_or("-".to_string(), |value| {
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    })
+}
@@ -0,0 +1,1247 @@
+//! Endpoints for operators under `/admin`, served only on the admin listener,
+//! never the main one, and only to requests bearing `RATE_LIMIT_ADMIN_TOKEN`
+//! or to operators with a client certificate when it has mutual TLS.
+
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{HeaderMap, HeaderValue, Request, Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+    routing::{delete, get, post},
+};
+use chrono::SecondsFormat;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::{net::SocketAddr, os::unix::fs::PermissionsExt, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tokio::net::{TcpListener, UnixListener};
+use tower::ServiceExt;
+
+use crate::admin_grpc;
+use crate::audit;
+use crate::bans::{self, Ban, BanTarget};
+use crate::client_ip::{is_allowlisted, is_denylisted, parse_cidr};
+use crate::config::{
+    ADMIN_LISTENER, ADMIN_TOKEN, ANONYMOUS_POLICY, API_KEY_HEADER, AUDIT_CONFIG, AdminAddr,
+    AdminListenerConfig, AuditSink, BODY_KEY_CONFIG, CLIENT_IP_HEADERS, CLUSTER_CONFIG,
+    CONFIG_FILE, CONFIG_KV, CONFIG_PATH, CONFIG_WATCH_SECONDS, CORS_CONFIG, DENYLIST_CONFIG,
+    DRAIN_POLICY, DYNAMODB_CONFIG, DrainPolicy, ERROR_BODY_CONFIG, EVENTS_CONFIG, EVICTION_CONFIG,
+    EventSink, GOSSIP_CONFIG, HYBRID_SYNC_MS, JWT_CONFIG, KEY_EXTRACTORS, KEY_HASH_SALT, KEY_SCOPE,
+    LISTEN_ADDR, MAX_BODY_BYTES, MAX_TRACKED_KEYS, MEMCACHED_CONFIG, METRICS_TOP_KEYS,
+    QUERY_KEY_MAX_LENGTH, QUOTA_CONFIG, QuotaPeriod, RATE_LIMIT_ALGORITHM, RATE_LIMIT_HEADERS,
+    RATE_LIMIT_MODE, RATE_LIMIT_PROFILE, RATE_LIMITER_BACKEND, RATE_LIMITER_TYPE, REDIS_CONFIG,
+    REJECTION_CONFIG, REQUEST_TIMEOUT, RateLimitConfig, RateLimiterBackend, RateLimiterType,
+    SESSION_COOKIE, SNAPSHOT_CONFIG, SQLITE_CONFIG, STORE_FAILURE_POLICY, STORE_OVERRIDES_CONFIG,
+    SUBNET_AGGREGATION, THROTTLE_CONFIG, TIER_LOOKUP_CONFIG, TLS_CONFIG, TRUSTED_PROXIES, UPSTREAM,
+    USER_AGENT_CLASSES, WARNING_THRESHOLD, WEBHOOK_CONFIG, WEBSOCKET_CONFIG, limits,
+};
+use crate::config_file::RouteRule;
+use crate::denylist::denylist;
+use crate::drain;
+use crate::metrics;
+use crate::middleware::{
+    Client, MiddlewareState, RateLimitStateEnum, client_profile, limiter, rule_key,
+};
+use crate::overrides;
+use crate::rate_limiter::{QuotaLimiter, RateLimitDecision, RateLimiter};
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::rules::{self, Change, RuleError};
+use crate::snapshot::Snapshot;
+use crate::stats;
+use crate::status::describe;
+use crate::switch;
+use crate::telemetry;
+use crate::tier::Tier;
+use crate::tls::{self, ClientCertFingerprint};
+use crate::top;
+
+/// Shown in place of secrets.
+const REDACTED: &str = "<redacted>";
+
+/// Keys `GET /admin/top` lists of each unless asked for another number, and
+/// the most it lists.
+pub const DEFAULT_TOP: usize = 20;
+pub const MAX_TOP: usize = 1000;
+/// Keys `GET /admin/keys` lists per page unless asked for another number,
+/// and the most it lists.
+const DEFAULT_KEYS_PAGE: usize = 100;
+const MAX_KEYS_PAGE: usize = 1000;
+/// Content type of limiter state exported and imported as MessagePack
+/// rather than JSON.
+const MSGPACK: &str = "application/msgpack";
+/// How long a temporary limit set without `ttl_seconds` lasts.
+const DEFAULT_OVERRIDE_TTL: Duration = Duration::from_secs(3600);
+
+/// A refusal of an operation, with the status and message the HTTP API
+/// answers it with; the gRPC service maps the status to a gRPC code.
+pub type AdminError = (StatusCode, String);
+
+pub fn router(state: &MiddlewareState) -> Router<MiddlewareState> {
+    Router::new()
+        .route("/admin/config", get(config_handler))
+        .route("/admin/bans", get(bans_handler).post(ban_handler))
+        .route("/admin/bans/:id", delete(lift_ban_handler))
+        .route("/admin/keys", get(keys_handler))
+        .route("/admin/limiter", post(switch_limiter_handler))
+        .route("/admin/drain", get(drain_handler).put(set_drain_handler))
+        .route("/admin/rules", get(rules_handler))
+        .route(
+            "/admin/rules/:name",
+            get(rule_handler)
+                .put(put_rule_handler)
+                .delete(delete_rule_handler),
+        )
+        .route("/admin/state/export", get(export_state_handler))
+        // Bounded by `RATE_LIMIT_MAX_BODY_BYTES` only, as the state of every
+        // key can take more than a usual request body.
+        .route(
+            "/admin/state/import",
+            post(import_state_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/admin/keys/*key",
+            get(key_handler)
+                .delete(reset_key_handler)
+                .patch(override_key_handler),
+        )
+        .route("/admin/top", get(top_handler))
+        .route(
+            "/admin/log_level",
+            get(log_level_handler).put(set_log_level_handler),
+        )
+        .route("/admin/stats/stream", get(stats::stream_handler))
+        .route_service(&admin_grpc::route(), admin_grpc::service(state.clone()))
+        .route_layer(axum::middleware::from_fn(require_token))
+}
+
+/// Whether the admin API is served, which takes a listener of its own and a
+/// way to authenticate operators.
+pub fn enabled() -> bool {
+    ADMIN_LISTENER
+        .as_ref()
+        .is_some_and(|config| ADMIN_TOKEN.is_some() || config.mutual_tls())
+}
+
+/// Serves `app`, the admin API, on the admin listener in the background.
+/// The listener is bound before returning, so a taken address fails startup.
+pub async fn serve(config: &AdminListenerConfig, app: Router) {
+    match &config.addr {
+        AdminAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .unwrap_or_else(|e| panic!("failed to bind the admin listener {}: {}", addr, e));
+            match &config.tls {
+                Some(tls_config) => {
+                    let server_config = tls::server_config(tls_config)
+                        .unwrap_or_else(|e| panic!("invalid admin TLS configuration: {}", e));
+                    tracing::info!("Serving the admin API over HTTPS on {}", addr);
+                    tokio::spawn(tls::serve(listener, app, server_config));
+                }
+                None => {
+                    tracing::info!("Serving the admin API on {}", addr);
+                    tokio::spawn(async move {
+                        axum::serve(
+                            listener,
+                            app.into_make_service_with_connect_info::<SocketAddr>(),
+                        )
+                        .await
+                        .unwrap();
+                    });
+                }
+            }
+        }
+        AdminAddr::Unix(path) => {
+            // A socket left behind by a previous run would fail the bind.
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path).unwrap_or_else(|e| {
+                panic!("failed to bind the admin socket {}: {}", path.display(), e)
+            });
+            // Only the server's own user may connect.
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .unwrap_or_else(|e| panic!("failed to restrict the admin socket: {}", e));
+            tracing::info!("Serving the admin API on {}", path.display());
+            tokio::spawn(serve_unix(listener, app));
+        }
+    }
+}
+
+async fn serve_unix(listener: UnixListener, app: Router) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::error!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        let app = app.clone();
+        tokio::spawn(async move {
+            let service =
+                hyper::service::service_fn(move |req: Request<Incoming>| app.clone().oneshot(req));
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Admin connection closed with error: {}", e);
+            }
+        });
+    }
+}
+
+async fn require_token(req: Request<Body>, next: Next) -> Response<Body> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    let authorized = match ADMIN_TOKEN.as_deref() {
+        // Digests compared in constant time, so neither the token nor its
+        // length can be guessed by timing.
+        Some(expected) => token.is_some_and(|token| {
+            Sha256::digest(token)
+                .ct_eq(&Sha256::digest(expected))
+                .into()
+        }),
+        // Without a token, the API is only served on an admin listener with
+        // mutual TLS, whose handshake already verified the certificate.
+        None => req.extensions().get::<ClientCertFingerprint>().is_some(),
+    };
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Unauthorized.",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// The configuration in force, after the command line, environment, config
+/// file, profile and reloads are merged, with secrets redacted.
+async fn config_handler(State(state): State<MiddlewareState>) -> Json<Value> {
+    let limits = limits();
+    Json(json!({
+        "listen_addr": LISTEN_ADDR.to_string(),
+        "upstream": UPSTREAM.as_ref().map(|upstream| json!({
+            "targets": upstream.targets.iter().map(|url| redact_url(url)).collect::<Vec<_>>(),
+            "balance": upstream.balance,
+            "health_check": upstream.health_check,
+            "outbound_limit": upstream.outbound_limit,
+            "outbound_queue": upstream.outbound_limit.as_ref().map(|_| upstream.outbound_queue()),
+            "retry_after": upstream.retry_after,
+            "cache": upstream.cache,
+        })),
+        "request_timeout_ms": REQUEST_TIMEOUT.map(|timeout| timeout.as_millis() as u64),
+        "max_body_bytes": *MAX_BODY_BYTES,
+        "config_file": *CONFIG_PATH,
+        "config_kv": CONFIG_KV.as_ref().map(|kv| json!({
+            "store": kv.store,
+            "url": redact_url(&kv.url),
+            "key": kv.key,
+        })),
+        "config_watch_seconds": *CONFIG_WATCH_SECONDS,
+        "profile": *RATE_LIMIT_PROFILE,
+        "mode": *RATE_LIMIT_MODE,
+        "headers": *RATE_LIMIT_HEADERS,
+        "warning_threshold": *WARNING_THRESHOLD,
+        "throttle": *THROTTLE_CONFIG,
+        "rejection": *REJECTION_CONFIG,
+        "cors": CORS_CONFIG.as_ref().map(|cors| json!({
+            "origins": cors.origins.as_ref().map_or(json!("*"), |origins| {
+                json!(origins.iter().filter_map(|o| o.to_str().ok()).collect::<Vec<_>>())
+            }),
+            "methods": cors.methods.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "headers": cors.headers.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "credentials": cors.credentials,
+            "max_age_seconds": cors.max_age_seconds,
+        })),
+        "websocket": *WEBSOCKET_CONFIG,
+        "error_body": {
+            "format": ERROR_BODY_CONFIG.format,
+            "fields": ERROR_BODY_CONFIG.fields.iter().map(|(field, name)| json!({
+                "field": field,
+                "name": name,
+            })).collect::<Vec<_>>(),
+        },
+        "limiter": {
+            "type": state.limiter.get().memory_type().unwrap_or(*RATE_LIMITER_TYPE),
+            "algorithm": *RATE_LIMIT_ALGORITHM,
+            "failure_policy": *STORE_FAILURE_POLICY,
+            "drain_policy": *DRAIN_POLICY,
+            "named": CONFIG_FILE.limiters.iter().map(|(name, limiter)| {
+                let algorithm = (limiter.kind == RateLimiterType::Store)
+                    .then(|| limiter.algorithm.unwrap_or(*RATE_LIMIT_ALGORITHM));
+                (name.clone(), json!({ "type": limiter.kind, "algorithm": algorithm }))
+            }).collect::<serde_json::Map<_, _>>(),
+        },
+        "backend": backend(),
+        "config_version": limits.version,
+        "limits": *limits,
+        "keys": {
+            "extractors": KEY_EXTRACTORS.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "scope": *KEY_SCOPE,
+            "anonymous_policy": *ANONYMOUS_POLICY,
+            "api_key_header": API_KEY_HEADER.as_str(),
+            "session_cookie": *SESSION_COOKIE,
+            "query_key_max_length": *QUERY_KEY_MAX_LENGTH,
+            "body_key": BODY_KEY_CONFIG.as_ref().map(|body| json!({
+                "field": body.field,
+                "paths": body.paths,
+                "max_bytes": body.max_bytes,
+            })),
+            "user_agent_classes": USER_AGENT_CLASSES.iter().map(|class| json!({
+                "name": class.name,
+                "patterns": class.patterns,
+                "limit": class.config,
+            })).collect::<Vec<_>>(),
+            "jwt": {
+                "secret": JWT_CONFIG.secret.as_ref().map(|_| REDACTED),
+                "jwks_url": JWT_CONFIG.jwks_url.as_deref().map(redact_url),
+                "jwks_refresh_seconds": JWT_CONFIG.jwks_refresh_seconds,
+                "claim": JWT_CONFIG.claim,
+            },
+            "hashed": KEY_HASH_SALT.is_some(),
+        },
+        "tier_lookup": TIER_LOOKUP_CONFIG.as_ref().map(|lookup| json!({
+            "url": redact_url(&lookup.url),
+            "timeout_ms": lookup.timeout_ms,
+            "cache_ttl_seconds": lookup.cache_ttl_seconds,
+            "cache_max_keys": lookup.cache_max_keys,
+            "max_lookups_per_second": lookup.max_lookups_per_second,
+        })),
+        "store_overrides": STORE_OVERRIDES_CONFIG.as_ref().map(|overrides| json!({
+            "prefix": overrides.prefix,
+            "cache_ttl_seconds": overrides.cache_ttl_seconds,
+            "cache_max_keys": overrides.cache_max_keys,
+        })),
+        "clients": {
+            "trusted_proxies": TRUSTED_PROXIES.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "ip_headers": *CLIENT_IP_HEADERS,
+            "subnet_aggregation": {
+                "ipv4_prefix": SUBNET_AGGREGATION.ipv4_prefix,
+                "ipv6_prefix": SUBNET_AGGREGATION.ipv6_prefix,
+            },
+            "denylist": {
+                "entries": *denylist(),
+                "source": DENYLIST_CONFIG.source.as_deref().map(redact_url),
+                "refresh_seconds": DENYLIST_CONFIG.refresh_seconds,
+            },
+        },
+        "quota": QUOTA_CONFIG.as_ref().map(|quota| json!({
+            "max_requests": quota.max_requests,
+            "period": quota.period,
+            "database_url": redact_url(&quota.database_url),
+        })),
+        "events": EVENTS_CONFIG.as_ref().map(|events| json!({
+            "sink": match &events.sink {
+                EventSink::Kafka { brokers } => json!({ "kafka": brokers }),
+                EventSink::Nats { url } => json!({ "nats": redact_url(url) }),
+            },
+            "topic": events.topic,
+            "buffer": events.buffer,
+        })),
+        // Webhook URLs often carry their secret in the path, so only the
+        // hosts are shown.
+        "webhooks": WEBHOOK_CONFIG.as_ref().map(|webhooks| json!({
+            "hosts": webhooks.urls.iter().filter_map(|url| {
+                reqwest::Url::parse(url).ok()?.host_str().map(str::to_string)
+            }).collect::<Vec<_>>(),
+            "format": webhooks.format,
+            "events": webhooks.events,
+            "traffic_threshold": webhooks.traffic_threshold,
+            "cooldown_seconds": webhooks.cooldown_seconds,
+        })),
+        "audit": AUDIT_CONFIG.as_ref().map(|audit| json!({
+            "sink": match &audit.sink {
+                AuditSink::File { path } => json!({ "file": path }),
+                AuditSink::Http { url } => json!({ "http": redact_url(url) }),
+            },
+            "buffer": audit.buffer,
+            "overflow": audit.overflow,
+            "salt": audit.salt.as_ref().map(|_| REDACTED),
+        })),
+        "snapshot": SNAPSHOT_CONFIG.as_ref().map(|snapshot| json!({
+            "path": snapshot.path,
+            "interval_seconds": snapshot.interval_seconds,
+        })),
+        "eviction": {
+            "interval_seconds": EVICTION_CONFIG.interval_seconds,
+            "slack_seconds": EVICTION_CONFIG.slack_seconds,
+            "max_tracked_keys": *MAX_TRACKED_KEYS,
+        },
+        "metrics_top_keys": *METRICS_TOP_KEYS,
+        "tls": TLS_CONFIG.as_ref().map(|tls| json!({
+            "cert_path": tls.cert_path,
+            "key_path": tls.key_path,
+            "client_ca_path": tls.client_ca_path,
+            "client_cert_required": tls.client_cert_required,
+        })),
+        "admin_listener": ADMIN_LISTENER.as_ref().map(|listener| json!({
+            "addr": match &listener.addr {
+                AdminAddr::Tcp(addr) => addr.to_string(),
+                AdminAddr::Unix(path) => format!("unix:{}", path.display()),
+            },
+            "tls": listener.tls.as_ref().map(|tls| json!({
+                "cert_path": tls.cert_path,
+                "key_path": tls.key_path,
+                "client_ca_path": tls.client_ca_path,
+            })),
+        })),
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewBan {
+    key: Option<String>,
+    cidr: Option<String>,
+    /// How long the ban lasts, until it is lifted without one.
+    duration_seconds: Option<u64>,
+    reason: Option<String>,
+}
+
+/// The bans in force.
+async fn bans_handler() -> Json<Value> {
+    Json(json!({
+        "bans": bans::bans().iter().map(ban_json).collect::<Vec<_>>(),
+    }))
+}
+
+/// Bans a client by key, or the addresses of a network, e.g. `{"cidr":
+/// "203.0.113.0/24", "duration_seconds": 3600, "reason": "scraping"}`.
+async fn ban_handler(
+    State(state): State<MiddlewareState>,
+    Json(body): Json<NewBan>,
+) -> Response<Body> {
+    match create_ban(
+        &state,
+        body.key,
+        body.cidr,
+        body.duration_seconds,
+        body.reason,
+    )
+    .await
+    {
+        Ok(ban) => (StatusCode::CREATED, Json(ban_json(&ban))).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Bans the client identified by `key`, or the addresses of `cidr`, one of
+/// which is set.
+pub async fn create_ban(
+    state: &MiddlewareState,
+    key: Option<String>,
+    cidr: Option<String>,
+    duration_seconds: Option<u64>,
+    reason: Option<String>,
+) -> Result<Ban, AdminError> {
+    let target = match (key, cidr) {
+        (Some(key), None) => BanTarget::Key(key),
+        (None, Some(cidr)) => match parse_cidr(&cidr) {
+            Some(net) => BanTarget::Cidr(net.trunc()),
+            None => {
+                return Err((StatusCode::BAD_REQUEST, format!("Invalid CIDR: {:?}", cidr)));
+            }
+        },
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Set one of key or cidr.".to_string(),
+            ));
+        }
+    };
+    if duration_seconds == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "duration_seconds must be greater than 0.".to_string(),
+        ));
+    }
+    let ban = bans::ban(
+        &state.limiter.get(),
+        target,
+        duration_seconds.map(Duration::from_secs),
+        reason,
+    )
+    .await
+    .map_err(store_error)?;
+    tracing::info!(
+        "Banned {} until {} (ban {}: {})",
+        ban.target,
+        ban.expires_at
+            .map_or("lifted".to_string(), |expires_at| expires_at
+                .to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ban.id,
+        ban.reason.as_deref().unwrap_or("no reason given")
+    );
+    Ok(ban)
+}
+
+async fn lift_ban_handler(
+    State(state): State<MiddlewareState>,
+    Path(id): Path<String>,
+) -> Response<Body> {
+    match lift_ban(&state, &id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Lifts the ban with `id`, unless it was lifted or expired already.
+pub async fn lift_ban(state: &MiddlewareState, id: &str) -> Result<(), AdminError> {
+    let ban = bans::lift(&state.limiter.get(), id)
+        .await
+        .map_err(store_error)?
+        .ok_or((StatusCode::NOT_FOUND, "Unknown ban.".to_string()))?;
+    tracing::info!("Lifted ban {} of {}", ban.id, ban.target);
+    Ok(())
+}
+
+fn store_error(e: String) -> AdminError {
+    tracing::error!("Failed to store the bans: {}", e);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Failed to store the bans.".to_string(),
+    )
+}
+
+fn ban_json(ban: &Ban) -> Value {
+    let (key, cidr) = match &ban.target {
+        BanTarget::Key(key) => (Some(key.clone()), None),
+        BanTarget::Cidr(net) => (None, Some(net.to_string())),
+    };
+    json!({
+        "id": ban.id,
+        "key": key,
+        "cidr": cidr,
+        "reason": ban.reason,
+        "created_at": ban.created_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "expires_at": ban.expires_at.map(|expires_at| expires_at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    })
+}
+
+#[derive(Deserialize)]
+struct KeysQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    #[serde(default)]
+    sort: KeySort,
+    prefix: Option<String>,
+    min_count: Option<u64>,
+    /// Named limiter to list the keys of, rather than the main one.
+    limiter: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum KeySort {
+    #[default]
+    Key,
+    /// Most requests first.
+    Count,
+    /// Most recently seen first.
+    Idle,
+}
+
+/// The keys the limiter holds state for, a page at a time, as they are
+/// limited, so with the rule and scope they are counted under.
+async fn keys_handler(
+    State(state): State<MiddlewareState>,
+    Query(query): Query<KeysQuery>,
+) -> Response<Body> {
+    let Some(limiter) = selected_limiter(&state, query.limiter.as_deref()) else {
+        return (StatusCode::BAD_REQUEST, "Unknown limiter.").into_response();
+    };
+    let prefix = query.prefix.as_deref().unwrap_or_default();
+    let Some(mut keys) = limiter.keys(prefix, query.min_count.unwrap_or(0)).await else {
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "Keys are only listed by the standard and lock-free limiters.",
+        )
+            .into_response();
+    };
+    match query.sort {
+        KeySort::Key => keys.sort_unstable_by(|a, b| a.key.cmp(&b.key)),
+        KeySort::Count => {
+            keys.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)))
+        }
+        KeySort::Idle => {
+            keys.sort_unstable_by(|a, b| a.idle.cmp(&b.idle).then_with(|| a.key.cmp(&b.key)))
+        }
+    }
+
+    let total = keys.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_KEYS_PAGE).min(MAX_KEYS_PAGE);
+    let page: Vec<Value> = keys
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|tracked| {
+            json!({
+                "key": tracked.key,
+                "count": tracked.count,
+                "idle_seconds": tracked.idle.as_secs(),
+            })
+        })
+        .collect();
+    Json(json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "keys": page,
+    }))
+    .into_response()
+}
+
+/// The named limiter `name`, or the main one without a name, none if there
+/// is no limiter of that name.
+fn selected_limiter(state: &MiddlewareState, name: Option<&str>) -> Option<RateLimitStateEnum> {
+    match name {
+        Some(name) => state.limiters.get(name).map(|named| named.state.clone()),
+        None => Some(state.limiter.get()),
+    }
+}
+
+#[derive(Deserialize)]
+struct StateQuery {
+    /// Named limiter to export or import the state of, rather than the main
+    /// one.
+    limiter: Option<String>,
+}
+
+/// The state of the standard or lock-free limiter, in the snapshot format,
+/// as JSON or as MessagePack when asked for with `Accept`.
+async fn export_state_handler(
+    State(state): State<MiddlewareState>,
+    Query(query): Query<StateQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let Some(limiter) = selected_limiter(&state, query.limiter.as_deref()) else {
+        return (StatusCode::BAD_REQUEST, "Unknown limiter.").into_response();
+    };
+    let Some(snapshot) = Snapshot::take(&limiter).await else {
+        return state_not_implemented();
+    };
+    if !is_msgpack(headers.get(header::ACCEPT)) {
+        return Json(snapshot).into_response();
+    }
+    match rmp_serde::to_vec_named(&snapshot) {
+        Ok(bytes) => ([(header::CONTENT_TYPE, MSGPACK)], bytes).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to encode limiter state: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Loads state exported from this or another instance into the standard
+/// or lock-free limiter, over what it holds for the same keys, converting
+/// state exported by the other type.
+async fn import_state_handler(
+    State(state): State<MiddlewareState>,
+    Query(query): Query<StateQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    let Some(limiter) = selected_limiter(&state, query.limiter.as_deref()) else {
+        return (StatusCode::BAD_REQUEST, "Unknown limiter.").into_response();
+    };
+    let snapshot: Result<Snapshot, String> = if is_msgpack(headers.get(header::CONTENT_TYPE)) {
+        rmp_serde::from_slice(&body).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_slice(&body).map_err(|e| e.to_string())
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid state: {}", e)).into_response();
+        }
+    };
+    match snapshot.load(&limiter).await {
+        Some(imported) => {
+            tracing::info!(
+                "Imported the state of {} keys into the {} limiter",
+                imported,
+                query.limiter.as_deref().unwrap_or("main")
+            );
+            Json(json!({ "imported_keys": imported })).into_response()
+        }
+        None => state_not_implemented(),
+    }
+}
+
+fn is_msgpack(value: Option<&HeaderValue>) -> bool {
+    value
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("msgpack"))
+}
+
+fn state_not_implemented() -> Response<Body> {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        "State is only exported and imported by the standard and lock-free limiters.",
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimiterSwitch {
+    #[serde(rename = "type")]
+    kind: RateLimiterType,
+}
+
+/// Switches the main limiter to another in-memory type, carrying clients'
+/// counts over between the standard and lock-free limiters, with
+/// `409 Conflict` on other backends.
+async fn switch_limiter_handler(
+    State(state): State<MiddlewareState>,
+    Json(body): Json<LimiterSwitch>,
+) -> Response<Body> {
+    match switch::switch(&state.limiter, body.kind).await {
+        Ok(switched) => Json(json!({
+            "type": body.kind,
+            "previous": switched.previous,
+            "migrated_keys": switched.migrated,
+        }))
+        .into_response(),
+        Err(e) => (StatusCode::CONFLICT, e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    /// Path whose route rule to report on, `/` by default.
+    path: Option<String>,
+    /// Tier the client's API key or token puts it in, which the key alone
+    /// does not tell.
+    tier: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KeyOverride {
+    /// Limit to hold the key to, or `null` to drop its temporary limit.
+    limit: Option<RateLimitConfig>,
+    ttl_seconds: Option<u64>,
+}
+
+/// Where the client with `key` stands against its limits, read without
+/// counting a request, for support to answer "why am I being limited?".
+/// The key is the one the client is identified by, like its IP address or
+/// `api_key:<key>`, before route rules and scopes add to it.
+async fn key_handler(
+    State(state): State<MiddlewareState>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+) -> Response<Body> {
+    inspect_key(&state, key, query, false).await
+}
+
+/// Forgets what the client with `key` used of its rate limit and quota under
+/// the route rule for `path`, unblocking it at once.
+async fn reset_key_handler(
+    State(state): State<MiddlewareState>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+) -> Response<Body> {
+    inspect_key(&state, key, query, true).await
+}
+
+/// Holds the client with `key` to a limit of its own for a while, e.g.
+/// `{"limit": {"max_requests": 1000, "window_seconds": 60}, "ttl_seconds":
+/// 3600}`, or drops that limit with `{"limit": null}`.
+async fn override_key_handler(
+    State(state): State<MiddlewareState>,
+    Path(key): Path<String>,
+    Query(query): Query<KeyQuery>,
+    Json(body): Json<KeyOverride>,
+) -> Response<Body> {
+    let Some(limit) = body.limit else {
+        if overrides::remove_temporary(&key) {
+            tracing::info!("Dropped the temporary limit of {}", key);
+        }
+        return inspect_key(&state, key, query, false).await;
+    };
+    if let Err(e) = limit.check() {
+        return (StatusCode::BAD_REQUEST, format!("Invalid limit: {}", e)).into_response();
+    }
+    let ttl = match body.ttl_seconds {
+        Some(0) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "ttl_seconds must be greater than 0.",
+            )
+                .into_response();
+        }
+        Some(seconds) => Duration::from_secs(seconds),
+        None => DEFAULT_OVERRIDE_TTL,
+    };
+    tracing::info!("Holding {} to {} for {:?}", key, limit, ttl);
+    overrides::set_temporary(&key, limit, ttl);
+    inspect_key(&state, key, query, false).await
+}
+
+/// Reports on `key`, after forgetting its requests if `reset` is set.
+async fn inspect_key(
+    state: &MiddlewareState,
+    key: String,
+    query: KeyQuery,
+    reset: bool,
+) -> Response<Body> {
+    let path = query.path.as_deref().unwrap_or("/");
+    match report_key(state, key, path, query.tier, reset).await {
+        Ok(report) => Json(key_json(&report)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Where a client stands against its limits, as `GET /admin/keys/{key}` and
+/// the gRPC service report it.
+pub struct KeyReport {
+    pub key: String,
+    /// The key after the route rule, scope and anonymization add to it.
+    pub limited_as: String,
+    pub rule: String,
+    pub tier: Option<String>,
+    /// The temporary limit the key is held to and how long it has left.
+    pub temporary_override: Option<(Arc<RateLimitConfig>, Duration)>,
+    pub exempt: bool,
+    pub allowlisted: bool,
+    pub denylisted: bool,
+    pub ban: Option<Ban>,
+    pub rate_limit: RateLimitDecision,
+    pub quota: Option<(RateLimitDecision, QuotaPeriod)>,
+}
+
+/// Reports on the client with `key` under the route rule for `path`, after
+/// forgetting its requests if `reset` is set.
+pub async fn report_key(
+    state: &MiddlewareState,
+    key: String,
+    path: &str,
+    tier: Option<String>,
+    reset: bool,
+) -> Result<KeyReport, AdminError> {
+    let limits = limits();
+    let tier = match tier {
+        Some(name) => match limits.tiers.get(&name) {
+            Some(limit) => Some(Tier {
+                name,
+                limit: limit.clone(),
+            }),
+            None => return Err((StatusCode::BAD_REQUEST, "Unknown tier.".to_string())),
+        },
+        None => None,
+    };
+    let (config, tier) = client_profile(state, &limits, &HeaderMap::new(), &key, tier)
+        .await
+        .unwrap_or_else(|| (limits.default.clone(), None));
+    let client = Client {
+        key: key.clone(),
+        config,
+        tier: tier.clone(),
+    };
+    let route_rule = limits.routes.iter().find(|rule| rule.matches(path));
+    let (limited_as, config, rule) = rule_key(client, route_rule, path);
+    let limiter = limiter(state, route_rule, &config);
+    let quota_limiter = match (&state.quota_store, &*QUOTA_CONFIG) {
+        (Some(store), Some(quota)) => Some((QuotaLimiter::new(store.clone(), quota), quota)),
+        _ => None,
+    };
+
+    if reset {
+        if let Err(error) = limiter.reset(&limited_as).await {
+            metrics::record_store_error("rate_limit");
+            tracing::error!("Rate limit store failed, cannot reset key: {}", error);
+            return Err(unavailable_error());
+        }
+        if let Some((quota_limiter, _)) = &quota_limiter
+            && let Err(error) = quota_limiter.reset(&limited_as).await
+        {
+            metrics::record_store_error("quota");
+            tracing::error!("Quota store failed, cannot reset key: {}", error);
+            return Err(unavailable_error());
+        }
+        tracing::info!("Reset the requests of {}", limited_as);
+    }
+
+    let rate_limit = match limiter.peek(&limited_as).await {
+        Ok(decision) => decision,
+        Err(error) => {
+            metrics::record_store_error("rate_limit");
+            tracing::error!("Rate limit store failed, cannot report key: {}", error);
+            return Err(unavailable_error());
+        }
+    };
+    let quota = match quota_limiter {
+        Some((quota_limiter, quota)) => match quota_limiter.peek(&limited_as).await {
+            Ok(decision) => Some((decision, quota.period)),
+            Err(error) => {
+                metrics::record_store_error("quota");
+                tracing::error!("Quota store failed, cannot report key: {}", error);
+                return Err(unavailable_error());
+            }
+        },
+        None => None,
+    };
+
+    Ok(KeyReport {
+        temporary_override: overrides::temporary(&key),
+        exempt: limits.is_exempt(path),
+        allowlisted: is_allowlisted(&limits.allowlist, &key),
+        denylisted: is_denylisted(&denylist(), &key),
+        ban: bans::key_ban(&key).or_else(|| bans::ip_ban(&key)),
+        key,
+        limited_as,
+        rule: rule.to_string(),
+        tier,
+        rate_limit,
+        quota,
+    })
+}
+
+fn unavailable_error() -> AdminError {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Rate limiter unavailable.".to_string(),
+    )
+}
+
+fn key_json(report: &KeyReport) -> Value {
+    let mut rate_limit = describe(&report.rate_limit);
+    rate_limit["count"] = json!(
+        report
+            .rate_limit
+            .limit
+            .saturating_sub(report.rate_limit.remaining)
+    );
+    let quota = report.quota.map(|(decision, period)| {
+        let mut status = describe(&decision);
+        status["period"] = json!(period);
+        status
+    });
+    let temporary_override = report.temporary_override.as_ref().map(|(limit, left)| {
+        let mut status = json!(&**limit);
+        status["expires_seconds"] = json!(left.as_secs());
+        status
+    });
+    json!({
+        "key": report.key,
+        "limited_as": report.limited_as,
+        "rule": report.rule,
+        "tier": report.tier,
+        "temporary_override": temporary_override,
+        "exempt": report.exempt,
+        "allowlisted": report.allowlisted,
+        "denylisted": report.denylisted,
+        "ban": report.ban.as_ref().map(ban_json),
+        "rate_limit": rate_limit,
+        "quota": quota,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DrainSwitch {
+    enabled: bool,
+    /// Policy to drain under, `RATE_LIMIT_DRAIN_POLICY` without one.
+    policy: Option<DrainPolicy>,
+}
+
+async fn drain_handler() -> Json<Value> {
+    Json(drain_json())
+}
+
+/// Switches drain mode on, e.g. with `{"enabled": true, "policy": "pass"}`,
+/// or off with `{"enabled": false}`.
+async fn set_drain_handler(Json(body): Json<DrainSwitch>) -> Json<Value> {
+    if body.enabled {
+        let drain = drain::start(body.policy.unwrap_or(*DRAIN_POLICY));
+        tracing::warn!("Draining under the {:?} policy", drain.policy);
+    } else if drain::stop().is_some() {
+        tracing::info!("Stopped draining");
+    }
+    Json(drain_json())
+}
+
+fn drain_json() -> Value {
+    let drain = drain::draining();
+    json!({
+        "draining": drain.is_some(),
+        "policy": drain.map(|drain| drain.policy),
+        "since": drain.map(|drain| drain.since.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    })
+}
+
+/// The route rules in force, in the order they apply, with whether each is
+/// the config file's or managed through this API.
+async fn rules_handler(State(state): State<MiddlewareState>) -> Json<Value> {
+    Json(json!({
+        "rules": limits().routes.iter().map(rule_json).collect::<Vec<_>>(),
+        "persisted": state.limiter.get().is_shared(),
+    }))
+}
+
+async fn rule_handler(Path(name): Path<String>) -> Response<Body> {
+    match limits().routes.iter().find(|rule| rule.name == name) {
+        Some(rule) => Json(rule_json(rule)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Unknown rule.").into_response(),
+    }
+}
+
+/// Creates the rule `name`, or replaces it, with the rule of the body, e.g.
+/// `{"path": "/search", "max_requests": 10, "window_seconds": 60}`. A rule
+/// named like one of the config file takes its place until deleted.
+async fn put_rule_handler(
+    State(state): State<MiddlewareState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    fingerprint: Option<Extension<ClientCertFingerprint>>,
+    Json(body): Json<Value>,
+) -> Response<Body> {
+    match put_rule(
+        &state,
+        &name,
+        body,
+        request_id(&headers),
+        operator(&fingerprint),
+    )
+    .await
+    {
+        Ok((rule, true)) => (StatusCode::CREATED, Json(rule_json(&rule))).into_response(),
+        Ok((rule, false)) => Json(rule_json(&rule)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Creates the rule `name`, or replaces it, with `rule` as written in the
+/// config file, auditing the change. Returns the rule and whether it was
+/// created.
+pub async fn put_rule(
+    state: &MiddlewareState,
+    name: &str,
+    mut rule: Value,
+    request_id: Option<&str>,
+    operator: Option<&str>,
+) -> Result<(RouteRule, bool), AdminError> {
+    let Some(fields) = rule.as_object_mut() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The rule must be a JSON object.".to_string(),
+        ));
+    };
+    if let Some(Value::String(named)) = fields.insert("name".to_string(), json!(name))
+        && named != name
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The rule's name must be the one in the path.".to_string(),
+        ));
+    }
+    let rule: RouteRule = serde_json::from_value(rule)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid rule: {}", e)))?;
+    let (before, after) = rules::change(&state.limiter.get(), Change::Put(Box::new(rule.clone())))
+        .await
+        .map_err(|e| rule_error(name, e))?;
+    let created = before.is_none();
+    tracing::info!(
+        "{} route rule {}",
+        if created { "Created" } else { "Updated" },
+        name
+    );
+    audit::record_rule_change(before, after, request_id, operator).await;
+    Ok((rule, created))
+}
+
+/// Deletes the rule `name`, bringing back the config file's of the same
+/// name, if any. Rules of the config file alone are only deleted there.
+async fn delete_rule_handler(
+    State(state): State<MiddlewareState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    fingerprint: Option<Extension<ClientCertFingerprint>>,
+) -> Response<Body> {
+    match delete_rule(&state, &name, request_id(&headers), operator(&fingerprint)).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Deletes the rule `name` set through the API, auditing the change.
+pub async fn delete_rule(
+    state: &MiddlewareState,
+    name: &str,
+    request_id: Option<&str>,
+    operator: Option<&str>,
+) -> Result<(), AdminError> {
+    let (before, after) = rules::change(&state.limiter.get(), Change::Delete(name.to_string()))
+        .await
+        .map_err(|e| rule_error(name, e))?;
+    tracing::info!("Deleted route rule {}", name);
+    audit::record_rule_change(before, after, request_id, operator).await;
+    Ok(())
+}
+
+/// Where the rule `name` in force comes from, `file` or `admin`.
+pub fn rule_source(name: &str) -> &'static str {
+    if rules::is_runtime(name) {
+        "admin"
+    } else {
+        "file"
+    }
+}
+
+fn rule_json(rule: &RouteRule) -> Value {
+    let mut value = json!(rule);
+    value["source"] = json!(rule_source(&rule.name));
+    value
+}
+
+fn rule_error(name: &str, e: RuleError) -> AdminError {
+    match e {
+        RuleError::Invalid(e) => (StatusCode::BAD_REQUEST, format!("Invalid rule: {}", e)),
+        RuleError::Unknown => (StatusCode::NOT_FOUND, "Unknown rule.".to_string()),
+        RuleError::InFile => (
+            StatusCode::CONFLICT,
+            format!("Rule {:?} is defined in the config file.", name),
+        ),
+        RuleError::Store(e) => {
+            tracing::error!("Failed to store the route rules: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Failed to store the rules.".to_string(),
+            )
+        }
+    }
+}
+
+fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The operator, by the fingerprint of their client certificate, if any.
+fn operator(fingerprint: &Option<Extension<ClientCertFingerprint>>) -> Option<&str> {
+    fingerprint
+        .as_ref()
+        .map(|Extension(fingerprint)| fingerprint.0.as_str())
+}
+
+#[derive(Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn log_level_handler() -> Json<Value> {
+    Json(json!({ "level": telemetry::log_level() }))
+}
+
+/// Changes what is logged until the next change or restart, e.g. to
+/// `{"level": "debug"}` during an incident and back to `info` after.
+async fn set_log_level_handler(Json(body): Json<LogLevel>) -> Response<Body> {
+    match telemetry::set_log_level(&body.level) {
+        Ok(()) => Json(json!({ "level": telemetry::log_level() })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Invalid log level: {}", e)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    n: Option<usize>,
+}
+
+/// The keys with the most requests and those with the most denials in the
+/// last minute, to find the clients hammering the server.
+async fn top_handler(Query(query): Query<TopQuery>) -> Json<Value> {
+    let n = query.n.unwrap_or(DEFAULT_TOP).min(MAX_TOP);
+    let (requests, denials) = top::top(n);
+    Json(json!({
+        "window_seconds": top::WINDOW.as_secs(),
+        "requests": requests,
+        "denials": denials,
+    }))
+}
+
+/// Settings of the backend in use.
+fn backend() -> Value {
+    let settings = match *RATE_LIMITER_BACKEND {
+        RateLimiterBackend::Memory => json!({}),
+        RateLimiterBackend::Redis | RateLimiterBackend::Hybrid => json!({
+            "url": redact_url(&REDIS_CONFIG.url),
+            "key_prefix": REDIS_CONFIG.key_prefix,
+            "mode": REDIS_CONFIG.mode,
+            "sentinel_master": REDIS_CONFIG.sentinel_master,
+            "hash_tags": REDIS_CONFIG.hash_tags,
+            "hybrid_sync_ms": (*RATE_LIMITER_BACKEND == RateLimiterBackend::Hybrid)
+                .then_some(*HYBRID_SYNC_MS),
+        }),
+        RateLimiterBackend::Memcached => json!({
+            "servers": MEMCACHED_CONFIG.servers,
+            "key_prefix": MEMCACHED_CONFIG.key_prefix,
+            "timeout_ms": MEMCACHED_CONFIG.timeout_ms,
+        }),
+        RateLimiterBackend::DynamoDb => json!({
+            "table": DYNAMODB_CONFIG.table,
+            "region": DYNAMODB_CONFIG.region,
+            "endpoint": DYNAMODB_CONFIG.endpoint,
+            "key_prefix": DYNAMODB_CONFIG.key_prefix,
+            "timeout_ms": DYNAMODB_CONFIG.timeout_ms,
+        }),
+        RateLimiterBackend::Sqlite => json!({
+            "path": SQLITE_CONFIG.path,
+            "flush_ms": SQLITE_CONFIG.flush_ms,
+        }),
+        RateLimiterBackend::Gossip => json!({
+            "bind": GOSSIP_CONFIG.bind.to_string(),
+            "peers": GOSSIP_CONFIG.peers.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "node_id": GOSSIP_CONFIG.node_id,
+            "interval_ms": GOSSIP_CONFIG.interval_ms,
+        }),
+        RateLimiterBackend::Cluster => json!({
+            "peers": CLUSTER_CONFIG.peers,
+            "self_url": CLUSTER_CONFIG.self_url,
+            "listen": CLUSTER_CONFIG.listen.to_string(),
+            "secret": REDACTED,
+            "timeout_ms": CLUSTER_CONFIG.timeout_ms,
+        }),
+    };
+    json!({ "kind": *RATE_LIMITER_BACKEND, "settings": settings })
+}
+
+/// Hides the credentials of URLs like `redis://:password@host`, which may be
+/// comma-separated lists.
+fn redact_url(urls: &str) -> String {
+    urls.split(',')
+        .map(|url| match url.split_once("://") {
+            Some((scheme, rest)) => {
+                let authority_end = rest.find('/').unwrap_or(rest.len());
+                match rest[..authority_end].rsplit_once('@') {
+                    Some((_, host)) => {
+                        format!(
+                            "{}://{}@{}{}",
+                            scheme,
+                            REDACTED,
+                            host,
+                            &rest[authority_end..]
+                        )
+                    }
+                    None => url.to_string(),
+                }
+            }
+            None => url.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
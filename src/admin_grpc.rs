@@ -0,0 +1,320 @@
+//! The admin API over gRPC, the `rate_limit.admin.v1.Admin` service of
+//! `proto/admin.proto`, for control planes managing fleets of servers. It is
+//! served with the HTTP admin API, behind the same authentication, and its
+//! calls make the same operations, audited and logged alike.
+
+use axum::http::StatusCode;
+use chrono::SecondsFormat;
+use futures_util::Stream;
+use std::pin::Pin;
+use tonic::{Code, Request, Response, Status, server::NamedService};
+
+use crate::admin::{self, AdminError, KeyReport};
+use crate::bans::{self, BanTarget};
+use crate::config::{QuotaPeriod, limits};
+use crate::config_file::RouteRule;
+use crate::middleware::MiddlewareState;
+use crate::rejection::seconds;
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::stats::{self, Second};
+use crate::tls::ClientCertFingerprint;
+use crate::top::{self, Offender};
+
+mod proto {
+    tonic::include_proto!("rate_limit.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+use proto::{
+    CreateBanRequest, DeleteBanRequest, DeleteBanResponse, DeleteRuleRequest, DeleteRuleResponse,
+    GetRuleRequest, GetTopRequest, GetTopResponse, KeyRequest, KeyStatus, LimitStatus,
+    ListBansRequest, ListBansResponse, ListRulesRequest, ListRulesResponse, PutRuleRequest,
+    PutRuleResponse, QuotaStatus, Rule, Stats, StreamStatsRequest, TemporaryOverride,
+};
+
+/// Route of the service's calls, like `/rate_limit.admin.v1.Admin/InspectKey`.
+pub fn route() -> String {
+    format!("/{}/*method", AdminServer::<AdminService>::NAME)
+}
+
+pub fn service(state: MiddlewareState) -> AdminServer<AdminService> {
+    AdminServer::new(AdminService { state })
+}
+
+pub struct AdminService {
+    state: MiddlewareState,
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn inspect_key(
+        &self,
+        request: Request<KeyRequest>,
+    ) -> Result<Response<KeyStatus>, Status> {
+        self.report_key(request.into_inner(), false).await
+    }
+
+    async fn reset_key(&self, request: Request<KeyRequest>) -> Result<Response<KeyStatus>, Status> {
+        self.report_key(request.into_inner(), true).await
+    }
+
+    async fn list_bans(
+        &self,
+        _: Request<ListBansRequest>,
+    ) -> Result<Response<ListBansResponse>, Status> {
+        Ok(Response::new(ListBansResponse {
+            bans: bans::bans().iter().map(ban).collect(),
+        }))
+    }
+
+    async fn create_ban(
+        &self,
+        request: Request<CreateBanRequest>,
+    ) -> Result<Response<proto::Ban>, Status> {
+        let request = request.into_inner();
+        let (key, cidr) = match request.target {
+            Some(proto::create_ban_request::Target::Key(key)) => (Some(key), None),
+            Some(proto::create_ban_request::Target::Cidr(cidr)) => (None, Some(cidr)),
+            None => (None, None),
+        };
+        let created = admin::create_ban(
+            &self.state,
+            key,
+            cidr,
+            request.duration_seconds,
+            request.reason,
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(ban(&created)))
+    }
+
+    async fn delete_ban(
+        &self,
+        request: Request<DeleteBanRequest>,
+    ) -> Result<Response<DeleteBanResponse>, Status> {
+        admin::lift_ban(&self.state, &request.into_inner().id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(DeleteBanResponse {}))
+    }
+
+    async fn list_rules(
+        &self,
+        _: Request<ListRulesRequest>,
+    ) -> Result<Response<ListRulesResponse>, Status> {
+        Ok(Response::new(ListRulesResponse {
+            rules: limits().routes.iter().map(rule).collect(),
+            persisted: self.state.limiter.get().is_shared(),
+        }))
+    }
+
+    async fn get_rule(&self, request: Request<GetRuleRequest>) -> Result<Response<Rule>, Status> {
+        let name = request.into_inner().name;
+        limits()
+            .routes
+            .iter()
+            .find(|rule| rule.name == name)
+            .map(|found| Response::new(rule(found)))
+            .ok_or_else(|| Status::not_found("Unknown rule."))
+    }
+
+    async fn put_rule(
+        &self,
+        request: Request<PutRuleRequest>,
+    ) -> Result<Response<PutRuleResponse>, Status> {
+        let (request_id, operator) = caller(&request);
+        let request = request.into_inner();
+        let json = serde_json::from_str(&request.json)
+            .map_err(|e| Status::invalid_argument(format!("Invalid rule: {}", e)))?;
+        let (put, created) = admin::put_rule(
+            &self.state,
+            &request.name,
+            json,
+            request_id.as_deref(),
+            operator.as_deref(),
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(PutRuleResponse {
+            rule: Some(rule(&put)),
+            created,
+        }))
+    }
+
+    async fn delete_rule(
+        &self,
+        request: Request<DeleteRuleRequest>,
+    ) -> Result<Response<DeleteRuleResponse>, Status> {
+        let (request_id, operator) = caller(&request);
+        admin::delete_rule(
+            &self.state,
+            &request.into_inner().name,
+            request_id.as_deref(),
+            operator.as_deref(),
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(DeleteRuleResponse {}))
+    }
+
+    async fn get_top(
+        &self,
+        request: Request<GetTopRequest>,
+    ) -> Result<Response<GetTopResponse>, Status> {
+        let n = request
+            .into_inner()
+            .n
+            .map_or(admin::DEFAULT_TOP, |n| n as usize)
+            .min(admin::MAX_TOP);
+        let (requests, denials) = top::top(n);
+        Ok(Response::new(GetTopResponse {
+            window_seconds: top::WINDOW.as_secs(),
+            requests: requests.iter().map(offender).collect(),
+            denials: denials.iter().map(offender).collect(),
+        }))
+    }
+
+    type StreamStatsStream = Pin<Box<dyn Stream<Item = Result<Stats, Status>> + Send>>;
+
+    async fn stream_stats(
+        &self,
+        _: Request<StreamStatsRequest>,
+    ) -> Result<Response<Self::StreamStatsStream>, Status> {
+        let seconds =
+            stats::subscribe().ok_or_else(|| Status::unavailable("Stats are not kept."))?;
+        let stream = futures_util::stream::unfold(seconds, |mut seconds| async move {
+            let second = stats::next(&mut seconds).await?;
+            Some((Ok(second_stats(&second)), seconds))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl AdminService {
+    async fn report_key(
+        &self,
+        request: KeyRequest,
+        reset: bool,
+    ) -> Result<Response<KeyStatus>, Status> {
+        let path = if request.path.is_empty() {
+            "/"
+        } else {
+            &request.path
+        };
+        let report = admin::report_key(&self.state, request.key, path, request.tier, reset)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(key_status(report)))
+    }
+}
+
+/// The gRPC status of an operation refused with `status`.
+fn status((status, message): AdminError) -> Status {
+    let code = match status {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::FailedPrecondition,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, message)
+}
+
+/// The request ID and operator of a call, for the audit log.
+fn caller<T>(request: &Request<T>) -> (Option<String>, Option<String>) {
+    let request_id = request
+        .metadata()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let operator = request
+        .extensions()
+        .get::<ClientCertFingerprint>()
+        .map(|fingerprint| fingerprint.0.clone());
+    (request_id, operator)
+}
+
+fn key_status(report: KeyReport) -> KeyStatus {
+    let rate_limit = report.rate_limit;
+    KeyStatus {
+        temporary_override: report
+            .temporary_override
+            .map(|(limit, left)| TemporaryOverride {
+                limit_json: serde_json::to_string(&*limit).unwrap_or_default(),
+                expires_seconds: left.as_secs(),
+            }),
+        ban: report.ban.as_ref().map(ban),
+        rate_limit: Some(LimitStatus {
+            limit: rate_limit.limit,
+            window_seconds: seconds(rate_limit.window),
+            remaining: rate_limit.remaining,
+            reset_seconds: seconds(rate_limit.reset),
+            count: rate_limit.limit.saturating_sub(rate_limit.remaining),
+        }),
+        quota: report.quota.map(|(quota, period)| QuotaStatus {
+            limit: quota.limit,
+            remaining: quota.remaining,
+            reset_seconds: seconds(quota.reset),
+            period: match period {
+                QuotaPeriod::Day => "day",
+                QuotaPeriod::Month => "month",
+            }
+            .to_string(),
+        }),
+        key: report.key,
+        limited_as: report.limited_as,
+        rule: report.rule,
+        tier: report.tier,
+        exempt: report.exempt,
+        allowlisted: report.allowlisted,
+        denylisted: report.denylisted,
+    }
+}
+
+fn ban(ban: &bans::Ban) -> proto::Ban {
+    let rfc3339 =
+        |time: chrono::DateTime<chrono::Utc>| time.to_rfc3339_opts(SecondsFormat::Secs, true);
+    proto::Ban {
+        id: ban.id.clone(),
+        target: Some(match &ban.target {
+            BanTarget::Key(key) => proto::ban::Target::Key(key.clone()),
+            BanTarget::Cidr(net) => proto::ban::Target::Cidr(net.to_string()),
+        }),
+        reason: ban.reason.clone(),
+        created_at: rfc3339(ban.created_at),
+        expires_at: ban.expires_at.map(rfc3339),
+    }
+}
+
+fn rule(rule: &RouteRule) -> Rule {
+    Rule {
+        name: rule.name.clone(),
+        source: admin::rule_source(&rule.name).to_string(),
+        json: serde_json::to_string(rule).unwrap_or_default(),
+    }
+}
+
+fn offender(offender: &Offender) -> proto::Offender {
+    proto::Offender {
+        key: offender.key.clone(),
+        requests: offender.requests,
+        denials: offender.denials,
+    }
+}
+
+fn second_stats(second: &Second) -> Stats {
+    Stats {
+        time: second.time.clone(),
+        allowed: second.allowed,
+        denied: second.denied,
+        keys: second.keys as u64,
+        store_latency_ms: second
+            .store_latency_ms
+            .as_ref()
+            .map(|latency| proto::Latency {
+                mean: latency.mean,
+                max: latency.max,
+            }),
+    }
+}
@@ -0,0 +1,41 @@
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use super::{Record, Sink};
+
+/// Appends records to a file as JSON lines, synced to disk before a batch
+/// counts as written. The file is only ever appended to, never rotated or
+/// truncated by the server; a batch that failed partway is written again
+/// whole, so its first records may appear twice.
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub async fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("failed to open {}: {}", path, e))?;
+        Ok(Self { file })
+    }
+}
+
+impl Sink for FileSink {
+    async fn write(&mut self, records: &[Record]) -> Result<(), String> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record).map_err(|e| e.to_string())?;
+            lines.push(b'\n');
+        }
+        self.file
+            .write_all(&lines)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.file.sync_data().await.map_err(|e| e.to_string())
+    }
+}
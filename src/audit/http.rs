@@ -0,0 +1,43 @@
+use axum::http::header;
+use std::time::Duration;
+
+use super::{Record, Sink};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts records in batches to an endpoint, as `{"records": [...]}`, which
+/// takes them by answering with a success status.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("failed to build audit HTTP client"),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Sink for HttpSink {
+    async fn write(&mut self, records: &[Record]) -> Result<(), String> {
+        let body = serde_json::json!({ "records": records }).to_string();
+        let response = self
+            .client
+            .post(&self.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Audit log: a record of every request the rate limiter denied, for
+//! compliance, kept by a pluggable sink. Records hold a hash of the key
+//! rather than the key, and the version of the limits that denied it.
+//! Changes to route rules made through the admin API are recorded too.
+//!
+//! Requests only queue records; a background task hands them to the sink in
+//! batches, retrying until it takes them, so a record that was queued is
+//! never lost to a failing sink. When the queue is full, the overflow policy
+//! decides between holding denied requests and dropping their records.
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{future::Future, sync::OnceLock, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::config::{
+    AuditConfig, AuditOverflow, AuditSink, RATE_LIMIT_MODE, RateLimitMode, limits,
+};
+use crate::config_file::RouteRule;
+use crate::metrics;
+
+mod file;
+mod http;
+
+pub use self::file::FileSink;
+pub use self::http::HttpSink;
+
+/// Most records handed to the sink at once.
+const BATCH_SIZE: usize = 500;
+/// Wait before retrying a sink that failed, doubled up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+static AUDIT: OnceLock<Audit> = OnceLock::new();
+
+struct Audit {
+    sender: mpsc::Sender<Record>,
+    overflow: AuditOverflow,
+    salt: Option<String>,
+}
+
+/// What is recorded. Each kind is told apart by its fields, so denials are
+/// recorded as before rule changes were.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Record {
+    Denial(Denial),
+    RuleChange(Box<RuleChange>),
+}
+
+/// A denied request.
+#[derive(Serialize)]
+pub struct Denial {
+    pub timestamp: String,
+    /// SHA-256 of the key the request was limited under, salted if a salt is
+    /// configured.
+    pub key_hash: String,
+    pub rule: String,
+    /// Version of the limits in force, see `Limits::version`.
+    pub config_version: String,
+    pub request_id: Option<String>,
+    /// Whether the request was rejected, rather than let through by shadow
+    /// mode.
+    pub enforced: bool,
+}
+
+/// A route rule created, updated or deleted through the admin API.
+#[derive(Serialize)]
+pub struct RuleChange {
+    pub timestamp: String,
+    /// `created`, `updated` or `deleted`.
+    pub change: &'static str,
+    pub rule: String,
+    /// The rule before and after the change, none where there was none.
+    pub before: Option<RouteRule>,
+    pub after: Option<RouteRule>,
+    /// Version of the limits the change put in force.
+    pub config_version: String,
+    pub request_id: Option<String>,
+    /// SHA-256 fingerprint of the operator's client certificate, on an admin
+    /// listener with mutual TLS.
+    pub operator: Option<String>,
+}
+
+/// Where records are kept. Sinks take a batch whole or fail, in which case
+/// the same batch is handed to them again.
+pub trait Sink: Send + 'static {
+    fn write(&mut self, records: &[Record]) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// Opens the sink of `config` and starts handing it records.
+pub async fn spawn(config: &AuditConfig) -> Result<(), String> {
+    let (sender, receiver) = mpsc::channel(config.buffer);
+    match &config.sink {
+        AuditSink::File { path } => {
+            tokio::spawn(deliver(receiver, FileSink::open(path).await?));
+        }
+        AuditSink::Http { url } => {
+            tokio::spawn(deliver(receiver, HttpSink::new(url)));
+        }
+    }
+    let _ = AUDIT.set(Audit {
+        sender,
+        overflow: config.overflow,
+        salt: config.salt.clone(),
+    });
+    Ok(())
+}
+
+/// Records the denial of a request limited under `key` by `rule`, waiting
+/// for room in the queue if the overflow policy says so.
+pub async fn record(key: &str, rule: &str, config_version: &str, request_id: Option<&str>) {
+    let Some(audit) = AUDIT.get() else { return };
+    let mut hasher = Sha256::new();
+    if let Some(salt) = &audit.salt {
+        hasher.update(salt.as_bytes());
+    }
+    hasher.update(key.as_bytes());
+    let record = Record::Denial(Denial {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        key_hash: hex::encode(hasher.finalize()),
+        rule: rule.to_string(),
+        config_version: config_version.to_string(),
+        request_id: request_id.map(str::to_string),
+        enforced: *RATE_LIMIT_MODE == RateLimitMode::Enforce,
+    });
+    queue(audit, record).await;
+}
+
+/// Records a change of route rule from `before` to `after`, once the limits
+/// it puts in force are.
+pub async fn record_rule_change(
+    before: Option<RouteRule>,
+    after: Option<RouteRule>,
+    request_id: Option<&str>,
+    operator: Option<&str>,
+) {
+    let Some(audit) = AUDIT.get() else { return };
+    let change = match (&before, &after) {
+        (None, _) => "created",
+        (Some(_), Some(_)) => "updated",
+        (Some(_), None) => "deleted",
+    };
+    let Some(rule) = after
+        .as_ref()
+        .or(before.as_ref())
+        .map(|rule| rule.name.clone())
+    else {
+        return;
+    };
+    let record = Record::RuleChange(Box::new(RuleChange {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        change,
+        rule,
+        before,
+        after,
+        config_version: limits().version.clone(),
+        request_id: request_id.map(str::to_string),
+        operator: operator.map(str::to_string),
+    }));
+    queue(audit, record).await;
+}
+
+async fn queue(audit: &Audit, record: Record) {
+    let queued = match audit.overflow {
+        AuditOverflow::Block => audit.sender.send(record).await.is_ok(),
+        AuditOverflow::Drop => audit.sender.try_send(record).is_ok(),
+    };
+    if !queued {
+        metrics::record_dropped_audit_records(1);
+    }
+}
+
+async fn deliver(mut receiver: mpsc::Receiver<Record>, mut sink: impl Sink) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        if receiver.recv_many(&mut batch, BATCH_SIZE).await == 0 {
+            return;
+        }
+        let mut delay = RETRY_DELAY;
+        while let Err(e) = sink.write(&batch).await {
+            tracing::error!(
+                "Failed to write {} audit records, retrying in {:?}: {}",
+                batch.len(),
+                delay,
+                e
+            );
+            metrics::record_audit_write_failure();
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        batch.clear();
+    }
+}
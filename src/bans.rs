@@ -0,0 +1,749 @@
+//! Bans set through the admin API: clients rejected with 403 Forbidden
+//! before the limiter, by address or network, or by key, for a while or
+//! until lifted.
+//!
+//! Bans live in the backing store next to the route rules, under
+//! `config:bans`; each instance sharing it reads them every `SYNC_INTERVAL`,
+//! so a client banned through one is rejected by all of them. The gossip and
+//! cluster backends have no store, so their nodes send each other the bans
+//! they know and the IDs of those lifted instead. With the other backends
+//! keeping their state in process memory, a ban holds on the instance it is
+//! set on, until it restarts.
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{LazyLock, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+use crate::client_ip::{IpSet, parse_cidr, parse_ip};
+use crate::middleware::{RateLimitStateEnum, SharedLimiter};
+
+const STORE_KEY: &str = "config:bans";
+/// Time between two reads of the bans in the store, short so a client
+/// banned on one instance cannot go on at another for long.
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+/// How long stored bans last unless written again; memcached keeps nothing
+/// longer.
+const TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Age past which stored bans are written again, so those lasting until
+/// lifted never expire.
+const REFRESH_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+static BANS: LazyLock<RwLock<Bans>> = LazyLock::new(Default::default);
+/// Held through a change, so two on this instance are not interleaved.
+static CHANGING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Default, Deserialize, Serialize)]
+struct Stored {
+    bans: Vec<StoredBan>,
+    /// Seconds since the epoch.
+    written_at: u64,
+}
+
+/// A ban as stored, with times in milliseconds since the epoch.
+#[derive(Deserialize, Serialize)]
+struct StoredBan {
+    id: String,
+    key: Option<String>,
+    cidr: Option<String>,
+    reason: Option<String>,
+    created_at: i64,
+    expires_at: Option<i64>,
+}
+
+/// A ban lifted, remembered until `until`, in milliseconds since the epoch,
+/// so nodes still holding it do not bring it back.
+#[derive(Deserialize, Serialize)]
+struct LiftedBan {
+    id: String,
+    until: i64,
+}
+
+/// The bans a node knows and those it lifted, as sent to the other nodes of
+/// the gossip and cluster backends. Merging one is idempotent and a lift
+/// wins over the ban, so nodes agree whatever order replicas arrive in.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Replica {
+    bans: Vec<StoredBan>,
+    lifted: Vec<LiftedBan>,
+}
+
+impl Replica {
+    pub fn is_empty(&self) -> bool {
+        self.bans.is_empty() && self.lifted.is_empty()
+    }
+}
+
+/// Who a ban rejects.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BanTarget {
+    /// Clients identified by this key, like `api_key:k-1234`.
+    Key(String),
+    /// Requests from addresses in this network.
+    Cidr(IpNet),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "key {}", key),
+            Self::Cidr(net) => write!(f, "network {}", net),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub id: String,
+    pub target: BanTarget,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the ban lifts by itself, none for bans lasting until lifted.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    fn stored(&self) -> StoredBan {
+        let (key, cidr) = match &self.target {
+            BanTarget::Key(key) => (Some(key.clone()), None),
+            BanTarget::Cidr(net) => (None, Some(net.to_string())),
+        };
+        StoredBan {
+            id: self.id.clone(),
+            key,
+            cidr,
+            reason: self.reason.clone(),
+            created_at: self.created_at.timestamp_millis(),
+            expires_at: self.expires_at.map(|time| time.timestamp_millis()),
+        }
+    }
+
+    fn from_stored(stored: StoredBan) -> Result<Self, String> {
+        let target = match (stored.key, stored.cidr) {
+            (Some(key), None) => BanTarget::Key(key),
+            (None, Some(cidr)) => BanTarget::Cidr(
+                parse_cidr(&cidr).ok_or_else(|| format!("invalid ban network {:?}", cidr))?,
+            ),
+            _ => return Err(format!("ban {} has no single target", stored.id)),
+        };
+        let time = |millis: i64| {
+            DateTime::from_timestamp_millis(millis)
+                .ok_or_else(|| format!("invalid time {} of ban {}", millis, stored.id))
+        };
+        Ok(Self {
+            target,
+            reason: stored.reason,
+            created_at: time(stored.created_at)?,
+            expires_at: stored.expires_at.map(time).transpose()?,
+            id: stored.id,
+        })
+    }
+}
+
+/// The bans, with their networks and keys indexed so requests of clients
+/// that are not banned are let through with a lookup or two.
+#[derive(Default)]
+struct Bans {
+    by_id: HashMap<String, Ban>,
+    networks: IpSet,
+    keys: HashSet<String>,
+    /// IDs of bans lifted on this instance or a peer, with when to forget
+    /// them; only replicated bans need them.
+    lifted: HashMap<String, DateTime<Utc>>,
+}
+
+impl Bans {
+    fn replace(&mut self, bans: Vec<Ban>) {
+        self.by_id = bans.into_iter().map(|ban| (ban.id.clone(), ban)).collect();
+        self.index();
+    }
+
+    fn insert(&mut self, ban: Ban) {
+        self.by_id.insert(ban.id.clone(), ban);
+        self.index();
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Ban> {
+        let ban = self.by_id.remove(id)?;
+        let until = ban
+            .expires_at
+            .unwrap_or_else(|| Utc::now() + chrono::Duration::from_std(TTL).unwrap_or_default());
+        self.lifted.insert(ban.id.clone(), until);
+        self.index();
+        Some(ban)
+    }
+
+    /// Drops the bans that have expired, and the lifts remembered long
+    /// enough.
+    fn prune(&mut self) {
+        let now = Utc::now();
+        let before = self.by_id.len();
+        self.by_id.retain(|_, ban| ban.is_active(now));
+        self.lifted.retain(|_, until| *until > now);
+        if self.by_id.len() != before {
+            self.index();
+        }
+    }
+
+    fn replica(&self) -> Replica {
+        Replica {
+            bans: self.by_id.values().map(Ban::stored).collect(),
+            lifted: self
+                .lifted
+                .iter()
+                .map(|(id, until)| LiftedBan {
+                    id: id.clone(),
+                    until: until.timestamp_millis(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds the bans of `replica` not lifted here, and lifts those lifted
+    /// there, returning how many bans came and went.
+    fn merge(&mut self, replica: Replica) -> usize {
+        let now = Utc::now();
+        let mut changed = 0;
+        for lifted in replica.lifted {
+            let Some(until) = DateTime::from_timestamp_millis(lifted.until) else {
+                continue;
+            };
+            if until <= now {
+                continue;
+            }
+            changed += usize::from(self.by_id.remove(&lifted.id).is_some());
+            let remembered = self.lifted.entry(lifted.id).or_insert(until);
+            *remembered = (*remembered).max(until);
+        }
+        for stored in replica.bans {
+            if self.by_id.contains_key(&stored.id) || self.lifted.contains_key(&stored.id) {
+                continue;
+            }
+            match Ban::from_stored(stored) {
+                Ok(ban) if ban.is_active(now) => {
+                    self.by_id.insert(ban.id.clone(), ban);
+                    changed += 1;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Ignoring a ban from a peer: {}", e),
+            }
+        }
+        if changed > 0 {
+            self.index();
+        }
+        changed
+    }
+
+    fn index(&mut self) {
+        self.networks = self
+            .by_id
+            .values()
+            .filter_map(|ban| match ban.target {
+                BanTarget::Cidr(net) => Some(net),
+                BanTarget::Key(_) => None,
+            })
+            .collect();
+        self.keys = self
+            .by_id
+            .values()
+            .filter_map(|ban| match &ban.target {
+                BanTarget::Key(key) => Some(key.clone()),
+                BanTarget::Cidr(_) => None,
+            })
+            .collect();
+    }
+
+    /// The ban matching `target` that lasts longest, if any is in force.
+    fn find(&self, matches: impl Fn(&BanTarget) -> bool) -> Option<Ban> {
+        let now = Utc::now();
+        self.by_id
+            .values()
+            .filter(|ban| ban.is_active(now) && matches(&ban.target))
+            .max_by_key(|ban| ban.expires_at.unwrap_or(DateTime::<Utc>::MAX_UTC))
+            .cloned()
+    }
+}
+
+/// Bans `target` for `duration`, or until lifted without one, in the store
+/// of `limiter` first if it keeps the bans.
+pub async fn ban(
+    limiter: &RateLimitStateEnum,
+    target: BanTarget,
+    duration: Option<Duration>,
+    reason: Option<String>,
+) -> Result<Ban, String> {
+    let created_at = Utc::now();
+    let ban = Ban {
+        id: format!("{:016x}", rand::random::<u64>()),
+        target,
+        reason,
+        created_at,
+        expires_at: duration.and_then(|duration| {
+            chrono::Duration::from_std(duration)
+                .ok()
+                .and_then(|duration| created_at.checked_add_signed(duration))
+        }),
+    };
+    let _changing = CHANGING.lock().await;
+    let added = ban.clone();
+    let stored = update_stored(limiter, move |mut bans| {
+        bans.push(added.clone());
+        (true, bans, ())
+    })
+    .await;
+    match stored {
+        Some(result) => put_in_force(result?.0),
+        None => BANS
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(ban.clone()),
+    }
+    Ok(ban)
+}
+
+/// Lifts the ban with `id`, in the store of `limiter` first if it keeps the
+/// bans, returning it if there was one.
+pub async fn lift(limiter: &RateLimitStateEnum, id: &str) -> Result<Option<Ban>, String> {
+    let _changing = CHANGING.lock().await;
+    let stored = update_stored(limiter, |mut bans| {
+        let lifted = bans
+            .iter()
+            .position(|ban| ban.id == id)
+            .map(|index| bans.remove(index));
+        (lifted.is_some(), bans, lifted)
+    })
+    .await;
+    match stored {
+        Some(result) => {
+            let (bans, lifted) = result?;
+            put_in_force(bans);
+            Ok(lifted)
+        }
+        None => Ok(BANS.write().unwrap_or_else(|e| e.into_inner()).remove(id)),
+    }
+}
+
+/// Loads the bans kept in the store of `limiter`, then keeps reading them
+/// for bans set and lifted on other instances. With the cluster backend, the
+/// bans are sent to the other nodes instead; gossip carries them itself.
+pub async fn spawn_sync(limiter: SharedLimiter) {
+    let state = limiter.get();
+    if !state.is_shared() && !matches!(state, RateLimitStateEnum::Cluster(_)) {
+        return;
+    }
+    sync(&limiter.get()).await;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            sync(&limiter.get()).await;
+        }
+    });
+}
+
+async fn sync(limiter: &RateLimitStateEnum) {
+    if let RateLimitStateEnum::Cluster(cluster) = limiter {
+        let replica = replica();
+        if !replica.is_empty() {
+            cluster.send_bans(&replica).await;
+        }
+        return;
+    }
+    let now = now_seconds();
+    let stored = limiter
+        .update_shared(STORE_KEY, TTL, |current| {
+            match decode(current).and_then(|stored| Ok((stored.written_at, active(stored.bans)?))) {
+                Ok((written_at, bans)) => {
+                    let age = Duration::from_secs(now.saturating_sub(written_at));
+                    let refresh = current.is_some() && age > REFRESH_AFTER;
+                    (refresh.then(|| encode(&bans)), Ok(bans))
+                }
+                Err(e) => (None, Err(e)),
+            }
+        })
+        .await;
+    let bans = match stored.map(|result| result.and_then(|bans| bans)) {
+        Some(Ok(bans)) => bans,
+        Some(Err(e)) => {
+            tracing::error!("Failed to read the bans from the store: {}", e);
+            return;
+        }
+        None => return,
+    };
+    let unchanged = {
+        let current = BANS.read().unwrap_or_else(|e| e.into_inner());
+        current.by_id.len() == bans.len()
+            && bans.iter().all(|ban| current.by_id.contains_key(&ban.id))
+    };
+    if !unchanged {
+        tracing::info!("Applying {} bans from the store", bans.len());
+        put_in_force(bans);
+    }
+}
+
+/// Runs `f` on the bans in force kept in the store of `limiter`, none if it
+/// keeps none. `f` returns whether it changed them, the bans and a result
+/// handed back; expired bans are dropped whenever the bans are written.
+async fn update_stored<R: Send>(
+    limiter: &RateLimitStateEnum,
+    mut f: impl FnMut(Vec<Ban>) -> (bool, Vec<Ban>, R) + Send,
+) -> Option<Result<(Vec<Ban>, R), String>> {
+    let stored = limiter
+        .update_shared(STORE_KEY, TTL, |current| {
+            match decode(current).and_then(|stored| active(stored.bans)) {
+                Ok(bans) => {
+                    let (changed, bans, result) = f(bans);
+                    (changed.then(|| encode(&bans)), Ok((bans, result)))
+                }
+                Err(e) => (None, Err(e)),
+            }
+        })
+        .await;
+    stored.map(|result| result.and_then(|result| result))
+}
+
+fn put_in_force(bans: Vec<Ban>) {
+    BANS.write()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(bans);
+}
+
+/// The stored bans still in force.
+fn active(stored: Vec<StoredBan>) -> Result<Vec<Ban>, String> {
+    let now = Utc::now();
+    let mut bans = Vec::with_capacity(stored.len());
+    for ban in stored {
+        let ban = Ban::from_stored(ban)?;
+        if ban.is_active(now) {
+            bans.push(ban);
+        }
+    }
+    Ok(bans)
+}
+
+fn decode(value: Option<&[u8]>) -> Result<Stored, String> {
+    match value {
+        Some(value) => serde_json::from_slice(value).map_err(|e| format!("invalid bans: {}", e)),
+        None => Ok(Stored::default()),
+    }
+}
+
+fn encode(bans: &[Ban]) -> Vec<u8> {
+    serde_json::to_vec(&Stored {
+        bans: bans.iter().map(Ban::stored).collect(),
+        written_at: now_seconds(),
+    })
+    .unwrap_or_default()
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The bans in force and those lifted, for the other nodes.
+pub fn replica() -> Replica {
+    let mut bans = BANS.write().unwrap_or_else(|e| e.into_inner());
+    bans.prune();
+    bans.replica()
+}
+
+/// Puts in force the bans of another node, and lifts those it lifted.
+pub fn merge(replica: Replica) {
+    let changed = BANS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .merge(replica);
+    if changed > 0 {
+        tracing::info!("Applied {} ban changes from a peer", changed);
+    }
+}
+
+/// The bans in force, those expiring first first.
+pub fn bans() -> Vec<Ban> {
+    let now = Utc::now();
+    let mut bans: Vec<Ban> = BANS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .by_id
+        .values()
+        .filter(|ban| ban.is_active(now))
+        .cloned()
+        .collect();
+    bans.sort_by_key(|ban| {
+        (
+            ban.expires_at.unwrap_or(DateTime::<Utc>::MAX_UTC),
+            ban.created_at,
+        )
+    });
+    bans
+}
+
+/// The ban in force on requests from `ip`, if any.
+pub fn ip_ban(ip: &str) -> Option<Ban> {
+    let addr = parse_ip(ip)?;
+    lookup(
+        |bans| bans.networks.contains(addr),
+        |target| matches!(target, BanTarget::Cidr(net) if net.contains(&addr)),
+    )
+}
+
+/// The ban in force on the client identified by `key`, if any.
+pub fn key_ban(key: &str) -> Option<Ban> {
+    lookup(
+        |bans| bans.keys.contains(key),
+        |target| matches!(target, BanTarget::Key(banned) if banned == key),
+    )
+}
+
+/// Finds the ban matching `target` once the index says there may be one,
+/// pruning expired bans when only those matched.
+fn lookup(indexed: impl Fn(&Bans) -> bool, target: impl Fn(&BanTarget) -> bool) -> Option<Ban> {
+    let bans = BANS.read().unwrap_or_else(|e| e.into_inner());
+    if !indexed(&bans) {
+        return None;
+    }
+    let ban = bans.find(target);
+    drop(bans);
+    if ban.is_none() {
+        BANS.write().unwrap_or_else(|e| e.into_inner()).prune();
+    }
+    ban
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStore, SqliteStore};
+
+    /// Held by the tests changing the bans in force, as `put_in_force`
+    /// replaces them all.
+    static SERIAL: Mutex<()> = Mutex::const_new(());
+
+    fn local_ban(id: &str, key: &str, expires_in: Option<i64>) -> Ban {
+        let created_at = Utc::now();
+        Ban {
+            id: id.to_string(),
+            target: BanTarget::Key(key.to_string()),
+            reason: None,
+            created_at,
+            expires_at: expires_in.map(|seconds| created_at + chrono::Duration::seconds(seconds)),
+        }
+    }
+
+    #[test]
+    fn replicas_converge_and_lifts_win() {
+        let mut a = Bans::default();
+        let mut b = Bans::default();
+        a.insert(local_ban("1", "api_key:one", None));
+        b.insert(local_ban("2", "api_key:two", Some(60)));
+
+        assert_eq!(b.merge(a.replica()), 1);
+        assert_eq!(a.merge(b.replica()), 1);
+        assert!(a.keys.contains("api_key:two") && b.keys.contains("api_key:one"));
+        // Merging again changes nothing.
+        assert_eq!(a.merge(b.replica()), 0);
+
+        // A lift on one node is not undone by the other still holding the ban.
+        assert!(b.remove("1").is_some());
+        assert_eq!(b.merge(a.replica()), 0);
+        assert!(!b.keys.contains("api_key:one"));
+        assert_eq!(a.merge(b.replica()), 1);
+        assert!(!a.keys.contains("api_key:one"));
+        assert!(
+            a.find(|target| *target == BanTarget::Key("api_key:one".into()))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn expired_bans_and_lifts_are_not_merged() {
+        let mut a = Bans::default();
+        a.insert(local_ban("1", "api_key:one", Some(-1)));
+        a.lifted
+            .insert("2".into(), Utc::now() - chrono::Duration::seconds(1));
+        let mut b = Bans::default();
+        b.insert(local_ban("2", "api_key:two", None));
+
+        assert_eq!(b.merge(a.replica()), 0);
+        assert!(!b.by_id.contains_key("1") && b.by_id.contains_key("2"));
+
+        a.prune();
+        assert!(a.by_id.is_empty() && a.lifted.is_empty());
+        assert!(a.replica().is_empty());
+    }
+
+    #[test]
+    fn replicas_survive_serialization() {
+        let mut a = Bans::default();
+        a.insert(local_ban("1", "api_key:one", Some(60)));
+        a.insert(local_ban("2", "api_key:two", None));
+        a.remove("2");
+        let replica: Replica =
+            serde_json::from_slice(&serde_json::to_vec(&a.replica()).unwrap()).unwrap();
+
+        let mut b = Bans::default();
+        b.insert(local_ban("2", "api_key:two", None));
+        assert_eq!(b.merge(replica), 2);
+        assert_eq!(b.by_id.keys().collect::<Vec<_>>(), ["1"]);
+    }
+
+    fn in_memory() -> RateLimitStateEnum {
+        RateLimitStateEnum::MemoryStore(MemoryStore::new())
+    }
+
+    fn key(key: &str) -> BanTarget {
+        BanTarget::Key(key.to_string())
+    }
+
+    fn cidr(cidr: &str) -> BanTarget {
+        BanTarget::Cidr(cidr.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn keys_are_banned_until_lifted() {
+        let _serial = SERIAL.lock().await;
+        let limiter = in_memory();
+        let ban = ban(
+            &limiter,
+            key("api_key:banned-1"),
+            None,
+            Some("scraping".into()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ban.expires_at, None);
+        let found = key_ban("api_key:banned-1").unwrap();
+        assert_eq!(found.id, ban.id);
+        assert_eq!(found.reason.as_deref(), Some("scraping"));
+        assert!(key_ban("api_key:banned-10").is_none());
+        assert!(bans().iter().any(|listed| listed.id == ban.id));
+
+        assert_eq!(lift(&limiter, &ban.id).await.unwrap().unwrap().id, ban.id);
+        assert!(key_ban("api_key:banned-1").is_none());
+        assert!(lift(&limiter, &ban.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn networks_ban_their_addresses_until_they_expire() {
+        let _serial = SERIAL.lock().await;
+        let limiter = in_memory();
+        let v4 = ban(
+            &limiter,
+            cidr("198.51.100.0/24"),
+            Some(Duration::from_millis(50)),
+            None,
+        )
+        .await
+        .unwrap();
+        let v6 = ban(&limiter, cidr("2001:db8:1::/48"), None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(ip_ban("198.51.100.7").unwrap().id, v4.id);
+        assert_eq!(ip_ban("2001:db8:1:2::7").unwrap().id, v6.id);
+        assert!(ip_ban("198.51.101.7").is_none());
+        assert!(ip_ban("not an address").is_none());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(ip_ban("198.51.100.7").is_none());
+        assert!(bans().iter().all(|listed| listed.id != v4.id));
+        lift(&limiter, &v6.id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_longest_ban_is_reported_and_bans_are_listed_expiring_first() {
+        let _serial = SERIAL.lock().await;
+        let limiter = in_memory();
+        let hour = ban(
+            &limiter,
+            key("api_key:banned-2"),
+            Some(Duration::from_secs(3600)),
+            None,
+        )
+        .await
+        .unwrap();
+        let lasting = ban(&limiter, key("api_key:banned-2"), None, None)
+            .await
+            .unwrap();
+        let minute = ban(
+            &limiter,
+            key("api_key:banned-2"),
+            Some(Duration::from_secs(60)),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(key_ban("api_key:banned-2").unwrap().id, lasting.id);
+        let listed: Vec<String> = bans()
+            .into_iter()
+            .map(|ban| ban.id)
+            .filter(|id| [&hour.id, &lasting.id, &minute.id].contains(&id))
+            .collect();
+        assert_eq!(
+            listed,
+            [minute.id.clone(), hour.id.clone(), lasting.id.clone()]
+        );
+
+        lift(&limiter, &lasting.id).await.unwrap();
+        assert_eq!(key_ban("api_key:banned-2").unwrap().id, hour.id);
+        for ban in [hour, minute] {
+            lift(&limiter, &ban.id).await.unwrap();
+        }
+        assert!(key_ban("api_key:banned-2").is_none());
+    }
+
+    #[tokio::test]
+    async fn bans_are_kept_in_shared_stores() {
+        let _serial = SERIAL.lock().await;
+        let path = std::env::temp_dir().join(format!("bans-{:016x}.db", rand::random::<u64>()));
+        let store = SqliteStore::open(path.to_str().unwrap()).await.unwrap();
+        let limiter = RateLimitStateEnum::SqliteStore(store);
+        let stored = |limiter: &RateLimitStateEnum| {
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .update_shared(STORE_KEY, TTL, |current| (None, decode(current)))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap()
+                    .bans
+                    .into_iter()
+                    .map(|ban| ban.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let ban = ban(&limiter, key("api_key:banned-3"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(stored(&limiter).await, [ban.id.as_str()]);
+        assert!(key_ban("api_key:banned-3").is_some());
+
+        // An instance starting with the bans of the store puts them in force.
+        put_in_force(Vec::new());
+        assert!(key_ban("api_key:banned-3").is_none());
+        sync(&limiter).await;
+        assert_eq!(key_ban("api_key:banned-3").unwrap().id, ban.id);
+
+        lift(&limiter, &ban.id).await.unwrap();
+        assert!(stored(&limiter).await.is_empty());
+        assert!(key_ban("api_key:banned-3").is_none());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,210 @@
+//! Benchmark harness running the same workload against every limiter backend.
+//!
+//! Run with `cargo run --release --features bench -- bench`. The workload is
+//! shaped by `BENCH_REQUESTS`, `BENCH_KEYS` and `BENCH_CONCURRENCY`, and the
+//! limits come from the usual `RATE_LIMIT_*` variables. Redis and the hybrid
+//! backend are included when `BENCH_REDIS_URL` is set.
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+use crate::config::{HYBRID_SYNC_MS, RATE_LIMIT_ALGORITHM, REDIS_CONFIG, RedisConfig, limits};
+use crate::rate_limiter::{
+    LockFreeRateLimitState, LockFreeSlidingWindowRateLimiter, RateLimiter,
+    SlidingWindowRateLimiter, StoreRateLimiter,
+};
+use crate::storage::{
+    HybridRateLimitState, HybridRateLimiter, MemoryStore, RedisRateLimitState, RedisRateLimiter,
+};
+
+const DEFAULT_REQUESTS: usize = 100_000;
+const DEFAULT_KEYS: usize = 1_000;
+const DEFAULT_CONCURRENCY: usize = 32;
+
+struct Workload {
+    requests: usize,
+    keys: usize,
+    concurrency: usize,
+}
+
+impl Workload {
+    fn from_env() -> Self {
+        let var = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(default)
+        };
+        Self {
+            requests: var("BENCH_REQUESTS", DEFAULT_REQUESTS),
+            keys: var("BENCH_KEYS", DEFAULT_KEYS),
+            concurrency: var("BENCH_CONCURRENCY", DEFAULT_CONCURRENCY),
+        }
+    }
+
+    /// Requests the limiter should admit if it counted perfectly, assuming the
+    /// whole run fits in one window.
+    fn expected_allowed(&self) -> usize {
+        let per_key = self.requests / self.keys;
+        let remainder = self.requests % self.keys;
+        let max = limits().default.max_requests as usize;
+        (0..self.keys)
+            .map(|i| (per_key + usize::from(i < remainder)).min(max))
+            .sum()
+    }
+}
+
+struct BenchResult {
+    backend: &'static str,
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+    allowed: usize,
+}
+
+impl BenchResult {
+    fn percentile(&self, p: f64) -> Duration {
+        let index = ((self.latencies.len() as f64 - 1.0) * p).round() as usize;
+        self.latencies[index]
+    }
+}
+
+async fn backends() -> Vec<(&'static str, Arc<dyn RateLimiter>)> {
+    let config = limits().default.clone();
+    let mut backends: Vec<(&'static str, Arc<dyn RateLimiter>)> = vec![
+        (
+            "standard",
+            Arc::new(SlidingWindowRateLimiter::new(
+                Arc::new(RwLock::new(HashMap::new())),
+                config.clone(),
+            )),
+        ),
+        (
+            "lock_free",
+            Arc::new(LockFreeSlidingWindowRateLimiter::new(
+                LockFreeRateLimitState::new().requests,
+                config.clone(),
+            )),
+        ),
+        (
+            "memory_store",
+            Arc::new(StoreRateLimiter::new(
+                MemoryStore::new(),
+                *RATE_LIMIT_ALGORITHM,
+                config.clone(),
+            )),
+        ),
+    ];
+
+    if let Ok(url) = env::var("BENCH_REDIS_URL") {
+        let redis_config = RedisConfig {
+            url,
+            key_prefix: format!("rate_limit_bench:{}:", rand::random::<u32>()),
+            ..REDIS_CONFIG.clone()
+        };
+        let state = RedisRateLimitState::connect(&redis_config)
+            .await
+            .expect("failed to connect to Redis");
+        let hybrid = HybridRateLimitState::new(state.clone());
+        hybrid.spawn_sync(Duration::from_millis(*HYBRID_SYNC_MS));
+        backends.push((
+            "redis",
+            Arc::new(RedisRateLimiter::new(state, config.clone())),
+        ));
+        backends.push((
+            "hybrid",
+            Arc::new(HybridRateLimiter::new(hybrid, config.clone())),
+        ));
+    }
+
+    backends
+}
+
+async fn run_workload(
+    backend: &'static str,
+    limiter: Arc<dyn RateLimiter>,
+    workload: &Workload,
+) -> BenchResult {
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(workload.concurrency);
+
+    for worker in 0..workload.concurrency {
+        let limiter = limiter.clone();
+        let keys = workload.keys;
+        let requests: Vec<usize> = (worker..workload.requests)
+            .step_by(workload.concurrency)
+            .collect();
+
+        tasks.push(tokio::spawn(async move {
+            let mut latencies = Vec::with_capacity(requests.len());
+            let mut allowed = 0;
+            for request in requests {
+                let key = format!("bench-{}", request % keys);
+                let start = Instant::now();
+                if limiter.check_rate_limit(&key).await.is_ok() {
+                    limiter.record_request(&key).await;
+                    allowed += 1;
+                }
+                latencies.push(start.elapsed());
+            }
+            (latencies, allowed)
+        }));
+    }
+
+    let mut latencies = Vec::with_capacity(workload.requests);
+    let mut allowed = 0;
+    for task in tasks {
+        let (task_latencies, task_allowed) = task.await.expect("benchmark task panicked");
+        latencies.extend(task_latencies);
+        allowed += task_allowed;
+    }
+    latencies.sort();
+
+    BenchResult {
+        backend,
+        elapsed: started.elapsed(),
+        latencies,
+        allowed,
+    }
+}
+
+pub async fn run() {
+    let workload = Workload::from_env();
+    let expected = workload.expected_allowed();
+
+    println!(
+        "workload: {} requests, {} keys, {} workers, limit {}",
+        workload.requests,
+        workload.keys,
+        workload.concurrency,
+        limits().default
+    );
+    println!();
+    println!(
+        "{:<12} {:>14} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "backend", "throughput/s", "p50 (us)", "p99 (us)", "allowed", "expected", "accuracy"
+    );
+
+    for (name, limiter) in backends().await {
+        let result = run_workload(name, limiter, &workload).await;
+        let throughput = workload.requests as f64 / result.elapsed.as_secs_f64();
+        // Over- and under-admission both count against accuracy.
+        let error = result.allowed.abs_diff(expected) as f64 / expected.max(1) as f64;
+
+        println!(
+            "{:<12} {:>14.0} {:>10} {:>10} {:>10} {:>10} {:>9.2}%",
+            result.backend,
+            throughput,
+            result.percentile(0.50).as_micros(),
+            result.percentile(0.99).as_micros(),
+            result.allowed,
+            expected,
+            (1.0 - error).max(0.0) * 100.0
+        );
+    }
+}
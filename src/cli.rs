@@ -0,0 +1,62 @@
+//! Command line flags, which take precedence over the environment variables
+//! and the config file.
+
+use clap::{Parser, Subcommand};
+use std::net::IpAddr;
+use std::sync::{LazyLock, OnceLock};
+
+use crate::config::RateLimiterType;
+
+static PARSED: OnceLock<Args> = OnceLock::new();
+
+/// The flags the server was started with. The limiter embedded in another
+/// program takes none, as the command line is that program's.
+pub static ARGS: LazyLock<Args> = LazyLock::new(|| PARSED.get().cloned().unwrap_or_default());
+
+/// Parses the command line, exiting on `--help` and bad flags.
+pub fn parse() {
+    let _ = PARSED.set(Args::parse());
+    LazyLock::force(&ARGS);
+}
+
+#[derive(Parser, Clone, Debug, Default)]
+#[command(version)]
+pub struct Args {
+    /// Config file to load, TOML or YAML by extension
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Address to listen on, overriding the host of LISTEN_ADDR
+    #[arg(long, value_name = "IP")]
+    pub bind: Option<IpAddr>,
+
+    /// Port to listen on, overriding the port of LISTEN_ADDR
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Limiter implementation, like RATE_LIMITER_TYPE
+    #[arg(long, value_enum)]
+    pub limiter: Option<RateLimiterType>,
+
+    /// Requests allowed per window by default, like RATE_LIMIT_MAX_REQUESTS
+    #[arg(long)]
+    pub max_requests: Option<u32>,
+
+    /// Length of the default window in seconds, like RATE_LIMIT_WINDOW_SECONDS
+    #[arg(long, value_name = "SECONDS")]
+    pub window: Option<u64>,
+
+    /// Length of the default window in milliseconds, like RATE_LIMIT_WINDOW_MS
+    #[arg(long, value_name = "MS", conflicts_with = "window")]
+    pub window_ms: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Run the benchmark harness instead of the server
+    #[cfg(feature = "bench")]
+    Bench,
+}
@@ -0,0 +1,429 @@
+use axum::{body::Body, extract::ConnectInfo, http::Request};
+use ipnet::IpNet;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeSet, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::{CLIENT_IP_HEADERS, ClientIpHeader, SUBNET_AGGREGATION, TRUSTED_PROXIES};
+
+/// Returns the address of the client that sent the request.
+///
+/// The hops are read from the first header in `CLIENT_IP_HEADERS` present on
+/// the request, and only believed when the connected peer is a trusted
+/// proxy. They are walked right to left skipping trusted ones, so clients
+/// cannot spoof their address by prepending entries. Without trusted proxies
+/// configured the headers are ignored and the peer is the client, as the
+/// allowlist and denylist are checked against this address.
+pub fn client_ip(req: &Request<Body>) -> Option<String> {
+    resolve(req, &CLIENT_IP_HEADERS, &TRUSTED_PROXIES)
+}
+
+/// [`client_ip`] with the headers read, in order, and the proxies trusted.
+fn resolve(req: &Request<Body>, headers: &[ClientIpHeader], trusted: &[IpNet]) -> Option<String> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(&ip));
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    let forwarded_for = headers
+        .iter()
+        .map(|header| match header {
+            ClientIpHeader::XForwardedFor => x_forwarded_for(req),
+            ClientIpHeader::Forwarded => forwarded(req),
+            ClientIpHeader::XRealIp => x_real_ip(req),
+        })
+        .find(|hops| !hops.is_empty())
+        .unwrap_or_default();
+
+    if let Some(peer) = peer.filter(|ip| !is_trusted(*ip)) {
+        return Some(peer.to_string());
+    }
+
+    // Only parsed addresses are returned, so what clients write in the
+    // headers never becomes a key of its own.
+    let mut client = peer;
+    for hop in forwarded_for.iter().rev() {
+        match parse_ip(hop) {
+            Some(ip) if is_trusted(ip) => client = Some(ip),
+            Some(ip) => return Some(ip.to_string()),
+            // Garbage can only come from the client itself, so the last
+            // trusted hop is the best we know.
+            None => break,
+        }
+    }
+    client.map(|ip| ip.to_string())
+}
+
+fn header_values<'a>(req: &'a Request<Body>, name: &str) -> impl Iterator<Item = &'a str> {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+}
+
+fn x_forwarded_for(req: &Request<Body>) -> Vec<&str> {
+    header_values(req, "x-forwarded-for")
+        .flat_map(|v| v.split(','))
+        .map(|hop| strip_port(hop.trim()))
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+/// Reads the `for=` parameters of an RFC 7239 `Forwarded` header, e.g.
+/// `for=192.0.2.60;proto=http, for="[2001:db8::17]:4711"`.
+fn forwarded(req: &Request<Body>) -> Vec<&str> {
+    header_values(req, "forwarded")
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| strip_port(value.trim().trim_matches('"')))
+            })
+        })
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+fn x_real_ip(req: &Request<Body>) -> Vec<&str> {
+    header_values(req, "x-real-ip")
+        .map(|hop| strip_port(hop.trim()))
+        .filter(|hop| !hop.is_empty())
+        .take(1)
+        .collect()
+}
+
+/// Strips the port from `1.2.3.4:80` and the brackets and port from
+/// `[2001:db8::1]:80`, leaving bare IPv6 addresses untouched.
+fn strip_port(value: &str) -> &str {
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match value.split_once(':') {
+        Some((host, port)) if !port.contains(':') => host,
+        _ => value,
+    }
+}
+
+/// Maps a client address to the key it is limited under.
+///
+/// Addresses are canonicalized first, so `0:0:0:0:0:0:0:1` and `::1` or
+/// `::ffff:192.0.2.1` and `192.0.2.1` share a bucket, then truncated to the
+/// configured subnet, e.g. `203.0.113.7` to `203.0.113.0/24`. Values that are
+/// not IP addresses are returned unchanged.
+pub fn ip_key(ip: &str) -> String {
+    let Some(addr) = parse_ip(ip) else {
+        return ip.to_string();
+    };
+
+    let (prefix, max_prefix) = match addr {
+        IpAddr::V4(_) => (SUBNET_AGGREGATION.ipv4_prefix, 32),
+        IpAddr::V6(_) => (SUBNET_AGGREGATION.ipv6_prefix, 128),
+    };
+    if prefix == max_prefix {
+        return addr.to_string();
+    }
+    IpNet::new(addr, prefix)
+        .map(|net| net.trunc().to_string())
+        .unwrap_or_else(|_| addr.to_string())
+}
+
+/// Parses an address, dropping any IPv6 zone (`fe80::1%eth0`) and unwrapping
+/// IPv4-mapped IPv6 addresses.
+pub fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.split('%').next().unwrap_or(ip);
+    ip.parse::<IpAddr>().ok().map(|addr| addr.to_canonical())
+}
+
+/// Whether the client is on the allowlist and exempt from rate limiting.
+pub fn is_allowlisted(allowlist: &IpSet, ip: &str) -> bool {
+    parse_ip(ip).is_some_and(|ip| allowlist.contains(ip))
+}
+
+/// Whether the client is on the denylist and rejected outright.
+pub fn is_denylisted(denylist: &IpSet, ip: &str) -> bool {
+    parse_ip(ip).is_some_and(|ip| denylist.contains(ip))
+}
+
+/// A set of networks that looks addresses up with one hash lookup per
+/// distinct prefix length rather than a scan over every network, so long
+/// lists of partner ranges stay cheap to check on every request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IpSet {
+    nets: HashSet<IpNet>,
+    ipv4_prefixes: BTreeSet<u8>,
+    ipv6_prefixes: BTreeSet<u8>,
+}
+
+impl IpSet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let prefixes = match ip {
+            IpAddr::V4(_) => &self.ipv4_prefixes,
+            IpAddr::V6(_) => &self.ipv6_prefixes,
+        };
+        prefixes
+            .iter()
+            .any(|&prefix| IpNet::new(ip, prefix).is_ok_and(|net| self.nets.contains(&net.trunc())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+}
+
+/// Serialized as the sorted list of its networks.
+impl Serialize for IpSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut nets: Vec<&IpNet> = self.nets.iter().collect();
+        nets.sort();
+        serializer.collect_seq(nets.iter().map(|net| net.to_string()))
+    }
+}
+
+impl FromIterator<IpNet> for IpSet {
+    fn from_iter<T: IntoIterator<Item = IpNet>>(nets: T) -> Self {
+        let mut set = Self::default();
+        for net in nets {
+            match net {
+                IpNet::V4(_) => set.ipv4_prefixes.insert(net.prefix_len()),
+                IpNet::V6(_) => set.ipv6_prefixes.insert(net.prefix_len()),
+            };
+            set.nets.insert(net.trunc());
+        }
+        set
+    }
+}
+
+/// Parses a CIDR, accepting a bare address as a single-host network.
+pub fn parse_cidr(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XFF: &[ClientIpHeader] = &[ClientIpHeader::XForwardedFor];
+
+    fn request(peer: &str, headers: &[(&'static str, &str)]) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        for (name, value) in headers {
+            req.headers_mut()
+                .append(*name, value.parse().expect("valid header value"));
+        }
+        let peer = SocketAddr::new(peer.parse().expect("valid peer"), 40000);
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    }
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client_whatever_it_forwards() {
+        let req = request("203.0.113.9", &[("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn hops_prepended_by_the_client_are_ignored() {
+        let req = request(
+            "10.0.0.1",
+            &[("x-forwarded-for", "192.0.2.66, 198.51.100.7")],
+        );
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped_right_to_left() {
+        let req = request(
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "192.0.2.66, 198.51.100.7"),
+                ("x-forwarded-for", "10.0.0.3, 10.0.0.2"),
+            ],
+        );
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn garbage_hop_leaves_the_last_trusted_one() {
+        let req = request(
+            "10.0.0.1",
+            &[("x-forwarded-for", "198.51.100.7, unknown, 10.0.0.2")],
+        );
+        assert_eq!(resolve(&req, XFF, &proxies()).as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn hops_that_are_not_addresses_leave_the_peer() {
+        for value in ["aaaaaaaaaaaaaaaa", "unknown", "_hidden", "198.51.100.7.1"] {
+            let req = request("10.0.0.1", &[("x-forwarded-for", value)]);
+            assert_eq!(resolve(&req, XFF, &proxies()).as_deref(), Some("10.0.0.1"));
+            let req = request("10.0.0.1", &[("x-real-ip", value)]);
+            assert_eq!(
+                resolve(&req, &[ClientIpHeader::XRealIp], &proxies()).as_deref(),
+                Some("10.0.0.1")
+            );
+        }
+    }
+
+    #[test]
+    fn ports_and_brackets_are_stripped_from_hops() {
+        let req = request(
+            "10.0.0.1",
+            &[("x-forwarded-for", "[2001:db8::17]:4711, 198.51.100.7:8080")],
+        );
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("198.51.100.7")
+        );
+        let req = request("10.0.0.1", &[("x-forwarded-for", "[2001:db8::17]:4711")]);
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("2001:db8::17")
+        );
+        let req = request("10.0.0.1", &[("x-forwarded-for", "2001:db8::17")]);
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("2001:db8::17")
+        );
+    }
+
+    #[test]
+    fn only_trusted_hops_leave_the_first_of_them() {
+        let req = request("10.0.0.1", &[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(resolve(&req, XFF, &proxies()).as_deref(), Some("10.0.0.3"));
+    }
+
+    #[test]
+    fn trusted_peer_without_hops_is_the_client() {
+        let req = request("10.0.0.1", &[]);
+        assert_eq!(resolve(&req, XFF, &proxies()).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn hops_are_ignored_without_trusted_proxies() {
+        let req = request(
+            "203.0.113.9",
+            &[("x-forwarded-for", "192.0.2.66, 198.51.100.7")],
+        );
+        assert_eq!(resolve(&req, XFF, &[]).as_deref(), Some("203.0.113.9"));
+        assert_eq!(
+            resolve(&request("203.0.113.9", &[]), XFF, &[]).as_deref(),
+            Some("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn headers_are_read_in_the_configured_order() {
+        let req = request(
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "198.51.100.7"),
+                ("forwarded", "for=\"[2001:db8::17]:4711\";proto=https"),
+                ("x-real-ip", "192.0.2.1"),
+            ],
+        );
+        let order = [ClientIpHeader::Forwarded, ClientIpHeader::XForwardedFor];
+        assert_eq!(
+            resolve(&req, &order, &proxies()).as_deref(),
+            Some("2001:db8::17")
+        );
+        let order = [ClientIpHeader::XRealIp, ClientIpHeader::Forwarded];
+        assert_eq!(
+            resolve(&req, &order, &proxies()).as_deref(),
+            Some("192.0.2.1")
+        );
+    }
+
+    fn ip_set(nets: &[&str]) -> IpSet {
+        nets.iter().filter_map(|net| parse_cidr(net)).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ip_set_matches_addresses_inside_its_networks() {
+        let set = ip_set(&[
+            "10.0.0.0/8",
+            "192.0.2.0/24",
+            "198.51.100.7",
+            "2001:db8::/32",
+        ]);
+        assert!(set.contains(ip("10.255.0.1")));
+        assert!(set.contains(ip("192.0.2.0")));
+        assert!(set.contains(ip("192.0.2.255")));
+        assert!(set.contains(ip("198.51.100.7")));
+        assert!(set.contains(ip("2001:db8:ffff::1")));
+        assert!(!set.contains(ip("11.0.0.1")));
+        assert!(!set.contains(ip("192.0.3.0")));
+        assert!(!set.contains(ip("198.51.100.8")));
+        assert!(!set.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ip_set_keeps_families_apart() {
+        let set = ip_set(&["0.0.0.0/0"]);
+        assert!(set.contains(ip("203.0.113.9")));
+        assert!(!set.contains(ip("2001:db8::1")));
+        assert!(set.contains(parse_ip("::ffff:203.0.113.9").unwrap()));
+    }
+
+    #[test]
+    fn ip_set_truncates_networks_with_host_bits() {
+        let set = ip_set(&["192.0.2.77/24"]);
+        assert!(set.contains(ip("192.0.2.1")));
+        assert_eq!(set, ip_set(&["192.0.2.0/24"]));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn ip_set_matches_overlapping_networks_of_any_length() {
+        let set = ip_set(&["10.1.2.0/24", "10.0.0.0/8", "10.1.0.0/16"]);
+        assert!(set.contains(ip("10.1.2.3")));
+        assert!(set.contains(ip("10.9.9.9")));
+        assert!(ip_set(&[]).is_empty());
+        assert!(!ip_set(&[]).contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn lists_check_canonical_addresses() {
+        let set = ip_set(&["192.0.2.0/24", "fe80::/10"]);
+        assert!(is_allowlisted(&set, "::ffff:192.0.2.1"));
+        assert!(is_denylisted(&set, "fe80::1%eth0"));
+        assert!(!is_denylisted(&set, "not an address"));
+    }
+
+    #[test]
+    fn mapped_addresses_are_canonicalized() {
+        let req = request(
+            "::ffff:10.0.0.1",
+            &[("x-forwarded-for", "::ffff:198.51.100.7")],
+        );
+        assert_eq!(
+            resolve(&req, XFF, &proxies()).as_deref(),
+            Some("198.51.100.7")
+        );
+    }
+}
@@ -1,13 +1,102 @@
-use std::env;
-use std::sync::LazyLock;
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::{self, Display};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::ARGS;
+use crate::client_ip::{IpSet, parse_cidr};
+use crate::config_file::{FileConfig, RouteRule, Schedule, path_matches};
+use crate::rules;
 
 const DEFAULT_MAX_REQUESTS: u32 = 3;
+const DEFAULT_LOG_LEVEL: &str = "info";
 const DEFAULT_WINDOW_SECONDS: u64 = 5;
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379/";
+const DEFAULT_REDIS_KEY_PREFIX: &str = "rate_limit:";
+const DEFAULT_REDIS_SENTINEL_MASTER: &str = "mymaster";
+const DEFAULT_HYBRID_SYNC_MS: u64 = 100;
+const DEFAULT_MEMCACHED_SERVERS: &str = "127.0.0.1:11211";
+const DEFAULT_MEMCACHED_KEY_PREFIX: &str = "rate_limit:";
+const DEFAULT_MEMCACHED_TIMEOUT_MS: u64 = 500;
+const DEFAULT_DYNAMODB_TABLE: &str = "rate_limit";
+const DEFAULT_DYNAMODB_REGION: &str = "us-east-1";
+const DEFAULT_DYNAMODB_KEY_PREFIX: &str = "rate_limit:";
+const DEFAULT_DYNAMODB_TIMEOUT_MS: u64 = 500;
+const DEFAULT_SQLITE_PATH: &str = "rate_limit.db";
+const DEFAULT_SQLITE_FLUSH_MS: u64 = 1000;
+const DEFAULT_SNAPSHOT_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_EVICTION_INTERVAL_SECONDS: u64 = 60;
+const DEFAULT_EVICTION_SLACK_SECONDS: u64 = 60;
+const DEFAULT_GOSSIP_PORT: u16 = 7946;
+const DEFAULT_GOSSIP_INTERVAL_MS: u64 = 200;
+const DEFAULT_CLUSTER_TIMEOUT_MS: u64 = 200;
+const DEFAULT_CONFIG_WATCH_SECONDS: u64 = 2;
+const DEFAULT_CONSUL_URL: &str = "http://127.0.0.1:8500";
+const DEFAULT_ETCD_URL: &str = "http://127.0.0.1:2379";
+const DEFAULT_CONFIG_KV_KEY: &str = "rate_limit/config.toml";
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:3000";
+const DEFAULT_KAFKA_BROKERS: &str = "127.0.0.1:9092";
+const DEFAULT_NATS_URL: &str = "nats://127.0.0.1:4222";
+const DEFAULT_EVENTS_TOPIC: &str = "rate_limit.decisions";
+const DEFAULT_EVENTS_BUFFER: usize = 10_000;
+const DEFAULT_STATSD_PREFIX: &str = "rate_limit";
+const DEFAULT_STATSD_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_WEBHOOK_COOLDOWN_SECONDS: u64 = 300;
+const DEFAULT_AUDIT_BUFFER: usize = 10_000;
+const DEFAULT_POSTGRES_URL: &str = "postgres://localhost/rate_limit";
+const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+const DEFAULT_SESSION_COOKIE: &str = "session_id";
+const DEFAULT_QUERY_KEY_MAX_LENGTH: usize = 128;
+const DEFAULT_BODY_KEY_MAX_BYTES: usize = 64 * 1024;
+const DEFAULT_TIER_LOOKUP_TIMEOUT_MS: u64 = 500;
+const DEFAULT_TIER_CACHE_TTL_SECONDS: u64 = 300;
+const DEFAULT_TIER_CACHE_MAX_KEYS: usize = 10_000;
+const DEFAULT_TIER_LOOKUPS_PER_SECOND: u32 = 50;
+const DEFAULT_OVERRIDE_CACHE_TTL_SECONDS: u64 = 30;
+const DEFAULT_OVERRIDE_CACHE_MAX_KEYS: usize = 100_000;
+const DEFAULT_JWT_CLAIM: &str = "sub";
+const DEFAULT_JWKS_REFRESH_SECONDS: u64 = 300;
+const DEFAULT_IPV4_PREFIX: u8 = 24;
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+const DEFAULT_DENYLIST_REFRESH_SECONDS: u64 = 60;
+const DEFAULT_EXEMPT_PATHS: &[&str] = &["/metrics"];
+const DEFAULT_THROTTLE_MAX_QUEUE: usize = 100;
+const DEFAULT_CORS_METHODS: &str = "GET,HEAD,POST,PUT,PATCH,DELETE";
+const DEFAULT_CORS_MAX_AGE_SECONDS: u64 = 600;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_HEALTH_CHECK_PATH: &str = "/";
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_OUTBOUND_MAX_WAIT_MS: u64 = 1_000;
+const DEFAULT_RETRY_AFTER_MAX_SECONDS: u64 = 60;
+const DEFAULT_CACHE_TTL_SECONDS: u64 = 10;
+const DEFAULT_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_CACHE_MAX_ENTRY_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+/// Name of the rule applied to requests no route rule matches.
+pub const DEFAULT_RULE_NAME: &str = "default";
+
+/// Which limiter implementation runs on the selected backend. `Store` runs
+/// `RATE_LIMIT_ALGORITHM` generically over the backend's `RateLimitStore`.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
 pub enum RateLimiterType {
     Standard,
     LockFree,
+    Store,
 }
 
 impl RateLimiterType {
@@ -15,35 +104,2879 @@ impl RateLimiterType {
         match env::var("RATE_LIMITER_TYPE").as_deref() {
             Ok("standard") => Self::Standard,
             Ok("lock_free") => Self::LockFree,
-            _ => Self::LockFree,
+            Ok("store") => Self::Store,
+            value => {
+                unexpected("RATE_LIMITER_TYPE", value, "standard, lock_free, store");
+                CONFIG_FILE.backend.limiter.unwrap_or(Self::LockFree)
+            }
+        }
+    }
+}
+
+/// Where request counts are kept: in process memory, in Redis, memcached or
+/// DynamoDB so several replicas share them, locally with a background sync to
+/// Redis, in memory persisted to SQLite, or across the nodes themselves by
+/// gossip or consistent hashing.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimiterBackend {
+    Memory,
+    Redis,
+    Hybrid,
+    Memcached,
+    Sqlite,
+    Gossip,
+    Cluster,
+    #[serde(rename = "dynamodb")]
+    DynamoDb,
+}
+
+impl RateLimiterBackend {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMITER_BACKEND").as_deref() {
+            Ok("memory") => Self::Memory,
+            Ok("redis") => Self::Redis,
+            Ok("hybrid") => Self::Hybrid,
+            Ok("memcached") => Self::Memcached,
+            Ok("sqlite") => Self::Sqlite,
+            Ok("gossip") => Self::Gossip,
+            Ok("cluster") => Self::Cluster,
+            Ok("dynamodb") => Self::DynamoDb,
+            value => {
+                unexpected(
+                    "RATE_LIMITER_BACKEND",
+                    value,
+                    "memory, redis, hybrid, memcached, sqlite, gossip, cluster, dynamodb",
+                );
+                CONFIG_FILE.backend.kind.unwrap_or(Self::Memory)
+            }
         }
     }
 }
 
+/// Servers of a memcached cluster; keys are spread across them by hash.
 #[derive(Clone)]
-pub struct RateLimitConfig {
-    pub max_requests: u32,
-    pub window_seconds: u64,
+pub struct MemcachedConfig {
+    pub servers: Vec<String>,
+    pub key_prefix: String,
+    pub timeout_ms: u64,
 }
 
-impl Default for RateLimitConfig {
-    fn default() -> Self {
+/// DynamoDB table the state is kept in. `endpoint` can point at DynamoDB
+/// Local, none for the regional endpoint.
+#[derive(Clone)]
+pub struct DynamoDbConfig {
+    pub table: String,
+    pub region: String,
+    pub endpoint: Option<String>,
+    pub key_prefix: String,
+    pub timeout_ms: u64,
+}
+
+/// SQLite database a single node persists its state to.
+#[derive(Clone)]
+pub struct SqliteConfig {
+    pub path: String,
+    pub flush_ms: u64,
+}
+
+/// UDP address this node gossips on and the peers it exchanges counts with.
+#[derive(Clone)]
+pub struct GossipConfig {
+    pub bind: SocketAddr,
+    pub peers: Vec<SocketAddr>,
+    pub node_id: String,
+    pub interval_ms: u64,
+    /// Key of the HMAC every message carries, shared by all nodes.
+    pub secret: String,
+}
+
+/// Nodes sharing the key space by consistent hashing. `self_url` is this
+/// node's entry in `peers`, served on `listen` apart from the public
+/// listener, and `secret` is required of the other nodes' requests.
+#[derive(Clone)]
+pub struct ClusterConfig {
+    pub peers: Vec<String>,
+    pub self_url: String,
+    pub listen: SocketAddr,
+    pub secret: String,
+    pub timeout_ms: u64,
+}
+
+/// Message broker rate limit decisions are published to.
+#[derive(Clone, Debug)]
+pub enum EventSink {
+    Kafka { brokers: Vec<String> },
+    Nats { url: String },
+}
+
+/// Where decision events go; `topic` is the Kafka topic or NATS subject.
+/// Up to `buffer` events wait for the broker before new ones are dropped.
+#[derive(Clone, Debug)]
+pub struct EventsConfig {
+    pub sink: EventSink,
+    pub topic: String,
+    pub buffer: usize,
+}
+
+/// StatsD agent metrics are sent to, every `interval_seconds`, besides being
+/// served to Prometheus.
+#[derive(Clone)]
+pub struct StatsdConfig {
+    /// `host:port` of the agent.
+    pub addr: String,
+    /// Prepended to metric names, like `rate_limit.decisions`.
+    pub prefix: String,
+    /// Tags added to every metric, like `env:prod`.
+    pub tags: Vec<String>,
+    pub interval_seconds: u64,
+}
+
+/// Endpoints notified of what operators should hear about, like a client
+/// getting rate limited.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub format: WebhookFormat,
+    pub events: Vec<WebhookEvent>,
+    /// Requests per second across all clients above which `traffic` fires.
+    pub traffic_threshold: Option<u64>,
+    /// How long a key, or the traffic, stays quiet before firing again.
+    pub cooldown_seconds: u64,
+}
+
+/// What webhooks are sent for.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A key was rate limited, for the first time in the cooldown.
+    RateLimited,
+    /// Requests across all clients went over the traffic threshold.
+    Traffic,
+}
+
+impl WebhookEvent {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "rate_limited" => Some(Self::RateLimited),
+            "traffic" => Some(Self::Traffic),
+            _ => None,
+        }
+    }
+}
+
+/// How webhook bodies are written.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The events as JSON objects.
+    Json,
+    /// A Slack message summing them up, for incoming webhooks.
+    Slack,
+}
+
+impl WebhookFormat {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_WEBHOOK_FORMAT").as_deref() {
+            Ok("json") => Self::Json,
+            Ok("slack") => Self::Slack,
+            value => {
+                unexpected("RATE_LIMIT_WEBHOOK_FORMAT", value, "json, slack");
+                Self::Json
+            }
+        }
+    }
+}
+
+/// Where the record of every denial is kept.
+#[derive(Clone, Debug)]
+pub enum AuditSink {
+    /// A file records are appended to, never rewritten.
+    File { path: String },
+    /// An endpoint records are posted to in batches.
+    Http { url: String },
+}
+
+/// What to do with a denial when its record cannot be queued.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOverflow {
+    /// Hold the denied request until there is room, so no record is lost.
+    Block,
+    /// Drop the record, counting it, so the request is not held up.
+    Drop,
+}
+
+impl AuditOverflow {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_AUDIT_OVERFLOW").as_deref() {
+            Ok("block") => Self::Block,
+            Ok("drop") => Self::Drop,
+            value => {
+                unexpected("RATE_LIMIT_AUDIT_OVERFLOW", value, "block, drop");
+                Self::Block
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    pub sink: AuditSink,
+    /// Records that may wait for the sink before `overflow` applies.
+    pub buffer: usize,
+    pub overflow: AuditOverflow,
+    /// Salt of the key hashes, so they cannot be reversed by hashing every
+    /// IP address.
+    pub salt: Option<String>,
+}
+
+/// Sentry project errors are reported to, from its DSN.
+#[cfg(feature = "sentry")]
+#[derive(Clone, Debug)]
+pub struct SentryConfig {
+    pub dsn: sentry::types::Dsn,
+    pub environment: Option<String>,
+}
+
+/// File the in-memory limiters periodically save their state to and restore
+/// it from on startup.
+#[derive(Clone)]
+pub struct SnapshotConfig {
+    pub path: String,
+    pub interval_seconds: u64,
+}
+
+/// How often the in-memory limiters drop keys that have been idle longer
+/// than the longest window plus some slack.
+#[derive(Clone)]
+pub struct EvictionConfig {
+    pub interval_seconds: u64,
+    pub slack_seconds: u64,
+}
+
+/// Algorithm used by the Redis backend and the `store` limiter type.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    SlidingWindow,
+    TokenBucket,
+}
+
+impl RateLimitAlgorithm {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_ALGORITHM").as_deref() {
+            Ok("sliding_window") => Self::SlidingWindow,
+            Ok("token_bucket") => Self::TokenBucket,
+            value => {
+                unexpected(
+                    "RATE_LIMIT_ALGORITHM",
+                    value,
+                    "sliding_window, token_bucket",
+                );
+                CONFIG_FILE.backend.algorithm.unwrap_or(Self::SlidingWindow)
+            }
+        }
+    }
+}
+
+/// How the Redis backend reaches Redis: one server, a Redis Cluster, or the
+/// master of a Sentinel-managed deployment.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedisMode {
+    Standalone,
+    Cluster,
+    Sentinel,
+}
+
+impl RedisMode {
+    pub fn from_env() -> Self {
+        match env::var("REDIS_MODE").as_deref() {
+            Ok("standalone") => Self::Standalone,
+            Ok("cluster") => Self::Cluster,
+            Ok("sentinel") => Self::Sentinel,
+            value => {
+                unexpected("REDIS_MODE", value, "standalone, cluster, sentinel");
+                CONFIG_FILE.backend.redis.mode.unwrap_or(Self::Standalone)
+            }
+        }
+    }
+}
+
+/// `url` is a comma-separated list of seed nodes in cluster mode and of
+/// sentinels in sentinel mode.
+#[derive(Clone)]
+pub struct RedisConfig {
+    pub url: String,
+    pub key_prefix: String,
+    pub algorithm: RateLimitAlgorithm,
+    pub mode: RedisMode,
+    pub sentinel_master: String,
+    pub hash_tags: bool,
+}
+
+impl RedisConfig {
+    pub fn urls(&self) -> Vec<&str> {
+        self.url
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyStrategy {
+    Ip,
+    ApiKey,
+    Jwt,
+    Session,
+}
+
+impl KeyStrategy {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_KEY_STRATEGY").as_deref() {
+            Ok("ip") => Self::Ip,
+            Ok("api_key") => Self::ApiKey,
+            Ok("jwt") => Self::Jwt,
+            Ok("session") => Self::Session,
+            value => {
+                unexpected(
+                    "RATE_LIMIT_KEY_STRATEGY",
+                    value,
+                    "ip, api_key, jwt, session",
+                );
+                Self::Ip
+            }
+        }
+    }
+
+    /// Extractor chain equivalent to this strategy, always ending with the IP.
+    pub fn extractor_chain(self) -> Vec<KeyExtractorKind> {
+        match self {
+            Self::Ip => vec![KeyExtractorKind::Ip],
+            Self::ApiKey => vec![KeyExtractorKind::ApiKey, KeyExtractorKind::Ip],
+            Self::Jwt => vec![KeyExtractorKind::Jwt, KeyExtractorKind::Ip],
+            Self::Session => vec![KeyExtractorKind::Session, KeyExtractorKind::Ip],
+        }
+    }
+}
+
+/// One entry of the key extractor chain.
+#[derive(Clone, PartialEq, Debug)]
+pub enum KeyExtractorKind {
+    Ip,
+    ApiKey,
+    Header(HeaderName),
+    Cookie(String),
+    Query(String),
+    Session,
+    Jwt,
+    ClientCert,
+    UserAgentClass,
+    BodyField,
+}
+
+/// Written as in `RATE_LIMIT_KEY_EXTRACTORS`.
+impl Display for KeyExtractorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ip => write!(f, "ip"),
+            Self::ApiKey => write!(f, "api_key"),
+            Self::Header(name) => write!(f, "header:{}", name),
+            Self::Cookie(name) => write!(f, "cookie:{}", name),
+            Self::Query(name) => write!(f, "query:{}", name),
+            Self::Session => write!(f, "session"),
+            Self::Jwt => write!(f, "jwt"),
+            Self::ClientCert => write!(f, "client_cert"),
+            Self::UserAgentClass => write!(f, "ua_class"),
+            Self::BodyField => write!(f, "body"),
+        }
+    }
+}
+
+impl KeyExtractorKind {
+    fn parse(spec: &str) -> Option<Self> {
+        match spec.trim().split_once(':') {
+            None => match spec.trim() {
+                "ip" => Some(Self::Ip),
+                "api_key" => Some(Self::ApiKey),
+                "jwt" => Some(Self::Jwt),
+                "session" => Some(Self::Session),
+                "client_cert" => Some(Self::ClientCert),
+                "ua_class" => Some(Self::UserAgentClass),
+                "body" => Some(Self::BodyField),
+                _ => None,
+            },
+            Some(("header", name)) => HeaderName::try_from(name).ok().map(Self::Header),
+            Some(("cookie", name)) if !name.is_empty() => Some(Self::Cookie(name.to_string())),
+            Some(("query", name)) if !name.is_empty() => Some(Self::Query(name.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Reads the chain from `RATE_LIMIT_KEY_EXTRACTORS`
+    /// (e.g. `jwt,header:x-client-id,cookie:session,ip`), falling back to the
+    /// chain implied by `RATE_LIMIT_KEY_STRATEGY`.
+    pub fn chain_from_env() -> Vec<Self> {
+        let chain: Vec<Self> = env::var("RATE_LIMIT_KEY_EXTRACTORS")
+            .map(|v| parse_list("RATE_LIMIT_KEY_EXTRACTORS", &v, ',', Self::parse))
+            .unwrap_or_default();
+
+        if chain.is_empty() {
+            KeyStrategy::from_env().extractor_chain()
+        } else {
+            chain
+        }
+    }
+}
+
+/// What happens to requests whose limit could not be checked because the
+/// backing store was unavailable.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreFailurePolicy {
+    /// Let them through without limiting.
+    Open,
+    /// Reject them with 503 Service Unavailable.
+    Closed,
+    /// Limit them with an in-memory limiter on this replica until the store
+    /// is back.
+    Local,
+}
+
+impl StoreFailurePolicy {
+    pub fn from_env() -> Self {
+        match env::var("STORE_FAILURE_POLICY").as_deref() {
+            Ok("open") => Self::Open,
+            Ok("closed") => Self::Closed,
+            Ok("local") => Self::Local,
+            value => {
+                unexpected("STORE_FAILURE_POLICY", value, "open, closed, local");
+                CONFIG_FILE
+                    .backend
+                    .failure_policy
+                    .or(RATE_LIMIT_PROFILE.map(Profile::store_failure_policy))
+                    .unwrap_or(Self::Open)
+            }
+        }
+    }
+}
+
+/// What happens to requests while drain mode is switched on through the
+/// admin API.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainPolicy {
+    /// Reject clients without requests in the current window, letting those
+    /// with some use the rest of their budget.
+    RejectNew,
+    /// Let them all through without limiting.
+    Pass,
+}
+
+impl DrainPolicy {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_DRAIN_POLICY").as_deref() {
+            Ok("reject_new") => Self::RejectNew,
+            Ok("pass") => Self::Pass,
+            value => {
+                unexpected("RATE_LIMIT_DRAIN_POLICY", value, "reject_new, pass");
+                Self::RejectNew
+            }
+        }
+    }
+}
+
+/// What happens to requests none of the key extractors could identify.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymousPolicy {
+    /// Reject them outright.
+    Reject,
+    /// Let them through without limiting.
+    Bypass,
+    /// Limit each connection on its own.
+    PerConnection,
+    /// Limit them together under the anonymous limit.
+    Shared,
+}
+
+impl AnonymousPolicy {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_ANONYMOUS_POLICY").as_deref() {
+            Ok("reject") => Self::Reject,
+            Ok("bypass") => Self::Bypass,
+            Ok("per_connection") => Self::PerConnection,
+            Ok("shared") => Self::Shared,
+            value => {
+                unexpected(
+                    "RATE_LIMIT_ANONYMOUS_POLICY",
+                    value,
+                    "reject, bypass, per_connection, shared",
+                );
+                RATE_LIMIT_PROFILE
+                    .map(Profile::anonymous_policy)
+                    .unwrap_or(Self::Shared)
+            }
+        }
+    }
+}
+
+/// Whether rejections are enforced, or only logged and counted while every
+/// request passes, to try out new limits on production traffic.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitMode {
+    Enforce,
+    Shadow,
+}
+
+impl RateLimitMode {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_MODE").as_deref() {
+            Ok("enforce") => Self::Enforce,
+            Ok("shadow") => Self::Shadow,
+            value => {
+                unexpected("RATE_LIMIT_MODE", value, "enforce, shadow");
+                Self::Enforce
+            }
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log pipelines to ingest as is.
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_env() -> Self {
+        match env::var("LOG_FORMAT").as_deref() {
+            Ok("text") => Self::Text,
+            Ok("json") => Self::Json,
+            value => {
+                unexpected("LOG_FORMAT", value, "text, json");
+                Self::Text
+            }
+        }
+    }
+}
+
+/// How access log lines are written.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessLogFormat {
+    /// The combined log format of web servers, with the decision appended.
+    Combined,
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_ACCESS_LOG_FORMAT").as_deref() {
+            Ok("combined") => Self::Combined,
+            Ok("json") => Self::Json,
+            value => {
+                unexpected("RATE_LIMIT_ACCESS_LOG_FORMAT", value, "combined, json");
+                Self::Combined
+            }
+        }
+    }
+}
+
+/// How often the access log starts a new file.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl LogRotation {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_ACCESS_LOG_ROTATION").as_deref() {
+            Ok("hourly") => Self::Hourly,
+            Ok("daily") => Self::Daily,
+            Ok("never") => Self::Never,
+            value => {
+                unexpected(
+                    "RATE_LIMIT_ACCESS_LOG_ROTATION",
+                    value,
+                    "hourly, daily, never",
+                );
+                Self::Daily
+            }
+        }
+    }
+}
+
+/// File every request is logged to, apart from the diagnostic logs.
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub path: String,
+    pub format: AccessLogFormat,
+    pub rotation: LogRotation,
+    /// Rotated files kept, the oldest being deleted; all without a limit.
+    pub max_files: Option<usize>,
+}
+
+/// How much of the per-request logging is kept at high request rates.
+#[derive(Clone, Copy, Debug)]
+pub struct LogSamplingConfig {
+    /// One in this many allowed requests is logged; denials always are.
+    pub sample_rate: u64,
+    /// Most lines logged about any one client per second, without a limit
+    /// if none.
+    pub key_lines_per_second: Option<u32>,
+}
+
+/// Which header fields tell clients where they stand against their limit.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitHeaders {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`.
+    XRateLimit,
+    /// `RateLimit` and `RateLimit-Policy` of draft-ietf-httpapi-ratelimit-headers.
+    Ietf,
+    Both,
+    None,
+}
+
+impl RateLimitHeaders {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_HEADERS").as_deref() {
+            Ok("x-ratelimit") => Self::XRateLimit,
+            Ok("ietf") => Self::Ietf,
+            Ok("both") => Self::Both,
+            Ok("none") => Self::None,
+            value => {
+                unexpected("RATE_LIMIT_HEADERS", value, "x-ratelimit, ietf, both, none");
+                Self::XRateLimit
+            }
+        }
+    }
+
+    pub fn x_ratelimit(self) -> bool {
+        matches!(self, Self::XRateLimit | Self::Both)
+    }
+
+    pub fn ietf(self) -> bool {
+        matches!(self, Self::Ietf | Self::Both)
+    }
+}
+
+/// How 429 responses describe the rejection.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// A JSON object with the fields of `RATE_LIMIT_ERROR_FIELDS`.
+    Json,
+    /// The plain message.
+    Text,
+}
+
+/// A field of JSON 429 bodies.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorField {
+    Error,
+    Limit,
+    WindowSeconds,
+    RetryAfterSeconds,
+    RequestId,
+}
+
+impl ErrorField {
+    pub const ALL: [Self; 5] = [
+        Self::Error,
+        Self::Limit,
+        Self::WindowSeconds,
+        Self::RetryAfterSeconds,
+        Self::RequestId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Limit => "limit",
+            Self::WindowSeconds => "window_seconds",
+            Self::RetryAfterSeconds => "retry_after_seconds",
+            Self::RequestId => "request_id",
+        }
+    }
+
+    /// Parses `field` or `field:name`, the latter renaming the field.
+    fn parse(entry: &str) -> Option<(Self, String)> {
+        let (field, name) = match entry.split_once(':') {
+            Some((field, name)) => (field.trim(), name.trim()),
+            None => (entry, entry),
+        };
+        let field = Self::ALL.into_iter().find(|f| f.name() == field)?;
+        (!name.is_empty()).then(|| (field, name.to_string()))
+    }
+}
+
+/// The shape of 429 response bodies.
+#[derive(Clone, Debug)]
+pub struct ErrorBodyConfig {
+    pub format: ErrorFormat,
+    /// Fields of JSON bodies, with the names they are sent under.
+    pub fields: Vec<(ErrorField, String)>,
+}
+
+impl ErrorBodyConfig {
+    pub fn from_env() -> Self {
+        let format = match env::var("RATE_LIMIT_ERROR_FORMAT").as_deref() {
+            Ok("json") => ErrorFormat::Json,
+            Ok("text") => ErrorFormat::Text,
+            value => {
+                unexpected("RATE_LIMIT_ERROR_FORMAT", value, "json, text");
+                ErrorFormat::Json
+            }
+        };
+        let fields = match env::var("RATE_LIMIT_ERROR_FIELDS") {
+            Ok(_) if format == ErrorFormat::Text => {
+                invalid(
+                    "RATE_LIMIT_ERROR_FIELDS",
+                    "only applies to RATE_LIMIT_ERROR_FORMAT=json",
+                );
+                Vec::new()
+            }
+            Ok(v) => parse_list("RATE_LIMIT_ERROR_FIELDS", &v, ',', ErrorField::parse),
+            Err(_) => ErrorField::ALL
+                .into_iter()
+                .map(|field| (field, field.name().to_string()))
+                .collect(),
+        };
+        Self { format, fields }
+    }
+}
+
+/// Key-value store the reloadable part of the config can be pulled from.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KvStore {
+    Consul,
+    Etcd,
+}
+
+impl KvStore {
+    pub fn from_env() -> Option<Self> {
+        match env::var("RATE_LIMIT_CONFIG_KV").as_deref() {
+            Ok("consul") => Some(Self::Consul),
+            Ok("etcd") => Some(Self::Etcd),
+            value => {
+                unexpected("RATE_LIMIT_CONFIG_KV", value, "consul, etcd");
+                None
+            }
+        }
+    }
+
+    fn default_url(self) -> &'static str {
+        match self {
+            Self::Consul => DEFAULT_CONSUL_URL,
+            Self::Etcd => DEFAULT_ETCD_URL,
+        }
+    }
+}
+
+/// Key holding a config document, TOML or YAML by the key's extension, in
+/// the store at `url`, see `kv_config`.
+#[derive(Clone, Debug)]
+pub struct KvConfig {
+    pub store: KvStore,
+    pub url: String,
+    pub key: String,
+}
+
+/// Curated defaults for the settings left unset, so small deployments get
+/// sensible production behavior without tuning every knob. The environment,
+/// command line and config file still override them.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// Tight limits, and requests are rejected when in doubt.
+    Strict,
+    /// Moderate limits for typical public APIs.
+    Standard,
+    /// Generous limits, and requests are let through when in doubt.
+    Permissive,
+}
+
+impl Profile {
+    pub fn from_env() -> Option<Self> {
+        match env::var("RATE_LIMIT_PROFILE").as_deref() {
+            Ok("strict") => Some(Self::Strict),
+            Ok("standard") => Some(Self::Standard),
+            Ok("permissive") => Some(Self::Permissive),
+            value => {
+                unexpected("RATE_LIMIT_PROFILE", value, "strict, standard, permissive");
+                None
+            }
+        }
+    }
+
+    fn limit(self) -> RateLimitConfig {
+        let max_requests = match self {
+            Self::Strict => 30,
+            Self::Standard => 100,
+            Self::Permissive => 1000,
+        };
+        RateLimitConfig {
+            max_requests,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    /// Limit shared by anonymous requests, a fraction of the regular one.
+    fn anonymous_limit(self) -> RateLimitConfig {
+        let limit = self.limit();
+        RateLimitConfig {
+            max_requests: limit.max_requests / 10,
+            ..limit
+        }
+    }
+
+    fn anonymous_policy(self) -> AnonymousPolicy {
+        match self {
+            Self::Strict => AnonymousPolicy::Reject,
+            Self::Standard | Self::Permissive => AnonymousPolicy::Shared,
+        }
+    }
+
+    fn store_failure_policy(self) -> StoreFailurePolicy {
+        match self {
+            Self::Strict => StoreFailurePolicy::Closed,
+            Self::Standard => StoreFailurePolicy::Local,
+            Self::Permissive => StoreFailurePolicy::Open,
+        }
+    }
+}
+
+/// Whether a client has one budget overall or one per route.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyScope {
+    Global,
+    Route,
+}
+
+impl KeyScope {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_KEY_SCOPE").as_deref() {
+            Ok("global") => Self::Global,
+            Ok("route") => Self::Route,
+            value => {
+                unexpected("RATE_LIMIT_KEY_SCOPE", value, "global, route");
+                Self::Global
+            }
+        }
+    }
+}
+
+/// Headers a proxy may report the client address in.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    XForwardedFor,
+    Forwarded,
+    XRealIp,
+}
+
+impl ClientIpHeader {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Some(Self::XForwardedFor),
+            "forwarded" => Some(Self::Forwarded),
+            "x-real-ip" => Some(Self::XRealIp),
+            _ => None,
+        }
+    }
+
+    /// Reads the header precedence from `CLIENT_IP_HEADERS`.
+    pub fn precedence_from_env() -> Vec<Self> {
+        let headers: Vec<Self> = env::var("CLIENT_IP_HEADERS")
+            .map(|v| parse_list("CLIENT_IP_HEADERS", &v, ',', Self::parse))
+            .unwrap_or_default();
+
+        if headers.is_empty() {
+            vec![Self::XForwardedFor, Self::Forwarded, Self::XRealIp]
+        } else {
+            headers
+        }
+    }
+}
+
+/// Prefix lengths client addresses are truncated to, so a whole subnet shares
+/// one budget. Full-length prefixes key every address on its own.
+#[derive(Clone, Copy, Debug)]
+pub struct SubnetAggregation {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl SubnetAggregation {
+    pub fn from_env() -> Self {
+        let enabled = parse_env("RATE_LIMIT_SUBNET_AGGREGATION").unwrap_or(false);
+        let prefix = |name: &str, max: u8| {
+            parse_env(name).filter(|prefix| {
+                if *prefix > max {
+                    invalid(name, format!("prefix {} is longer than {}", prefix, max));
+                }
+                *prefix <= max
+            })
+        };
+
         Self {
-            max_requests: DEFAULT_MAX_REQUESTS,
-            window_seconds: DEFAULT_WINDOW_SECONDS,
+            ipv4_prefix: match enabled {
+                true => prefix("RATE_LIMIT_IPV4_PREFIX", 32).unwrap_or(DEFAULT_IPV4_PREFIX),
+                false => 32,
+            },
+            ipv6_prefix: prefix("RATE_LIMIT_IPV6_PREFIX", 128).unwrap_or(match enabled {
+                true => DEFAULT_IPV6_PREFIX,
+                false => 128,
+            }),
         }
     }
 }
 
-pub static RATE_LIMITER_TYPE: LazyLock<RateLimiterType> = LazyLock::new(RateLimiterType::from_env);
+/// How long requests over their limit wait for capacity before they are
+/// rejected, and how many requests of one client may wait at once.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// `0` turns throttling off, e.g. for a route when it is on globally.
+    pub max_wait_ms: u64,
+    #[serde(default = "default_throttle_max_queue")]
+    pub max_queue: usize,
+}
 
-pub static RATE_LIMIT_CONFIG: LazyLock<RateLimitConfig> = LazyLock::new(|| RateLimitConfig {
-    max_requests: env::var("RATE_LIMIT_MAX_REQUESTS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_MAX_REQUESTS),
-    window_seconds: env::var("RATE_LIMIT_WINDOW_SECONDS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_WINDOW_SECONDS),
-});
+impl ThrottleConfig {
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+}
+
+fn default_throttle_max_queue() -> usize {
+    DEFAULT_THROTTLE_MAX_QUEUE
+}
+
+/// Responses rejecting requests over their limit, for CDNs and clients that
+/// expect something other than `429 Too Many Requests` with the body of
+/// `RATE_LIMIT_ERROR_FORMAT`. Settings a route rule leaves out fall back to
+/// the global ones.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RejectionConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Body sent in place of the configured error format, with placeholders
+    /// like `{limit}` and `{retry_after_seconds}` naming the JSON body's
+    /// fields replaced by their values.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Content type of templated bodies, plain text by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl RejectionConfig {
+    pub fn from_env() -> Self {
+        let config = Self {
+            status: parse_env("RATE_LIMIT_REJECTION_STATUS"),
+            template: env::var("RATE_LIMIT_REJECTION_TEMPLATE").ok(),
+            content_type: env::var("RATE_LIMIT_REJECTION_CONTENT_TYPE").ok(),
+        };
+        if let Err((setting, problem)) = config.validate() {
+            let name = format!("RATE_LIMIT_REJECTION_{}", setting.to_uppercase());
+            invalid(&name, problem);
+        }
+        config
+    }
+
+    /// Checks the settings, naming the first invalid one and its problem.
+    pub fn validate(&self) -> Result<(), (&'static str, String)> {
+        if let Some(status) = self.status
+            && !StatusCode::from_u16(status)
+                .is_ok_and(|status| status.is_client_error() || status.is_server_error())
+        {
+            return Err(("status", format!("{} is not a 4xx or 5xx code", status)));
+        }
+        if let Some(content_type) = &self.content_type
+            && HeaderValue::from_str(content_type).is_err()
+        {
+            return Err((
+                "content_type",
+                format!("{:?} is not a valid header value", content_type),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "LimitFields", into = "LimitFields")]
+pub struct RateLimitConfig {
+    pub max_requests: u32,
+    pub window: Duration,
+}
+
+impl RateLimitConfig {
+    /// Parses a limit written as `max_requests/window`, with the window in
+    /// seconds or suffixed with `s` or `ms`, e.g. `100/60` or `10/250ms`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (max_requests, window) = value.trim().split_once('/')?;
+        let window = window.trim();
+        Some(Self {
+            max_requests: max_requests.trim().parse().ok()?,
+            window: match window.strip_suffix("ms") {
+                Some(millis) => Duration::from_millis(millis.parse().ok()?),
+                None => {
+                    Duration::from_secs(window.strip_suffix('s').unwrap_or(window).parse().ok()?)
+                }
+            },
+        })
+    }
+
+    /// Rejects limits that would block every request or never reset.
+    pub fn check(&self) -> Result<(), String> {
+        if self.max_requests == 0 {
+            return Err("max_requests must be greater than 0".to_string());
+        }
+        if self.window.as_millis() == 0 {
+            return Err("the window must be at least 1 ms".to_string());
+        }
+        Ok(())
+    }
+
+    /// The window in microseconds, the resolution the stores count in.
+    pub fn window_micros(&self) -> u64 {
+        self.window.as_micros().max(1) as u64
+    }
+}
+
+impl fmt::Display for RateLimitConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} requests per ", self.max_requests)?;
+        match self.window.subsec_millis() {
+            0 => write!(f, "{} seconds", self.window.as_secs()),
+            _ => write!(f, "{} ms", self.window.as_millis()),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: DEFAULT_MAX_REQUESTS,
+            window: Duration::from_secs(DEFAULT_WINDOW_SECONDS),
+        }
+    }
+}
+
+/// A limit as written in the config file, with the window given either in
+/// seconds or, for windows shorter than a second, in milliseconds.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LimitFields {
+    max_requests: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window_ms: Option<u64>,
+}
+
+impl From<RateLimitConfig> for LimitFields {
+    fn from(config: RateLimitConfig) -> Self {
+        let whole_seconds = config.window.subsec_millis() == 0;
+        Self {
+            max_requests: config.max_requests,
+            window_seconds: whole_seconds.then_some(config.window.as_secs()),
+            window_ms: (!whole_seconds).then_some(config.window.as_millis() as u64),
+        }
+    }
+}
+
+impl TryFrom<LimitFields> for RateLimitConfig {
+    type Error = String;
+
+    fn try_from(fields: LimitFields) -> Result<Self, Self::Error> {
+        let window = match (fields.window_seconds, fields.window_ms) {
+            (Some(seconds), None) => Duration::from_secs(seconds),
+            (None, Some(millis)) => Duration::from_millis(millis),
+            (Some(_), Some(_)) => return Err("set window_seconds or window_ms, not both".into()),
+            (None, None) => return Err("missing field `window_seconds` or `window_ms`".into()),
+        };
+        Ok(Self {
+            max_requests: fields.max_requests,
+            window,
+        })
+    }
+}
+
+/// Problems found while reading the configuration, reported by `validate`.
+static ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Problems found while `reload_limits` runs on this thread, returned by
+    /// it instead of piling up in `ERRORS` after startup.
+    static RELOAD_ERRORS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+fn report(problem: String) {
+    RELOAD_ERRORS.with_borrow_mut(|reloading| match reloading {
+        Some(errors) => errors.push(problem),
+        None => ERRORS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(problem),
+    });
+}
+
+fn invalid(name: &str, problem: impl Display) {
+    report(format!("{}: {}", name, problem));
+}
+
+/// Reports a value of `name` none of the arms of a `from_env` matched.
+fn unexpected(name: &str, value: Result<&str, &VarError>, choices: &str) {
+    match value {
+        Ok(value) => invalid(name, format!("{:?} is not one of {}", value, choices)),
+        Err(VarError::NotUnicode(_)) => invalid(name, "not valid unicode"),
+        Err(VarError::NotPresent) => {}
+    }
+}
+
+/// Parses `name` if it is set, reporting values that do not parse.
+fn parse_env<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = env::var(name).ok()?;
+    value
+        .trim()
+        .parse()
+        .map_err(|e| invalid(name, format!("{:?} is invalid: {}", value, e)))
+        .ok()
+}
+
+/// Like `parse_env` for settings where 0 would be meaningless.
+fn positive_env<T: FromStr + Default + PartialOrd + Display>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    parse_env(name).filter(|value: &T| {
+        if *value <= T::default() {
+            invalid(name, format!("must be greater than 0, got {}", value));
+        }
+        *value > T::default()
+    })
+}
+
+/// Parses a limit like `100/1` from `name` if it is set, reporting values
+/// that are not a valid one.
+fn limit_env(name: &str) -> Option<RateLimitConfig> {
+    let value = env::var(name).ok()?;
+    let Some(limit) = RateLimitConfig::parse(&value) else {
+        invalid(name, format!("{:?} is not a limit like 100/1", value));
+        return None;
+    };
+    if let Err(problem) = limit.check() {
+        invalid(name, problem);
+        return None;
+    }
+    Some(limit)
+}
+
+/// Parses the non-empty entries of a list setting, reporting and skipping the
+/// ones `parse` rejects.
+fn parse_list<T>(
+    name: &str,
+    value: &str,
+    separator: char,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Vec<T> {
+    value
+        .split(separator)
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = parse(entry);
+            if parsed.is_none() {
+                invalid(name, format!("cannot parse entry {:?}", entry));
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Config file given with `--config`, if any.
+pub static CONFIG_PATH: LazyLock<Option<String>> = LazyLock::new(|| ARGS.config.clone());
+
+/// Contents of the config file, empty without one. Environment variables take
+/// precedence over the values in it.
+pub static CONFIG_FILE: LazyLock<FileConfig> = LazyLock::new(|| match &*CONFIG_PATH {
+    Some(path) => FileConfig::load(path).unwrap_or_else(|e| {
+        report(format!("failed to load the config file {}", e));
+        FileConfig::default()
+    }),
+    None => FileConfig::default(),
+});
+
+/// How often the config file or etcd key is checked for changes; 0 only
+/// reloads the file on SIGHUP and loads the key once.
+pub static CONFIG_WATCH_SECONDS: LazyLock<u64> = LazyLock::new(|| {
+    parse_env("RATE_LIMIT_CONFIG_WATCH_SECONDS").unwrap_or(DEFAULT_CONFIG_WATCH_SECONDS)
+});
+
+/// Key-value store key the limits and rules are pulled from, if any.
+pub static CONFIG_KV: LazyLock<Option<KvConfig>> = LazyLock::new(|| {
+    let store = KvStore::from_env()?;
+    Some(KvConfig {
+        store,
+        url: env::var("RATE_LIMIT_CONFIG_KV_URL")
+            .map(|url| url.trim_end_matches('/').to_string())
+            .unwrap_or_else(|_| store.default_url().to_string()),
+        key: env::var("RATE_LIMIT_CONFIG_KV_KEY")
+            .unwrap_or_else(|_| DEFAULT_CONFIG_KV_KEY.to_string()),
+    })
+});
+
+pub static RATE_LIMITER_TYPE: LazyLock<RateLimiterType> =
+    LazyLock::new(|| ARGS.limiter.unwrap_or_else(RateLimiterType::from_env));
+
+pub static RATE_LIMITER_BACKEND: LazyLock<RateLimiterBackend> =
+    LazyLock::new(RateLimiterBackend::from_env);
+
+pub static RATE_LIMIT_ALGORITHM: LazyLock<RateLimitAlgorithm> =
+    LazyLock::new(RateLimitAlgorithm::from_env);
+
+pub static REDIS_CONFIG: LazyLock<RedisConfig> = LazyLock::new(|| {
+    let file = &CONFIG_FILE.backend.redis;
+    RedisConfig {
+        url: env::var("REDIS_URL")
+            .ok()
+            .or_else(|| file.url.clone())
+            .unwrap_or_else(|| DEFAULT_REDIS_URL.to_string()),
+        key_prefix: env::var("REDIS_KEY_PREFIX")
+            .ok()
+            .or_else(|| file.key_prefix.clone())
+            .unwrap_or_else(|| DEFAULT_REDIS_KEY_PREFIX.to_string()),
+        algorithm: *RATE_LIMIT_ALGORITHM,
+        mode: RedisMode::from_env(),
+        sentinel_master: env::var("REDIS_SENTINEL_MASTER")
+            .ok()
+            .or_else(|| file.sentinel_master.clone())
+            .unwrap_or_else(|| DEFAULT_REDIS_SENTINEL_MASTER.to_string()),
+        // Tagging is needed to keep a client's keys on one slot in a cluster,
+        // and off elsewhere so existing keys keep their names.
+        hash_tags: parse_env("REDIS_HASH_TAGS")
+            .or(file.hash_tags)
+            .unwrap_or(RedisMode::from_env() == RedisMode::Cluster),
+    }
+});
+
+pub static MEMCACHED_CONFIG: LazyLock<MemcachedConfig> = LazyLock::new(|| {
+    let file = &CONFIG_FILE.backend.memcached;
+    MemcachedConfig {
+        servers: env::var("MEMCACHED_SERVERS")
+            .ok()
+            .or_else(|| file.servers.as_ref().map(|servers| servers.join(",")))
+            .unwrap_or_else(|| DEFAULT_MEMCACHED_SERVERS.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(str::to_string)
+            .collect(),
+        key_prefix: env::var("MEMCACHED_KEY_PREFIX")
+            .ok()
+            .or_else(|| file.key_prefix.clone())
+            .unwrap_or_else(|| DEFAULT_MEMCACHED_KEY_PREFIX.to_string()),
+        timeout_ms: parse_env("MEMCACHED_TIMEOUT_MS")
+            .or(file.timeout_ms)
+            .unwrap_or(DEFAULT_MEMCACHED_TIMEOUT_MS),
+    }
+});
+
+pub static DYNAMODB_CONFIG: LazyLock<DynamoDbConfig> = LazyLock::new(|| {
+    let file = &CONFIG_FILE.backend.dynamodb;
+    let region = env::var("DYNAMODB_REGION")
+        .ok()
+        .or_else(|| file.region.clone())
+        .or_else(|| env::var("AWS_REGION").ok())
+        .unwrap_or_else(|| DEFAULT_DYNAMODB_REGION.to_string());
+    DynamoDbConfig {
+        table: env::var("DYNAMODB_TABLE")
+            .ok()
+            .or_else(|| file.table.clone())
+            .unwrap_or_else(|| DEFAULT_DYNAMODB_TABLE.to_string()),
+        endpoint: env::var("DYNAMODB_ENDPOINT")
+            .ok()
+            .or_else(|| file.endpoint.clone())
+            .map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+        region,
+        key_prefix: env::var("DYNAMODB_KEY_PREFIX")
+            .ok()
+            .or_else(|| file.key_prefix.clone())
+            .unwrap_or_else(|| DEFAULT_DYNAMODB_KEY_PREFIX.to_string()),
+        timeout_ms: parse_env("DYNAMODB_TIMEOUT_MS")
+            .or(file.timeout_ms)
+            .unwrap_or(DEFAULT_DYNAMODB_TIMEOUT_MS),
+    }
+});
+
+pub static SQLITE_CONFIG: LazyLock<SqliteConfig> = LazyLock::new(|| {
+    let file = &CONFIG_FILE.backend.sqlite;
+    SqliteConfig {
+        path: env::var("SQLITE_PATH")
+            .ok()
+            .or_else(|| file.path.clone())
+            .unwrap_or_else(|| DEFAULT_SQLITE_PATH.to_string()),
+        flush_ms: positive_env("SQLITE_FLUSH_MS")
+            .or(file.flush_ms.filter(|v| *v > 0))
+            .unwrap_or(DEFAULT_SQLITE_FLUSH_MS),
+    }
+});
+
+pub static SNAPSHOT_CONFIG: LazyLock<Option<SnapshotConfig>> = LazyLock::new(|| {
+    Some(SnapshotConfig {
+        path: env::var("RATE_LIMIT_SNAPSHOT_PATH").ok()?,
+        interval_seconds: positive_env("RATE_LIMIT_SNAPSHOT_INTERVAL_SECONDS")
+            .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_SECONDS),
+    })
+});
+
+pub static STATSD_CONFIG: LazyLock<Option<StatsdConfig>> = LazyLock::new(|| {
+    let addr = env::var("RATE_LIMIT_STATSD_ADDR").ok()?;
+    if addr.to_socket_addrs().is_err() {
+        invalid(
+            "RATE_LIMIT_STATSD_ADDR",
+            format!("{:?} is not a host:port address", addr),
+        );
+        return None;
+    }
+    Some(StatsdConfig {
+        addr,
+        prefix: env::var("RATE_LIMIT_STATSD_PREFIX")
+            .unwrap_or_else(|_| DEFAULT_STATSD_PREFIX.to_string()),
+        tags: env::var("RATE_LIMIT_STATSD_TAGS").map_or_else(
+            |_| Vec::new(),
+            |tags| {
+                parse_list("RATE_LIMIT_STATSD_TAGS", &tags, ',', |tag| {
+                    Some(tag.to_string())
+                })
+            },
+        ),
+        interval_seconds: positive_env("RATE_LIMIT_STATSD_INTERVAL_SECONDS")
+            .unwrap_or(DEFAULT_STATSD_INTERVAL_SECONDS),
+    })
+});
+
+pub static WEBHOOK_CONFIG: LazyLock<Option<WebhookConfig>> = LazyLock::new(|| {
+    let urls = env::var("RATE_LIMIT_WEBHOOK_URLS").ok()?;
+    let urls = parse_list("RATE_LIMIT_WEBHOOK_URLS", &urls, ',', |url| {
+        reqwest::Url::parse(url)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+            .then(|| url.to_string())
+    });
+    if urls.is_empty() {
+        return None;
+    }
+    Some(WebhookConfig {
+        urls,
+        format: WebhookFormat::from_env(),
+        events: env::var("RATE_LIMIT_WEBHOOK_EVENTS").map_or_else(
+            |_| vec![WebhookEvent::RateLimited, WebhookEvent::Traffic],
+            |events| {
+                parse_list(
+                    "RATE_LIMIT_WEBHOOK_EVENTS",
+                    &events,
+                    ',',
+                    WebhookEvent::parse,
+                )
+            },
+        ),
+        traffic_threshold: positive_env("RATE_LIMIT_WEBHOOK_TRAFFIC_THRESHOLD"),
+        cooldown_seconds: positive_env("RATE_LIMIT_WEBHOOK_COOLDOWN_SECONDS")
+            .unwrap_or(DEFAULT_WEBHOOK_COOLDOWN_SECONDS),
+    })
+});
+
+pub static AUDIT_CONFIG: LazyLock<Option<AuditConfig>> = LazyLock::new(|| {
+    let sink = match env::var("RATE_LIMIT_AUDIT_SINK").as_deref() {
+        Ok("file") => AuditSink::File {
+            path: env::var("RATE_LIMIT_AUDIT_PATH")
+                .map_err(|_| invalid("RATE_LIMIT_AUDIT_PATH", "is required by the file sink"))
+                .ok()?,
+        },
+        Ok("http") => AuditSink::Http {
+            url: env::var("RATE_LIMIT_AUDIT_URL")
+                .ok()
+                .filter(|url| {
+                    reqwest::Url::parse(url)
+                        .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+                })
+                .or_else(|| {
+                    invalid(
+                        "RATE_LIMIT_AUDIT_URL",
+                        "must be an http or https URL for the http sink",
+                    );
+                    None
+                })?,
+        },
+        value => {
+            unexpected("RATE_LIMIT_AUDIT_SINK", value, "file, http");
+            return None;
+        }
+    };
+    Some(AuditConfig {
+        sink,
+        buffer: positive_env("RATE_LIMIT_AUDIT_BUFFER").unwrap_or(DEFAULT_AUDIT_BUFFER),
+        overflow: AuditOverflow::from_env(),
+        salt: env::var("RATE_LIMIT_AUDIT_SALT").ok(),
+    })
+});
+
+/// Error reporting to Sentry, off unless `SENTRY_DSN` is set, like
+/// `https://<public key>@o1.ingest.sentry.io/<project ID>`.
+#[cfg(feature = "sentry")]
+pub static SENTRY_CONFIG: LazyLock<Option<SentryConfig>> = LazyLock::new(|| {
+    let dsn = env::var("SENTRY_DSN").ok().filter(|dsn| !dsn.is_empty())?;
+    let Ok(dsn) = dsn.parse() else {
+        invalid(
+            "SENTRY_DSN",
+            "must be a DSN like https://<key>@<host>/<project>",
+        );
+        return None;
+    };
+    Some(SentryConfig {
+        dsn,
+        environment: env::var("SENTRY_ENVIRONMENT").ok(),
+    })
+});
+
+pub static EVICTION_CONFIG: LazyLock<EvictionConfig> = LazyLock::new(|| EvictionConfig {
+    interval_seconds: positive_env("RATE_LIMIT_EVICTION_INTERVAL_SECONDS")
+        .unwrap_or(DEFAULT_EVICTION_INTERVAL_SECONDS),
+    slack_seconds: parse_env("RATE_LIMIT_EVICTION_SLACK_SECONDS")
+        .unwrap_or(DEFAULT_EVICTION_SLACK_SECONDS),
+});
+
+/// Throttling of requests over their limit, unless route rules set their own.
+/// Off unless a maximum wait is set.
+pub static THROTTLE_CONFIG: LazyLock<Option<ThrottleConfig>> = LazyLock::new(|| {
+    let max_queue = positive_env("RATE_LIMIT_THROTTLE_MAX_QUEUE");
+    Some(ThrottleConfig {
+        max_wait_ms: positive_env("RATE_LIMIT_THROTTLE_MAX_WAIT_MS")?,
+        max_queue: max_queue.unwrap_or(DEFAULT_THROTTLE_MAX_QUEUE),
+    })
+});
+
+/// Most keys the in-memory limiters track before evicting the least recently
+/// seen ones, bounding memory when clients can mint keys (e.g. by spoofing
+/// `X-Forwarded-For`). Unbounded by default.
+pub static MAX_TRACKED_KEYS: LazyLock<Option<usize>> =
+    LazyLock::new(|| positive_env("RATE_LIMIT_MAX_TRACKED_KEYS"));
+
+/// Keys given a label of their own on per-key metrics, the rest counted as
+/// `other`. Per-key metrics are off unless set.
+pub static METRICS_TOP_KEYS: LazyLock<Option<usize>> =
+    LazyLock::new(|| positive_env("RATE_LIMIT_METRICS_TOP_KEYS"));
+
+pub static GOSSIP_CONFIG: LazyLock<GossipConfig> = LazyLock::new(|| {
+    let secret = env::var("RATE_LIMIT_GOSSIP_SECRET").unwrap_or_default();
+    if *RATE_LIMITER_BACKEND == RateLimiterBackend::Gossip && secret.is_empty() {
+        invalid(
+            "RATE_LIMIT_GOSSIP_SECRET",
+            "must be set for the gossip backend, so only other nodes' counts are merged",
+        );
+    }
+    GossipConfig {
+        bind: parse_env("RATE_LIMIT_GOSSIP_BIND")
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_GOSSIP_PORT))),
+        peers: env::var("RATE_LIMIT_GOSSIP_PEERS")
+            .map(|v| parse_list("RATE_LIMIT_GOSSIP_PEERS", &v, ',', |peer| peer.parse().ok()))
+            .unwrap_or_default(),
+        node_id: env::var("RATE_LIMIT_GOSSIP_NODE_ID")
+            .unwrap_or_else(|_| format!("{:016x}", rand::random::<u64>())),
+        interval_ms: positive_env("RATE_LIMIT_GOSSIP_INTERVAL_MS")
+            .unwrap_or(DEFAULT_GOSSIP_INTERVAL_MS),
+        secret,
+    }
+});
+
+pub static CLUSTER_CONFIG: LazyLock<ClusterConfig> = LazyLock::new(|| {
+    let enabled = *RATE_LIMITER_BACKEND == RateLimiterBackend::Cluster;
+    let self_url = env::var("RATE_LIMIT_CLUSTER_SELF")
+        .unwrap_or_default()
+        .trim()
+        .trim_end_matches('/')
+        .to_string();
+    // Other nodes reach this one at its URL, so it listens on that port
+    // unless told otherwise.
+    let self_port = reqwest::Url::parse(&self_url)
+        .ok()
+        .and_then(|url| url.port_or_known_default());
+    let listen = match (parse_env("RATE_LIMIT_CLUSTER_LISTEN"), self_port) {
+        (Some(listen), _) => listen,
+        (None, Some(port)) => SocketAddr::from(([0, 0, 0, 0], port)),
+        (None, None) => {
+            if enabled {
+                invalid(
+                    "RATE_LIMIT_CLUSTER_SELF",
+                    format!("{:?} is not a URL to listen for other nodes at", self_url),
+                );
+            }
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        }
+    };
+    if enabled && listen.port() == LISTEN_ADDR.port() {
+        invalid(
+            "RATE_LIMIT_CLUSTER_LISTEN",
+            "must be another port than the public listener's, so only other nodes reach it",
+        );
+    }
+    let secret = env::var("RATE_LIMIT_CLUSTER_SECRET").unwrap_or_default();
+    if enabled && secret.is_empty() {
+        invalid(
+            "RATE_LIMIT_CLUSTER_SECRET",
+            "must be set for the cluster backend, so only other nodes are answered",
+        );
+    }
+    ClusterConfig {
+        peers: env::var("RATE_LIMIT_CLUSTER_PEERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|peer| peer.trim().trim_end_matches('/').to_string())
+            .filter(|peer| !peer.is_empty())
+            .collect(),
+        self_url,
+        listen,
+        secret,
+        timeout_ms: parse_env("RATE_LIMIT_CLUSTER_TIMEOUT_MS")
+            .unwrap_or(DEFAULT_CLUSTER_TIMEOUT_MS),
+    }
+});
+
+pub static LISTEN_ADDR: LazyLock<SocketAddr> = LazyLock::new(|| {
+    let mut addr = parse_env("LISTEN_ADDR")
+        .or(CONFIG_FILE.listen_addr)
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.parse().unwrap());
+    if let Some(ip) = ARGS.bind {
+        addr.set_ip(ip);
+    }
+    if let Some(port) = ARGS.port {
+        addr.set_port(port);
+    }
+    addr
+});
+
+/// Service admitted requests are forwarded to in proxy mode, unless their
+/// route rule names another. Without one, the server answers requests itself.
+pub static UPSTREAM: LazyLock<Option<UpstreamPool>> = LazyLock::new(|| {
+    let urls = env::var("UPSTREAM_URL").ok()?;
+    let mut health_check = HealthCheck::default();
+    if let Ok(path) = env::var("UPSTREAM_HEALTH_CHECK_PATH") {
+        health_check.path = path;
+    }
+    if let Some(seconds) = positive_env("UPSTREAM_HEALTH_CHECK_INTERVAL_SECONDS") {
+        health_check.interval_seconds = seconds;
+    }
+    let outbound_limit = limit_env("UPSTREAM_OUTBOUND_LIMIT").map(Arc::new);
+    let max_wait_ms = parse_env("UPSTREAM_OUTBOUND_MAX_WAIT_MS");
+    let max_queue = positive_env("UPSTREAM_OUTBOUND_MAX_QUEUE");
+    let outbound_queue = (max_wait_ms.is_some() || max_queue.is_some()).then(|| ThrottleConfig {
+        max_wait_ms: max_wait_ms.unwrap_or(DEFAULT_OUTBOUND_MAX_WAIT_MS),
+        max_queue: max_queue.unwrap_or(DEFAULT_THROTTLE_MAX_QUEUE),
+    });
+    let pool = UpstreamPool {
+        targets: urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect(),
+        balance: Balance::from_env(),
+        health_check,
+        outbound_limit,
+        outbound_queue,
+        retry_after: RetryAfter {
+            max_seconds: parse_env("UPSTREAM_RETRY_AFTER_MAX_SECONDS")
+                .unwrap_or(DEFAULT_RETRY_AFTER_MAX_SECONDS),
+            propagate: parse_env("UPSTREAM_RETRY_AFTER_PROPAGATE").unwrap_or(false),
+        },
+        cache: parse_env("UPSTREAM_CACHE_TTL_SECONDS").map(|ttl_seconds| CacheConfig {
+            ttl_seconds,
+            max_bytes: parse_env("UPSTREAM_CACHE_MAX_BYTES").unwrap_or(DEFAULT_CACHE_MAX_BYTES),
+            max_entry_bytes: parse_env("UPSTREAM_CACHE_MAX_ENTRY_BYTES")
+                .unwrap_or(DEFAULT_CACHE_MAX_ENTRY_BYTES),
+        }),
+    };
+    if let Err(problem) = pool.validate() {
+        invalid("UPSTREAM_URL", problem);
+        return None;
+    }
+    Some(pool)
+});
+
+pub static LOG_FORMAT: LazyLock<LogFormat> = LazyLock::new(LogFormat::from_env);
+
+/// Logs written at startup, as filter directives like `debug` or
+/// `info,rate_limit_server::storage=debug`, until changed with
+/// `PUT /admin/log_level`.
+pub static LOG_LEVEL: LazyLock<String> = LazyLock::new(|| match env::var("LOG_LEVEL") {
+    Ok(level) => match EnvFilter::try_new(&level) {
+        Ok(_) => level,
+        Err(e) => {
+            invalid("LOG_LEVEL", e);
+            DEFAULT_LOG_LEVEL.to_string()
+        }
+    },
+    Err(_) => DEFAULT_LOG_LEVEL.to_string(),
+});
+
+pub static ACCESS_LOG_CONFIG: LazyLock<Option<AccessLogConfig>> = LazyLock::new(|| {
+    Some(AccessLogConfig {
+        path: env::var("RATE_LIMIT_ACCESS_LOG_PATH").ok()?,
+        format: AccessLogFormat::from_env(),
+        rotation: LogRotation::from_env(),
+        max_files: positive_env("RATE_LIMIT_ACCESS_LOG_MAX_FILES"),
+    })
+});
+
+pub static LOG_SAMPLING_CONFIG: LazyLock<LogSamplingConfig> = LazyLock::new(|| LogSamplingConfig {
+    sample_rate: positive_env("RATE_LIMIT_LOG_SAMPLE_RATE").unwrap_or(1),
+    key_lines_per_second: positive_env("RATE_LIMIT_LOG_KEY_LINES_PER_SECOND"),
+});
+
+/// Whether spans are exported over OTLP, to the collector named by the
+/// standard `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, which the exporter reads itself.
+pub static OTLP_TRACING: LazyLock<bool> = LazyLock::new(|| {
+    [
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+    ]
+    .iter()
+    .any(|name| env::var(name).is_ok_and(|v| !v.trim().is_empty()))
+});
+
+/// Longest a request may take until its response starts, so slow clients and
+/// handlers cannot tie up the server. `0` turns the timeout off.
+pub static REQUEST_TIMEOUT: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    let ms = parse_env("RATE_LIMIT_REQUEST_TIMEOUT_MS").unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS);
+    (ms > 0).then(|| Duration::from_millis(ms))
+});
+
+/// Largest request body accepted. `0` turns the limit off.
+pub static MAX_BODY_BYTES: LazyLock<Option<usize>> = LazyLock::new(|| {
+    let bytes = parse_env("RATE_LIMIT_MAX_BODY_BYTES").unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    (bytes > 0).then_some(bytes)
+});
+
+pub static HYBRID_SYNC_MS: LazyLock<u64> =
+    LazyLock::new(|| positive_env("RATE_LIMIT_HYBRID_SYNC_MS").unwrap_or(DEFAULT_HYBRID_SYNC_MS));
+
+/// Settings for validating bearer tokens when keying by JWT claim.
+///
+/// Tokens are verified with `secret` (HS256) when set, otherwise against the
+/// key set published at `jwks_url`.
+#[derive(Clone)]
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh_seconds: u64,
+    pub claim: String,
+}
+
+/// A class of clients recognized by `User-Agent` substrings, limited under its
+/// own profile.
+#[derive(Clone, Debug)]
+pub struct UserAgentClass {
+    pub name: String,
+    /// Lowercase substrings, any of which puts a client in this class.
+    pub patterns: Vec<String>,
+    /// The class's own limit; classes without one get the default limit.
+    pub config: Option<Arc<RateLimitConfig>>,
+}
+
+impl UserAgentClass {
+    /// Parses a `name:pattern|pattern=max/window` entry; `RATE_LIMIT_UA_CLASSES`
+    /// separates them with `;`, e.g.
+    /// `good_bot:googlebot|bingbot=100/60;bot:bot|crawler|spider=5/60`.
+    ///
+    /// Classes without a limit use the default one. Earlier classes win when a
+    /// `User-Agent` matches several.
+    pub fn parse(entry: &str) -> Option<Self> {
+        let (class, limit) = match entry.split_once('=') {
+            Some((class, limit)) => (class, Some(Arc::new(RateLimitConfig::parse(limit)?))),
+            None => (entry, None),
+        };
+        let (name, patterns) = class.trim().split_once(':')?;
+        Some(Self {
+            name: name.trim().to_string(),
+            patterns: patterns
+                .split('|')
+                .map(|p| p.trim().to_ascii_lowercase())
+                .filter(|p| !p.is_empty())
+                .collect(),
+            config: limit,
+        })
+    }
+}
+
+/// JSON request body field used as key by the `body` extractor, the routes
+/// whose bodies are read for it, and the largest body buffered to find it.
+#[derive(Clone)]
+pub struct BodyKeyConfig {
+    pub field: String,
+    pub paths: Vec<String>,
+    pub max_bytes: usize,
+}
+
+impl BodyKeyConfig {
+    /// Whether the body of a request to `path` is read for its key.
+    pub fn reads(&self, path: &str) -> bool {
+        self.paths.iter().any(|pattern| path_matches(pattern, path))
+    }
+}
+
+/// Endpoint API keys are resolved to tiers with, see `tier::TierResolver`.
+#[derive(Clone)]
+pub struct TierLookupConfig {
+    pub url: String,
+    pub timeout_ms: u64,
+    pub cache_ttl_seconds: u64,
+    /// Most API keys whose tiers are cached.
+    pub cache_max_keys: usize,
+    /// Most lookups sent a second; keys beyond are left without a tier.
+    pub max_lookups_per_second: u32,
+}
+
+/// Where per-client limit overrides are looked up in the backing store, see
+/// `overrides::StoreOverrides`.
+#[derive(Clone)]
+pub struct StoreOverridesConfig {
+    pub prefix: String,
+    pub cache_ttl_seconds: u64,
+    /// Most client keys whose overrides are cached.
+    pub cache_max_keys: usize,
+}
+
+/// Calendar period (UTC) a quota counts requests over.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+/// Long-horizon limit enforced on top of the rate limit, with counters kept
+/// durably in Postgres.
+#[derive(Clone, Debug)]
+pub struct QuotaConfig {
+    pub max_requests: u64,
+    pub period: QuotaPeriod,
+    pub database_url: String,
+}
+
+impl QuotaConfig {
+    /// Parses a quota written as `max_requests/period`, e.g. `10000/day` or
+    /// `100000/month`.
+    fn parse(value: &str, database_url: String) -> Option<Self> {
+        let (max_requests, period) = value.trim().split_once('/')?;
+        Some(Self {
+            max_requests: max_requests.trim().parse().ok()?,
+            period: match period.trim() {
+                "day" => QuotaPeriod::Day,
+                "month" => QuotaPeriod::Month,
+                _ => return None,
+            },
+            database_url,
+        })
+    }
+}
+
+/// Clients rejected with 403 Forbidden before any limiter work: the `entries`
+/// given inline plus those listed at `source`, a file path or HTTP(S) URL
+/// refetched every `refresh_seconds`.
+#[derive(Clone, Debug)]
+pub struct DenylistConfig {
+    pub entries: Vec<IpNet>,
+    pub source: Option<String>,
+    pub refresh_seconds: u64,
+}
+
+/// Where proxied requests go: one URL, or several targets balanced between.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Upstream {
+    Url(String),
+    Pool(UpstreamPool),
+}
+
+/// Told apart by hand rather than with `#[serde(untagged)]`, so mistakes in
+/// a pool are reported as such instead of as matching neither form.
+impl<'de> Deserialize<'de> for Upstream {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Upstream;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a URL or a table with targets")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, url: &str) -> Result<Upstream, E> {
+                Ok(Upstream::Url(url.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Upstream, A::Error> {
+                let map = serde::de::value::MapAccessDeserializer::new(map);
+                UpstreamPool::deserialize(map).map(Upstream::Pool)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl Upstream {
+    /// The upstream as a pool, of one target for a plain URL.
+    pub fn pool(&self) -> UpstreamPool {
+        match self {
+            Self::Url(url) => UpstreamPool {
+                targets: vec![url.clone()],
+                balance: Balance::default(),
+                health_check: HealthCheck::default(),
+                outbound_limit: None,
+                outbound_queue: None,
+                retry_after: RetryAfter::default(),
+                cache: None,
+            },
+            Self::Pool(pool) => pool.clone(),
+        }
+    }
+}
+
+/// Targets serving the same service, requests being spread over the healthy
+/// ones.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamPool {
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    #[serde(default)]
+    pub health_check: HealthCheck,
+    /// Most requests forwarded to the upstream per window, from all clients
+    /// together, protecting upstreams that cannot take more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbound_limit: Option<Arc<RateLimitConfig>>,
+    /// How long, and how many, requests over the outbound limit wait for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbound_queue: Option<ThrottleConfig>,
+    #[serde(default)]
+    pub retry_after: RetryAfter,
+    /// Caching of the upstream's responses to `GET` requests, off without.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheConfig>,
+}
+
+impl UpstreamPool {
+    /// Waiting for the outbound limit, up to a second by default.
+    pub fn outbound_queue(&self) -> ThrottleConfig {
+        self.outbound_queue.unwrap_or(ThrottleConfig {
+            max_wait_ms: DEFAULT_OUTBOUND_MAX_WAIT_MS,
+            max_queue: DEFAULT_THROTTLE_MAX_QUEUE,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.targets.is_empty() {
+            return Err("has no targets".to_string());
+        }
+        if let Some(target) = self.targets.iter().find(|target| {
+            !reqwest::Url::parse(target).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        }) {
+            return Err(format!("{:?} is not an HTTP(S) URL", target));
+        }
+        if !self.health_check.path.starts_with('/') {
+            return Err(format!(
+                "health check path {:?} does not start with /",
+                self.health_check.path
+            ));
+        }
+        if self.health_check.interval_seconds == 0 {
+            return Err("health check interval_seconds must be greater than 0".to_string());
+        }
+        if let Some(Err(problem)) = self.outbound_limit.as_ref().map(|limit| limit.check()) {
+            return Err(format!("outbound limit: {}", problem));
+        }
+        if self
+            .outbound_queue
+            .is_some_and(|queue| queue.max_queue == 0)
+        {
+            return Err("outbound queue max_queue must be greater than 0".to_string());
+        }
+        if let Some(Err(problem)) = self.cache.as_ref().map(CacheConfig::check) {
+            return Err(format!("cache: {}", problem));
+        }
+        Ok(())
+    }
+}
+
+/// How requests are spread over the healthy targets of a pool.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// Each target in turn.
+    #[default]
+    RoundRobin,
+    /// The target with the fewest requests in flight.
+    LeastConnections,
+}
+
+impl Balance {
+    pub fn from_env() -> Self {
+        match env::var("UPSTREAM_BALANCE").as_deref() {
+            Ok("round_robin") => Self::RoundRobin,
+            Ok("least_connections") => Self::LeastConnections,
+            value => {
+                unexpected("UPSTREAM_BALANCE", value, "round_robin, least_connections");
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Requests sent to each target of a pool every `interval_seconds`, targets
+/// not answering `path` with a 2xx status being taken out of rotation until
+/// they do again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval_seconds: u64,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_HEALTH_CHECK_PATH.to_string(),
+            interval_seconds: DEFAULT_HEALTH_CHECK_INTERVAL_SECONDS,
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds)
+    }
+}
+
+/// How a `Retry-After` on the `429` and `503` responses of an upstream is
+/// honored: requests to it are held back until then, for up to
+/// `max_seconds`, `0` ignoring the header.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryAfter {
+    pub max_seconds: u64,
+    /// Whether clients are told how long is left of the backoff in place of
+    /// the upstream's `Retry-After`.
+    pub propagate: bool,
+}
+
+impl Default for RetryAfter {
+    fn default() -> Self {
+        Self {
+            max_seconds: DEFAULT_RETRY_AFTER_MAX_SECONDS,
+            propagate: false,
+        }
+    }
+}
+
+impl RetryAfter {
+    pub fn max(&self) -> Duration {
+        Duration::from_secs(self.max_seconds)
+    }
+}
+
+/// Responses to `GET` requests kept for up to `ttl_seconds`, so clients
+/// asking for the same thing do not all reach the upstream. Bodies over
+/// `max_entry_bytes` are not kept, nor more than `max_bytes` of them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub ttl_seconds: u64,
+    pub max_bytes: usize,
+    pub max_entry_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_seconds: DEFAULT_CACHE_TTL_SECONDS,
+            max_bytes: DEFAULT_CACHE_MAX_BYTES,
+            max_entry_bytes: DEFAULT_CACHE_MAX_ENTRY_BYTES,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_seconds)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.ttl_seconds == 0 {
+            return Err("ttl_seconds must be greater than 0".to_string());
+        }
+        if self.max_entry_bytes == 0 || self.max_entry_bytes > self.max_bytes {
+            return Err("max_entry_bytes must be from 1 to max_bytes".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Cross-origin access for browser clients, so scripts on the `origins` can
+/// read responses, rejections and their rate limit headers included.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// `None` allows any origin.
+    pub origins: Option<Vec<HeaderValue>>,
+    pub methods: Vec<Method>,
+    /// Request headers scripts may send, besides the CORS-safelisted ones.
+    pub headers: Vec<HeaderName>,
+    /// Whether cookies and `Authorization` headers are sent along.
+    pub credentials: bool,
+    pub max_age_seconds: u64,
+}
+
+/// Limits on WebSocket connections proxied upstream, which outlive the one
+/// request counted against the client's rate limit.
+#[derive(Clone, Debug, Serialize)]
+pub struct WebSocketConfig {
+    /// Most connections of one key open at once.
+    pub max_connections: Option<usize>,
+    /// Most messages each connection may send upstream.
+    pub message_limit: Option<Arc<RateLimitConfig>>,
+    pub message_policy: MessagePolicy,
+    /// Messages over the limit the `drop` policy discards before closing the
+    /// connection; it is never closed without.
+    pub max_violations: Option<u64>,
+}
+
+/// What becomes of WebSocket messages over their connection's message limit.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessagePolicy {
+    /// The connection is closed at the first one.
+    Close,
+    /// They are discarded, the connection staying open.
+    Drop,
+}
+
+impl MessagePolicy {
+    pub fn from_env() -> Self {
+        match env::var("RATE_LIMIT_WS_MESSAGE_POLICY").as_deref() {
+            Ok("close") => Self::Close,
+            Ok("drop") => Self::Drop,
+            value => {
+                unexpected("RATE_LIMIT_WS_MESSAGE_POLICY", value, "close, drop");
+                Self::Close
+            }
+        }
+    }
+}
+
+/// Certificate and key to serve HTTPS with, and the CA client certificates
+/// are verified against when mutual TLS is enabled.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+    pub client_cert_required: bool,
+}
+
+/// Where the admin API is served instead of the main listener.
+#[derive(Clone, Debug)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// The listener of the admin API, kept apart from the public one.
+#[derive(Clone)]
+pub struct AdminListenerConfig {
+    pub addr: AdminAddr,
+    /// Certificate to serve HTTPS with, requiring client certificates when
+    /// it has a client CA.
+    pub tls: Option<TlsConfig>,
+}
+
+impl AdminListenerConfig {
+    /// Whether operators are authenticated by their client certificates.
+    pub fn mutual_tls(&self) -> bool {
+        self.tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_ca_path.is_some())
+    }
+}
+
+pub static DENYLIST_CONFIG: LazyLock<DenylistConfig> = LazyLock::new(|| DenylistConfig {
+    entries: env::var("RATE_LIMIT_DENYLIST")
+        .map(|v| parse_list("RATE_LIMIT_DENYLIST", &v, ',', parse_cidr))
+        .unwrap_or_default(),
+    source: env::var("RATE_LIMIT_DENYLIST_SOURCE").ok(),
+    refresh_seconds: positive_env("RATE_LIMIT_DENYLIST_REFRESH_SECONDS")
+        .unwrap_or(DEFAULT_DENYLIST_REFRESH_SECONDS),
+});
+
+pub static KEY_EXTRACTORS: LazyLock<Vec<KeyExtractorKind>> =
+    LazyLock::new(KeyExtractorKind::chain_from_env);
+
+pub static SESSION_COOKIE: LazyLock<String> = LazyLock::new(|| {
+    env::var("RATE_LIMIT_SESSION_COOKIE").unwrap_or_else(|_| DEFAULT_SESSION_COOKIE.to_string())
+});
+
+/// Longest query parameter value accepted as a key, so clients cannot flood the
+/// limiter with arbitrarily large keys.
+pub static QUERY_KEY_MAX_LENGTH: LazyLock<usize> = LazyLock::new(|| {
+    parse_env("RATE_LIMIT_QUERY_KEY_MAX_LENGTH").unwrap_or(DEFAULT_QUERY_KEY_MAX_LENGTH)
+});
+
+pub static KEY_SCOPE: LazyLock<KeyScope> = LazyLock::new(KeyScope::from_env);
+
+pub static RATE_LIMIT_MODE: LazyLock<RateLimitMode> = LazyLock::new(RateLimitMode::from_env);
+
+pub static RATE_LIMIT_HEADERS: LazyLock<RateLimitHeaders> =
+    LazyLock::new(RateLimitHeaders::from_env);
+
+/// Percentage of a client's budget that, once used, gets its responses an
+/// `X-RateLimit-Warning` header. No warnings are sent without one.
+pub static WARNING_THRESHOLD: LazyLock<Option<u64>> = LazyLock::new(|| {
+    let value = env::var("RATE_LIMIT_WARNING_THRESHOLD").ok()?;
+    match value.trim().trim_end_matches('%').parse() {
+        Ok(percent @ 1..=100) => Some(percent),
+        _ => {
+            invalid(
+                "RATE_LIMIT_WARNING_THRESHOLD",
+                format!("{:?} is not a percentage from 1 to 100", value),
+            );
+            None
+        }
+    }
+});
+
+pub static ERROR_BODY_CONFIG: LazyLock<ErrorBodyConfig> = LazyLock::new(ErrorBodyConfig::from_env);
+
+pub static REJECTION_CONFIG: LazyLock<RejectionConfig> = LazyLock::new(RejectionConfig::from_env);
+
+pub static RATE_LIMIT_PROFILE: LazyLock<Option<Profile>> = LazyLock::new(Profile::from_env);
+
+/// Bearer token the admin endpoints require; they are not served without one.
+pub static ADMIN_TOKEN: LazyLock<Option<String>> = LazyLock::new(|| {
+    let token = env::var("RATE_LIMIT_ADMIN_TOKEN").ok()?;
+    if token.trim().is_empty() {
+        invalid("RATE_LIMIT_ADMIN_TOKEN", "must not be empty");
+        return None;
+    }
+    if env::var_os("RATE_LIMIT_ADMIN_LISTEN").is_none() {
+        invalid(
+            "RATE_LIMIT_ADMIN_TOKEN",
+            "needs RATE_LIMIT_ADMIN_LISTEN, so the admin API is not served on the public listener",
+        );
+    }
+    Some(token.trim().to_string())
+});
+
+/// Serves the admin API on a listener of its own, a TCP address or
+/// `unix:<path>`, rather than the main one, so it is never reachable by the
+/// public.
+pub static ADMIN_LISTENER: LazyLock<Option<AdminListenerConfig>> = LazyLock::new(|| {
+    let value = env::var("RATE_LIMIT_ADMIN_LISTEN").ok()?;
+    let addr = match value.trim().strip_prefix("unix:") {
+        Some("") => {
+            invalid("RATE_LIMIT_ADMIN_LISTEN", "unix: needs a socket path");
+            return None;
+        }
+        Some(path) => AdminAddr::Unix(PathBuf::from(path)),
+        None => match value.trim().parse() {
+            Ok(addr) => AdminAddr::Tcp(addr),
+            Err(e) => {
+                invalid(
+                    "RATE_LIMIT_ADMIN_LISTEN",
+                    format!("{:?} is not an address or unix:<path>: {}", value, e),
+                );
+                return None;
+            }
+        },
+    };
+    let tls = match (
+        env::var("RATE_LIMIT_ADMIN_TLS_CERT_PATH"),
+        env::var("RATE_LIMIT_ADMIN_TLS_KEY_PATH"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: env::var("RATE_LIMIT_ADMIN_TLS_CLIENT_CA_PATH").ok(),
+            client_cert_required: true,
+        }),
+        (Err(_), Err(_)) => None,
+        _ => {
+            invalid(
+                "RATE_LIMIT_ADMIN_TLS_CERT_PATH",
+                "needs RATE_LIMIT_ADMIN_TLS_KEY_PATH, and the other way around",
+            );
+            None
+        }
+    };
+    let config = AdminListenerConfig { addr, tls };
+    if config.tls.is_some() && matches!(config.addr, AdminAddr::Unix(_)) {
+        invalid(
+            "RATE_LIMIT_ADMIN_LISTEN",
+            "TLS is only served on TCP addresses",
+        );
+    }
+    if ADMIN_TOKEN.is_none() && !config.mutual_tls() {
+        invalid(
+            "RATE_LIMIT_ADMIN_LISTEN",
+            "needs RATE_LIMIT_ADMIN_TOKEN or RATE_LIMIT_ADMIN_TLS_CLIENT_CA_PATH to authenticate operators",
+        );
+    }
+    Some(config)
+});
+
+pub static API_KEY_HEADER: LazyLock<HeaderName> = LazyLock::new(|| {
+    parse_env("RATE_LIMIT_API_KEY_HEADER")
+        .unwrap_or(HeaderName::from_static(DEFAULT_API_KEY_HEADER))
+});
+
+pub static ANONYMOUS_POLICY: LazyLock<AnonymousPolicy> = LazyLock::new(AnonymousPolicy::from_env);
+
+pub static STORE_FAILURE_POLICY: LazyLock<StoreFailurePolicy> =
+    LazyLock::new(StoreFailurePolicy::from_env);
+
+/// Drain policy applied when drain mode is switched on without one.
+pub static DRAIN_POLICY: LazyLock<DrainPolicy> = LazyLock::new(DrainPolicy::from_env);
+
+pub static JWT_CONFIG: LazyLock<JwtConfig> = LazyLock::new(|| JwtConfig {
+    secret: env::var("RATE_LIMIT_JWT_SECRET").ok(),
+    jwks_url: env::var("RATE_LIMIT_JWT_JWKS_URL").ok(),
+    jwks_refresh_seconds: parse_env("RATE_LIMIT_JWT_JWKS_REFRESH_SECONDS")
+        .unwrap_or(DEFAULT_JWKS_REFRESH_SECONDS),
+    claim: env::var("RATE_LIMIT_JWT_CLAIM").unwrap_or_else(|_| DEFAULT_JWT_CLAIM.to_string()),
+});
+
+pub static TRUSTED_PROXIES: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .map(|v| parse_list("TRUSTED_PROXIES", &v, ',', parse_cidr))
+        .unwrap_or_default()
+});
+
+pub static CLIENT_IP_HEADERS: LazyLock<Vec<ClientIpHeader>> =
+    LazyLock::new(ClientIpHeader::precedence_from_env);
+
+pub static SUBNET_AGGREGATION: LazyLock<SubnetAggregation> =
+    LazyLock::new(SubnetAggregation::from_env);
+
+/// Off unless allowed origins are set, `*` for any.
+pub static CORS_CONFIG: LazyLock<Option<CorsConfig>> = LazyLock::new(|| {
+    let origins = env::var("RATE_LIMIT_CORS_ORIGINS").ok()?;
+    let origins = (origins.trim() != "*").then(|| {
+        parse_list("RATE_LIMIT_CORS_ORIGINS", &origins, ',', |origin| {
+            (origin != "*").then(|| origin.parse().ok()).flatten()
+        })
+    });
+    let methods =
+        env::var("RATE_LIMIT_CORS_METHODS").unwrap_or_else(|_| DEFAULT_CORS_METHODS.to_string());
+    // Clients must be able to send the headers they are identified by.
+    let headers = env::var("RATE_LIMIT_CORS_HEADERS").map_or_else(
+        |_| {
+            vec![
+                axum::http::header::AUTHORIZATION,
+                axum::http::header::CONTENT_TYPE,
+                API_KEY_HEADER.clone(),
+                HeaderName::from_static(crate::request_id::REQUEST_ID_HEADER),
+            ]
+        },
+        |v| parse_list("RATE_LIMIT_CORS_HEADERS", &v, ',', |h| h.parse().ok()),
+    );
+    let credentials = parse_env("RATE_LIMIT_CORS_CREDENTIALS").unwrap_or(false);
+    if credentials && origins.is_none() {
+        invalid(
+            "RATE_LIMIT_CORS_CREDENTIALS",
+            "cannot be used with RATE_LIMIT_CORS_ORIGINS=*, list the origins instead",
+        );
+        return None;
+    }
+    Some(CorsConfig {
+        origins,
+        methods: parse_list("RATE_LIMIT_CORS_METHODS", &methods, ',', |m| {
+            m.to_uppercase().parse().ok()
+        }),
+        headers,
+        credentials,
+        max_age_seconds: parse_env("RATE_LIMIT_CORS_MAX_AGE_SECONDS")
+            .unwrap_or(DEFAULT_CORS_MAX_AGE_SECONDS),
+    })
+});
+
+pub static WEBSOCKET_CONFIG: LazyLock<WebSocketConfig> = LazyLock::new(|| WebSocketConfig {
+    max_connections: positive_env("RATE_LIMIT_WS_MAX_CONNECTIONS"),
+    message_limit: limit_env("RATE_LIMIT_WS_MESSAGE_LIMIT").map(Arc::new),
+    message_policy: MessagePolicy::from_env(),
+    max_violations: positive_env("RATE_LIMIT_WS_MAX_VIOLATIONS"),
+});
+
+pub static TLS_CONFIG: LazyLock<Option<TlsConfig>> = LazyLock::new(|| {
+    Some(TlsConfig {
+        cert_path: env::var("TLS_CERT_PATH").ok()?,
+        key_path: env::var("TLS_KEY_PATH").ok()?,
+        client_ca_path: env::var("TLS_CLIENT_CA_PATH").ok(),
+        client_cert_required: parse_env("TLS_CLIENT_CERT_REQUIRED").unwrap_or(false),
+    })
+});
+
+/// Salt keys are hashed with before reaching the limiter, when key hashing is
+/// enabled with `RATE_LIMIT_HASH_KEYS`.
+///
+/// Without `RATE_LIMIT_KEY_SALT` a random salt is generated, so hashes are
+/// only stable for the lifetime of the process.
+pub static KEY_HASH_SALT: LazyLock<Option<Vec<u8>>> = LazyLock::new(|| {
+    if !parse_env("RATE_LIMIT_HASH_KEYS").unwrap_or(false) {
+        return None;
+    }
+    Some(
+        env::var("RATE_LIMIT_KEY_SALT")
+            .map(String::into_bytes)
+            .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec()),
+    )
+});
+
+pub static USER_AGENT_CLASSES: LazyLock<Vec<UserAgentClass>> = LazyLock::new(|| {
+    env::var("RATE_LIMIT_UA_CLASSES")
+        .map(|v| parse_list("RATE_LIMIT_UA_CLASSES", &v, ';', UserAgentClass::parse))
+        .unwrap_or_default()
+});
+
+pub static TIER_LOOKUP_CONFIG: LazyLock<Option<TierLookupConfig>> = LazyLock::new(|| {
+    Some(TierLookupConfig {
+        url: env::var("RATE_LIMIT_TIER_LOOKUP_URL").ok()?,
+        timeout_ms: parse_env("RATE_LIMIT_TIER_LOOKUP_TIMEOUT_MS")
+            .unwrap_or(DEFAULT_TIER_LOOKUP_TIMEOUT_MS),
+        cache_ttl_seconds: parse_env("RATE_LIMIT_TIER_CACHE_TTL_SECONDS")
+            .unwrap_or(DEFAULT_TIER_CACHE_TTL_SECONDS),
+        cache_max_keys: positive_env("RATE_LIMIT_TIER_CACHE_MAX_KEYS")
+            .unwrap_or(DEFAULT_TIER_CACHE_MAX_KEYS),
+        max_lookups_per_second: positive_env("RATE_LIMIT_TIER_LOOKUPS_PER_SECOND")
+            .unwrap_or(DEFAULT_TIER_LOOKUPS_PER_SECOND),
+    })
+});
+
+pub static STORE_OVERRIDES_CONFIG: LazyLock<Option<StoreOverridesConfig>> = LazyLock::new(|| {
+    let prefix = env::var("RATE_LIMIT_OVERRIDE_STORE_PREFIX").ok()?;
+    let shared_store = match *RATE_LIMITER_BACKEND {
+        RateLimiterBackend::Redis => *RATE_LIMITER_TYPE == RateLimiterType::Store,
+        RateLimiterBackend::Memcached | RateLimiterBackend::DynamoDb => true,
+        _ => false,
+    };
+    if !shared_store {
+        invalid(
+            "RATE_LIMIT_OVERRIDE_STORE_PREFIX",
+            "overrides can only be looked up in the redis, memcached or dynamodb store",
+        );
+    }
+    Some(StoreOverridesConfig {
+        prefix,
+        cache_ttl_seconds: parse_env("RATE_LIMIT_OVERRIDE_CACHE_TTL_SECONDS")
+            .unwrap_or(DEFAULT_OVERRIDE_CACHE_TTL_SECONDS),
+        cache_max_keys: positive_env("RATE_LIMIT_OVERRIDE_CACHE_MAX_KEYS")
+            .unwrap_or(DEFAULT_OVERRIDE_CACHE_MAX_KEYS),
+    })
+});
+
+/// Set when the key extractors include `body`, which bodies are otherwise
+/// never read for.
+pub static BODY_KEY_CONFIG: LazyLock<Option<BodyKeyConfig>> = LazyLock::new(|| {
+    if !KEY_EXTRACTORS.contains(&KeyExtractorKind::BodyField) {
+        return None;
+    }
+    let Ok(field) = env::var("RATE_LIMIT_BODY_KEY_FIELD") else {
+        invalid(
+            "RATE_LIMIT_BODY_KEY_FIELD",
+            "is required by the body extractor",
+        );
+        return None;
+    };
+    let paths = env::var("RATE_LIMIT_BODY_KEY_PATHS")
+        .map(|v| {
+            parse_list("RATE_LIMIT_BODY_KEY_PATHS", &v, ',', |pattern| {
+                pattern.starts_with('/').then(|| pattern.to_string())
+            })
+        })
+        .unwrap_or_default();
+    if paths.is_empty() {
+        invalid(
+            "RATE_LIMIT_BODY_KEY_PATHS",
+            "must list the paths whose bodies the body extractor reads",
+        );
+    }
+    Some(BodyKeyConfig {
+        field,
+        paths,
+        max_bytes: parse_env("RATE_LIMIT_BODY_KEY_MAX_BYTES").unwrap_or(DEFAULT_BODY_KEY_MAX_BYTES),
+    })
+});
+
+pub static QUOTA_CONFIG: LazyLock<Option<QuotaConfig>> = LazyLock::new(|| {
+    let value = env::var("RATE_LIMIT_QUOTA").ok()?;
+    let quota = QuotaConfig::parse(
+        &value,
+        env::var("POSTGRES_URL").unwrap_or_else(|_| DEFAULT_POSTGRES_URL.to_string()),
+    );
+    if quota.is_none() {
+        invalid(
+            "RATE_LIMIT_QUOTA",
+            format!("{:?} is not max_requests/day or max_requests/month", value),
+        );
+    }
+    quota
+});
+
+pub static EVENTS_CONFIG: LazyLock<Option<EventsConfig>> = LazyLock::new(|| {
+    let sink = match env::var("RATE_LIMIT_EVENTS_SINK").as_deref() {
+        Ok("kafka") => EventSink::Kafka {
+            brokers: env::var("RATE_LIMIT_EVENTS_KAFKA_BROKERS")
+                .unwrap_or_else(|_| DEFAULT_KAFKA_BROKERS.to_string())
+                .split(',')
+                .map(|broker| broker.trim().to_string())
+                .filter(|broker| !broker.is_empty())
+                .collect(),
+        },
+        Ok("nats") => EventSink::Nats {
+            url: env::var("RATE_LIMIT_EVENTS_NATS_URL")
+                .unwrap_or_else(|_| DEFAULT_NATS_URL.to_string()),
+        },
+        value => {
+            unexpected("RATE_LIMIT_EVENTS_SINK", value, "kafka, nats");
+            return None;
+        }
+    };
+    Some(EventsConfig {
+        sink,
+        topic: env::var("RATE_LIMIT_EVENTS_TOPIC")
+            .unwrap_or_else(|_| DEFAULT_EVENTS_TOPIC.to_string()),
+        buffer: positive_env("RATE_LIMIT_EVENTS_BUFFER").unwrap_or(DEFAULT_EVENTS_BUFFER),
+    })
+});
+
+/// The limits in force, which change when the config file is reloaded or a
+/// schedule block starts or ends. Environment variables override the file's
+/// values as at startup.
+#[derive(Clone, Debug, Serialize)]
+pub struct Limits {
+    pub default: Arc<RateLimitConfig>,
+    /// Limit shared by all anonymous requests under the `shared` policy,
+    /// defaulting to the regular limit.
+    pub anonymous: Arc<RateLimitConfig>,
+    /// Limits of the named tiers, parsed from `name=max/window` entries such
+    /// as `free=10/60,pro=100/60,enterprise=1000/60`.
+    pub tiers: HashMap<String, Arc<RateLimitConfig>>,
+    /// Tiers of individual API keys, from `key=tier` entries.
+    #[serde(serialize_with = "hashed_keys")]
+    pub api_key_tiers: HashMap<String, String>,
+    /// JWT claim naming the tier of the token's bearer.
+    pub tier_claim: Option<String>,
+    /// Limits of individual clients by key, e.g. `api_key:k-1234` or an IP,
+    /// which take precedence over their tier.
+    #[serde(serialize_with = "hashed_keys")]
+    pub overrides: HashMap<String, Arc<RateLimitConfig>>,
+    /// Route rules of the config file, and those managed through the admin
+    /// API in their place or ahead of them.
+    pub routes: Vec<RouteRule>,
+    /// Route rules of the config file alone.
+    #[serde(skip)]
+    file_routes: Vec<RouteRule>,
+    /// Clients that are never rate limited, such as internal monitoring and
+    /// partner ranges.
+    pub allowlist: IpSet,
+    /// Paths that are never rate limited, so health checks and scrapes do
+    /// not use up client budgets.
+    pub exempt_paths: Vec<String>,
+    /// Name of the schedule block whose limits are in force, if any.
+    pub schedule: Option<String>,
+    schedules: Vec<Schedule>,
+    /// The default and anonymous limits outside of schedule blocks.
+    #[serde(skip)]
+    unscheduled: (Arc<RateLimitConfig>, Arc<RateLimitConfig>),
+    /// Identifies these limits, see `versioned`.
+    #[serde(skip)]
+    pub version: String,
+}
+
+/// Serializes a map by client key with the keys replaced by the start of
+/// their SHA-256, so `GET /admin/config` shows no customer's API key while
+/// the version still changes with them.
+fn hashed_keys<V: Serialize, S: Serializer>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().map(|(key, value)| {
+        let digest = Sha256::digest(key);
+        (format!("sha256:{}", hex::encode(&digest[..8])), value)
+    }))
+}
+
+impl Limits {
+    pub fn new(file: &FileConfig) -> Self {
+        // `{prefix}_WINDOW_MS` takes precedence over `{prefix}_WINDOW_SECONDS`.
+        let limit = |prefix: &str, file: RateLimitConfig| RateLimitConfig {
+            max_requests: parse_env(&format!("{}_MAX_REQUESTS", prefix))
+                .unwrap_or(file.max_requests),
+            window: parse_env(&format!("{}_WINDOW_MS", prefix))
+                .map(Duration::from_millis)
+                .or_else(|| {
+                    parse_env(&format!("{}_WINDOW_SECONDS", prefix)).map(Duration::from_secs)
+                })
+                .unwrap_or(file.window),
+        };
+        let mut default = limit(
+            "RATE_LIMIT",
+            file.limits
+                .default
+                .clone()
+                .or(RATE_LIMIT_PROFILE.map(Profile::limit))
+                .unwrap_or_default(),
+        );
+        default.max_requests = ARGS.max_requests.unwrap_or(default.max_requests);
+        default.window = ARGS
+            .window_ms
+            .map(Duration::from_millis)
+            .or(ARGS.window.map(Duration::from_secs))
+            .unwrap_or(default.window);
+        let anonymous = limit(
+            "RATE_LIMIT_ANONYMOUS",
+            file.limits
+                .anonymous
+                .clone()
+                .or(RATE_LIMIT_PROFILE.map(Profile::anonymous_limit))
+                .unwrap_or_else(|| default.clone()),
+        );
+
+        let (default, anonymous) = (Arc::new(default), Arc::new(anonymous));
+        let limits = Self {
+            default: default.clone(),
+            anonymous: anonymous.clone(),
+            tiers: env::var("RATE_LIMIT_TIERS")
+                .map(|v| {
+                    parse_list("RATE_LIMIT_TIERS", &v, ',', |entry| {
+                        let (name, limit) = entry.split_once('=')?;
+                        let limit = RateLimitConfig::parse(limit)?;
+                        Some((name.trim().to_string(), Arc::new(limit)))
+                    })
+                    .into_iter()
+                    .collect()
+                })
+                .unwrap_or_else(|_| file.limits.tiers.clone()),
+            api_key_tiers: env::var("RATE_LIMIT_API_KEY_TIERS")
+                .map(|v| {
+                    parse_list("RATE_LIMIT_API_KEY_TIERS", &v, ',', |entry| {
+                        let (key, tier) = entry.split_once('=')?;
+                        Some((key.trim().to_string(), tier.trim().to_string()))
+                    })
+                    .into_iter()
+                    .collect()
+                })
+                .unwrap_or_else(|_| file.limits.api_key_tiers.clone()),
+            tier_claim: env::var("RATE_LIMIT_JWT_TIER_CLAIM")
+                .ok()
+                .or_else(|| file.limits.tier_claim.clone()),
+            overrides: env::var("RATE_LIMIT_OVERRIDES")
+                .map(|v| {
+                    parse_list("RATE_LIMIT_OVERRIDES", &v, ',', |entry| {
+                        let (key, limit) = entry.rsplit_once('=')?;
+                        let limit = RateLimitConfig::parse(limit)?;
+                        Some((key.trim().to_string(), Arc::new(limit)))
+                    })
+                    .into_iter()
+                    .collect()
+                })
+                .unwrap_or_else(|_| file.limits.overrides.clone()),
+            routes: rules::merged(&file.routes),
+            file_routes: file.routes.clone(),
+            allowlist: match env::var("RATE_LIMIT_ALLOWLIST") {
+                Ok(v) => parse_list("RATE_LIMIT_ALLOWLIST", &v, ',', parse_cidr)
+                    .into_iter()
+                    .collect(),
+                Err(_) => file
+                    .allowlist
+                    .iter()
+                    .filter_map(|entry| parse_cidr(entry))
+                    .collect(),
+            },
+            exempt_paths: env::var("RATE_LIMIT_EXEMPT_PATHS")
+                .map(|v| {
+                    parse_list("RATE_LIMIT_EXEMPT_PATHS", &v, ',', |pattern| {
+                        pattern.starts_with('/').then(|| pattern.to_string())
+                    })
+                })
+                .ok()
+                .or_else(|| file.exempt_paths.clone())
+                .unwrap_or_else(|| DEFAULT_EXEMPT_PATHS.iter().map(|p| p.to_string()).collect()),
+            schedule: None,
+            schedules: file.schedules.clone(),
+            unscheduled: (default, anonymous),
+            version: String::new(),
+        };
+        limits
+            .reschedule(Utc::now())
+            .unwrap_or_else(|| limits.versioned())
+    }
+
+    /// These limits with their version set: the start of a SHA-256 of their
+    /// contents, so instances given the same config report the same version
+    /// and any reload or schedule switch changing them a new one.
+    fn versioned(mut self) -> Self {
+        // Values serialize with their fields sorted, whatever the order of
+        // the maps they came from.
+        let contents = serde_json::to_value(&self)
+            .map(|value| value.to_string())
+            .unwrap_or_default();
+        self.version = hex::encode(&Sha256::digest(contents)[..6]);
+        self
+    }
+
+    /// These limits with the schedule block active at `now` in force, or
+    /// `None` when that block is in force already.
+    ///
+    /// A block replaces the default and anonymous limits it sets, wherever
+    /// they came from; the others keep their regular values.
+    pub fn reschedule(&self, now: DateTime<Utc>) -> Option<Self> {
+        let active = self.schedules.iter().find(|block| block.is_active(now));
+        if active.map(|block| &block.name) == self.schedule.as_ref() {
+            return None;
+        }
+        let (default, anonymous) = &self.unscheduled;
+        Some(
+            Self {
+                default: active
+                    .and_then(|block| block.default.clone())
+                    .unwrap_or_else(|| default.clone()),
+                anonymous: active
+                    .and_then(|block| block.anonymous.clone())
+                    .unwrap_or_else(|| anonymous.clone()),
+                schedule: active.map(|block| block.name.clone()),
+                ..self.clone()
+            }
+            .versioned(),
+        )
+    }
+
+    /// These limits with the route rules managed through the admin API
+    /// merged in again, after they changed.
+    pub fn with_runtime_rules(&self) -> Self {
+        Self {
+            routes: rules::merged(&self.file_routes),
+            ..self.clone()
+        }
+        .versioned()
+    }
+
+    /// The route rules of the config file, without those managed through
+    /// the admin API.
+    pub fn file_routes(&self) -> &[RouteRule] {
+        &self.file_routes
+    }
+
+    pub fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+
+    /// Names of the rules requests can match, the default one included.
+    pub fn rule_names(&self) -> Vec<String> {
+        std::iter::once(DEFAULT_RULE_NAME.to_string())
+            .chain(self.routes.iter().map(|rule| rule.name.clone()))
+            .collect()
+    }
+
+    /// Every limit a request can be checked against.
+    fn configs(&self) -> impl Iterator<Item = &Arc<RateLimitConfig>> {
+        [&self.default, &self.anonymous]
+            .into_iter()
+            .chain(self.tiers.values())
+            .chain(self.overrides.values())
+            .chain(USER_AGENT_CLASSES.iter().filter_map(|c| c.config.as_ref()))
+            .chain(self.routes.iter().map(|rule| &rule.limit))
+            .chain(
+                self.schedules
+                    .iter()
+                    .flat_map(|block| [&block.default, &block.anonymous])
+                    .flatten(),
+            )
+    }
+}
+
+static LIMITS: LazyLock<RwLock<Arc<Limits>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Limits::new(&CONFIG_FILE))));
+
+/// The limits in force. Requests keep the limits they started with across a
+/// reload.
+pub fn limits() -> Arc<Limits> {
+    LIMITS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Puts new limits in force, returning the previous ones.
+pub fn replace_limits(limits: Limits) -> Arc<Limits> {
+    let mut current = LIMITS.write().unwrap_or_else(|e| e.into_inner());
+    std::mem::replace(&mut *current, Arc::new(limits))
+}
+
+/// Puts the limits `update` derives from the current ones in force, unless it
+/// returns `None`. Holding the lock throughout keeps a concurrent reload from
+/// being undone.
+pub fn update_limits(update: impl FnOnce(&Limits) -> Option<Limits>) -> Option<Arc<Limits>> {
+    let mut current = LIMITS.write().unwrap_or_else(|e| e.into_inner());
+    let limits = Arc::new(update(&current)?);
+    *current = limits.clone();
+    Some(limits)
+}
+
+/// Longest window of any configured limit, i.e. how long a key's state can
+/// matter after its last request.
+pub fn max_window() -> Duration {
+    limits()
+        .configs()
+        .map(|config| config.window)
+        .max()
+        .unwrap_or(Duration::from_secs(DEFAULT_WINDOW_SECONDS))
+}
+
+/// Reads every setting and returns the problems found, so a typo fails
+/// startup instead of silently falling back to a default.
+pub fn validate() -> Result<(), Vec<String>> {
+    LazyLock::force(&CONFIG_WATCH_SECONDS);
+    LazyLock::force(&CONFIG_KV);
+    LazyLock::force(&RATE_LIMITER_TYPE);
+    LazyLock::force(&RATE_LIMITER_BACKEND);
+    LazyLock::force(&REDIS_CONFIG);
+    LazyLock::force(&MEMCACHED_CONFIG);
+    LazyLock::force(&DYNAMODB_CONFIG);
+    LazyLock::force(&SQLITE_CONFIG);
+    LazyLock::force(&SNAPSHOT_CONFIG);
+    LazyLock::force(&THROTTLE_CONFIG);
+    LazyLock::force(&EVICTION_CONFIG);
+    LazyLock::force(&MAX_TRACKED_KEYS);
+    LazyLock::force(&METRICS_TOP_KEYS);
+    LazyLock::force(&GOSSIP_CONFIG);
+    LazyLock::force(&CLUSTER_CONFIG);
+    LazyLock::force(&LISTEN_ADDR);
+    LazyLock::force(&UPSTREAM);
+    LazyLock::force(&REQUEST_TIMEOUT);
+    LazyLock::force(&MAX_BODY_BYTES);
+    LazyLock::force(&HYBRID_SYNC_MS);
+    LazyLock::force(&DENYLIST_CONFIG);
+    LazyLock::force(&KEY_EXTRACTORS);
+    LazyLock::force(&QUERY_KEY_MAX_LENGTH);
+    LazyLock::force(&KEY_SCOPE);
+    LazyLock::force(&RATE_LIMIT_MODE);
+    LazyLock::force(&RATE_LIMIT_HEADERS);
+    LazyLock::force(&ERROR_BODY_CONFIG);
+    LazyLock::force(&REJECTION_CONFIG);
+    LazyLock::force(&WARNING_THRESHOLD);
+    LazyLock::force(&RATE_LIMIT_PROFILE);
+    LazyLock::force(&ADMIN_TOKEN);
+    LazyLock::force(&API_KEY_HEADER);
+    LazyLock::force(&ANONYMOUS_POLICY);
+    LazyLock::force(&STORE_FAILURE_POLICY);
+    LazyLock::force(&DRAIN_POLICY);
+    LazyLock::force(&JWT_CONFIG);
+    LazyLock::force(&TRUSTED_PROXIES);
+    LazyLock::force(&CLIENT_IP_HEADERS);
+    LazyLock::force(&SUBNET_AGGREGATION);
+    LazyLock::force(&CORS_CONFIG);
+    LazyLock::force(&WEBSOCKET_CONFIG);
+    LazyLock::force(&TLS_CONFIG);
+    LazyLock::force(&ADMIN_LISTENER);
+    LazyLock::force(&KEY_HASH_SALT);
+    LazyLock::force(&USER_AGENT_CLASSES);
+    LazyLock::force(&TIER_LOOKUP_CONFIG);
+    LazyLock::force(&STORE_OVERRIDES_CONFIG);
+    LazyLock::force(&BODY_KEY_CONFIG);
+    LazyLock::force(&QUOTA_CONFIG);
+    LazyLock::force(&EVENTS_CONFIG);
+    LazyLock::force(&STATSD_CONFIG);
+    LazyLock::force(&ACCESS_LOG_CONFIG);
+    LazyLock::force(&LOG_SAMPLING_CONFIG);
+    LazyLock::force(&WEBHOOK_CONFIG);
+    LazyLock::force(&AUDIT_CONFIG);
+    #[cfg(feature = "sentry")]
+    LazyLock::force(&SENTRY_CONFIG);
+
+    check_limits(&limits());
+
+    let errors = std::mem::take(&mut *ERRORS.lock().unwrap_or_else(|e| e.into_inner()));
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// Builds the limits of a reloaded config file, returning the problems found
+/// in them rather than reporting them to `validate`, which only runs at
+/// startup.
+pub fn reload_limits(file: &FileConfig) -> Result<Limits, Vec<String>> {
+    RELOAD_ERRORS.set(Some(Vec::new()));
+    let limits = Limits::new(file);
+    check_limits(&limits);
+    let errors = RELOAD_ERRORS.take().unwrap_or_default();
+    match errors.is_empty() {
+        true => Ok(limits),
+        false => Err(errors),
+    }
+}
+
+/// Reports the limits that would block every request or never reset.
+fn check_limits(limits: &Limits) {
+    let named = [
+        ("default limit".to_string(), &limits.default),
+        ("anonymous limit".to_string(), &limits.anonymous),
+    ]
+    .into_iter()
+    .chain(
+        limits
+            .tiers
+            .iter()
+            .map(|(name, config)| (format!("tier {}", name), config)),
+    )
+    .chain(
+        limits
+            .overrides
+            .iter()
+            .map(|(key, config)| (format!("override {}", key), config)),
+    )
+    .chain(USER_AGENT_CLASSES.iter().filter_map(|class| {
+        let config = class.config.as_ref()?;
+        Some((format!("user agent class {}", class.name), config))
+    }))
+    .chain(
+        limits
+            .routes
+            .iter()
+            .map(|rule| (format!("route rule {}", rule.name), &rule.limit)),
+    );
+    for (name, config) in named {
+        if let Err(e) = config.check() {
+            invalid(&name, e);
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Structured configuration loaded with `--config path.toml` (or `.yaml`).
+//!
+//! The file covers what environment variables express poorly: per-route
+//! rules, allowlists and grouped backend settings. Environment variables
+//! still override the values they cover, so a deployment can share one file
+//! and tweak single settings per instance.
+
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::client_ip::parse_cidr;
+use crate::config::{
+    DEFAULT_RULE_NAME, RateLimitAlgorithm, RateLimitConfig, RateLimiterBackend, RateLimiterType,
+    RedisMode, RejectionConfig, StoreFailurePolicy, ThrottleConfig, Upstream,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub listen_addr: Option<SocketAddr>,
+    pub limits: LimitsSection,
+    /// Checked in order; the first rule matching a request's path applies.
+    pub routes: Vec<RouteRule>,
+    /// Addresses and CIDRs that are never rate limited.
+    pub allowlist: Vec<String>,
+    /// Path patterns that are never rate limited, like route rule paths.
+    pub exempt_paths: Option<Vec<String>>,
+    /// Blocks of time with limits of their own; the first active one applies.
+    pub schedules: Vec<Schedule>,
+    /// Limiters with state of their own, by name, for route rules to use.
+    pub limiters: HashMap<String, LimiterSection>,
+    pub backend: BackendSection,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub default: Option<RateLimitConfig>,
+    pub anonymous: Option<RateLimitConfig>,
+    pub tiers: HashMap<String, Arc<RateLimitConfig>>,
+    /// Tier of each API key, e.g. `{ "key-1234" = "pro" }`.
+    pub api_key_tiers: HashMap<String, String>,
+    /// JWT claim naming the tier of the token's bearer, e.g. `plan`.
+    pub tier_claim: Option<String>,
+    /// Limits of individual clients by key, e.g. `{ "api_key:k-1234" = { ... } }`.
+    pub overrides: HashMap<String, Arc<RateLimitConfig>>,
+}
+
+/// A limit applied to the requests whose path matches `path`, either exactly
+/// or, for patterns ending in `*`, by prefix (e.g. `/auth/*`).
+///
+/// Clients get a separate budget per rule, so a strict rule on `/auth/*`
+/// does not eat into their default budget.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RouteRule {
+    pub name: String,
+    pub path: String,
+    /// Named limiter counting the rule's requests instead of the main one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limiter: Option<String>,
+    /// Throttling of the rule's requests in place of the global one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleConfig>,
+    /// Response to the rule's rejected requests, over the global one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<RejectionConfig>,
+    /// Service the rule's requests are forwarded to in proxy mode, in place
+    /// of `UPSTREAM_URL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<Upstream>,
+    #[serde(flatten)]
+    pub limit: Arc<RateLimitConfig>,
+}
+
+impl RouteRule {
+    pub fn matches(&self, path: &str) -> bool {
+        path_matches(&self.path, path)
+    }
+}
+
+/// An in-process limiter with state of its own, so a group of routes can use
+/// a different implementation or algorithm than the main limiter, e.g. a
+/// token bucket for `/auth/*` and a sliding window for `/public/*`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimiterSection {
+    #[serde(rename = "type")]
+    pub kind: RateLimiterType,
+    /// Algorithm of the `store` type, like `backend.algorithm` by default.
+    pub algorithm: Option<RateLimitAlgorithm>,
+}
+
+/// Checks route rules, in the order they apply, against the named
+/// `limiters` they may use.
+pub fn validate_routes(
+    routes: &[RouteRule],
+    limiters: &HashMap<String, LimiterSection>,
+) -> Result<(), String> {
+    let mut names = vec![DEFAULT_RULE_NAME];
+    for rule in routes {
+        if names.contains(&rule.name.as_str()) {
+            return Err(format!("route rule name {:?} is used twice", rule.name));
+        }
+        names.push(&rule.name);
+        if let Some(limiter) = &rule.limiter
+            && !limiters.contains_key(limiter)
+        {
+            return Err(format!(
+                "route rule {:?} uses undefined limiter {:?}",
+                rule.name, limiter
+            ));
+        }
+        if rule
+            .throttle
+            .is_some_and(|throttle| throttle.max_queue == 0)
+        {
+            return Err(format!(
+                "route rule {:?}: throttle max_queue must be greater than 0",
+                rule.name
+            ));
+        }
+        if let Some(Err((setting, problem))) =
+            rule.rejection.as_ref().map(RejectionConfig::validate)
+        {
+            return Err(format!(
+                "route rule {:?}: rejection {} {}",
+                rule.name, setting, problem
+            ));
+        }
+        if let Some(Err(problem)) = rule.upstream.as_ref().map(|u| u.pool().validate()) {
+            return Err(format!("route rule {:?}: upstream {}", rule.name, problem));
+        }
+        rule.limit
+            .check()
+            .map_err(|e| format!("route rule {:?}: {}", rule.name, e))?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is `pattern` or, for patterns ending in `*`, starts with it.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+/// Limits in force during a block of time each day, e.g. looser limits at
+/// night. Times are UTC, and a block ending before it starts runs past
+/// midnight, counting as the day it started on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    pub name: String,
+    /// Days the block starts on; every day when empty.
+    #[serde(default)]
+    pub days: Vec<Day>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub default: Option<Arc<RateLimitConfig>>,
+    pub anonymous: Option<Arc<RateLimitConfig>>,
+}
+
+impl Schedule {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let time = TimeOfDay::of(now);
+        let today = self.runs_on(now.weekday());
+        if self.start < self.end {
+            return today && self.start <= time && time < self.end;
+        }
+        (today && time >= self.start) || (self.runs_on(now.weekday().pred()) && time < self.end)
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.iter().any(|d| d.weekday() == day)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    fn weekday(self) -> Weekday {
+        match self {
+            Self::Mon => Weekday::Mon,
+            Self::Tue => Weekday::Tue,
+            Self::Wed => Weekday::Wed,
+            Self::Thu => Weekday::Thu,
+            Self::Fri => Weekday::Fri,
+            Self::Sat => Weekday::Sat,
+            Self::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// Time of day written as `HH:MM`, stored as minutes since midnight.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    fn of(now: DateTime<Utc>) -> Self {
+        Self(now.hour() * 60 + now.minute())
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(TimeOfDay(minutes): TimeOfDay) -> Self {
+        format!("{:02}:{:02}", minutes / 60, minutes % 60)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{:?} is not a time of day as HH:MM", value);
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendSection {
+    pub kind: Option<RateLimiterBackend>,
+    pub limiter: Option<RateLimiterType>,
+    pub algorithm: Option<RateLimitAlgorithm>,
+    pub failure_policy: Option<StoreFailurePolicy>,
+    pub redis: RedisSection,
+    pub memcached: MemcachedSection,
+    pub dynamodb: DynamoDbSection,
+    pub sqlite: SqliteSection,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisSection {
+    pub url: Option<String>,
+    pub key_prefix: Option<String>,
+    pub mode: Option<RedisMode>,
+    pub sentinel_master: Option<String>,
+    pub hash_tags: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemcachedSection {
+    pub servers: Option<Vec<String>>,
+    pub key_prefix: Option<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamoDbSection {
+    pub table: Option<String>,
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    pub key_prefix: Option<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteSection {
+    pub path: Option<String>,
+    pub flush_ms: Option<u64>,
+}
+
+impl FileConfig {
+    /// Reads and checks a config file, picking the format by extension.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(path, &contents)
+    }
+
+    /// Parses and validates the contents of `name`, YAML if its extension is
+    /// `.yaml` or `.yml` and TOML otherwise.
+    pub fn parse(name: &str, contents: &str) -> Result<Self, String> {
+        let config: Self = match Path::new(name).extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(contents).map_err(|e| format!("{}: {}", name, e))?
+            }
+            _ => toml::from_str(contents).map_err(|e| format!("{}: {}", name, e))?,
+        };
+        config.validate().map_err(|e| format!("{}: {}", name, e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_routes(&self.routes, &self.limiters)?;
+        if let Some((name, _)) = self.limiters.iter().find(|(_, limiter)| {
+            limiter.algorithm.is_some() && limiter.kind != RateLimiterType::Store
+        }) {
+            return Err(format!(
+                "limiter {:?} sets an algorithm, which only the store type takes",
+                name
+            ));
+        }
+        for (index, schedule) in self.schedules.iter().enumerate() {
+            if self.schedules[..index]
+                .iter()
+                .any(|other| other.name == schedule.name)
+            {
+                return Err(format!("schedule name {:?} is used twice", schedule.name));
+            }
+        }
+        let limits = [
+            ("limits.default", &self.limits.default),
+            ("limits.anonymous", &self.limits.anonymous),
+        ]
+        .into_iter()
+        .filter_map(|(name, limit)| Some((name.to_string(), limit.as_ref()?)))
+        .chain(
+            self.limits
+                .tiers
+                .iter()
+                .map(|(name, limit)| (format!("tier {:?}", name), &**limit)),
+        )
+        .chain(
+            self.limits
+                .overrides
+                .iter()
+                .map(|(key, limit)| (format!("override {:?}", key), &**limit)),
+        )
+        .chain(self.schedules.iter().flat_map(|schedule| {
+            [&schedule.default, &schedule.anonymous]
+                .into_iter()
+                .flatten()
+                .map(|limit| (format!("schedule {:?}", schedule.name), &**limit))
+        }));
+        for (name, limit) in limits {
+            limit.check().map_err(|e| format!("{}: {}", name, e))?;
+        }
+        if let Some(pattern) = self
+            .exempt_paths
+            .iter()
+            .flatten()
+            .find(|p| !p.starts_with('/'))
+        {
+            return Err(format!("exempt path {:?} does not start with /", pattern));
+        }
+        if let Some(entry) = self.allowlist.iter().find(|e| parse_cidr(e).is_none()) {
+            return Err(format!(
+                "allowlist entry {:?} is not an address or CIDR",
+                entry
+            ));
+        }
+        Ok(())
+    }
+}
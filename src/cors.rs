@@ -0,0 +1,40 @@
+//! CORS headers for browser clients. The layer sits outside the rate limit
+//! middleware, so it answers preflights itself and adds its headers to
+//! rejections too; without them, scripts cannot read why they were rejected.
+
+use axum::http::HeaderName;
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::CorsConfig;
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// Response headers scripts may read besides the CORS-safelisted ones.
+const EXPOSED_HEADERS: &[&str] = &[
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+    "x-ratelimit-warning",
+    "ratelimit",
+    "ratelimit-policy",
+    REQUEST_ID_HEADER,
+];
+
+pub fn layer(config: &CorsConfig) -> CorsLayer {
+    let origins = match &config.origins {
+        Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+        None => AllowOrigin::any(),
+    };
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(config.methods.clone())
+        .allow_headers(config.headers.clone())
+        .allow_credentials(config.credentials)
+        .expose_headers(
+            EXPOSED_HEADERS
+                .iter()
+                .map(|h| HeaderName::from_static(h))
+                .collect::<Vec<_>>(),
+        )
+        .max_age(Duration::from_secs(config.max_age_seconds))
+}
@@ -0,0 +1,100 @@
+//! Addresses rejected with 403 Forbidden before any limiter work.
+//!
+//! The list combines the entries of `RATE_LIMIT_DENYLIST` with those of a
+//! file or URL, one address or CIDR per line with `#` starting a comment,
+//! which is refetched periodically so blocks apply without a restart.
+
+use ipnet::IpNet;
+use std::{
+    sync::{Arc, LazyLock, RwLock},
+    time::Duration,
+};
+
+use crate::client_ip::{IpSet, parse_cidr};
+use crate::config::DENYLIST_CONFIG;
+use crate::metrics;
+
+static DENYLIST: LazyLock<RwLock<Arc<IpSet>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(DENYLIST_CONFIG.entries.iter().copied().collect())));
+
+/// The denylist in force.
+pub fn denylist() -> Arc<IpSet> {
+    DENYLIST.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Loads the denylist source, if any, and keeps refreshing it in the
+/// background. The first load is awaited so the list is in force before the
+/// server accepts requests; a source that fails to load leaves the previous
+/// list in force.
+pub async fn spawn_refresh() {
+    let Some(source) = DENYLIST_CONFIG.source.clone() else {
+        return;
+    };
+    refresh(&source).await;
+
+    let interval = Duration::from_secs(DENYLIST_CONFIG.refresh_seconds);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            refresh(&source).await;
+        }
+    });
+}
+
+async fn refresh(source: &str) {
+    match load(source).await {
+        Ok(entries) => {
+            let loaded = entries.len();
+            let denylist: IpSet = DENYLIST_CONFIG
+                .entries
+                .iter()
+                .copied()
+                .chain(entries)
+                .collect();
+            let mut current = DENYLIST.write().unwrap_or_else(|e| e.into_inner());
+            let changed = **current != denylist;
+            *current = Arc::new(denylist);
+            drop(current);
+            metrics::record_denylist_refresh("success");
+            if changed {
+                tracing::info!("Loaded {} denylist entries from {}", loaded, source);
+            }
+        }
+        Err(e) => {
+            metrics::record_denylist_refresh("failure");
+            tracing::error!("Failed to load the denylist from {}: {}", source, e);
+        }
+    }
+}
+
+async fn load(source: &str) -> Result<Vec<IpNet>, String> {
+    let contents = if source.starts_with("http://") || source.starts_with("https://") {
+        fetch(source).await.map_err(|e| e.to_string())?
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .map_err(|e| e.to_string())?
+    };
+    parse(&contents)
+}
+
+async fn fetch(url: &str) -> Result<String, reqwest::Error> {
+    reqwest::get(url).await?.error_for_status()?.text().await
+}
+
+/// Parses one address or CIDR per line, rejecting the whole list if any line
+/// is malformed so a typo does not silently unblock anyone.
+fn parse(contents: &str) -> Result<Vec<IpNet>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            (!entry.is_empty()).then(|| {
+                parse_cidr(entry).ok_or_else(|| {
+                    format!("line {}: {:?} is not an address or CIDR", index + 1, entry)
+                })
+            })
+        })
+        .collect()
+}
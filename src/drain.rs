@@ -0,0 +1,41 @@
+//! Drain mode, switched on and off through the admin API during incidents
+//! and blue/green switchovers: clients already sending requests keep their
+//! budgets while new ones are turned away, or every request is let through
+//! without limiting.
+//!
+//! Drain mode is kept by this instance only and ends on restart.
+
+use chrono::{DateTime, Utc};
+use std::sync::RwLock;
+
+use crate::config::DrainPolicy;
+
+static DRAIN: RwLock<Option<Drain>> = RwLock::new(None);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Drain {
+    pub policy: DrainPolicy,
+    pub since: DateTime<Utc>,
+}
+
+/// Drain mode in force, if any.
+pub fn draining() -> Option<Drain> {
+    *DRAIN.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Switches drain mode on under `policy`, or over to it if already on.
+pub fn start(policy: DrainPolicy) -> Drain {
+    let mut drain = DRAIN.write().unwrap_or_else(|e| e.into_inner());
+    let started = Drain {
+        policy,
+        // Switching the policy does not restart the drain.
+        since: drain.map_or_else(Utc::now, |drain| drain.since),
+    };
+    *drain = Some(started);
+    started
+}
+
+/// Switches drain mode off, returning what was in force, if any.
+pub fn stop() -> Option<Drain> {
+    DRAIN.write().unwrap_or_else(|e| e.into_inner()).take()
+}
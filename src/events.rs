@@ -0,0 +1,370 @@
+//! Publishes every rate limit decision to Kafka or NATS.
+//!
+//! The request path only pushes events onto a bounded channel; a background
+//! task sends them to the broker in batches, so a slow or unreachable broker
+//! costs dropped events rather than request latency.
+
+use chrono::Utc;
+use rskafka::{
+    client::{
+        ClientBuilder,
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+    },
+    record::Record,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::config::{EventSink, EventsConfig};
+use crate::metrics;
+
+/// Most events sent to the broker in one request.
+const BATCH_SIZE: usize = 500;
+/// Wait before reconnecting to a broker that failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize)]
+pub struct DecisionEvent {
+    pub key: String,
+    pub route: String,
+    pub decision: Decision,
+    /// Requests left in the window, when the limiter reports it.
+    pub remaining: Option<u32>,
+    pub timestamp_ms: u64,
+}
+
+impl DecisionEvent {
+    pub fn new(key: String, route: String, decision: Decision) -> Self {
+        Self {
+            key,
+            route,
+            decision,
+            remaining: None,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventPublisher {
+    sender: mpsc::Sender<DecisionEvent>,
+}
+
+impl EventPublisher {
+    /// Starts the task delivering published events to the configured broker.
+    pub fn spawn(config: &'static EventsConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.buffer);
+        tokio::spawn(deliver(receiver, config));
+        Self { sender }
+    }
+
+    /// Queues an event without waiting; it is dropped if the queue is full.
+    pub fn publish(&self, event: DecisionEvent) {
+        if self.sender.try_send(event).is_err() {
+            metrics::record_dropped_events(1);
+        }
+    }
+}
+
+enum Connection {
+    Kafka(Vec<PartitionClient>),
+    /// The stream and the server line read so far.
+    Nats(BufReader<TcpStream>, Vec<u8>),
+}
+
+async fn deliver(mut receiver: mpsc::Receiver<DecisionEvent>, config: &'static EventsConfig) {
+    let mut connection = None;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut round = 0;
+
+    loop {
+        let connected = match &mut connection {
+            Some(connected) => connected,
+            None => match connect(config).await {
+                Ok(connected) => connection.insert(connected),
+                Err(e) => {
+                    tracing::error!("Failed to connect to the event broker: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            },
+        };
+
+        let received = match connected {
+            // NATS servers ping idle clients and disconnect those that do not
+            // answer, so the connection is read while waiting for events.
+            Connection::Nats(stream, line) => tokio::select! {
+                received = receiver.recv_many(&mut batch, BATCH_SIZE) => received,
+                pinged = answer_ping(stream, line) => {
+                    if let Err(e) = pinged {
+                        tracing::error!("Lost the connection to NATS: {}", e);
+                        connection = None;
+                    }
+                    continue;
+                }
+            },
+            Connection::Kafka(_) => receiver.recv_many(&mut batch, BATCH_SIZE).await,
+        };
+        if received == 0 {
+            return;
+        }
+
+        round += 1;
+        if let Err(e) = send(connected, &config.topic, &batch, round).await {
+            tracing::error!("Failed to publish {} events: {}", batch.len(), e);
+            metrics::record_dropped_events(batch.len());
+            connection = None;
+        }
+        batch.clear();
+    }
+}
+
+async fn connect(config: &EventsConfig) -> Result<Connection, String> {
+    match &config.sink {
+        EventSink::Kafka { brokers } => {
+            let client = ClientBuilder::new(brokers.clone())
+                .build()
+                .await
+                .map_err(|e| e.to_string())?;
+            let topic = client
+                .list_topics()
+                .await
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .find(|topic| topic.name == config.topic)
+                .ok_or_else(|| format!("topic {} does not exist", config.topic))?;
+            let mut partitions = Vec::with_capacity(topic.partitions.len());
+            for partition in topic.partitions {
+                partitions.push(
+                    client
+                        .partition_client(&config.topic, partition, UnknownTopicHandling::Error)
+                        .await
+                        .map_err(|e| e.to_string())?,
+                );
+            }
+            Ok(Connection::Kafka(partitions))
+        }
+        EventSink::Nats { url } => {
+            let addr = url.strip_prefix("nats://").unwrap_or(url);
+            let mut stream =
+                BufReader::new(TcpStream::connect(addr).await.map_err(|e| e.to_string())?);
+            // The server greets with INFO before accepting commands.
+            let mut info = String::new();
+            stream
+                .read_line(&mut info)
+                .await
+                .map_err(|e| e.to_string())?;
+            stream
+                .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Connection::Nats(stream, Vec::new()))
+        }
+    }
+}
+
+/// Sends one batch; Kafka batches go to the topic's partitions in turn.
+async fn send(
+    connection: &mut Connection,
+    topic: &str,
+    batch: &[DecisionEvent],
+    round: usize,
+) -> Result<(), String> {
+    match connection {
+        Connection::Kafka(partitions) => {
+            let Some(partition) = partitions.get(round % partitions.len().max(1)) else {
+                return Err("topic has no partitions".to_string());
+            };
+            let records = batch
+                .iter()
+                .map(|event| Record {
+                    key: Some(event.key.clone().into_bytes()),
+                    value: Some(serde_json::to_vec(event).expect("event serializes")),
+                    headers: BTreeMap::new(),
+                    timestamp: Utc::now(),
+                })
+                .collect();
+            partition
+                .produce(records, Compression::NoCompression)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Connection::Nats(stream, _) => {
+            let mut commands = Vec::new();
+            for event in batch {
+                let payload = serde_json::to_vec(event).expect("event serializes");
+                commands
+                    .extend_from_slice(format!("PUB {} {}\r\n", topic, payload.len()).as_bytes());
+                commands.extend_from_slice(&payload);
+                commands.extend_from_slice(b"\r\n");
+            }
+            stream.write_all(&commands).await.map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Reads from NATS until a line is complete, answering it if it is a ping.
+/// Partial lines stay in `line`, so a cancelled read loses nothing.
+async fn answer_ping(stream: &mut BufReader<TcpStream>, line: &mut Vec<u8>) -> Result<(), String> {
+    match stream.read_until(b'\n', line).await {
+        Ok(0) => return Err("connection closed".to_string()),
+        Ok(_) => {}
+        Err(e) => return Err(e.to_string()),
+    }
+    let result = if line.starts_with(b"PING") {
+        stream
+            .write_all(b"PONG\r\n")
+            .await
+            .map_err(|e| e.to_string())
+    } else if line.starts_with(b"-ERR") {
+        Err(String::from_utf8_lossy(line).trim().to_string())
+    } else {
+        Ok(())
+    };
+    line.clear();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    /// A NATS server one test's publisher connects to.
+    struct Broker {
+        listener: TcpListener,
+        config: &'static EventsConfig,
+    }
+
+    impl Broker {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = Box::leak(Box::new(EventsConfig {
+                sink: EventSink::Nats {
+                    url: format!("nats://{}", listener.local_addr().unwrap()),
+                },
+                topic: "rate_limit.decisions".to_string(),
+                buffer: 10,
+            }));
+            Self { listener, config }
+        }
+
+        /// Accepts the publisher's connection, greeting it like NATS does.
+        async fn accept(&self) -> BufReader<TcpStream> {
+            let (stream, _) = self.listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            assert!(line(&mut stream).await.starts_with("CONNECT "));
+            stream
+        }
+    }
+
+    async fn line(stream: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_line(&mut line))
+            .await
+            .expect("the publisher went quiet")
+            .unwrap();
+        line
+    }
+
+    /// The subject and event of the next message published.
+    async fn published(stream: &mut BufReader<TcpStream>) -> (String, Value) {
+        let command = line(stream).await;
+        let mut parts = command.split_whitespace();
+        assert_eq!(parts.next(), Some("PUB"));
+        let subject = parts.next().unwrap().to_string();
+        let length: usize = parts.next().unwrap().parse().unwrap();
+        let payload = line(stream).await;
+        assert_eq!(payload.trim_end().len(), length);
+        (subject, serde_json::from_str(&payload).unwrap())
+    }
+
+    fn event(key: &str, decision: Decision) -> DecisionEvent {
+        DecisionEvent {
+            remaining: Some(7),
+            ..DecisionEvent::new(key.to_string(), "/api".to_string(), decision)
+        }
+    }
+
+    #[tokio::test]
+    async fn decisions_are_published_to_the_subject() {
+        let broker = Broker::start().await;
+        let publisher = EventPublisher::spawn(broker.config);
+        let mut stream = broker.accept().await;
+
+        publisher.publish(event("ip:203.0.113.7", Decision::Deny));
+        publisher.publish(event("ip:203.0.113.8", Decision::Allow));
+        let (subject, first) = published(&mut stream).await;
+        assert_eq!(subject, "rate_limit.decisions");
+        assert_eq!(first["key"], "ip:203.0.113.7");
+        assert_eq!(first["route"], "/api");
+        assert_eq!(first["decision"], "deny");
+        assert_eq!(first["remaining"], 7);
+        assert!(first["timestamp_ms"].as_u64().unwrap() > 0);
+        let (_, second) = published(&mut stream).await;
+        assert_eq!(second["decision"], "allow");
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_while_idle() {
+        let broker = Broker::start().await;
+        let _publisher = EventPublisher::spawn(broker.config);
+        let mut stream = broker.accept().await;
+
+        stream.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(line(&mut stream).await, "PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn lost_connections_are_reopened() {
+        let broker = Broker::start().await;
+        let publisher = EventPublisher::spawn(broker.config);
+        drop(broker.accept().await);
+
+        let mut stream = broker.accept().await;
+        publisher.publish(event("ip:203.0.113.7", Decision::Deny));
+        assert_eq!(published(&mut stream).await.1["key"], "ip:203.0.113.7");
+
+        // So are connections the server reports an error on.
+        stream
+            .write_all(b"-ERR 'Stale Connection'\r\n")
+            .await
+            .unwrap();
+        let mut stream = broker.accept().await;
+        publisher.publish(event("ip:203.0.113.8", Decision::Deny));
+        assert_eq!(published(&mut stream).await.1["key"], "ip:203.0.113.8");
+    }
+
+    #[tokio::test]
+    async fn events_are_dropped_rather_than_waited_for_when_the_queue_is_full() {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let publisher = EventPublisher { sender };
+        for key in ["a", "b", "c"] {
+            publisher.publish(event(key, Decision::Allow));
+        }
+        assert_eq!(receiver.recv().await.unwrap().key, "a");
+        assert_eq!(receiver.recv().await.unwrap().key, "b");
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use crate::config::{EvictionConfig, max_window};
+use crate::metrics;
+use crate::middleware::{MiddlewareState, RateLimitStateEnum, SharedLimiter};
+
+/// Periodically drops the state of keys that can no longer affect a limit
+/// decision, so the in-memory limiters do not keep every client ever seen.
+/// The other backends expire keys on their own.
+pub fn spawn_eviction(limiter: SharedLimiter, config: &'static EvictionConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+        loop {
+            ticker.tick().await;
+            // Recomputed every round, as reloads can change the windows.
+            let idle = max_window() + Duration::from_secs(config.slack_seconds);
+            let evicted = match &limiter.get() {
+                RateLimitStateEnum::Standard(state) => state.evict_idle(idle).await,
+                RateLimitStateEnum::LockFree(state) => state.evict_idle(idle),
+                RateLimitStateEnum::MemoryStore(store) => store.evict_expired(),
+                RateLimitStateEnum::SqliteStore(store) => store.evict_expired(),
+                RateLimitStateEnum::Gossip(state) => state.evict_expired(),
+                RateLimitStateEnum::Cluster(state) => state.evict_expired(),
+                _ => return,
+            };
+            metrics::record_eviction("idle", evicted);
+        }
+    });
+}
+
+/// Periodically drops the expired answers the tier resolver and store
+/// overrides cache by client key, so keys clients sent once are not kept.
+pub fn spawn_cache_eviction(state: &MiddlewareState, config: &'static EvictionConfig) {
+    let tier_resolver = state.tier_resolver.clone();
+    let store_overrides = state.store_overrides.clone();
+    if tier_resolver.is_none() && store_overrides.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+        loop {
+            ticker.tick().await;
+            if let Some(tier_resolver) = &tier_resolver {
+                tier_resolver.evict_expired();
+            }
+            if let Some(store_overrides) = &store_overrides {
+                store_overrides.evict_expired();
+            }
+        }
+    });
+}
@@ -0,0 +1,105 @@
+//! gRPC calls: HTTP/2 `POST`s to `/package.Service/Method` with an
+//! `application/grpc` content type. They are limited per method like routes,
+//! and what the server answers them itself, rejections included, is turned
+//! into a gRPC status, since gRPC clients do not read HTTP errors.
+
+use axum::{
+    body::{Body, to_bytes},
+    http::{HeaderMap, HeaderValue, Request, Response, StatusCode, header},
+    middleware::Next,
+};
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+const UNKNOWN: u16 = 2;
+const DEADLINE_EXCEEDED: u16 = 4;
+const PERMISSION_DENIED: u16 = 7;
+const RESOURCE_EXHAUSTED: u16 = 8;
+const UNIMPLEMENTED: u16 = 12;
+const INTERNAL: u16 = 13;
+const UNAVAILABLE: u16 = 14;
+const UNAUTHENTICATED: u16 = 16;
+
+/// Most of an error body kept as the `grpc-message`.
+const MAX_MESSAGE_BYTES: usize = 1024;
+
+/// Whether `headers` are those of a gRPC call or response.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// The service and method a gRPC call's path names, e.g.
+/// `("helloworld.Greeter", "SayHello")` for `/helloworld.Greeter/SayHello`.
+pub fn method(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    (!service.is_empty() && !method.is_empty() && !method.contains('/'))
+        .then_some((service, method))
+}
+
+/// The gRPC status code for an HTTP status the server answered a call with
+/// (https://grpc.github.io/grpc/core/md_doc_statuscodes.html).
+fn code(status: StatusCode) -> u16 {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::PAYLOAD_TOO_LARGE => RESOURCE_EXHAUSTED,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            UNAVAILABLE
+        }
+        StatusCode::REQUEST_TIMEOUT => DEADLINE_EXCEEDED,
+        StatusCode::NOT_FOUND => UNIMPLEMENTED,
+        StatusCode::FORBIDDEN => PERMISSION_DENIED,
+        StatusCode::UNAUTHORIZED => UNAUTHENTICATED,
+        StatusCode::BAD_REQUEST => INTERNAL,
+        _ => UNKNOWN,
+    }
+}
+
+/// Answers gRPC calls the server itself refuses, or an upstream answers with
+/// a plain HTTP error, with a trailers-only gRPC response: `200 OK` carrying
+/// the `grpc-status` the HTTP status maps to and the body as `grpc-message`.
+/// Other headers, like `Retry-After` and the quota headers, are kept.
+pub async fn translate(req: Request<Body>, next: Next) -> Response<Body> {
+    if !is_grpc(req.headers()) {
+        return next.run(req).await;
+    }
+    let response = next.run(req).await;
+    if response.status() == StatusCode::OK || is_grpc(response.headers()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let code = code(parts.status);
+    let message = match to_bytes(body, MAX_MESSAGE_BYTES).await {
+        Ok(body) => String::from_utf8_lossy(&body).trim().to_string(),
+        Err(_) => String::new(),
+    };
+    parts.status = StatusCode::OK;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    parts.headers.insert(GRPC_STATUS, code.into());
+    if !message.is_empty()
+        && let Ok(value) = HeaderValue::from_str(&percent_encode(&message))
+    {
+        parts.headers.insert(GRPC_MESSAGE, value);
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Percent-encodes `message` for the `grpc-message` header, which keeps
+/// printable ASCII but `%` as is.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,80 @@
+//! Probes for orchestrators like Kubernetes, served outside the rate limit so
+//! they neither use up clients' budgets nor get rejected.
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+
+use crate::middleware::{MiddlewareState, RateLimitStateEnum};
+use crate::reload::load_error;
+
+/// Longest a store may take to answer before it counts as unreachable.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `GET /healthz`: the process is up and serving requests.
+pub async fn liveness_handler() -> &'static str {
+    "OK"
+}
+
+/// `GET /readyz`: the backing stores answer and the config in force is the
+/// one last given, with `503 Service Unavailable` if not. The body lists
+/// each check as `ok` or its error.
+pub async fn readiness_handler(State(state): State<MiddlewareState>) -> Response {
+    let mut checks = vec![("store", check(ping(&state.limiter.get())).await)];
+    if let Some(store) = &state.quota_store {
+        checks.push(("quota_store", check(store.ping()).await));
+    }
+    checks.push(("config", load_error().map_or(Ok(()), Err)));
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+    let checks: Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            if let Err(error) = &result {
+                tracing::warn!("Readiness check {} failed: {}", name, error);
+            }
+            (
+                name.to_string(),
+                Value::from(result.err().unwrap_or("ok".to_string())),
+            )
+        })
+        .collect();
+    let (status, label) = if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    (status, Json(json!({ "status": label, "checks": checks }))).into_response()
+}
+
+async fn check(ping: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+    tokio::time::timeout(CHECK_TIMEOUT, ping)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()))
+}
+
+/// Checks that the store behind `limiter` answers.
+async fn ping(limiter: &RateLimitStateEnum) -> Result<(), String> {
+    match limiter {
+        RateLimitStateEnum::Redis(state) => state.ping().await,
+        RateLimitStateEnum::Hybrid(state) => state.ping().await,
+        RateLimitStateEnum::RedisStore(store) => store.ping().await,
+        RateLimitStateEnum::MemcachedStore(store) => store.ping().await,
+        RateLimitStateEnum::DynamoDbStore(store) => store.ping().await,
+        RateLimitStateEnum::SqliteStore(store) => store.ping().await,
+        // State held in memory, or by peers a node decides without when they
+        // are unreachable. Limiters of other crates are left to check their
+        // own stores.
+        RateLimitStateEnum::Standard(_)
+        | RateLimitStateEnum::LockFree(_)
+        | RateLimitStateEnum::MemoryStore(_)
+        | RateLimitStateEnum::Gossip(_)
+        | RateLimitStateEnum::Cluster(_)
+        | RateLimitStateEnum::Custom(_) => Ok(()),
+    }
+}
@@ -0,0 +1,239 @@
+use axum::{body::Body, http::Request};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    errors::{Error, ErrorKind},
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+};
+use serde_json::{Map, Value};
+use std::{
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use crate::config::{JWT_CONFIG, JwtConfig};
+
+pub static JWT_VALIDATOR: LazyLock<JwtValidator> = LazyLock::new(|| JwtValidator::new(&JWT_CONFIG));
+
+/// Verifies bearer tokens and pulls claims out of them.
+pub struct JwtValidator {
+    secret: Option<DecodingKey>,
+    jwks: RwLock<JwkSet>,
+}
+
+impl JwtValidator {
+    pub fn new(config: &JwtConfig) -> Self {
+        Self {
+            secret: config
+                .secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        }
+    }
+
+    /// Returns a claim of a valid `Authorization: Bearer` token.
+    pub fn claim_from_request(&self, req: &Request<Body>, claim: &str) -> Option<String> {
+        let token = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))?;
+
+        match self.validate(token.trim(), claim) {
+            Ok(claim) => claim,
+            Err(e) => {
+                tracing::debug!("Rejected bearer token: {}", e);
+                None
+            }
+        }
+    }
+
+    fn validate(&self, token: &str, claim: &str) -> Result<Option<String>, Error> {
+        let claims = match &self.secret {
+            Some(secret) => {
+                decode::<Map<String, Value>>(token, secret, &validation(Algorithm::HS256))?
+            }
+            None => {
+                let header = decode_header(token)?;
+                let jwk = self
+                    .find_jwk(header.kid.as_deref())
+                    .ok_or_else(|| Error::from(ErrorKind::InvalidKeyFormat))?;
+                // The key decides the algorithm, never the token, so a token
+                // cannot have an RSA public key taken for an HMAC secret.
+                let algorithm =
+                    jwk_algorithm(&jwk).ok_or_else(|| Error::from(ErrorKind::InvalidAlgorithm))?;
+                if header.alg != algorithm {
+                    return Err(ErrorKind::InvalidAlgorithm.into());
+                }
+                let key = DecodingKey::from_jwk(&jwk)?;
+                decode::<Map<String, Value>>(token, &key, &validation(algorithm))?
+            }
+        };
+
+        Ok(match claims.claims.get(claim) {
+            Some(Value::String(value)) if !value.is_empty() => Some(value.clone()),
+            Some(Value::Number(value)) => Some(value.to_string()),
+            _ => None,
+        })
+    }
+
+    /// The key of `kid`, or the only key of the set for tokens without one.
+    fn find_jwk(&self, kid: Option<&str>) -> Option<Jwk> {
+        let jwks = self.jwks.read().unwrap_or_else(|e| e.into_inner());
+        match (kid, jwks.keys.as_slice()) {
+            (Some(kid), _) => jwks.find(kid).cloned(),
+            (None, [key]) => Some(key.clone()),
+            (None, _) => None,
+        }
+    }
+
+    async fn refresh_jwks(&self, url: &str) -> Result<usize, reqwest::Error> {
+        let jwks: JwkSet = reqwest::get(url).await?.error_for_status()?.json().await?;
+        let count = jwks.keys.len();
+        *self.jwks.write().unwrap_or_else(|e| e.into_inner()) = jwks;
+        Ok(count)
+    }
+}
+
+/// The algorithm tokens signed with `jwk` must use: its `alg`, or else the
+/// usual one of its key type. Keys for encryption and symmetric keys without
+/// an `alg` verify nothing.
+fn jwk_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(alg) = &jwk.common.key_algorithm {
+        return alg.to_string().parse().ok();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Some(Algorithm::ES256),
+            EllipticCurve::P384 => Some(Algorithm::ES384),
+            _ => None,
+        },
+        AlgorithmParameters::OctetKeyPair(params) => {
+            (params.curve == EllipticCurve::Ed25519).then_some(Algorithm::EdDSA)
+        }
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+fn validation(algorithm: Algorithm) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.validate_aud = false;
+    validation
+}
+
+/// Periodically fetches the JWKS so token validation never blocks on the network.
+pub fn spawn_jwks_refresh() {
+    let Some(url) = JWT_CONFIG.jwks_url.clone() else {
+        return;
+    };
+    if JWT_CONFIG.secret.is_some() {
+        return;
+    }
+
+    let interval = Duration::from_secs(JWT_CONFIG.jwks_refresh_seconds);
+    tokio::spawn(async move {
+        loop {
+            match JWT_VALIDATOR.refresh_jwks(&url).await {
+                Ok(count) => tracing::info!("Loaded {} keys from JWKS at {}", count, url),
+                Err(e) => tracing::error!("Failed to fetch JWKS from {}: {}", url, e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    // Public keys of RFC 7517, appendix A.1.
+    fn rsa_jwk(kid: &str, alg: Option<&str>) -> Value {
+        let mut jwk = json!({
+            "kty": "RSA",
+            "kid": kid,
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+        });
+        if let Some(alg) = alg {
+            jwk["alg"] = json!(alg);
+        }
+        jwk
+    }
+
+    fn ec_jwk(kid: &str) -> Value {
+        json!({
+            "kty": "EC",
+            "kid": kid,
+            "crv": "P-256",
+            "x": "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4",
+            "y": "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM",
+        })
+    }
+
+    fn jwk(value: Value) -> Jwk {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn validator(keys: Vec<Value>) -> JwtValidator {
+        let validator = JwtValidator::new(&JwtConfig {
+            secret: None,
+            jwks_url: None,
+            jwks_refresh_seconds: 300,
+            claim: "sub".to_string(),
+        });
+        *validator.jwks.write().unwrap() = serde_json::from_value(json!({ "keys": keys })).unwrap();
+        validator
+    }
+
+    #[test]
+    fn keys_decide_the_algorithm() {
+        assert_eq!(
+            jwk_algorithm(&jwk(rsa_jwk("a", Some("RS384")))),
+            Some(Algorithm::RS384)
+        );
+        assert_eq!(
+            jwk_algorithm(&jwk(rsa_jwk("a", None))),
+            Some(Algorithm::RS256)
+        );
+        assert_eq!(jwk_algorithm(&jwk(ec_jwk("a"))), Some(Algorithm::ES256));
+        assert_eq!(jwk_algorithm(&jwk(rsa_jwk("a", Some("RSA-OAEP")))), None);
+        assert_eq!(
+            jwk_algorithm(&jwk(json!({ "kty": "oct", "k": "c2VjcmV0" }))),
+            None
+        );
+    }
+
+    #[test]
+    fn tokens_cannot_pick_another_algorithm() {
+        let validator = validator(vec![rsa_jwk("rsa", Some("RS256"))]);
+        // Signed with the RSA key's public modulus as an HMAC secret, the
+        // classic confusion: the token names HS256 and the key's kid.
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rsa".to_string());
+        let token = encode(
+            &header,
+            &json!({ "sub": "user-1", "exp": 4_000_000_000u64 }),
+            &EncodingKey::from_secret(b"0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc"),
+        )
+        .unwrap();
+        let error = validator.validate(&token, "sub").unwrap_err();
+        assert_eq!(*error.kind(), ErrorKind::InvalidAlgorithm);
+    }
+
+    #[test]
+    fn tokens_without_kid_need_a_single_key() {
+        let one = validator(vec![rsa_jwk("rsa", None)]);
+        assert!(one.find_jwk(None).is_some());
+
+        let two = validator(vec![rsa_jwk("rsa", None), ec_jwk("ec")]);
+        assert!(two.find_jwk(None).is_none());
+        assert_eq!(
+            two.find_jwk(Some("ec")).and_then(|jwk| jwk.common.key_id),
+            Some("ec".to_string())
+        );
+        assert!(two.find_jwk(Some("other")).is_none());
+    }
+}
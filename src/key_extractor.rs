@@ -0,0 +1,485 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::MatchedPath,
+    http::{HeaderName, Request, header::CONTENT_LENGTH, header::CONTENT_TYPE},
+};
+use futures_util::{StreamExt, stream};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::client_ip::{client_ip, ip_key};
+use crate::config::{
+    API_KEY_HEADER, BodyKeyConfig, JWT_CONFIG, KEY_HASH_SALT, KEY_SCOPE, KeyExtractorKind,
+    KeyScope, QUERY_KEY_MAX_LENGTH, RateLimitConfig, SESSION_COOKIE, USER_AGENT_CLASSES,
+    UserAgentClass,
+};
+use crate::grpc;
+use crate::jwt::JWT_VALIDATOR;
+use crate::tls::ClientCertFingerprint;
+
+/// Derives the key a request is rate limited under.
+///
+/// Returning `None` hands the request to the next extractor in the chain.
+/// Any `Fn(&Request<Body>) -> Option<String>` closure is an extractor too.
+pub trait KeyExtractor: Send + Sync {
+    fn extract(&self, req: &Request<Body>) -> Option<String>;
+
+    /// Limit applied to keys from this extractor, `None` for the default.
+    fn profile(&self, _req: &Request<Body>) -> Option<Arc<RateLimitConfig>> {
+        None
+    }
+}
+
+/// Prefix of the keys of the `api_key` extractor, whose keys only count if
+/// they are known, see [`crate::middleware::identify`].
+pub const API_KEY_PREFIX: &str = "api_key";
+
+/// A key found by the chain along with the limit it is subject to.
+pub struct ExtractedKey {
+    pub key: String,
+    pub profile: Option<Arc<RateLimitConfig>>,
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&Request<Body>) -> Option<String> + Send + Sync,
+{
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        self(req)
+    }
+}
+
+/// Keys by the canonical client IP, see [`client_ip`], or by its subnet when
+/// subnet aggregation is enabled.
+pub struct IpExtractor;
+
+impl KeyExtractor for IpExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        client_ip(req).map(|ip| ip_key(&ip))
+    }
+}
+
+/// Keys by the value of a request header, e.g. an API key.
+pub struct HeaderExtractor {
+    header: HeaderName,
+    prefix: String,
+}
+
+impl HeaderExtractor {
+    pub fn new(header: HeaderName, prefix: impl Into<String>) -> Self {
+        Self {
+            header,
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl KeyExtractor for HeaderExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        header_value(req, &self.header).map(|value| format!("{}:{}", self.prefix, value))
+    }
+}
+
+/// Keys by the value of a cookie, e.g. a session ID.
+pub struct CookieExtractor {
+    name: String,
+    prefix: String,
+}
+
+impl CookieExtractor {
+    pub fn new(name: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl KeyExtractor for CookieExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_all("cookie")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, value)| *name == self.name && !value.is_empty())
+            .map(|(_, value)| format!("{}:{}", self.prefix, value))
+    }
+}
+
+/// Keys by the value of a query parameter, e.g. `?api_key=...` sent by legacy
+/// clients.
+///
+/// Only values of at most `max_length` unreserved URL characters are accepted,
+/// anything else is ignored so the next extractor decides.
+pub struct QueryExtractor {
+    name: String,
+    max_length: usize,
+}
+
+impl QueryExtractor {
+    pub fn new(name: impl Into<String>, max_length: usize) -> Self {
+        Self {
+            name: name.into(),
+            max_length,
+        }
+    }
+
+    fn is_valid(&self, value: &str) -> bool {
+        !value.is_empty()
+            && value.len() <= self.max_length
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~'))
+    }
+}
+
+impl KeyExtractor for QueryExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        req.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, value)| value)
+            .filter(|value| self.is_valid(value))
+            .map(|value| format!("query:{}:{}", self.name, value))
+    }
+}
+
+/// Keys by a claim of a valid bearer JWT.
+pub struct JwtExtractor;
+
+impl KeyExtractor for JwtExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        JWT_VALIDATOR
+            .claim_from_request(req, &JWT_CONFIG.claim)
+            .map(|claim| format!("jwt:{}", claim))
+    }
+}
+
+/// Keys by the SHA-256 fingerprint of the client certificate presented over
+/// mutual TLS.
+pub struct ClientCertExtractor;
+
+impl KeyExtractor for ClientCertExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        req.extensions()
+            .get::<ClientCertFingerprint>()
+            .map(|ClientCertFingerprint(fingerprint)| format!("cert:{}", fingerprint))
+    }
+}
+
+/// Classifies clients by `User-Agent` (e.g. known good bots, unknown bots,
+/// browsers) and keys them by class and IP, each class having its own limit.
+///
+/// Clients matching no class are keyed as `other` under the default limit.
+pub struct UserAgentClassExtractor {
+    classes: &'static [UserAgentClass],
+}
+
+impl UserAgentClassExtractor {
+    pub fn new(classes: &'static [UserAgentClass]) -> Self {
+        Self { classes }
+    }
+
+    fn classify(&self, req: &Request<Body>) -> Option<&'static UserAgentClass> {
+        let user_agent =
+            header_value(req, &HeaderName::from_static("user-agent"))?.to_ascii_lowercase();
+        self.classes.iter().find(|class| {
+            class
+                .patterns
+                .iter()
+                .any(|pattern| user_agent.contains(pattern.as_str()))
+        })
+    }
+}
+
+impl KeyExtractor for UserAgentClassExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        let class = self
+            .classify(req)
+            .map_or("other", |class| class.name.as_str());
+        let ip = IpExtractor.extract(req)?;
+        Some(format!("ua:{}:{}", class, ip))
+    }
+
+    fn profile(&self, req: &Request<Body>) -> Option<Arc<RateLimitConfig>> {
+        self.classify(req).and_then(|class| class.config.clone())
+    }
+}
+
+/// Value of the configured JSON body field, attached to the request by
+/// [`read_body_key`].
+#[derive(Clone, Debug)]
+pub struct BodyKey(pub String);
+
+/// Keys by a field of the JSON request body, e.g. `account_id` of a webhook.
+pub struct BodyFieldExtractor;
+
+impl KeyExtractor for BodyFieldExtractor {
+    fn extract(&self, req: &Request<Body>) -> Option<String> {
+        req.extensions()
+            .get::<BodyKey>()
+            .map(|BodyKey(value)| format!("body:{}", value))
+    }
+}
+
+/// Buffers JSON request bodies to find the configured field, attaching its
+/// value as [`BodyKey`] and handing back a request with the body restored.
+///
+/// Bodies that are not JSON or announce a length above the limit are left
+/// untouched. Streamed bodies turning out larger than the limit are handed
+/// on whole, what was read followed by the rest, without a key, so the next
+/// extractor keys them.
+pub async fn read_body_key(req: Request<Body>, config: &BodyKeyConfig) -> Request<Body> {
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| {
+            let v = v.trim();
+            v.eq_ignore_ascii_case("application/json") || v.ends_with("+json")
+        });
+    let too_large = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|length| length > config.max_bytes);
+    if !is_json || too_large {
+        return req;
+    }
+
+    let (mut parts, mut body) = req.into_parts();
+    let mut read = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let data = match frame {
+            Ok(frame) => frame.into_data().unwrap_or_default(),
+            // The handler gets the error where it happened.
+            Err(e) => {
+                let body = stream::iter([Ok(Bytes::from(read)), Err(e)]);
+                return Request::from_parts(parts, Body::from_stream(body));
+            }
+        };
+        read.extend_from_slice(&data);
+        if read.len() > config.max_bytes {
+            let body = stream::iter([Ok(Bytes::from(read))]).chain(body.into_data_stream());
+            return Request::from_parts(parts, Body::from_stream(body));
+        }
+    }
+    let bytes = Bytes::from(read);
+
+    let pointer = format!("/{}", config.field.replace('.', "/"));
+    let value = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|json| match json.pointer(&pointer)? {
+            Value::String(value) if !value.is_empty() => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        });
+    if let Some(value) = value {
+        parts.extensions.insert(BodyKey(value));
+    }
+
+    Request::from_parts(parts, Body::from(bytes))
+}
+
+fn header_value<'a>(req: &'a Request<Body>, header: &HeaderName) -> Option<&'a str> {
+    req.headers()
+        .get(header)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Narrows a client key to `route` when keys are scoped per route, e.g.
+/// `203.0.113.7|/users/:id`.
+pub fn scoped_key(key: String, route: &str) -> String {
+    match *KEY_SCOPE {
+        KeyScope::Global => key,
+        KeyScope::Route => format!("{}|{}", key, route),
+    }
+}
+
+/// The route template a request matched, rather than its raw path, so path
+/// parameters do not create a bucket per value, or the method path of a gRPC
+/// call, e.g. `/helloworld.Greeter/SayHello`. Requests matching no route
+/// share one.
+pub fn matched_route(req: &Request<Body>) -> &str {
+    if let Some(matched) = req.extensions().get::<MatchedPath>() {
+        return matched.as_str();
+    }
+    let path = req.uri().path();
+    if grpc::is_grpc(req.headers()) && grpc::method(path).is_some() {
+        return path;
+    }
+    "<unmatched>"
+}
+
+/// Replaces a key with its salted SHA-256 hash when key hashing is enabled,
+/// so neither the limiter state nor anything persisted from it holds raw IPs
+/// or credentials.
+pub fn anonymized_key(key: String) -> String {
+    match &*KEY_HASH_SALT {
+        Some(salt) => {
+            let mut hasher = Sha256::new();
+            hasher.update(salt);
+            hasher.update(key.as_bytes());
+            hex::encode(hasher.finalize())
+        }
+        None => key,
+    }
+}
+
+/// Extractors tried in priority order; the first one yielding a key wins.
+#[derive(Default)]
+pub struct KeyExtractorChain {
+    extractors: Vec<Box<dyn KeyExtractor>>,
+}
+
+impl KeyExtractorChain {
+    pub fn from_config(kinds: &[KeyExtractorKind]) -> Self {
+        kinds
+            .iter()
+            .fold(Self::default(), |chain, kind| match kind {
+                KeyExtractorKind::Ip => chain.with(IpExtractor),
+                KeyExtractorKind::Header(name) => chain.with(HeaderExtractor::new(
+                    name.clone(),
+                    format!("header:{}", name),
+                )),
+                KeyExtractorKind::ApiKey => {
+                    chain.with(HeaderExtractor::new(API_KEY_HEADER.clone(), API_KEY_PREFIX))
+                }
+                KeyExtractorKind::Cookie(name) => chain.with(CookieExtractor::new(
+                    name.clone(),
+                    format!("cookie:{}", name),
+                )),
+                KeyExtractorKind::Query(name) => {
+                    chain.with(QueryExtractor::new(name.clone(), *QUERY_KEY_MAX_LENGTH))
+                }
+                KeyExtractorKind::Session => {
+                    chain.with(CookieExtractor::new(SESSION_COOKIE.as_str(), "session"))
+                }
+                KeyExtractorKind::Jwt => chain.with(JwtExtractor),
+                KeyExtractorKind::ClientCert => chain.with(ClientCertExtractor),
+                KeyExtractorKind::BodyField => chain.with(BodyFieldExtractor),
+                KeyExtractorKind::UserAgentClass => {
+                    chain.with(UserAgentClassExtractor::new(&USER_AGENT_CLASSES))
+                }
+            })
+    }
+
+    pub fn with(mut self, extractor: impl KeyExtractor + 'static) -> Self {
+        self.extractors.push(Box::new(extractor));
+        self
+    }
+
+    pub fn extract(&self, req: &Request<Body>) -> Option<ExtractedKey> {
+        self.extract_all(req).next()
+    }
+
+    /// Every key the extractors find in `req`, in the order of the chain.
+    pub fn extract_all<'a>(
+        &'a self,
+        req: &'a Request<Body>,
+    ) -> impl Iterator<Item = ExtractedKey> + 'a {
+        self.extractors.iter().filter_map(move |extractor| {
+            extractor.extract(req).map(|key| ExtractedKey {
+                key,
+                profile: extractor.profile(req),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_bytes: usize) -> BodyKeyConfig {
+        BodyKeyConfig {
+            field: "data.account_id".to_string(),
+            paths: vec!["/webhooks/*".to_string()],
+            max_bytes,
+        }
+    }
+
+    fn request(content_type: &str, body: Body) -> Request<Body> {
+        Request::post("/webhooks/billing")
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap()
+    }
+
+    /// A body of unknown length, as sent in chunks.
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<_, std::io::Error>> = chunks.iter().map(|c| Ok(*c)).collect();
+        Body::from_stream(stream::iter(chunks))
+    }
+
+    async fn body(req: Request<Body>) -> String {
+        let bytes = axum::body::to_bytes(req.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn finds_the_field_and_restores_the_body() {
+        let json = r#"{"data":{"account_id":"acct-7"}}"#;
+        let req = read_body_key(request("application/json", Body::from(json)), &config(64)).await;
+        assert_eq!(
+            BodyFieldExtractor.extract(&req).as_deref(),
+            Some("body:acct-7")
+        );
+        assert_eq!(body(req).await, json);
+    }
+
+    #[tokio::test]
+    async fn hands_on_streamed_bodies_over_the_limit_whole() {
+        let chunks = [
+            r#"{"data":{"account_id":"#,
+            r#""acct-7"},"#,
+            r#""padding":"xxxxxxxx"}"#,
+        ];
+        let req = read_body_key(
+            request("application/json; charset=utf-8", streamed(&chunks)),
+            &config(32),
+        )
+        .await;
+        assert_eq!(BodyFieldExtractor.extract(&req), None);
+        assert_eq!(body(req).await, chunks.concat());
+    }
+
+    #[tokio::test]
+    async fn leaves_other_bodies_alone() {
+        let req = read_body_key(
+            request(
+                "text/plain",
+                Body::from(r#"{"data":{"account_id":"acct-7"}}"#),
+            ),
+            &config(64),
+        )
+        .await;
+        assert_eq!(BodyFieldExtractor.extract(&req), None);
+
+        let req = request("application/json", Body::from("[]"));
+        let (mut parts, body) = req.into_parts();
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, "1000".parse().unwrap());
+        let req = read_body_key(Request::from_parts(parts, body), &config(64)).await;
+        assert_eq!(BodyFieldExtractor.extract(&req), None);
+    }
+
+    #[test]
+    fn reads_only_configured_paths() {
+        assert!(config(64).reads("/webhooks/billing"));
+        assert!(!config(64).reads("/users/1"));
+    }
+}
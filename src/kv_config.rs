@@ -0,0 +1,154 @@
+//! Limits and rules pulled from Consul KV or etcd, so a fleet of instances
+//! picks up limit changes within seconds without a redeploy.
+//!
+//! The key holds a document in the config file format, of which the same
+//! settings are applied as on a file reload. Consul is watched with blocking
+//! queries, which return as soon as the key changes; etcd is polled every
+//! `RATE_LIMIT_CONFIG_WATCH_SECONDS` for a new revision of the key.
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::config::{CONFIG_WATCH_SECONDS, KvConfig, KvStore};
+use crate::config_file::FileConfig;
+use crate::reload;
+
+/// How long a Consul blocking query waits for a change before returning.
+const CONSUL_WAIT: Duration = Duration::from_secs(60);
+/// Pause before retrying after the store could not be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct EtcdRangeResponse {
+    #[serde(default)]
+    kvs: Vec<EtcdKeyValue>,
+}
+
+#[derive(Deserialize)]
+struct EtcdKeyValue {
+    value: String,
+    /// An int64, which the etcd gateway encodes as a JSON string.
+    mod_revision: String,
+}
+
+/// A version of the key's contents, changing whenever they are written.
+#[derive(PartialEq)]
+struct Version(String);
+
+/// Loads the config from the store and keeps watching it in the background.
+/// The first load is awaited so its limits are in force before the server
+/// accepts requests; a load that fails leaves the previous limits in force.
+pub async fn spawn_watch(config: &'static KvConfig) {
+    let client = reqwest::Client::builder()
+        .timeout(CONSUL_WAIT + Duration::from_secs(10))
+        .build()
+        .expect("failed to build config store HTTP client");
+
+    let mut version = None;
+    if let Err(e) = refresh(&client, config, &mut version).await {
+        tracing::error!("Failed to load the config from {:?}: {}", config.store, e);
+        reload::record_load_failure(e);
+    }
+    if *CONFIG_WATCH_SECONDS == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            if config.store == KvStore::Etcd {
+                tokio::time::sleep(Duration::from_secs(*CONFIG_WATCH_SECONDS)).await;
+            }
+            if let Err(e) = refresh(&client, config, &mut version).await {
+                tracing::error!("Failed to watch the config in {:?}: {}", config.store, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    });
+}
+
+/// Fetches the key, blocking on Consul until it changes from `version`, and
+/// applies its contents when they are a new version.
+async fn refresh(
+    client: &reqwest::Client,
+    config: &KvConfig,
+    version: &mut Option<Version>,
+) -> Result<(), String> {
+    let (latest, contents) = match config.store {
+        KvStore::Consul => fetch_consul(client, config, version.as_ref()).await?,
+        KvStore::Etcd => fetch_etcd(client, config).await?,
+    };
+    if version.as_ref() == Some(&latest) {
+        return Ok(());
+    }
+    *version = Some(latest);
+    let loaded = match contents {
+        Some(contents) => FileConfig::parse(&config.key, &contents),
+        None => Err(format!("{}: key not found", config.key)),
+    };
+    // A config that fails to apply is logged and recorded there, and is no
+    // reason to fetch it again sooner.
+    let _ = reload::apply(loaded);
+    Ok(())
+}
+
+async fn fetch_consul(
+    client: &reqwest::Client,
+    config: &KvConfig,
+    version: Option<&Version>,
+) -> Result<(Version, Option<String>), String> {
+    let mut request = client.get(format!("{}/v1/kv/{}?raw", config.url, config.key));
+    if let Some(Version(index)) = version
+        && !index.is_empty()
+    {
+        request = request.query(&[
+            ("index", index.as_str()),
+            ("wait", &format!("{}s", CONSUL_WAIT.as_secs())),
+        ]);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    // The index comes with missing keys too, so their creation is waited for
+    // like any other change.
+    let index = response
+        .headers()
+        .get("x-consul-index")
+        .and_then(|v| v.to_str().ok())
+        .ok_or("response without an X-Consul-Index header")?
+        .to_string();
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok((Version(index), None));
+    }
+    let contents = response
+        .error_for_status()
+        .map_err(|e| e.to_string())?
+        .text()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((Version(index), Some(contents)))
+}
+
+async fn fetch_etcd(
+    client: &reqwest::Client,
+    config: &KvConfig,
+) -> Result<(Version, Option<String>), String> {
+    let response: EtcdRangeResponse = client
+        .post(format!("{}/v3/kv/range", config.url))
+        .json(&serde_json::json!({ "key": BASE64.encode(&config.key) }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(kv) = response.kvs.into_iter().next() else {
+        return Ok((Version(String::new()), None));
+    };
+    let contents = BASE64
+        .decode(&kv.value)
+        .map_err(|e| format!("invalid value encoding: {}", e))?;
+    Ok((
+        Version(kv.mod_revision),
+        Some(String::from_utf8_lossy(&contents).into_owned()),
+    ))
+}
@@ -0,0 +1,61 @@
+//! A rate limiter for HTTP services, run as a server of its own or embedded
+//! in an axum app or any other tower stack.
+//!
+//! Embedded, requests are limited by
+//! [`RateLimitLayer`](middleware::RateLimitLayer), or in axum by
+//! [`rate_limit_middleware`](middleware::rate_limit_middleware), with the
+//! state of [`MiddlewareState::new`](middleware::MiddlewareState::new),
+//! over one of the limiters of [`rate_limiter`] or the backends of
+//! [`storage`]. Clients are told apart by the [`key_extractor`]s, and held
+//! to the limits of [`config`], which reads the same environment variables
+//! and config file as the server, but not its command line.
+
+mod access_log;
+mod admin;
+mod admin_grpc;
+mod audit;
+mod bans;
+#[cfg(feature = "bench")]
+mod bench;
+mod cli;
+mod client_ip;
+pub mod config;
+pub mod config_file;
+mod cors;
+mod denylist;
+mod drain;
+mod events;
+mod eviction;
+mod grpc;
+mod health;
+mod jwt;
+pub mod key_extractor;
+mod kv_config;
+mod log_sampling;
+mod lookup_cache;
+mod metrics;
+pub mod middleware;
+mod overrides;
+mod proxy;
+pub mod rate_limiter;
+mod rejection;
+mod reload;
+mod request_id;
+mod request_limits;
+mod rules;
+#[cfg(feature = "sentry")]
+mod sentry;
+pub mod server;
+mod snapshot;
+mod stats;
+mod statsd;
+mod status;
+pub mod storage;
+mod store_health;
+mod switch;
+mod telemetry;
+mod throttle;
+mod tier;
+mod tls;
+mod top;
+mod webhooks;
@@ -0,0 +1,91 @@
+//! Log volume: at high request rates, a line per request is more than disks
+//! and log pipelines can take. The lines of allowed requests can be sampled,
+//! one in N being logged, while denials always are, and the lines logged
+//! about any one client can be capped per second, so a client flooding the
+//! server does not flood its logs too.
+
+use dashmap::DashMap;
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::config::{LOG_SAMPLING_CONFIG, LogSamplingConfig};
+
+/// Allowed requests seen, one in the sample rate of which is logged.
+static ALLOWED: AtomicU64 = AtomicU64::new(0);
+
+static KEY_LIMIT: OnceLock<KeyLimit> = OnceLock::new();
+
+struct KeyLimit {
+    lines_per_second: u32,
+    /// Lines logged and suppressed this second, by key.
+    counts: DashMap<String, (u32, u64)>,
+}
+
+/// Starts capping the lines logged per client, if `config` has a cap,
+/// summing up each second how many were suppressed.
+pub fn spawn(config: &LogSamplingConfig) {
+    let Some(lines_per_second) = config.key_lines_per_second else {
+        return;
+    };
+    let _ = KEY_LIMIT.set(KeyLimit {
+        lines_per_second,
+        counts: DashMap::new(),
+    });
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(limit) = KEY_LIMIT.get() else { return };
+            let (mut keys, mut suppressed) = (0, 0);
+            limit.counts.retain(|_, (_, dropped)| {
+                if *dropped > 0 {
+                    keys += 1;
+                    suppressed += *dropped;
+                }
+                false
+            });
+            // One line for all of them, however many clients went over.
+            if suppressed > 0 {
+                tracing::warn!(
+                    keys,
+                    suppressed,
+                    "Suppressed log lines of clients over their limit"
+                );
+            }
+        }
+    });
+}
+
+/// Whether the line of a request is logged: all denials, and one in the
+/// sample rate of the other requests.
+pub fn sampled(allowed: bool) -> bool {
+    let rate = LOG_SAMPLING_CONFIG.sample_rate;
+    !allowed || rate <= 1 || ALLOWED.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate)
+}
+
+/// Whether a line about the client of `key` may be logged, counting it
+/// against the client's lines this second if so.
+pub fn for_key(key: &str) -> bool {
+    let Some(limit) = KEY_LIMIT.get() else {
+        return true;
+    };
+    let admit = |(logged, dropped): &mut (u32, u64)| {
+        if *logged < limit.lines_per_second {
+            *logged += 1;
+            true
+        } else {
+            *dropped += 1;
+            false
+        }
+    };
+    if let Some(mut counts) = limit.counts.get_mut(key) {
+        return admit(&mut counts);
+    }
+    admit(&mut limit.counts.entry(key.to_string()).or_insert((0, 0)))
+}
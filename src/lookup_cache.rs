@@ -0,0 +1,102 @@
+//! Answers of lookups by client key, like tiers and store overrides, cached
+//! for a TTL. Keys come from clients, so the cache is bounded and expired
+//! answers are swept by the eviction task rather than kept forever.
+
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+use crate::rate_limiter::least_recently_seen;
+
+pub struct LookupCache<T> {
+    ttl: Duration,
+    max_keys: usize,
+    entries: DashMap<String, (Option<T>, Instant)>,
+}
+
+impl<T: Clone> LookupCache<T> {
+    pub fn new(ttl: Duration, max_keys: usize) -> Self {
+        Self {
+            ttl,
+            max_keys,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// The answer cached for `key`, `None` unless there is one still fresh.
+    pub fn get(&self, key: &str) -> Option<Option<T>> {
+        let entry = self.entries.get(key)?;
+        let (value, fetched_at) = entry.value();
+        (fetched_at.elapsed() < self.ttl).then(|| value.clone())
+    }
+
+    /// Caches `value` as the answer for `key`, evicting the answers fetched
+    /// longest ago once more than the maximum are cached.
+    pub fn insert(&self, key: &str, value: Option<T>) {
+        self.entries
+            .insert(key.to_string(), (value, Instant::now()));
+        if self.entries.len() <= self.max_keys {
+            return;
+        }
+        self.evict_expired();
+        let evicted = least_recently_seen(
+            self.entries
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().1)),
+            self.entries.len(),
+            self.max_keys,
+        );
+        for key in &evicted {
+            self.entries.remove(key);
+        }
+    }
+
+    /// Drops the answers older than the TTL, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.ttl);
+        before.saturating_sub(self.entries.len())
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_fresh_for_the_ttl() {
+        let cache = LookupCache::new(Duration::from_millis(50), 10);
+        cache.insert("a", Some(1));
+        cache.insert("b", None);
+        assert_eq!(cache.get("a"), Some(Some(1)));
+        assert_eq!(cache.get("b"), Some(None));
+        assert_eq!(cache.get("c"), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.evict_expired(), 2);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn oldest_answers_are_evicted_over_the_maximum() {
+        let cache = LookupCache::new(Duration::from_secs(60), 10);
+        for i in 0..11 {
+            cache.insert(&format!("key-{}", i), Some(i));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(cache.len(), 9);
+        assert_eq!(cache.get("key-0"), None);
+        assert_eq!(cache.get("key-10"), Some(Some(10)));
+
+        for i in 0..1000 {
+            cache.insert(&format!("more-{}", i), None);
+        }
+        assert!(cache.len() <= 10);
+    }
+}
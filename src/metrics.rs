@@ -0,0 +1,500 @@
+use dashmap::DashMap;
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    sync::{
+        LazyLock, OnceLock, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::rate_limiter::MapHealth;
+use crate::statsd;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+static KEY_LABELS: OnceLock<KeyLabels> = OnceLock::new();
+
+/// Label of the keys not among the top ones on per-key metrics.
+const OTHER_KEYS: &str = "other";
+/// Time between two picks of the keys labelled on per-key metrics.
+const KEY_LABELS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Upper bounds, in seconds, of the buckets latencies are counted in, from
+/// the tens of microseconds an in-memory check takes to the seconds a slow
+/// store or a throttled request may.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Minimal counter, gauge and histogram registry rendered in the Prometheus
+/// text format, counters also sent to StatsD when configured.
+#[derive(Default)]
+pub struct Metrics {
+    counters: DashMap<String, AtomicU64>,
+    gauges: DashMap<String, AtomicU64>,
+    histograms: DashMap<String, Histogram>,
+}
+
+/// Latencies counted in the bucket they fall in, the last one for those
+/// over every bound, and summed.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        statsd::count(name, labels, value);
+        let series = series_name(name, labels);
+        if let Some(counter) = self.counters.get(&series) {
+            counter.fetch_add(value, Ordering::Relaxed);
+            return;
+        }
+        self.counters
+            .entry(series)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let series = series_name(name, labels);
+        if let Some(gauge) = self.gauges.get(&series) {
+            gauge.store(value, Ordering::Relaxed);
+            return;
+        }
+        self.gauges
+            .entry(series)
+            .or_insert_with(|| AtomicU64::new(0))
+            .store(value, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        let record = |histogram: &Histogram| {
+            histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            histogram
+                .sum_nanos
+                .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        };
+        let series = series_name(name, labels);
+        if let Some(histogram) = self.histograms.get(&series) {
+            record(&histogram);
+            return;
+        }
+        record(&self.histograms.entry(series).or_default());
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        render_values(&mut output, "counter", &self.counters);
+        render_values(&mut output, "gauge", &self.gauges);
+
+        let mut histograms: Vec<_> = self.histograms.iter().collect();
+        histograms.sort_by(|a, b| a.key().cmp(b.key()));
+        let mut last_name = "";
+        for entry in &histograms {
+            let (metric, labels) = match entry.key().split_once('{') {
+                Some((metric, labels)) => (metric, labels.trim_end_matches('}')),
+                None => (entry.key().as_str(), ""),
+            };
+            if metric != last_name {
+                let _ = writeln!(output, "# TYPE {} histogram", metric);
+                last_name = metric;
+            }
+            let separator = if labels.is_empty() { "" } else { "," };
+            let histogram = entry.value();
+            let mut count = 0;
+            for (bound, bucket) in LATENCY_BUCKETS
+                .iter()
+                .map(|bound| bound.to_string())
+                .chain(["+Inf".to_string()])
+                .zip(&histogram.buckets)
+            {
+                count += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    output,
+                    "{}_bucket{{{}{}le=\"{}\"}} {}",
+                    metric, labels, separator, bound, count
+                );
+            }
+            let labels = if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels)
+            };
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(output, "{}_sum{} {}", metric, labels, sum);
+            let _ = writeln!(output, "{}_count{} {}", metric, labels, count);
+        }
+        output
+    }
+}
+
+fn render_values(output: &mut String, kind: &str, values: &DashMap<String, AtomicU64>) {
+    let mut series: Vec<(String, u64)> = values
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+        .collect();
+    series.sort();
+
+    let mut last_name = "";
+    for (name, value) in &series {
+        let metric = name.split('{').next().unwrap_or(name);
+        if metric != last_name {
+            let _ = writeln!(output, "# TYPE {} {}", metric, kind);
+            last_name = metric;
+        }
+        let _ = writeln!(output, "{} {}", name, value);
+    }
+}
+
+fn series_name(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Keys given a label of their own on per-key metrics, at most `limit` of
+/// them, the rest counted together as `other` so the series stay bounded.
+/// The keys with the most requests over an interval are labelled during the
+/// next one, and counted exactly while they are; slots left free, like
+/// before the first pick, go to keys as they come.
+struct KeyLabels {
+    limit: usize,
+    labelled: RwLock<HashSet<String>>,
+    /// Requests per key since the last pick.
+    requests: DashMap<String, u64>,
+}
+
+impl KeyLabels {
+    /// The label `key` is counted under.
+    fn label<'a>(&self, key: &'a str) -> &'a str {
+        let labelled = self.labelled.read().unwrap_or_else(|e| e.into_inner());
+        if labelled.contains(key) {
+            return key;
+        }
+        if labelled.len() >= self.limit {
+            return OTHER_KEYS;
+        }
+        drop(labelled);
+        let mut labelled = self.labelled.write().unwrap_or_else(|e| e.into_inner());
+        if labelled.len() < self.limit {
+            labelled.insert(key.to_string());
+            return key;
+        }
+        OTHER_KEYS
+    }
+
+    /// Labels the keys with the most requests since the last pick.
+    fn pick(&self) {
+        let mut requests: Vec<(String, u64)> = self
+            .requests
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.requests.clear();
+        if requests.len() > self.limit {
+            requests.select_nth_unstable_by(self.limit, |a, b| b.1.cmp(&a.1));
+            requests.truncate(self.limit);
+        }
+        *self.labelled.write().unwrap_or_else(|e| e.into_inner()) =
+            requests.into_iter().map(|(key, _)| key).collect();
+    }
+}
+
+/// Starts counting decisions per key, labelling the `top_keys` with the most
+/// requests and the others as `other`.
+pub fn spawn_key_labels(top_keys: usize) {
+    let _ = KEY_LABELS.set(KeyLabels {
+        limit: top_keys,
+        labelled: RwLock::new(HashSet::new()),
+        requests: DashMap::new(),
+    });
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(KEY_LABELS_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(labels) = KEY_LABELS.get() else {
+                return;
+            };
+            labels.pick();
+        }
+    });
+}
+
+/// Rules that appeared or disappeared between two configurations.
+#[derive(Debug, Default)]
+pub struct RuleDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl RuleDiff {
+    pub fn between(old: &[String], new: &[String]) -> Self {
+        Self {
+            added: new.iter().filter(|r| !old.contains(r)).cloned().collect(),
+            removed: old.iter().filter(|r| !new.contains(r)).cloned().collect(),
+        }
+    }
+}
+
+pub fn record_config_reload(result: Result<&RuleDiff, &str>) {
+    match result {
+        Ok(diff) => {
+            METRICS.increment("rate_limit_config_reloads_total", &[("result", "success")]);
+            METRICS.add(
+                "rate_limit_config_rules_added_total",
+                &[],
+                diff.added.len() as u64,
+            );
+            METRICS.add(
+                "rate_limit_config_rules_removed_total",
+                &[],
+                diff.removed.len() as u64,
+            );
+            tracing::info!(
+                event = "config_reload",
+                result = "success",
+                rules_added = ?diff.added,
+                rules_removed = ?diff.removed,
+                "Configuration loaded"
+            );
+        }
+        Err(error) => {
+            METRICS.increment("rate_limit_config_reloads_total", &[("result", "failure")]);
+            tracing::error!(
+                event = "config_reload",
+                result = "failure",
+                error,
+                "Configuration reload failed"
+            );
+        }
+    }
+}
+
+/// Records the schedule block whose limits came into force, `None` for the
+/// regular limits.
+pub fn record_schedule_switch(schedule: Option<&str>) {
+    let schedule = schedule.unwrap_or("none");
+    METRICS.increment(
+        "rate_limit_schedule_switches_total",
+        &[("schedule", schedule)],
+    );
+    tracing::info!(
+        event = "schedule_switch",
+        schedule,
+        "Limits of schedule in force"
+    );
+}
+
+/// Records whether a checked request was allowed, by the tier of its client,
+/// and the requests it has left of its limit, if known.
+pub fn record_decision(allowed: bool, remaining: Option<u64>, tier: Option<&str>) {
+    let result = if allowed { "allow" } else { "deny" };
+    let tier = tier.unwrap_or("none");
+    METRICS.increment(
+        "rate_limit_decisions_total",
+        &[("result", result), ("tier", tier)],
+    );
+    if let Some(remaining) = remaining {
+        statsd::observe_remaining(tier, remaining);
+    }
+}
+
+/// Records the time the rate limit middleware spent on a request, from
+/// taking it to handing it on or rejecting it.
+pub fn record_middleware_latency(latency: Duration) {
+    METRICS.observe("rate_limit_middleware_duration_seconds", &[], latency);
+}
+
+/// Records how much the in-memory `limiter` holds.
+pub fn record_store_health(limiter: &str, health: &MapHealth) {
+    let labels = [("limiter", limiter)];
+    METRICS.set("rate_limit_tracked_keys", &labels, health.keys as u64);
+    METRICS.set("rate_limit_memory_bytes", &labels, health.bytes as u64);
+    for (shard, keys) in health.shards.iter().enumerate() {
+        let shard = shard.to_string();
+        METRICS.set(
+            "rate_limit_shard_keys",
+            &[("limiter", limiter), ("shard", &shard)],
+            *keys as u64,
+        );
+    }
+}
+
+/// Records the time a snapshot of the in-memory state took to write, and
+/// whether it was written.
+pub fn record_snapshot(duration: Duration, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    METRICS.observe(
+        "rate_limit_snapshot_duration_seconds",
+        &[("result", result)],
+        duration,
+    );
+}
+
+/// Records whether a request limited under `key` was allowed, under the key
+/// if it is among the top keys, else under `other`.
+pub fn record_key_decision(key: &str, allowed: bool) {
+    let Some(labels) = KEY_LABELS.get() else {
+        return;
+    };
+    match labels.requests.get_mut(key) {
+        Some(mut requests) => *requests += 1,
+        None => *labels.requests.entry(key.to_string()).or_default() += 1,
+    }
+    let result = if allowed { "allow" } else { "deny" };
+    METRICS.increment(
+        "rate_limit_key_decisions_total",
+        &[("key", labels.label(key)), ("result", result)],
+    );
+}
+
+pub fn record_rule_match(rule: &str) {
+    METRICS.increment("rate_limit_rule_matches_total", &[("rule", rule)]);
+    tracing::debug!(
+        event = "rule_match",
+        rule,
+        "Request matched rate limit rule"
+    );
+}
+
+/// Records keys dropped from memory, either for being `idle` or to stay
+/// within `capacity`.
+pub fn record_eviction(reason: &str, keys: usize) {
+    if keys == 0 {
+        return;
+    }
+    METRICS.add(
+        "rate_limit_evicted_keys_total",
+        &[("reason", reason)],
+        keys as u64,
+    );
+    tracing::debug!(event = "eviction", reason, keys, "Evicted keys");
+}
+
+/// Records a failed call to the store behind a `check`, either the
+/// `rate_limit` or the `quota` check.
+pub fn record_store_error(check: &str) {
+    METRICS.increment("rate_limit_store_errors_total", &[("check", check)]);
+}
+
+/// Records decision events lost because the queue was full or the broker
+/// failed.
+pub fn record_dropped_events(events: usize) {
+    METRICS.add("rate_limit_events_dropped_total", &[], events as u64);
+}
+
+/// Records denials whose audit record was dropped for the queue being full.
+pub fn record_dropped_audit_records(records: usize) {
+    METRICS.add(
+        "rate_limit_audit_records_dropped_total",
+        &[],
+        records as u64,
+    );
+}
+
+/// Records a batch of audit records the sink failed to take, to be retried.
+pub fn record_audit_write_failure() {
+    METRICS.increment("rate_limit_audit_write_failures_total", &[]);
+}
+
+/// Records a request shadow mode let through that would have been rejected.
+pub fn record_shadow_rejection() {
+    METRICS.increment("rate_limit_shadow_rejections_total", &[]);
+}
+
+/// Records how a throttled request's wait ended: `admitted`, `timed_out`, or
+/// `queue_full` when too many of its client's requests already waited.
+pub fn record_throttled(result: &str) {
+    METRICS.increment("rate_limit_throttled_total", &[("result", result)]);
+}
+
+/// Records a request rejected for coming from a denylisted address.
+pub fn record_denied() {
+    METRICS.increment("rate_limit_denied_total", &[]);
+}
+
+/// Records a request rejected for coming from a banned address or client.
+pub fn record_banned(target: &str) {
+    METRICS.increment("rate_limit_banned_total", &[("target", target)]);
+}
+
+/// Records a request rejected, or let through unlimited, in drain mode.
+pub fn record_drained(result: &str) {
+    METRICS.increment("rate_limit_drained_total", &[("result", result)]);
+}
+
+/// Records a request refused before any handler finished with it, for taking
+/// too long or having too large a body.
+pub fn record_refused(reason: &str) {
+    METRICS.increment("rate_limit_requests_refused_total", &[("reason", reason)]);
+}
+
+/// Records how the wait of a request over an upstream's outbound limit
+/// ended.
+pub fn record_outbound_throttled(result: &str) {
+    METRICS.increment("rate_limit_outbound_throttled_total", &[("result", result)]);
+}
+
+/// Records an upstream asking, with a `Retry-After`, for requests to be held
+/// back longer than they already were.
+pub fn record_upstream_backoff() {
+    METRICS.increment("rate_limit_upstream_backoffs_total", &[]);
+}
+
+/// Records a proxied request the response cache could answer, as a `hit`,
+/// or a `miss` going upstream.
+pub fn record_cache_lookup(result: &str) {
+    METRICS.increment("rate_limit_cache_lookups_total", &[("result", result)]);
+}
+
+/// Records webhook events `sent`, `failed` after their retries, or
+/// `dropped` for the queue being full.
+pub fn record_webhook_events(result: &str, events: usize) {
+    METRICS.add(
+        "rate_limit_webhook_events_total",
+        &[("result", result)],
+        events as u64,
+    );
+}
+
+/// Records a WebSocket limit being enforced: a connection `refused` for its
+/// key having too many open, or a message over its connection's limit being
+/// `dropped` or getting the connection `closed`.
+pub fn record_websocket_limited(action: &str) {
+    METRICS.increment("rate_limit_websocket_limited_total", &[("action", action)]);
+}
+
+/// Records an attempt to load the denylist source.
+pub fn record_denylist_refresh(result: &str) {
+    METRICS.increment("rate_limit_denylist_refreshes_total", &[("result", result)]);
+}
+
+pub async fn metrics_handler() -> String {
+    METRICS.render()
+}
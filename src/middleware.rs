@@ -1,54 +1,1171 @@
 use axum::{
-    body::Body,
-    extract::State,
-    http::{Request, Response, StatusCode},
+    BoxError,
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header},
     middleware::Next,
     response::IntoResponse,
 };
 
+use futures_util::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tower::{Layer, Service};
+use tracing::{Instrument, field};
+
+use crate::audit;
+use crate::bans::{self, Ban};
+use crate::client_ip::{client_ip, is_allowlisted, is_denylisted};
+use crate::config::{
+    ANONYMOUS_POLICY, AnonymousPolicy, BODY_KEY_CONFIG, DEFAULT_RULE_NAME, DrainPolicy,
+    KEY_EXTRACTORS, Limits, QUOTA_CONFIG, RATE_LIMIT_ALGORITHM, RATE_LIMIT_HEADERS,
+    RATE_LIMIT_MODE, RateLimitAlgorithm, RateLimitConfig, RateLimitMode, RateLimiterType,
+    STORE_FAILURE_POLICY, StoreFailurePolicy, THROTTLE_CONFIG, WARNING_THRESHOLD, limits,
+};
+use crate::config_file::{LimiterSection, RouteRule};
+use crate::denylist::denylist;
+use crate::drain;
+use crate::events::{Decision, DecisionEvent, EventPublisher};
+use crate::key_extractor::{
+    API_KEY_PREFIX, ExtractedKey, KeyExtractorChain, anonymized_key, matched_route, read_body_key,
+    scoped_key,
+};
+use crate::log_sampling;
+use crate::metrics;
+use crate::overrides::{self, StoreOverrides};
 use crate::rate_limiter::{
-    LockFreeRateLimitState, LockFreeSlidingWindowRateLimiter, RateLimitState, RateLimiterEnum,
-    SlidingWindowRateLimiter,
+    LimiterFactory, LockFreeRateLimitState, LockFreeSlidingWindowRateLimiter, MapHealth,
+    QuotaLimiter, RateLimitDecision, RateLimitError, RateLimitState, RateLimiter,
+    SlidingWindowRateLimiter, StoreRateLimiter, TrackedKey,
 };
+use crate::rejection::{Rejection, seconds};
+use crate::request_id::REQUEST_ID_HEADER;
+#[cfg(feature = "sentry")]
+use crate::sentry;
+use crate::stats;
+use crate::storage::{
+    ClusterRateLimitState, ClusterRateLimiter, DynamoDbStore, GossipRateLimitState,
+    GossipRateLimiter, HybridRateLimitState, HybridRateLimiter, MemcachedStore, MemoryStore,
+    PostgresQuotaStore, RateLimitStore, RedisRateLimitState, RedisRateLimiter, RedisStore,
+    SqliteStore,
+};
+use crate::throttle::Throttle;
+use crate::tier::{Tier, TierResolver, configured_tier};
+use crate::top;
+use crate::webhooks;
 
 #[derive(Clone)]
 pub enum RateLimitStateEnum {
     Standard(RateLimitState),
     LockFree(LockFreeRateLimitState),
+    Redis(RedisRateLimitState),
+    Hybrid(HybridRateLimitState),
+    MemoryStore(MemoryStore),
+    RedisStore(RedisStore),
+    MemcachedStore(MemcachedStore),
+    DynamoDbStore(DynamoDbStore),
+    SqliteStore(SqliteStore),
+    Gossip(GossipRateLimitState),
+    Cluster(ClusterRateLimitState),
+    /// A limiter of another crate, built for each limit by the factory.
+    Custom(LimiterFactory),
+}
+
+impl RateLimitStateEnum {
+    /// The type of a limiter keeping its keys in this process's memory, none
+    /// for the other backends.
+    pub fn memory_type(&self) -> Option<RateLimiterType> {
+        match self {
+            Self::Standard(_) => Some(RateLimiterType::Standard),
+            Self::LockFree(_) => Some(RateLimiterType::LockFree),
+            Self::MemoryStore(_) => Some(RateLimiterType::Store),
+            _ => None,
+        }
+    }
+
+    /// Keys the limiter holds in this process's memory, none for backends
+    /// keeping them elsewhere.
+    pub async fn tracked_keys(&self) -> Option<usize> {
+        match self {
+            Self::Standard(state) => Some(state.tracked_keys().await),
+            Self::LockFree(state) => Some(state.tracked_keys()),
+            Self::Hybrid(state) => Some(state.tracked_keys()),
+            Self::MemoryStore(store) => Some(store.tracked_keys()),
+            Self::Gossip(state) => Some(state.tracked_keys()),
+            Self::Cluster(state) => Some(state.tracked_keys()),
+            _ => None,
+        }
+    }
+
+    /// Keys starting with `prefix` with at least `min_count` requests, for
+    /// the limiters keeping per-key counts in maps, none for the others.
+    pub async fn keys(&self, prefix: &str, min_count: u64) -> Option<Vec<TrackedKey>> {
+        match self {
+            Self::Standard(state) => Some(state.keys(prefix, min_count).await),
+            Self::LockFree(state) => Some(state.keys(prefix, min_count)),
+            _ => None,
+        }
+    }
+
+    /// Whether the limiter keeps its state in a store instances share, where
+    /// what is set at runtime, like route rules and bans, is kept too.
+    pub fn is_shared(&self) -> bool {
+        matches!(
+            self,
+            Self::Redis(_)
+                | Self::Hybrid(_)
+                | Self::RedisStore(_)
+                | Self::MemcachedStore(_)
+                | Self::DynamoDbStore(_)
+                | Self::SqliteStore(_)
+        )
+    }
+
+    /// Runs `f` on the value of `key` in the shared store, like
+    /// `RateLimitStore::update`, none if the limiter has none.
+    pub async fn update_shared<R: Send>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        f: impl FnMut(Option<&[u8]>) -> (Option<Vec<u8>>, R) + Send,
+    ) -> Option<Result<R, String>> {
+        let result = match self {
+            Self::Redis(state) => RedisStore::new(state.clone()).update(key, ttl, f).await,
+            Self::Hybrid(state) => {
+                RedisStore::new(state.redis().clone())
+                    .update(key, ttl, f)
+                    .await
+            }
+            Self::RedisStore(store) => store.update(key, ttl, f).await,
+            Self::MemcachedStore(store) => store.update(key, ttl, f).await,
+            Self::DynamoDbStore(store) => store.update(key, ttl, f).await,
+            Self::SqliteStore(store) => store.update(key, ttl, f).await,
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// How much the limiter holds in this process's memory, none for
+    /// backends keeping their keys elsewhere.
+    pub async fn health(&self) -> Option<MapHealth> {
+        match self {
+            Self::Standard(state) => Some(state.health().await),
+            Self::LockFree(state) => Some(state.health()),
+            Self::Hybrid(state) => Some(state.health()),
+            Self::MemoryStore(store) => Some(store.health()),
+            Self::Gossip(state) => Some(state.health()),
+            Self::Cluster(state) => Some(state.health()),
+            _ => None,
+        }
+    }
+}
+
+/// A limiter's state, shared so the main one can be swapped for one of
+/// another type at runtime under the requests and background tasks using it.
+#[derive(Clone)]
+pub struct SharedLimiter(Arc<std::sync::RwLock<RateLimitStateEnum>>);
+
+impl SharedLimiter {
+    pub fn new(limiter: RateLimitStateEnum) -> Self {
+        Self(Arc::new(std::sync::RwLock::new(limiter)))
+    }
+
+    /// The limiter in use. Requests and background rounds each take it once,
+    /// so a swap applies from the next one.
+    pub fn get(&self) -> RateLimitStateEnum {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set(&self, limiter: RateLimitStateEnum) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = limiter;
+    }
+}
+
+/// A limiter route rules are bound to by name, with state of its own.
+#[derive(Clone)]
+pub struct NamedLimiter {
+    pub state: RateLimitStateEnum,
+    pub algorithm: RateLimitAlgorithm,
+}
+
+impl NamedLimiter {
+    pub fn new(section: &LimiterSection) -> Self {
+        let state = match section.kind {
+            RateLimiterType::Standard => RateLimitStateEnum::Standard(RateLimitState {
+                requests: Arc::new(RwLock::new(HashMap::new())),
+            }),
+            RateLimiterType::LockFree => {
+                RateLimitStateEnum::LockFree(LockFreeRateLimitState::new())
+            }
+            RateLimiterType::Store => RateLimitStateEnum::MemoryStore(MemoryStore::new()),
+        };
+        Self {
+            state,
+            algorithm: section.algorithm.unwrap_or(*RATE_LIMIT_ALGORITHM),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MiddlewareState {
+    /// The main limiter, which `POST /admin/limiter` can switch to another
+    /// in-memory type.
+    pub limiter: SharedLimiter,
+    /// Limiters route rules can be bound to instead of the main one.
+    pub limiters: Arc<HashMap<String, NamedLimiter>>,
+    pub key_extractors: Arc<KeyExtractorChain>,
+    pub tier_resolver: Option<Arc<TierResolver>>,
+    pub store_overrides: Option<Arc<StoreOverrides>>,
+    pub quota_store: Option<PostgresQuotaStore>,
+    /// Limits requests while the backing store is unavailable under the
+    /// `local` store failure policy.
+    pub fallback_store: MemoryStore,
+    pub events: Option<EventPublisher>,
+    pub throttle: Arc<Throttle>,
+}
+
+impl MiddlewareState {
+    /// State limiting requests with `limiter` alone, for apps embedding the
+    /// middleware: clients are told apart as configured by
+    /// `RATE_LIMIT_KEY_EXTRACTORS`, with no named limiters, tiers, store
+    /// overrides, quotas or decision events.
+    pub fn new(limiter: RateLimitStateEnum) -> Self {
+        Self {
+            limiter: SharedLimiter::new(limiter),
+            limiters: Arc::new(HashMap::new()),
+            key_extractors: Arc::new(KeyExtractorChain::from_config(&KEY_EXTRACTORS)),
+            tier_resolver: None,
+            store_overrides: None,
+            quota_store: None,
+            fallback_store: MemoryStore::new(),
+            events: None,
+            throttle: Arc::new(Throttle::default()),
+        }
+    }
+}
+
+/// The key a request was counted under, set on requests that were checked
+/// for the handlers behind to limit what else they do by it.
+#[derive(Clone)]
+pub struct ClientKey(pub String);
+
+/// What was decided on a request that was checked, set on its response for
+/// the request log.
+#[derive(Clone)]
+pub struct Checked {
+    pub ip: String,
+    pub key: String,
+    pub allowed: bool,
+    /// Requests the client has left of its limit, if known.
+    pub remaining: Option<u64>,
+}
+
+/// Who a request is counted against, and the limit it is held to.
+pub struct Client {
+    pub key: String,
+    pub config: Arc<RateLimitConfig>,
+    /// Tier the limit is taken from, if any.
+    pub tier: Option<String>,
+}
+
+/// The limit of an identified client: its own override if it has one, a
+/// temporary one first, else its tier, `tier` being the one set in the
+/// config. Overrides come without a tier name.
+pub async fn client_profile(
+    state: &MiddlewareState,
+    limits: &Limits,
+    headers: &HeaderMap,
+    key: &str,
+    tier: Option<Tier>,
+) -> Option<(Arc<RateLimitConfig>, Option<String>)> {
+    if let Some((profile, _)) = overrides::temporary(key) {
+        return Some((profile, None));
+    }
+    if let Some(profile) = limits.overrides.get(key) {
+        return Some((profile.clone(), None));
+    }
+    if let Some(overrides) = &state.store_overrides
+        && let Some(profile) = overrides.profile(&state.limiter.get(), key).await
+    {
+        return Some((profile, None));
+    }
+    let tier = match (tier, &state.tier_resolver) {
+        (Some(tier), _) => Some(tier),
+        (None, Some(resolver)) => resolver.tier(headers).await,
+        (None, None) => None,
+    }?;
+    Some((tier.limit, Some(tier.name)))
+}
+
+async fn is_known_api_key(
+    state: &MiddlewareState,
+    limits: &Limits,
+    headers: &HeaderMap,
+    key: &str,
+) -> bool {
+    let api_key = &key[API_KEY_PREFIX.len() + 1..];
+    limits.api_key_tiers.contains_key(api_key)
+        || client_profile(state, limits, headers, key, None)
+            .await
+            .is_some()
+}
+
+/// What a request tells about its sender, read up front as requests cannot
+/// be held across awaits.
+pub enum Identity {
+    Extracted {
+        extracted: ExtractedKey,
+        /// Tier the config assigns the request to.
+        tier: Option<Tier>,
+        /// What the request is identified as by the rest of the chain, if
+        /// `extracted` is an API key, for when the key turns out unknown.
+        unknown_key: Option<Box<Identity>>,
+    },
+    /// A client the anonymous policy made of a request without a key.
+    Anonymous(Client),
+    /// A request without a key the anonymous policy rejects or bypasses.
+    Unidentified,
+}
+
+impl Identity {
+    pub fn of(state: &MiddlewareState, limits: &Limits, req: &Request<Body>, ip: &str) -> Self {
+        Self::from_keys(state.key_extractors.extract_all(req), limits, req, ip)
+    }
+
+    fn from_keys(
+        mut keys: impl Iterator<Item = ExtractedKey>,
+        limits: &Limits,
+        req: &Request<Body>,
+        ip: &str,
+    ) -> Self {
+        let Some(extracted) = keys.next() else {
+            return Self::anonymous(limits, req, ip);
+        };
+        let unknown_key = if is_api_key(&extracted.key) {
+            Some(Box::new(Self::from_keys(keys, limits, req, ip)))
+        } else {
+            None
+        };
+        Self::Extracted {
+            extracted,
+            tier: configured_tier(limits, req),
+            unknown_key,
+        }
+    }
+
+    fn anonymous(limits: &Limits, req: &Request<Body>, ip: &str) -> Self {
+        match *ANONYMOUS_POLICY {
+            AnonymousPolicy::Reject | AnonymousPolicy::Bypass => Self::Unidentified,
+            AnonymousPolicy::PerConnection => {
+                let connection = req
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.to_string())
+                    .unwrap_or_else(|| ip.to_string());
+                Self::Anonymous(Client {
+                    key: format!("connection:{}", connection),
+                    config: limits.default.clone(),
+                    tier: None,
+                })
+            }
+            AnonymousPolicy::Shared => Self::Anonymous(Client {
+                key: "anonymous".to_string(),
+                config: limits.anonymous.clone(),
+                tier: None,
+            }),
+        }
+    }
+}
+
+fn is_api_key(key: &str) -> bool {
+    key.strip_prefix(API_KEY_PREFIX)
+        .is_some_and(|rest| rest.starts_with(':'))
+}
+
+/// The client a request with `identity` and `headers` comes from and its
+/// limit, before route rules, or `None` if it is unidentified.
+///
+/// An API key only identifies the client if it is known: it has a tier in
+/// `api_key_tiers` or from the lookup service, or a limit override. Anyone
+/// can make up keys, so requests with an unknown one are identified by the
+/// rest of the chain, or else as anonymous, rather than each getting a fresh
+/// budget.
+pub async fn identify(
+    state: &MiddlewareState,
+    limits: &Limits,
+    headers: &HeaderMap,
+    mut identity: Identity,
+) -> Option<Client> {
+    let (extracted, tier) = loop {
+        match identity {
+            Identity::Extracted {
+                extracted,
+                unknown_key: Some(fallback),
+                ..
+            } if !is_known_api_key(state, limits, headers, &extracted.key).await => {
+                identity = *fallback;
+            }
+            Identity::Extracted {
+                extracted, tier, ..
+            } => break (extracted, tier),
+            Identity::Anonymous(client) => return Some(client),
+            Identity::Unidentified => return None,
+        }
+    };
+    let profile = client_profile(state, limits, headers, &extracted.key, tier).await;
+    let (config, tier) = match (profile, extracted.profile) {
+        (Some((config, tier)), _) => (config, tier),
+        (None, Some(config)) => (config, None),
+        (None, None) => (limits.default.clone(), None),
+    };
+    Some(Client {
+        key: extracted.key,
+        config,
+        tier,
+    })
+}
+
+/// The key and limit `client`'s requests on `route` are counted under,
+/// along with the name of the rule applying.
+pub fn rule_key<'a>(
+    client: Client,
+    route_rule: Option<&'a RouteRule>,
+    route: &str,
+) -> (String, Arc<RateLimitConfig>, &'a str) {
+    // A route rule replaces the client's limit, under a key of its own so the
+    // two budgets do not mix.
+    let (key, config, rule) = match route_rule {
+        Some(rule) => (
+            format!("{}|{}", client.key, rule.name),
+            rule.limit.clone(),
+            rule.name.as_str(),
+        ),
+        None => (client.key, client.config, DEFAULT_RULE_NAME),
+    };
+    (anonymized_key(scoped_key(key, route)), config, rule)
+}
+
+/// The limiter counting requests under `route_rule`, holding them to
+/// `config`.
+pub fn limiter(
+    state: &MiddlewareState,
+    route_rule: Option<&RouteRule>,
+    config: &Arc<RateLimitConfig>,
+) -> Arc<dyn RateLimiter> {
+    let named = route_rule
+        .and_then(|rule| rule.limiter.as_ref())
+        .and_then(|name| state.limiters.get(name));
+    let (limiter, algorithm) = match named {
+        Some(named) => (named.state.clone(), named.algorithm),
+        None => (state.limiter.get(), *RATE_LIMIT_ALGORITHM),
+    };
+    let config = config.clone();
+    match limiter {
+        RateLimitStateEnum::Standard(state) => {
+            Arc::new(SlidingWindowRateLimiter::new(state.requests, config))
+        }
+        RateLimitStateEnum::LockFree(state) => Arc::new(LockFreeSlidingWindowRateLimiter::new(
+            state.requests,
+            config,
+        )),
+        RateLimitStateEnum::Redis(state) => Arc::new(RedisRateLimiter::new(state, config)),
+        RateLimitStateEnum::Hybrid(state) => Arc::new(HybridRateLimiter::new(state, config)),
+        RateLimitStateEnum::MemoryStore(store) => {
+            Arc::new(StoreRateLimiter::new(store, algorithm, config))
+        }
+        RateLimitStateEnum::RedisStore(store) => {
+            Arc::new(StoreRateLimiter::new(store, algorithm, config))
+        }
+        RateLimitStateEnum::MemcachedStore(store) => {
+            Arc::new(StoreRateLimiter::new(store, algorithm, config))
+        }
+        RateLimitStateEnum::DynamoDbStore(store) => {
+            Arc::new(StoreRateLimiter::new(store, algorithm, config))
+        }
+        RateLimitStateEnum::SqliteStore(store) => {
+            Arc::new(StoreRateLimiter::new(store, algorithm, config))
+        }
+        RateLimitStateEnum::Gossip(state) => Arc::new(GossipRateLimiter::new(state, config)),
+        RateLimitStateEnum::Cluster(state) => Arc::new(ClusterRateLimiter::new(state, config)),
+        RateLimitStateEnum::Custom(factory) => factory(config),
+    }
+}
+
+/// Whether the request is a CORS preflight, sent by browsers ahead of
+/// cross-origin requests.
+fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
 }
 
 pub async fn rate_limit_middleware(
-    State(state): State<RateLimitStateEnum>,
+    State(state): State<MiddlewareState>,
     req: Request<Body>,
     next: Next,
 ) -> Response<Body> {
-    let ip = req
-        .headers()
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("unknown");
+    checked(state, req, Box::new(|req| Box::pin(next.run(req)))).await
+}
 
-    let path = req.uri().path();
-    tracing::info!("Incoming request - IP: {}, Path: {}", ip, path);
+/// Limits requests to the services it wraps, like `rate_limit_middleware`,
+/// in any tower stack: hyper through `hyper_util`'s `TowerToHyperService`,
+/// tonic's `Server::layer`, or anything else taking a `tower::Layer`.
+///
+/// Client addresses are read from the `ConnectInfo<SocketAddr>` request
+/// extension axum sets; stacks without it should insert it, or clients are
+/// told apart by forwarding headers and keys alone. Bodies are passed on as
+/// axum's `Body`.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    state: MiddlewareState,
+}
 
-    let limiter = match state {
-        RateLimitStateEnum::Standard(state) => {
-            RateLimiterEnum::Standard(SlidingWindowRateLimiter::new(state.requests))
+impl RateLimitLayer {
+    pub fn new(state: MiddlewareState) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            state: self.state.clone(),
         }
-        RateLimitStateEnum::LockFree(state) => {
-            RateLimiterEnum::LockFree(LockFreeSlidingWindowRateLimiter::new(state.requests))
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    state: MiddlewareState,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for RateLimitService<S>
+where
+    S: Service<Request<Body>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // The service polled ready is the one called; the clone left in its
+        // place is polled for the next request.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = self.state.clone();
+        Box::pin(async move {
+            // The rest of the stack answers with a response, so an error of
+            // the inner service is set aside and returned in its stead.
+            let failed = Arc::new(std::sync::Mutex::new(None));
+            let next: Rest = {
+                let failed = failed.clone();
+                Box::new(move |req| {
+                    Box::pin(async move {
+                        match inner.call(req).await {
+                            Ok(response) => response.map(Body::new),
+                            Err(e) => {
+                                *failed.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                            }
+                        }
+                    })
+                })
+            };
+            let response = checked(state, req.map(Body::new), next).await;
+            match failed.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                Some(e) => Err(e),
+                None => Ok(response),
+            }
+        })
+    }
+}
+
+/// Runs the rest of the stack on a request let through.
+type Rest = Box<dyn FnOnce(Request<Body>) -> BoxFuture<'static, Response<Body>> + Send>;
+
+async fn checked(state: MiddlewareState, req: Request<Body>, next: Rest) -> Response<Body> {
+    let started = Instant::now();
+    let mut handled = Duration::ZERO;
+    let response = limit(
+        state,
+        req,
+        Handler {
+            next,
+            elapsed: &mut handled,
+        },
+    )
+    .await;
+    metrics::record_middleware_latency(started.elapsed().saturating_sub(handled));
+    response
+}
+
+/// The rest of the stack, timed so the middleware's own latency can be told
+/// apart from the handler's.
+struct Handler<'a> {
+    next: Rest,
+    elapsed: &'a mut Duration,
+}
+
+impl Handler<'_> {
+    async fn run(self, req: Request<Body>) -> Response<Body> {
+        let started = Instant::now();
+        let response = (self.next)(req).await;
+        *self.elapsed = started.elapsed();
+        response
+    }
+}
+
+async fn limit(state: MiddlewareState, req: Request<Body>, next: Handler<'_>) -> Response<Body> {
+    let ip = client_ip(&req).unwrap_or_else(|| "unknown".to_string());
+    if is_denylisted(&denylist(), &ip) {
+        if log_sampling::for_key(&ip) {
+            tracing::warn!("Rejected request from denylisted IP: {}", ip);
         }
+        metrics::record_denied();
+        return (StatusCode::FORBIDDEN, "Forbidden.").into_response();
+    }
+    if let Some(ban) = bans::ip_ban(&ip) {
+        return banned(&ban, "ip", &ip);
+    }
+
+    // Taken once so a reload cannot change the limits halfway through.
+    let limits = limits();
+    // Preflights are the browser asking, not the client, so they are never
+    // counted against it.
+    if limits.is_exempt(req.uri().path()) || is_preflight(&req) {
+        return next.run(req).await;
+    }
+    if is_allowlisted(&limits.allowlist, &ip) {
+        return next.run(req).await;
+    }
+
+    let mut req = match &*BODY_KEY_CONFIG {
+        Some(config) if config.reads(req.uri().path()) => read_body_key(req, config).await,
+        _ => req,
+    };
+    let path = req.uri().path();
+    let route_rule = limits.routes.iter().find(|rule| rule.matches(path));
+
+    let identity = Identity::of(&state, &limits, &req, &ip);
+    let Some(client) = identify(&state, &limits, req.headers(), identity).await else {
+        return match *ANONYMOUS_POLICY {
+            AnonymousPolicy::Reject if *RATE_LIMIT_MODE == RateLimitMode::Shadow => {
+                if log_sampling::for_key(&ip) {
+                    tracing::warn!(
+                        "Shadow mode, letting through unidentifiable request from IP: {}",
+                        ip
+                    );
+                }
+                metrics::record_shadow_rejection();
+                next.run(req).await
+            }
+            AnonymousPolicy::Reject => {
+                if log_sampling::for_key(&ip) {
+                    tracing::warn!("Rejected unidentifiable request from IP: {}", ip);
+                }
+                (StatusCode::FORBIDDEN, "Unable to identify client.").into_response()
+            }
+            _ => next.run(req).await,
+        };
     };
+    if let Some(ban) = bans::key_ban(&client.key) {
+        return banned(&ban, "key", &client.key);
+    }
+    let tier = client.tier.clone();
+    let (key, config, rule) = rule_key(client, route_rule, matched_route(&req));
+    metrics::record_rule_match(rule);
+    let limiter = limiter(&state, route_rule, &config);
+    if let Some(drain) = drain::draining() {
+        match drain.policy {
+            DrainPolicy::Pass => {
+                metrics::record_drained("passed");
+                return next.run(req).await;
+            }
+            DrainPolicy::RejectNew if is_new(&*limiter, &key).await => return drained(&key),
+            DrainPolicy::RejectNew => {}
+        }
+    }
+
+    // The decision's span ends with it, before the request is handled.
+    let decision = {
+        let span = tracing::info_span!(
+            "rate_limit",
+            ip = %ip,
+            key = %key,
+            rule,
+            decision = field::Empty,
+        );
+        let decision = check(
+            &*limiter,
+            &state,
+            &key,
+            config,
+            route_rule,
+            &ip,
+            req.headers(),
+        )
+        .instrument(span.clone())
+        .await;
+        span.record("decision", if decision.is_ok() { "allow" } else { "deny" });
+        decision
+    };
+    let remaining = match &decision {
+        Ok(remaining) => remaining.rate_limit.map(|decision| decision.remaining),
+        Err(_) => Some(0),
+    };
+    metrics::record_decision(decision.is_ok(), remaining, tier.as_deref());
+    metrics::record_key_decision(&key, decision.is_ok());
+    top::record(&key, decision.is_ok());
+    stats::record_decision(&key, decision.is_ok());
+    webhooks::record(&key, rule, &ip, decision.is_ok());
+    if decision.is_err() {
+        audit::record(
+            &key,
+            rule,
+            &limits.version,
+            req.headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|v| v.to_str().ok()),
+        )
+        .await;
+    }
+
+    if let Some(events) = &state.events {
+        events.publish(DecisionEvent::new(
+            key.clone(),
+            matched_route(&req).to_string(),
+            match decision {
+                Ok(_) => Decision::Allow,
+                Err(_) => Decision::Deny,
+            },
+        ));
+    }
 
-    match limiter.check_rate_limit(ip).await {
-        Ok(_) => {
-            limiter.record_request(ip).await;
-            tracing::info!("Rate limit check passed for IP: {}", ip);
+    req.extensions_mut().insert(ClientKey(key.clone()));
+    let allowed = decision.is_ok();
+    let mut response = match decision {
+        Ok(remaining) => {
+            limiter.record_request(&key).await;
+            let mut response = next.run(req).await;
+            insert_quota_headers(response.headers_mut(), &remaining, rule);
+            insert_warning_header(response.headers_mut(), &remaining);
+            response
+        }
+        // Not recorded, so the counts stay what enforcing would leave.
+        Err(response) if *RATE_LIMIT_MODE == RateLimitMode::Shadow => {
+            if log_sampling::for_key(&key) {
+                tracing::warn!(
+                    "Shadow mode, letting through request that would get {} for IP: {}",
+                    response.status(),
+                    ip
+                );
+            }
+            metrics::record_shadow_rejection();
             next.run(req).await
         }
-        Err(message) => {
-            tracing::warn!("Rate limit exceeded for IP: {}", ip);
-            (StatusCode::TOO_MANY_REQUESTS, message).into_response()
+        Err(response) => response,
+    };
+    response.extensions_mut().insert(Checked {
+        ip,
+        key,
+        allowed,
+        remaining,
+    });
+    response
+}
+
+/// Rejects a request of `client` under `ban`, whatever the rate limit mode.
+fn banned(ban: &Ban, target: &str, client: &str) -> Response<Body> {
+    if log_sampling::for_key(client) {
+        tracing::warn!(
+            "Rejected request from banned {} {} (ban {}: {})",
+            target,
+            client,
+            ban.id,
+            ban.reason.as_deref().unwrap_or("no reason given")
+        );
+    }
+    metrics::record_banned(target);
+    (StatusCode::FORBIDDEN, "Forbidden.").into_response()
+}
+
+/// Whether `key` has no requests counted in its current window, so is a
+/// client drain mode turns away. Clients whose counts could not be read are
+/// left to the limiter.
+async fn is_new(limiter: &dyn RateLimiter, key: &str) -> bool {
+    limiter
+        .peek(key)
+        .await
+        .is_ok_and(|decision| decision.remaining >= decision.limit)
+}
+
+/// Rejects a request of a new client in drain mode, whatever the rate limit
+/// mode.
+fn drained(key: &str) -> Response<Body> {
+    if log_sampling::for_key(key) {
+        tracing::warn!("Draining, rejected request from new client {}", key);
+    }
+    metrics::record_drained("rejected");
+    (StatusCode::SERVICE_UNAVAILABLE, "Service draining.").into_response()
+}
+
+/// Name of the quota's policy in the `RateLimit` header fields.
+const QUOTA_POLICY_NAME: &str = "quota";
+
+/// What is left of the limits an admitted request was checked against. A
+/// limit is missing if it has none configured, or if its store failed and
+/// the request was let through unchecked.
+#[derive(Default)]
+struct Remaining {
+    rate_limit: Option<RateLimitDecision>,
+    quota: Option<RateLimitDecision>,
+}
+
+/// Checks the rate limit, waiting for capacity if the route `rule` or the
+/// global config throttle, and then the quota, returning the response to
+/// reject the request with if either is exceeded.
+async fn check(
+    limiter: &dyn RateLimiter,
+    state: &MiddlewareState,
+    key: &str,
+    config: Arc<RateLimitConfig>,
+    rule: Option<&RouteRule>,
+    ip: &str,
+    headers: &HeaderMap,
+) -> Result<Remaining, Response<Body>> {
+    // Shadow mode lets requests through anyway, so they are not held up.
+    let throttle = rule
+        .map_or(*THROTTLE_CONFIG, |rule| rule.throttle.or(*THROTTLE_CONFIG))
+        .filter(|throttle| throttle.max_wait_ms > 0 && *RATE_LIMIT_MODE == RateLimitMode::Enforce);
+    let rejection = rule.and_then(|rule| rule.rejection.as_ref());
+    let mut remaining = Remaining::default();
+    let started = Instant::now();
+    let mut result = limiter.check_rate_limit(key).await;
+    stats::record_store_latency(started.elapsed());
+    if let (Some(throttle), Err(error @ RateLimitError::Exceeded { .. })) = (throttle, &result) {
+        if log_sampling::for_key(key) {
+            tracing::info!("Rate limit exceeded for IP: {}, throttling", ip);
+        }
+        result = state
+            .throttle
+            .wait(limiter, key, &throttle, error.clone())
+            .await;
+    }
+    match result {
+        Ok(decision) => remaining.rate_limit = Some(decision),
+        Err(RateLimitError::Exceeded { reason, decision }) => {
+            if log_sampling::for_key(key) {
+                tracing::warn!("Rate limit exceeded for IP: {}", ip);
+            }
+            return Err(Rejection { reason, decision }.into_response(headers, rejection));
+        }
+        Err(RateLimitError::Unavailable(error)) => {
+            metrics::record_store_error("rate_limit");
+            #[cfg(feature = "sentry")]
+            sentry::capture_error("store_failure", &error);
+            match *STORE_FAILURE_POLICY {
+                StoreFailurePolicy::Open => {
+                    tracing::error!("Rate limit store failed, allowing request: {}", error);
+                }
+                StoreFailurePolicy::Closed => {
+                    tracing::error!("Rate limit store failed, rejecting request: {}", error);
+                    return Err(
+                        (StatusCode::SERVICE_UNAVAILABLE, "Rate limiter unavailable.")
+                            .into_response(),
+                    );
+                }
+                StoreFailurePolicy::Local => {
+                    tracing::error!("Rate limit store failed, limiting locally: {}", error);
+                    let fallback = StoreRateLimiter::new(
+                        state.fallback_store.clone(),
+                        *RATE_LIMIT_ALGORITHM,
+                        config,
+                    );
+                    match fallback.check_rate_limit(key).await {
+                        Ok(decision) => remaining.rate_limit = Some(decision),
+                        Err(RateLimitError::Exceeded { reason, decision }) => {
+                            if log_sampling::for_key(key) {
+                                tracing::warn!("Rate limit exceeded for IP: {}", ip);
+                            }
+                            return Err(
+                                Rejection { reason, decision }.into_response(headers, rejection)
+                            );
+                        }
+                        Err(RateLimitError::Unavailable(_)) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    if let (Some(store), Some(quota)) = (&state.quota_store, &*QUOTA_CONFIG) {
+        match QuotaLimiter::new(store.clone(), quota)
+            .check_rate_limit(key)
+            .await
+        {
+            Ok(decision) => remaining.quota = Some(decision),
+            Err(RateLimitError::Exceeded { reason, decision }) => {
+                if log_sampling::for_key(key) {
+                    tracing::warn!("Quota exceeded for IP: {}", ip);
+                }
+                return Err(Rejection { reason, decision }.into_response(headers, rejection));
+            }
+            // Quotas have no local fallback, so `local` lets requests through
+            // like `open`.
+            Err(RateLimitError::Unavailable(error)) => {
+                metrics::record_store_error("quota");
+                #[cfg(feature = "sentry")]
+                sentry::capture_error("quota_store_failure", &error);
+                if *STORE_FAILURE_POLICY == StoreFailurePolicy::Closed {
+                    tracing::error!("Quota store failed, rejecting request: {}", error);
+                    return Err(
+                        (StatusCode::SERVICE_UNAVAILABLE, "Rate limiter unavailable.")
+                            .into_response(),
+                    );
+                }
+                tracing::error!("Quota store failed, allowing request: {}", error);
+            }
+        }
+    }
+
+    Ok(remaining)
+}
+
+/// Tells the client its limits, the requests it has left and the seconds
+/// until more are allowed, in the header fields `RATE_LIMIT_HEADERS` picks.
+///
+/// The `X-RateLimit` fields describe one limit only, the one with the fewest
+/// requests remaining, while `RateLimit` and `RateLimit-Policy` list the
+/// rate limit under the name of its rule and the quota as `quota`.
+fn insert_quota_headers(headers: &mut HeaderMap, remaining: &Remaining, rule: &str) {
+    let policies: Vec<(&str, &RateLimitDecision)> = [
+        (rule, remaining.rate_limit.as_ref()),
+        (QUOTA_POLICY_NAME, remaining.quota.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(name, quota)| Some((name, quota?)))
+    .collect();
+
+    if RATE_LIMIT_HEADERS.x_ratelimit()
+        && let Some((_, quota)) = policies.iter().min_by_key(|(_, quota)| quota.remaining)
+    {
+        for (name, value) in [
+            ("x-ratelimit-limit", quota.limit),
+            ("x-ratelimit-remaining", quota.remaining),
+            ("x-ratelimit-reset", seconds(quota.reset)),
+        ] {
+            headers.insert(name, HeaderValue::from(value));
+        }
+    }
+
+    if RATE_LIMIT_HEADERS.ietf() && !policies.is_empty() {
+        let join = |field: fn(&RateLimitDecision) -> String| {
+            policies
+                .iter()
+                .map(|(name, quota)| format!("{};{}", sf_string(name), field(quota)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let policy = join(|quota| format!("q={};w={}", quota.limit, seconds(quota.window)));
+        let status = join(|quota| format!("r={};t={}", quota.remaining, seconds(quota.reset)));
+        // Rule names outside visible ASCII cannot be sent as header values.
+        if let (Ok(policy), Ok(status)) = (
+            HeaderValue::from_str(&policy),
+            HeaderValue::from_str(&status),
+        ) {
+            headers.insert("ratelimit-policy", policy);
+            headers.insert("ratelimit", status);
+        }
+    }
+}
+
+/// Warns clients that used `WARNING_THRESHOLD` percent or more of a limit
+/// with the share they used, e.g. `X-RateLimit-Warning: 85%`, so they can
+/// slow down before being rejected.
+fn insert_warning_header(headers: &mut HeaderMap, remaining: &Remaining) {
+    let Some(threshold) = *WARNING_THRESHOLD else {
+        return;
+    };
+    let used = [&remaining.rate_limit, &remaining.quota]
+        .into_iter()
+        .flatten()
+        .map(|decision| {
+            decision.limit.saturating_sub(decision.remaining) * 100 / decision.limit.max(1)
+        })
+        .max();
+    if let Some(used) = used.filter(|used| *used >= threshold)
+        && let Ok(value) = HeaderValue::from_str(&format!("{}%", used))
+    {
+        headers.insert("x-ratelimit-warning", value);
+    }
+}
+
+/// Quotes `value` as a structured field string (RFC 8941).
+fn sf_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{API_KEY_HEADER, KeyExtractorKind};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    fn state() -> MiddlewareState {
+        let mut state = MiddlewareState::new(RateLimitStateEnum::MemoryStore(MemoryStore::new()));
+        state.key_extractors = Arc::new(KeyExtractorChain::from_config(&[
+            KeyExtractorKind::ApiKey,
+            KeyExtractorKind::Ip,
+        ]));
+        state
+    }
+
+    fn request(api_key: &str) -> Request<Body> {
+        let mut req = Request::builder()
+            .header(&*API_KEY_HEADER, api_key)
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(ConnectInfo(
+            "203.0.113.7:4000".parse::<SocketAddr>().unwrap(),
+        ));
+        req
+    }
+
+    async fn client(state: &MiddlewareState, limits: &Limits, api_key: &str) -> Option<Client> {
+        let req = request(api_key);
+        let identity = Identity::of(state, limits, &req, "203.0.113.7");
+        identify(state, limits, req.headers(), identity).await
+    }
+
+    #[tokio::test]
+    async fn unknown_api_keys_fall_back_to_the_rest_of_the_chain() {
+        let state = state();
+        let limits = (*limits()).clone();
+
+        for api_key in ["k-made-up", "k-made-up-too"] {
+            let client = client(&state, &limits, api_key).await.unwrap();
+            assert_eq!(client.key, "203.0.113.7");
         }
     }
+
+    #[tokio::test]
+    async fn api_keys_with_a_tier_or_override_are_known() {
+        let state = state();
+        let mut limits = (*limits()).clone();
+        let limit = Arc::new(RateLimitConfig {
+            max_requests: 5000,
+            window: Duration::from_secs(60),
+        });
+        limits.tiers.insert("pro".to_string(), limit.clone());
+        limits
+            .api_key_tiers
+            .insert("k-1234".to_string(), "pro".to_string());
+        limits.overrides.insert("api_key:k-5678".to_string(), limit);
+
+        let client_1234 = client(&state, &limits, "k-1234").await.unwrap();
+        assert_eq!(client_1234.key, "api_key:k-1234");
+        assert_eq!(client_1234.tier.as_deref(), Some("pro"));
+        let client_5678 = client(&state, &limits, "k-5678").await.unwrap();
+        assert_eq!(client_5678.key, "api_key:k-5678");
+        assert_eq!(client_5678.config.max_requests, 5000);
+    }
+
+    /// A service answering requests with their body, counting them.
+    fn echo(
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible, Future: Send>
+    + Clone
+    + Send
+    + 'static {
+        tower::service_fn(move |req: Request<Body>| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { Ok(Response::new(req.into_body())) }
+        })
+    }
+
+    fn layer_request(ip: &str, body: &str) -> Request<String> {
+        let mut req = Request::builder()
+            .uri("/layer")
+            .body(body.to_string())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(ip.parse().unwrap(), 4000)));
+        req
+    }
+
+    #[tokio::test]
+    async fn the_layer_rejects_requests_over_the_limit_before_the_service() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = RateLimitLayer::new(state()).layer(echo(calls.clone()));
+        let max_requests = limits().default.max_requests;
+
+        for sent in 1..=max_requests {
+            let response = service
+                .clone()
+                .oneshot(layer_request("192.0.2.61", "hello"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            if RATE_LIMIT_HEADERS.x_ratelimit() {
+                assert_eq!(
+                    response.headers()["x-ratelimit-remaining"],
+                    (max_requests - sent).to_string()
+                );
+            }
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, "hello");
+        }
+        let response = service
+            .clone()
+            .oneshot(layer_request("192.0.2.61", "hello"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(calls.load(Ordering::Relaxed), max_requests as usize);
+
+        // Other clients keep their own budget.
+        let response = service
+            .clone()
+            .oneshot(layer_request("192.0.2.62", "hello"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn the_layer_returns_errors_of_the_service() {
+        let failing = tower::service_fn(|_: Request<Body>| async {
+            Err::<Response<Body>, _>("upstream down")
+        });
+        let result = RateLimitLayer::new(state())
+            .layer(failing)
+            .oneshot(layer_request("192.0.2.63", ""))
+            .await;
+        assert_eq!(result.unwrap_err(), "upstream down");
+    }
 }
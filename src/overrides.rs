@@ -0,0 +1,131 @@
+//! Per-client limits kept in the backing store, so a client's limit can be
+//! raised or lowered with a single write instead of a config change, and
+//! temporary ones set through the admin API.
+//!
+//! The limit of key `api_key:k-1234` is read from the store key
+//! `{prefix}api_key:k-1234` as text like `1000/60`, the window in seconds or
+//! suffixed with `ms`.
+
+use dashmap::DashMap;
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use crate::config::{RateLimitConfig, StoreOverridesConfig};
+use crate::lookup_cache::LookupCache;
+use crate::middleware::RateLimitStateEnum;
+use crate::storage::RateLimitStore;
+
+/// Looks overrides up in the store the limiter keeps its state in, caching
+/// answers for a TTL, for a bounded number of client keys.
+pub struct StoreOverrides {
+    prefix: String,
+    cache: LookupCache<Arc<RateLimitConfig>>,
+}
+
+impl StoreOverrides {
+    pub fn new(config: &StoreOverridesConfig) -> Self {
+        Self {
+            prefix: config.prefix.clone(),
+            cache: LookupCache::new(
+                Duration::from_secs(config.cache_ttl_seconds),
+                config.cache_max_keys,
+            ),
+        }
+    }
+
+    /// Drops the lookup results older than the cache TTL, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        self.cache.evict_expired()
+    }
+
+    /// Returns the limit stored for `key`, if any.
+    pub async fn profile(
+        &self,
+        limiter: &RateLimitStateEnum,
+        key: &str,
+    ) -> Option<Arc<RateLimitConfig>> {
+        if let Some(profile) = self.cache.get(key) {
+            return profile;
+        }
+
+        // Failures are cached like answers so an unavailable store is not
+        // asked twice per request.
+        let stored_key = format!("{}{}", self.prefix, key);
+        let lookup = match limiter {
+            RateLimitStateEnum::RedisStore(store) => lookup(store, &stored_key).await,
+            RateLimitStateEnum::MemcachedStore(store) => lookup(store, &stored_key).await,
+            RateLimitStateEnum::DynamoDbStore(store) => lookup(store, &stored_key).await,
+            _ => Ok(None),
+        };
+        let profile = match lookup {
+            Ok(profile) => profile.map(Arc::new),
+            Err(e) => {
+                tracing::error!("Override lookup for {} failed: {}", key, e);
+                None
+            }
+        };
+        self.cache.insert(key, profile.clone());
+        profile
+    }
+}
+
+/// Limits set with `PATCH /admin/keys/{key}`, and when they expire. They are
+/// kept by this instance only and lost on restart.
+static TEMPORARY: LazyLock<DashMap<String, (Arc<RateLimitConfig>, Instant)>> =
+    LazyLock::new(DashMap::new);
+
+/// Holds `key` to `limit` for `ttl`, over any other limit it has.
+pub fn set_temporary(key: &str, limit: RateLimitConfig, ttl: Duration) {
+    TEMPORARY.insert(key.to_string(), (Arc::new(limit), Instant::now() + ttl));
+}
+
+/// Drops the temporary limit of `key`, returning whether it had one.
+pub fn remove_temporary(key: &str) -> bool {
+    TEMPORARY.remove(key).is_some()
+}
+
+/// The temporary limit of `key` and the time it has left, if it has one.
+pub fn temporary(key: &str) -> Option<(Arc<RateLimitConfig>, Duration)> {
+    let (limit, expires_at) = TEMPORARY.get(key).map(|entry| entry.value().clone())?;
+    let left = expires_at.checked_duration_since(Instant::now());
+    if left.is_none() {
+        TEMPORARY.remove_if(key, |_, (_, current)| *current == expires_at);
+    }
+    Some((limit, left?))
+}
+
+async fn lookup(store: &impl RateLimitStore, key: &str) -> Result<Option<RateLimitConfig>, String> {
+    let Some(value) = store.get(key).await? else {
+        return Ok(None);
+    };
+    let value = String::from_utf8_lossy(&value);
+    let limit = RateLimitConfig::parse(&value)
+        .ok_or_else(|| format!("{:?} is not a limit like 1000/60", value))?;
+    limit.check()?;
+    Ok(Some(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[tokio::test]
+    async fn cache_is_bounded_and_swept() {
+        let overrides = StoreOverrides::new(&StoreOverridesConfig {
+            prefix: "override:".to_string(),
+            cache_ttl_seconds: 0,
+            cache_max_keys: 10,
+        });
+        let limiter = RateLimitStateEnum::MemoryStore(MemoryStore::new());
+        for i in 0..100 {
+            let key = format!("198.51.100.{}", i);
+            assert!(overrides.profile(&limiter, &key).await.is_none());
+            assert!(overrides.cache.len() <= 10);
+        }
+        assert!(overrides.evict_expired() > 0);
+        assert_eq!(overrides.cache.len(), 0);
+    }
+}
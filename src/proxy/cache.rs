@@ -0,0 +1,513 @@
+//! Response caching: an upstream's responses to `GET` requests are kept for
+//! a while and served to clients asking for the same path and query without
+//! reaching the upstream. Requests missing together wait for the first of
+//! them to fill the cache rather than all going upstream.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header},
+};
+use dashmap::{DashMap, mapref::entry::Entry};
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+use std::time::Duration;
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
+use tokio::time::Instant;
+
+use super::websocket;
+use crate::config::{API_KEY_HEADER, CacheConfig};
+use crate::metrics;
+
+/// Header telling clients whether the response came from the cache.
+const X_CACHE: &str = "x-cache";
+
+/// The key `req` is cached under, if it may be answered from the cache:
+/// `GET` requests not asking to bypass caches, and without credentials or
+/// cookies, which could make the response the client's own. Responses may
+/// vary by encoding, so the accepted encodings are part of the key.
+pub fn key(req: &Request<Body>) -> Option<String> {
+    let headers = req.headers();
+    if req.method() != Method::GET
+        || headers.contains_key(header::AUTHORIZATION)
+        || headers.contains_key(header::COOKIE)
+        || headers.contains_key(&*API_KEY_HEADER)
+        || websocket::is_upgrade(headers)
+    {
+        return None;
+    }
+    let bypass = directives(headers).any(|(name, _)| name == "no-cache" || name == "no-store");
+    if bypass {
+        return None;
+    }
+    let uri = req.uri();
+    let target = uri.path_and_query().map_or(uri.path(), |pq| pq.as_str());
+    let encoding = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    Some(format!("{} {}", target, encoding))
+}
+
+/// The `Cache-Control` directives in `headers`, lowercased, with their
+/// values if any.
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+}
+
+struct Cached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    entries: DashMap<String, Cached>,
+    /// Size of the bodies kept.
+    bytes: AtomicUsize,
+    /// Keys a request is fetching from the upstream, locked until it is done.
+    filling: Arc<DashMap<String, Arc<RwLock<()>>>>,
+}
+
+/// What the cache has for a request.
+pub enum Lookup {
+    Hit(Response<Body>),
+    /// Nothing, the request being the one to fetch it for the others.
+    Fill(Fill),
+    /// Nothing, even after waiting for another request to fetch it.
+    Miss,
+}
+
+/// A request fetching a key, which others wait for until dropped.
+pub struct Fill {
+    filling: Arc<DashMap<String, Arc<RwLock<()>>>>,
+    key: String,
+    _lock: OwnedRwLockWriteGuard<()>,
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        self.filling.remove(&self.key);
+    }
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            config: config.clone(),
+            entries: DashMap::new(),
+            bytes: AtomicUsize::new(0),
+            filling: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// The response cached under `key`, waiting for it when another request
+    /// is fetching it already.
+    pub async fn lookup(&self, key: &str) -> Lookup {
+        if let Some(response) = self.get(key) {
+            return Lookup::Hit(response);
+        }
+        let lock = match self.filling.entry(key.to_string()) {
+            Entry::Vacant(vacant) => {
+                let lock = Arc::new(RwLock::new(()));
+                let guard = lock.clone().try_write_owned().expect("new locks are free");
+                vacant.insert(lock);
+                metrics::record_cache_lookup("miss");
+                return Lookup::Fill(Fill {
+                    filling: self.filling.clone(),
+                    key: key.to_string(),
+                    _lock: guard,
+                });
+            }
+            Entry::Occupied(occupied) => occupied.get().clone(),
+        };
+        drop(lock.read().await);
+        match self.get(key) {
+            Some(response) => Lookup::Hit(response),
+            None => {
+                metrics::record_cache_lookup("miss");
+                Lookup::Miss
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Response<Body>> {
+        let now = Instant::now();
+        let response = {
+            let cached = self.entries.get(key)?;
+            (cached.expires > now).then(|| {
+                let mut response = Response::new(Body::from(cached.body.clone()));
+                *response.status_mut() = cached.status;
+                *response.headers_mut() = cached.headers.clone();
+                let age = (now - cached.stored).as_secs();
+                response.headers_mut().insert(header::AGE, age.into());
+                response
+                    .headers_mut()
+                    .insert(X_CACHE, HeaderValue::from_static("HIT"));
+                response
+            })
+        };
+        if response.is_none() {
+            self.remove_expired(key, now);
+        } else {
+            metrics::record_cache_lookup("hit");
+        }
+        response
+    }
+
+    /// How long a response may be kept: the TTL, or less if the upstream's
+    /// `Cache-Control` says so. Responses other than `200 OK`, setting
+    /// cookies, varying by more than their encoding, too large or of unknown
+    /// length are not kept.
+    pub fn ttl(&self, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        if status != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let length: usize = headers
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()?;
+        if length > self.config.max_entry_bytes {
+            return None;
+        }
+        let varies = headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|name| !name.trim().eq_ignore_ascii_case("accept-encoding"));
+        if varies {
+            return None;
+        }
+        let mut ttl = self.config.ttl();
+        for (name, value) in directives(headers) {
+            match name.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" | "s-maxage" => {
+                    if let Some(seconds) = value.and_then(|v| v.parse().ok()) {
+                        ttl = ttl.min(Duration::from_secs(seconds));
+                    }
+                }
+                _ => {}
+            }
+        }
+        (!ttl.is_zero()).then_some(ttl)
+    }
+
+    /// Keeps a response for `ttl`, marked as not coming from the cache in
+    /// the copy returned.
+    pub fn store(
+        &self,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        ttl: Duration,
+    ) -> Response<Body> {
+        let now = Instant::now();
+        self.make_room(body.len(), now);
+        self.bytes.fetch_add(body.len(), Ordering::Relaxed);
+        let cached = Cached {
+            status,
+            headers: headers.clone(),
+            body: body.clone(),
+            stored: now,
+            expires: now + ttl,
+        };
+        if let Some(old) = self.entries.insert(key, cached) {
+            self.bytes.fetch_sub(old.body.len(), Ordering::Relaxed);
+        }
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        mark_miss(response.headers_mut());
+        response
+    }
+
+    /// Evicts entries until `bytes` more fit in `max_bytes`: the expired ones,
+    /// then those expiring soonest.
+    fn make_room(&self, bytes: usize, now: Instant) {
+        let fits =
+            |cache: &Self| cache.bytes.load(Ordering::Relaxed) + bytes <= cache.config.max_bytes;
+        if fits(self) {
+            return;
+        }
+        self.entries.retain(|_, cached| {
+            let keep = cached.expires > now;
+            if !keep {
+                self.bytes.fetch_sub(cached.body.len(), Ordering::Relaxed);
+            }
+            keep
+        });
+        while !fits(self) {
+            let soonest = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.expires)
+                .map(|entry| entry.key().clone());
+            let Some(key) = soonest else {
+                return;
+            };
+            if let Some((_, cached)) = self.entries.remove(&key) {
+                self.bytes.fetch_sub(cached.body.len(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn remove_expired(&self, key: &str, now: Instant) {
+        if let Some((_, cached)) = self
+            .entries
+            .remove_if(key, |_, cached| cached.expires <= now)
+        {
+            self.bytes.fetch_sub(cached.body.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Marks a response to a request the cache could have answered as coming
+/// from the upstream.
+pub fn mark_miss(headers: &mut HeaderMap) {
+    headers.insert(X_CACHE, HeaderValue::from_static("MISS"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_bytes: usize) -> Cache {
+        Cache::new(&CacheConfig {
+            ttl_seconds: 60,
+            max_bytes,
+            max_entry_bytes: 100,
+        })
+    }
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri("/items?page=2");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn response_headers(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        map
+    }
+
+    /// Stores a 4 byte response under `key`.
+    fn store(cache: &Cache, key: &str, ttl_ms: u64) {
+        cache.store(
+            key.to_string(),
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from_static(b"1234"),
+            Duration::from_millis(ttl_ms),
+        );
+    }
+
+    async fn body(response: Response<Body>) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn key_is_the_target_and_accepted_encodings() {
+        assert_eq!(
+            key(&request(Method::GET, &[])).as_deref(),
+            Some("/items?page=2 ")
+        );
+        assert_eq!(
+            key(&request(Method::GET, &[("accept-encoding", "gzip")])).as_deref(),
+            Some("/items?page=2 gzip")
+        );
+    }
+
+    #[test]
+    fn requests_with_credentials_or_asking_to_bypass_are_not_cached() {
+        let api_key = API_KEY_HEADER.as_str();
+        for headers in [
+            &[("authorization", "Bearer token")][..],
+            &[("cookie", "session=1")],
+            &[(api_key, "k-1234")],
+            &[("cache-control", "no-cache")],
+            &[("cache-control", "max-age=0, No-Store")],
+            &[("connection", "upgrade"), ("upgrade", "websocket")],
+        ] {
+            assert_eq!(key(&request(Method::GET, headers)), None, "{:?}", headers);
+        }
+        assert_eq!(key(&request(Method::POST, &[])), None);
+        assert_eq!(key(&request(Method::HEAD, &[])), None);
+    }
+
+    #[test]
+    fn ttl_follows_the_upstream() {
+        let cache = cache(1000);
+        let ttl = |status, headers: &[(&str, &str)]| {
+            let mut headers = response_headers(headers);
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("10"));
+            cache.ttl(status, &headers)
+        };
+
+        assert_eq!(ttl(StatusCode::OK, &[]), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ttl(StatusCode::OK, &[("cache-control", "public, max-age=5")]),
+            Some(Duration::from_secs(5))
+        );
+        // The upstream can shorten the TTL, never lengthen it.
+        assert_eq!(
+            ttl(StatusCode::OK, &[("cache-control", "s-maxage=600")]),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(ttl(StatusCode::OK, &[("cache-control", "max-age=0")]), None);
+        assert_eq!(ttl(StatusCode::OK, &[("cache-control", "private")]), None);
+        assert_eq!(ttl(StatusCode::OK, &[("cache-control", "no-store")]), None);
+        assert_eq!(ttl(StatusCode::NOT_FOUND, &[]), None);
+        assert_eq!(ttl(StatusCode::OK, &[("set-cookie", "session=1")]), None);
+
+        assert_eq!(
+            ttl(StatusCode::OK, &[("vary", "Accept-Encoding")]),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            ttl(StatusCode::OK, &[("vary", "accept-encoding, Cookie")]),
+            None
+        );
+        assert_eq!(ttl(StatusCode::OK, &[("vary", "*")]), None);
+    }
+
+    #[test]
+    fn responses_of_unknown_or_excessive_length_are_not_cached() {
+        let cache = cache(1000);
+        assert_eq!(cache.ttl(StatusCode::OK, &HeaderMap::new()), None);
+        let length = |length: &str| response_headers(&[("content-length", length)]);
+        assert_eq!(cache.ttl(StatusCode::OK, &length("101")), None);
+        assert_eq!(cache.ttl(StatusCode::OK, &length("chunked")), None);
+        assert!(cache.ttl(StatusCode::OK, &length("100")).is_some());
+    }
+
+    #[tokio::test]
+    async fn stored_responses_are_served_until_they_expire() {
+        let cache = cache(1000);
+        let response = cache.store(
+            "k".to_string(),
+            StatusCode::OK,
+            response_headers(&[("content-type", "text/plain")]),
+            Bytes::from_static(b"hello"),
+            Duration::from_millis(50),
+        );
+        assert_eq!(response.headers()[X_CACHE], "MISS");
+
+        let Lookup::Hit(hit) = cache.lookup("k").await else {
+            panic!("a stored response was not served");
+        };
+        assert_eq!(hit.headers()[X_CACHE], "HIT");
+        assert_eq!(hit.headers()["content-type"], "text/plain");
+        assert_eq!(hit.headers()[header::AGE], "0");
+        assert_eq!(body(hit).await, "hello");
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(cache.lookup("k").await, Lookup::Fill(_)));
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn room_is_made_from_the_soonest_expiring_entries() {
+        let cache = cache(10);
+        store(&cache, "late", 90_000);
+        store(&cache, "soon", 30_000);
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), 8);
+
+        store(&cache, "new", 60_000);
+        assert!(cache.entries.contains_key("late") && cache.entries.contains_key("new"));
+        assert!(!cache.entries.contains_key("soon"));
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), 8);
+
+        // Replacing an entry counts its size once.
+        store(&cache, "new", 60_000);
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn expired_entries_are_evicted_first() {
+        let cache = cache(10);
+        store(&cache, "brief", 1);
+        store(&cache, "late", 90_000);
+        std::thread::sleep(Duration::from_millis(5));
+        store(&cache, "next", 30_000);
+        assert!(!cache.entries.contains_key("brief"));
+        assert!(cache.entries.contains_key("late") && cache.entries.contains_key("next"));
+        assert_eq!(cache.bytes.load(Ordering::Relaxed), 8);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_wait_for_the_first_to_fill() {
+        let cache = Arc::new(cache(1000));
+        let Lookup::Fill(fill) = cache.lookup("k").await else {
+            panic!("the first miss was not told to fill");
+        };
+        let waiting = tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                match cache.lookup("k").await {
+                    Lookup::Hit(response) => Some(body(response).await),
+                    _ => None,
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        cache.store(
+            "k".to_string(),
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from_static(b"filled"),
+            Duration::from_secs(60),
+        );
+        drop(fill);
+        assert_eq!(waiting.await.unwrap().as_deref(), Some(&b"filled"[..]));
+        assert!(cache.filling.is_empty());
+    }
+
+    #[tokio::test]
+    async fn waiters_miss_when_the_fill_fails() {
+        let cache = Arc::new(cache(1000));
+        let Lookup::Fill(fill) = cache.lookup("k").await else {
+            panic!("the first miss was not told to fill");
+        };
+        let waiting = tokio::spawn({
+            let cache = cache.clone();
+            async move { matches!(cache.lookup("k").await, Lookup::Miss) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(fill);
+        assert!(waiting.await.unwrap());
+        // The next request fetches it again.
+        assert!(matches!(cache.lookup("k").await, Lookup::Fill(_)));
+    }
+}
@@ -0,0 +1,362 @@
+//! Proxy mode: requests the rate limiter admits are forwarded to the
+//! upstream of the route rule they match, or the `UPSTREAM_URL` service,
+//! bodies streamed both ways, turning the server into a gateway in front of
+//! one or several services.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, header},
+    response::IntoResponse,
+};
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use reqwest::Url;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{Instrument, field};
+
+use crate::config::{TLS_CONFIG, UPSTREAM, UpstreamPool, limits};
+use crate::grpc;
+use crate::rejection::seconds;
+use crate::telemetry;
+
+mod cache;
+mod pool;
+mod websocket;
+
+use self::cache::Lookup;
+use self::pool::Pool;
+use self::websocket::Connections;
+
+/// Headers describing a single connection rather than the request, which
+/// proxies must not forward (RFC 9110, section 7.6.1).
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Whether the server runs as a proxy, decided at startup: with an
+/// `UPSTREAM_URL` or route rules naming upstreams.
+pub fn enabled() -> bool {
+    !configured().is_empty()
+}
+
+/// The upstreams of the limits in force, by the key of their pool.
+fn configured() -> Vec<(String, UpstreamPool)> {
+    let limits = limits();
+    let rules = limits
+        .routes
+        .iter()
+        .filter_map(|rule| rule.upstream.as_ref());
+    rules
+        .map(|upstream| upstream.pool())
+        .chain(UPSTREAM.clone())
+        .map(|pool| (pool_key(&pool), pool))
+        .collect()
+}
+
+/// Identifies pools by their settings, so a reload keeping an upstream keeps
+/// its health and balancing state too.
+fn pool_key(pool: &UpstreamPool) -> String {
+    serde_json::to_string(pool).expect("upstream settings serialize")
+}
+
+pub struct Proxy {
+    client: reqwest::Client,
+    /// Client of gRPC calls, which speaks HTTP/2 to upstreams without
+    /// negotiating it, cleartext ones included.
+    grpc_client: reqwest::Client,
+    pools: DashMap<String, Arc<Pool>>,
+    connections: Connections,
+}
+
+impl Proxy {
+    /// A proxy to the configured upstreams, health checking their targets in
+    /// the background.
+    pub fn spawn() -> Arc<Self> {
+        let proxy = Arc::new(Self {
+            client: client(reqwest::Client::builder()),
+            grpc_client: client(reqwest::Client::builder().http2_prior_knowledge()),
+            pools: DashMap::new(),
+            connections: Connections::default(),
+        });
+        for (_, pool) in configured() {
+            proxy.pool(&pool);
+        }
+        proxy
+    }
+
+    /// The pool of `config`, created and health checked from its first use,
+    /// e.g. after a reload added it.
+    fn pool(self: &Arc<Self>, config: &UpstreamPool) -> Arc<Pool> {
+        let key = pool_key(config);
+        if let Some(pool) = self.pools.get(&key) {
+            return pool.clone();
+        }
+        self.pools
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let pool = Arc::new(Pool::new(config));
+                if pool.is_checked() {
+                    tokio::spawn(self.clone().watch(key, pool.clone(), config.clone()));
+                }
+                pool
+            })
+            .clone()
+    }
+
+    /// Health checks the targets of `pool` until a reload removes it.
+    async fn watch(self: Arc<Self>, key: String, pool: Arc<Pool>, config: UpstreamPool) {
+        let mut interval = tokio::time::interval(config.health_check.interval());
+        loop {
+            interval.tick().await;
+            if !configured()
+                .iter()
+                .any(|(configured, _)| *configured == key)
+            {
+                self.pools.remove(&key);
+                return;
+            }
+            pool.check(&self.client, &config.health_check).await;
+        }
+    }
+}
+
+fn client(builder: reqwest::ClientBuilder) -> reqwest::Client {
+    builder
+        // Redirects are the client's to follow, not the gateway's.
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("failed to build the upstream HTTP client")
+}
+
+/// The upstream `req` goes to: that of the first route rule matching its
+/// path, like the rule it was limited by, or `UPSTREAM_URL`.
+fn upstream(req: &Request<Body>) -> Option<UpstreamPool> {
+    let limits = limits();
+    let rule = limits
+        .routes
+        .iter()
+        .find(|rule| rule.matches(req.uri().path()));
+    match rule.and_then(|rule| rule.upstream.as_ref()) {
+        Some(upstream) => Some(upstream.pool()),
+        None => UPSTREAM.clone(),
+    }
+}
+
+/// Where `req` goes at `upstream`: its path appended to the upstream's, with
+/// its query.
+fn target(upstream: Url, req: &Request<Body>) -> Url {
+    let mut url = upstream;
+    let path = format!("{}{}", url.path().trim_end_matches('/'), req.uri().path());
+    url.set_path(&path);
+    url.set_query(req.uri().query());
+    url
+}
+
+/// Forwards the request to a healthy target of its upstream once the
+/// upstream's outbound limit allows, and streams back the response, or
+/// relays the WebSocket connection it upgrades to. gRPC calls go over HTTP/2,
+/// their trailers streamed back too. A `Retry-After` on a
+/// `429` or `503` response holds back the upstream's next requests. Answers
+/// `502 Bad Gateway` when the target cannot be reached, `503 Service
+/// Unavailable` when no target is healthy or the outbound limit or a backoff
+/// was waited for in vain, and `404 Not Found` for paths without an
+/// upstream.
+pub async fn forward(State(proxy): State<Arc<Proxy>>, req: Request<Body>) -> Response<Body> {
+    let Some(upstream) = upstream(&req) else {
+        return (StatusCode::NOT_FOUND, "No upstream for this path.").into_response();
+    };
+    let pool = proxy.pool(&upstream);
+    let retry_after = upstream.retry_after;
+    // Held until the response is cached, so requests for the same key wait
+    // for it instead of going upstream too.
+    let mut _fill = None;
+    let cache_key = pool.cache().and_then(|_| cache::key(&req));
+    if let Some(cache) = pool.cache()
+        && let Some(key) = &cache_key
+    {
+        match cache.lookup(key).await {
+            Lookup::Hit(response) => return response,
+            Lookup::Fill(fill) => _fill = Some(fill),
+            Lookup::Miss => {}
+        }
+    }
+    if let Err(reset) = pool.admit().await {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, seconds(reset).to_string())],
+            "Upstream busy.",
+        )
+            .into_response();
+    }
+    let Some(in_flight) = pool.pick() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No healthy upstream.").into_response();
+    };
+    let url = target(in_flight.target().url.clone(), &req);
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    let (parts, body) = req.into_parts();
+    if websocket::is_upgrade(&parts.headers) {
+        return websocket::forward(&proxy.connections, parts, url, peer, pool, in_flight).await;
+    }
+
+    let grpc = grpc::is_grpc(&parts.headers);
+    let mut headers = forwarded_headers(parts.headers, peer);
+    let client = if grpc {
+        // Upstreams only answer gRPC calls declaring they read trailers.
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        &proxy.grpc_client
+    } else {
+        &proxy.client
+    };
+    let span = upstream_span(&url);
+    telemetry::inject(&span, &mut headers);
+    let upstream = client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        .send()
+        .instrument(span.clone())
+        .await;
+    if let Ok(response) = &upstream {
+        span.record("status", response.status().as_u16());
+    }
+    let upstream = match upstream {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::error!("Upstream request failed: {}", error);
+            if error.is_connect() {
+                pool.report_failure(in_flight.target());
+            }
+            return (StatusCode::BAD_GATEWAY, "Bad gateway.").into_response();
+        }
+    };
+
+    let status = upstream.status();
+    let mut headers = upstream.headers().clone();
+    strip_hop_by_hop(&mut headers);
+    let delay = headers
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    if let Some(delay) = delay
+        && retry_after.max_seconds > 0
+        && matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+    {
+        let left = pool.back_off(delay);
+        if retry_after.propagate {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds(left)));
+        }
+    }
+
+    if let Some(cache) = pool.cache()
+        && let Some(key) = cache_key
+    {
+        if let Some(ttl) = cache.ttl(status, &headers) {
+            return match upstream.bytes().await {
+                Ok(body) => cache.store(key, status, headers, body, ttl),
+                Err(error) => {
+                    tracing::error!("Upstream response failed: {}", error);
+                    (StatusCode::BAD_GATEWAY, "Bad gateway.").into_response()
+                }
+            };
+        }
+        cache::mark_miss(&mut headers);
+    }
+    // The request stays in flight until its response body ends.
+    let body = reqwest::Body::from(upstream).map_frame(move |frame| {
+        let _ = &in_flight;
+        frame
+    });
+    let mut response = Response::new(Body::new(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+/// The span of a request to `url`, lasting until its response starts.
+fn upstream_span(url: &Url) -> tracing::Span {
+    tracing::info_span!(
+        "upstream",
+        url = %url,
+        status = field::Empty,
+        otel.kind = "client",
+    )
+}
+
+/// The delay a `Retry-After` value asks for, given in seconds or as an HTTP
+/// date (RFC 9110, section 10.2.3). Dates in the past ask for none.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// The headers of a request from `peer` to pass upstream: those of the
+/// request, less the ones of its connection, and `X-Forwarded-*`.
+fn forwarded_headers(mut headers: HeaderMap, peer: Option<IpAddr>) -> HeaderMap {
+    let host = headers.remove(header::HOST);
+    strip_hop_by_hop(&mut headers);
+    if let Some(peer) = peer {
+        let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+            Some(hops) => format!("{}, {}", hops, peer),
+            None => peer.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+    }
+    if let Some(host) = host {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+    let proto = if TLS_CONFIG.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    headers
+}
+
+/// Removes the hop-by-hop headers, including those the `Connection` header
+/// names.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
@@ -0,0 +1,242 @@
+//! Upstream pools: the targets serving one service, requests being balanced
+//! over those passing their health checks, and held to the service's
+//! outbound limit and to the backoffs it asks for.
+
+use reqwest::Url;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
+
+use super::cache::Cache;
+use crate::config::{Balance, HealthCheck, RateLimitAlgorithm, ThrottleConfig, UpstreamPool};
+use crate::metrics;
+use crate::rate_limiter::{RateLimitError, RateLimiter, StoreRateLimiter};
+use crate::storage::MemoryStore;
+use crate::throttle::Throttle;
+
+/// Longest a target may take to answer its health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The one key outbound limiters count, all requests to a pool sharing it.
+const OUTBOUND_KEY: &str = "outbound";
+
+pub struct Target {
+    pub url: Url,
+    healthy: AtomicBool,
+    /// Requests forwarded to the target whose response has not ended yet.
+    active: AtomicUsize,
+}
+
+impl Target {
+    /// Puts the target in or out of rotation, logging changes.
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                tracing::info!("Upstream target {} is healthy again", self.url);
+            } else {
+                tracing::warn!("Upstream target {} is unhealthy", self.url);
+            }
+        }
+    }
+}
+
+/// A request in flight to a target, counted until dropped.
+pub struct InFlight(Arc<Target>);
+
+impl InFlight {
+    fn new(target: Arc<Target>) -> Self {
+        target.active.fetch_add(1, Ordering::Relaxed);
+        Self(target)
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.0
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Pool {
+    targets: Vec<Arc<Target>>,
+    balance: Balance,
+    /// Where the next pick starts, rotating between equally good targets.
+    next: AtomicUsize,
+    outbound: Option<Outbound>,
+    /// Until when requests are held back, after the upstream answered with a
+    /// `Retry-After`.
+    backoff: Mutex<Option<Instant>>,
+    max_backoff: Duration,
+    /// Longest requests wait for the outbound limit or a backoff to end.
+    max_wait: Duration,
+    cache: Option<Cache>,
+}
+
+/// The outbound limit of a pool, whatever client a request comes from, with
+/// the requests waiting for it.
+struct Outbound {
+    limiter: StoreRateLimiter<MemoryStore>,
+    throttle: Throttle,
+    queue: ThrottleConfig,
+}
+
+impl Pool {
+    /// A pool of the targets of `config`, which must have been validated.
+    pub fn new(config: &UpstreamPool) -> Self {
+        Self {
+            targets: config
+                .targets
+                .iter()
+                .map(|url| {
+                    Arc::new(Target {
+                        url: Url::parse(url).expect("upstream URLs are validated when loaded"),
+                        healthy: AtomicBool::new(true),
+                        active: AtomicUsize::new(0),
+                    })
+                })
+                .collect(),
+            balance: config.balance,
+            next: AtomicUsize::new(0),
+            outbound: config.outbound_limit.as_ref().map(|limit| Outbound {
+                // Checked and counted at once, so concurrent requests cannot
+                // slip past the limit together.
+                limiter: StoreRateLimiter::new(
+                    MemoryStore::new(),
+                    RateLimitAlgorithm::SlidingWindow,
+                    limit.clone(),
+                ),
+                throttle: Throttle::new(metrics::record_outbound_throttled),
+                queue: config.outbound_queue(),
+            }),
+            backoff: Mutex::new(None),
+            max_backoff: config.retry_after.max(),
+            max_wait: config.outbound_queue().max_wait(),
+            cache: config.cache.as_ref().map(Cache::new),
+        }
+    }
+
+    /// The cache of the upstream's responses, if it has one.
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    /// Waits until the backoff, if any, is over and the outbound limit lets
+    /// another request through, or returns how long until it would once the
+    /// wait is given up. Backoffs are not waited for if they end later than
+    /// requests may wait.
+    pub async fn admit(&self) -> Result<(), Duration> {
+        let backoff = *self.backoff.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(until) = backoff {
+            let left = until.saturating_duration_since(Instant::now());
+            if left > self.max_wait {
+                return Err(left);
+            }
+            sleep_until(until).await;
+        }
+        let Some(outbound) = &self.outbound else {
+            return Ok(());
+        };
+        let decision = match outbound.limiter.check_rate_limit(OUTBOUND_KEY).await {
+            Ok(decision) => Ok(decision),
+            Err(error) => {
+                let limiter = &outbound.limiter;
+                let queue = &outbound.queue;
+                outbound
+                    .throttle
+                    .wait(limiter, OUTBOUND_KEY, queue, error)
+                    .await
+            }
+        };
+        match decision {
+            Ok(_) => {
+                outbound.limiter.record_request(OUTBOUND_KEY).await;
+                Ok(())
+            }
+            Err(RateLimitError::Exceeded { decision, .. }) => Err(decision.reset),
+            // In-memory limiters cannot fail.
+            Err(RateLimitError::Unavailable(_)) => Ok(()),
+        }
+    }
+
+    /// Holds requests back for `delay`, up to the configured maximum, as the
+    /// upstream asked; backoffs already in place that end later are kept.
+    /// Returns how long is left of the backoff.
+    pub fn back_off(&self, delay: Duration) -> Duration {
+        let now = Instant::now();
+        let until = now + delay.min(self.max_backoff);
+        let mut backoff = self.backoff.lock().unwrap_or_else(|e| e.into_inner());
+        match *backoff {
+            Some(current) if current >= until => current - now,
+            _ => {
+                tracing::warn!(
+                    "Upstream asked to back off, holding requests for {:?}",
+                    until - now
+                );
+                metrics::record_upstream_backoff();
+                *backoff = Some(until);
+                until - now
+            }
+        }
+    }
+
+    /// Whether targets are health checked, which only pools with others to
+    /// fall back on are.
+    pub fn is_checked(&self) -> bool {
+        self.targets.len() > 1
+    }
+
+    /// The healthy target the next request goes to, none if all failed.
+    pub fn pick(&self) -> Option<InFlight> {
+        let healthy: Vec<&Arc<Target>> = self
+            .targets
+            .iter()
+            .filter(|target| target.healthy.load(Ordering::Relaxed))
+            .collect();
+        if healthy.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let target = match self.balance {
+            Balance::RoundRobin => healthy[start % healthy.len()],
+            Balance::LeastConnections => (0..healthy.len())
+                .map(|i| healthy[(start + i) % healthy.len()])
+                .min_by_key(|target| target.active.load(Ordering::Relaxed))
+                .expect("healthy targets are not empty"),
+        };
+        Some(InFlight::new(target.clone()))
+    }
+
+    /// Takes `target` out of rotation after a failed request, until its
+    /// next health check passes.
+    pub fn report_failure(&self, target: &Target) {
+        if self.is_checked() {
+            target.set_healthy(false);
+        }
+    }
+
+    /// Checks every target at once, updating which are in rotation.
+    pub async fn check(&self, client: &reqwest::Client, health_check: &HealthCheck) {
+        let mut checks = JoinSet::new();
+        for target in &self.targets {
+            let mut url = target.url.clone();
+            url.set_path(&health_check.path);
+            url.set_query(None);
+            let request = client.get(url).timeout(HEALTH_CHECK_TIMEOUT).send();
+            let target = target.clone();
+            checks.spawn(async move {
+                let healthy = request
+                    .await
+                    .is_ok_and(|response| response.status().is_success());
+                target.set_healthy(healthy);
+            });
+        }
+        while checks.join_next().await.is_some() {}
+    }
+}
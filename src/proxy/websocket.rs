@@ -0,0 +1,470 @@
+//! WebSocket proxying: upgrade requests are forwarded like others, counting
+//! once against the client's rate limit, and the connections they open are
+//! limited in how many one key holds open and how fast each sends messages.
+
+use axum::{
+    body::Body,
+    extract::{
+        FromRequestParts, WebSocketUpgrade,
+        ws::{self, WebSocket},
+    },
+    http::{HeaderMap, Response, StatusCode, header, request::Parts},
+    response::IntoResponse,
+};
+use dashmap::DashMap;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use std::{net::IpAddr, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+};
+use tracing::Instrument;
+
+use super::pool::{InFlight, Pool};
+use crate::config::{
+    MessagePolicy, RATE_LIMIT_MODE, RateLimitAlgorithm, RateLimitMode, WEBSOCKET_CONFIG,
+    WebSocketConfig,
+};
+use crate::metrics;
+use crate::middleware::ClientKey;
+use crate::rate_limiter::{RateLimiter, StoreRateLimiter};
+use crate::storage::MemoryStore;
+use crate::telemetry;
+
+/// Headers of the client's handshake the upstream handshake makes its own.
+const HANDSHAKE_HEADERS: &[&str] = &[
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-accept",
+];
+
+/// The key a connection's messages are counted under in its own limiter.
+const MESSAGES_KEY: &str = "messages";
+
+/// Close code telling the client it broke the message limit (RFC 6455,
+/// section 7.4.1).
+const POLICY_VIOLATION: u16 = 1008;
+
+type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Whether `headers` ask to upgrade the connection to a WebSocket.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::UPGRADE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// WebSocket connections open per key.
+#[derive(Clone, Default)]
+pub struct Connections(Arc<DashMap<String, usize>>);
+
+impl Connections {
+    /// Counts a connection of `key` if fewer than `max` are open.
+    fn open(&self, key: &str, max: usize) -> Option<Connection> {
+        let mut open = self.0.entry(key.to_string()).or_insert(0);
+        if *open >= max {
+            return None;
+        }
+        *open += 1;
+        Some(Connection {
+            connections: self.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// An open connection, counted until dropped.
+struct Connection {
+    connections: Connections,
+    key: String,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(mut open) = self.connections.0.get_mut(&self.key) {
+            *open -= 1;
+        }
+        self.connections
+            .0
+            .remove_if(&self.key, |_, open| *open == 0);
+    }
+}
+
+/// Opens a WebSocket to `url` for the upgrade request in `parts` and relays
+/// messages both ways once the client's connection is upgraded. Answers `429
+/// Too Many Requests` when the client's key has `max_connections` open, the
+/// upstream's response when it refuses the upgrade, and `502 Bad Gateway`
+/// when it cannot be reached.
+pub async fn forward(
+    connections: &Connections,
+    mut parts: Parts,
+    url: Url,
+    peer: Option<IpAddr>,
+    pool: Arc<Pool>,
+    in_flight: InFlight,
+) -> Response<Body> {
+    let upgrade = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(upgrade) => upgrade,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let config = &*WEBSOCKET_CONFIG;
+    let key = parts.extensions.get::<ClientKey>();
+    let connection = match (key, config.max_connections) {
+        (Some(ClientKey(key)), Some(max)) => match connections.open(key, max) {
+            Some(connection) => Some(connection),
+            None if *RATE_LIMIT_MODE == RateLimitMode::Shadow => {
+                tracing::warn!(
+                    "Shadow mode, letting through WebSocket connection over {} open for key: {}",
+                    max,
+                    key
+                );
+                metrics::record_shadow_rejection();
+                None
+            }
+            None => {
+                tracing::warn!(
+                    "Refused WebSocket connection over {} open for key: {}",
+                    max,
+                    key
+                );
+                metrics::record_websocket_limited("refused");
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Too many WebSocket connections.",
+                )
+                    .into_response();
+            }
+        },
+        _ => None,
+    };
+
+    let mut headers = super::forwarded_headers(parts.headers, peer);
+    for name in HANDSHAKE_HEADERS {
+        headers.remove(*name);
+    }
+    let mut url = url;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    let _ = url.set_scheme(scheme);
+    let mut request = match url.as_str().into_client_request() {
+        Ok(request) => request,
+        Err(error) => {
+            tracing::error!("Invalid upstream WebSocket request: {}", error);
+            return (StatusCode::BAD_GATEWAY, "Bad gateway.").into_response();
+        }
+    };
+    request.headers_mut().extend(headers);
+    let span = super::upstream_span(&url);
+    telemetry::inject(&span, request.headers_mut());
+
+    let connected = tokio_tungstenite::connect_async(request)
+        .instrument(span.clone())
+        .await;
+    if let Ok((_, handshake)) = &connected {
+        span.record("status", handshake.status().as_u16());
+    }
+    let (upstream, handshake) = match connected {
+        Ok(connected) => connected,
+        // Refusals, like a 401 or 404, are the upstream's answer to pass on.
+        Err(tungstenite::Error::Http(response)) => {
+            let (parts, body) = response.into_parts();
+            return Response::from_parts(parts, Body::from(body.unwrap_or_default()));
+        }
+        Err(error) => {
+            tracing::error!("Upstream WebSocket connection failed: {}", error);
+            if matches!(error, tungstenite::Error::Io(_)) {
+                pool.report_failure(in_flight.target());
+            }
+            return (StatusCode::BAD_GATEWAY, "Bad gateway.").into_response();
+        }
+    };
+    // The client gets the subprotocol the upstream chose, if any.
+    let protocol = handshake
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    upgrade
+        .protocols(protocol)
+        .on_upgrade(move |client| async move {
+            // Both stay counted for as long as the connection is open.
+            let _held = (connection, in_flight);
+            relay(client, upstream, config).await;
+        })
+}
+
+/// Messages of one connection, held to the message limit.
+struct Messages {
+    limiter: Option<StoreRateLimiter<MemoryStore>>,
+    policy: MessagePolicy,
+    max_violations: Option<u64>,
+    violations: u64,
+}
+
+/// What becomes of a message.
+enum Verdict {
+    Forward,
+    Drop,
+    Close,
+}
+
+impl Messages {
+    fn new(config: &WebSocketConfig) -> Self {
+        Self {
+            limiter: config.message_limit.as_ref().map(|limit| {
+                StoreRateLimiter::new(
+                    MemoryStore::new(),
+                    RateLimitAlgorithm::SlidingWindow,
+                    limit.clone(),
+                )
+            }),
+            policy: config.message_policy,
+            max_violations: config.max_violations,
+            violations: 0,
+        }
+    }
+
+    /// Counts a data message from the client against the limit.
+    async fn admit(&mut self) -> Verdict {
+        let Some(limiter) = &self.limiter else {
+            return Verdict::Forward;
+        };
+        if limiter.check_rate_limit(MESSAGES_KEY).await.is_ok() {
+            return Verdict::Forward;
+        }
+        if *RATE_LIMIT_MODE == RateLimitMode::Shadow {
+            metrics::record_shadow_rejection();
+            return Verdict::Forward;
+        }
+        self.violations += 1;
+        let closing = match self.policy {
+            MessagePolicy::Close => true,
+            MessagePolicy::Drop => self.max_violations.is_some_and(|max| self.violations > max),
+        };
+        if closing {
+            metrics::record_websocket_limited("closed");
+            Verdict::Close
+        } else {
+            metrics::record_websocket_limited("dropped");
+            Verdict::Drop
+        }
+    }
+}
+
+/// Passes messages between the client and the upstream until either closes
+/// the connection, or the client's messages break the policy. Pings and pongs
+/// are answered on each side rather than passed on.
+async fn relay(client: WebSocket, upstream: Upstream, config: &WebSocketConfig) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let mut messages = Messages::new(config);
+    loop {
+        tokio::select! {
+            message = client_rx.next() => {
+                let Some(Ok(message)) = message else { break };
+                if matches!(message, ws::Message::Text(_) | ws::Message::Binary(_)) {
+                    match messages.admit().await {
+                        Verdict::Forward => {}
+                        Verdict::Drop => continue,
+                        Verdict::Close => {
+                            tracing::warn!("Closing WebSocket over its message limit");
+                            let _ = client_tx
+                                .send(ws::Message::Close(Some(ws::CloseFrame {
+                                    code: POLICY_VIOLATION,
+                                    reason: "Message rate limit exceeded.".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                    }
+                }
+                let Some(message) = to_upstream(message) else { continue };
+                if upstream_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+            message = upstream_rx.next() => {
+                let Some(Ok(message)) = message else { break };
+                let Some(message) = to_client(message) else { continue };
+                if client_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = upstream_tx.close().await;
+    let _ = client_tx.close().await;
+}
+
+fn to_upstream(message: ws::Message) -> Option<tungstenite::Message> {
+    Some(match message {
+        ws::Message::Text(text) => tungstenite::Message::Text(text),
+        ws::Message::Binary(data) => tungstenite::Message::Binary(data),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|frame| tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(frame.code),
+                reason: frame.reason,
+            }))
+        }
+        ws::Message::Ping(_) | ws::Message::Pong(_) => return None,
+    })
+}
+
+fn to_client(message: tungstenite::Message) -> Option<ws::Message> {
+    Some(match message {
+        tungstenite::Message::Text(text) => ws::Message::Text(text),
+        tungstenite::Message::Binary(data) => ws::Message::Binary(data),
+        tungstenite::Message::Close(frame) => {
+            ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason,
+            }))
+        }
+        tungstenite::Message::Ping(_)
+        | tungstenite::Message::Pong(_)
+        | tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitConfig;
+    use axum::{Router, routing::get};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    fn config(policy: MessagePolicy, max_violations: Option<u64>) -> WebSocketConfig {
+        WebSocketConfig {
+            max_connections: None,
+            message_limit: Some(Arc::new(RateLimitConfig {
+                max_requests: 2,
+                window: Duration::from_secs(60),
+            })),
+            message_policy: policy,
+            max_violations,
+        }
+    }
+
+    async fn verdicts(config: &WebSocketConfig, count: usize) -> Vec<&'static str> {
+        let mut messages = Messages::new(config);
+        let mut verdicts = Vec::new();
+        for _ in 0..count {
+            verdicts.push(match messages.admit().await {
+                Verdict::Forward => "forward",
+                Verdict::Drop => "drop",
+                Verdict::Close => "close",
+            });
+        }
+        verdicts
+    }
+
+    #[test]
+    fn connections_are_counted_until_dropped() {
+        let connections = Connections::default();
+        let first = connections.open("ip:1", 2).unwrap();
+        let second = connections.open("ip:1", 2).unwrap();
+        assert!(connections.open("ip:1", 2).is_none());
+        // Keys are counted apart.
+        let other = connections.open("ip:2", 2).unwrap();
+
+        drop(first);
+        let third = connections.open("ip:1", 2).unwrap();
+        assert!(connections.open("ip:1", 2).is_none());
+
+        drop((second, third, other));
+        assert!(connections.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_close_policy_closes_at_the_first_message_over_the_limit() {
+        assert_eq!(
+            verdicts(&config(MessagePolicy::Close, None), 3).await,
+            ["forward", "forward", "close"]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_drop_policy_closes_after_max_violations() {
+        assert_eq!(
+            verdicts(&config(MessagePolicy::Drop, Some(2)), 5).await,
+            ["forward", "forward", "drop", "drop", "close"]
+        );
+        assert_eq!(
+            verdicts(&config(MessagePolicy::Drop, None), 6).await,
+            ["forward", "forward", "drop", "drop", "drop", "drop"]
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_without_a_message_limit_are_never_closed() {
+        let config = WebSocketConfig {
+            message_limit: None,
+            ..config(MessagePolicy::Close, None)
+        };
+        assert_eq!(verdicts(&config, 3).await, ["forward"; 3]);
+    }
+
+    /// Serves an upstream echoing every message back.
+    async fn echo_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(message)) = socket.next().await {
+                        if message.is_text() && socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_message_limit_are_closed() {
+        let upstream = echo_upstream().await;
+        let config: &'static WebSocketConfig =
+            Box::leak(Box::new(config(MessagePolicy::Close, None)));
+        let app = Router::new().route(
+            "/",
+            get(move |upgrade: WebSocketUpgrade| async move {
+                upgrade.on_upgrade(move |client| async move {
+                    let (upstream, _) = tokio_tungstenite::connect_async(upstream).await.unwrap();
+                    relay(client, upstream, config).await;
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        for text in ["one", "two"] {
+            client
+                .send(tungstenite::Message::Text(text.into()))
+                .await
+                .unwrap();
+            let echoed = client.next().await.unwrap().unwrap();
+            assert_eq!(echoed.to_text().unwrap(), text);
+        }
+        client
+            .send(tungstenite::Message::Text("three".into()))
+            .await
+            .unwrap();
+        match client.next().await.unwrap().unwrap() {
+            tungstenite::Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), POLICY_VIOLATION);
+            }
+            message => panic!("expected the connection to close, got {:?}", message),
+        }
+    }
+}
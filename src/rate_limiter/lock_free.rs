@@ -1,13 +1,18 @@
+use async_trait::async_trait;
 use dashmap::DashMap;
 use std::{
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, SystemTime},
 };
 
-use super::{RateLimiter, RequestState};
-use crate::config::RateLimitConfig;
+use super::{
+    MapHealth, RateLimitDecision, RateLimitError, RateLimiter, RequestState, TrackedKey,
+    least_recently_seen, map_health,
+};
+use crate::config::{MAX_TRACKED_KEYS, RateLimitConfig};
+use crate::metrics;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct LockFreeRateLimitState {
     pub requests: Arc<DashMap<String, RequestState>>,
 }
@@ -18,31 +23,65 @@ impl LockFreeRateLimitState {
             requests: Arc::new(DashMap::new()),
         }
     }
+
+    pub fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn health(&self) -> MapHealth {
+        map_health(&self.requests, |_| 0)
+    }
+
+    /// Keys starting with `prefix` with at least `min_count` requests, read
+    /// one shard at a time.
+    pub fn keys(&self, prefix: &str, min_count: u64) -> Vec<TrackedKey> {
+        self.requests
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix) && u64::from(entry.count) >= min_count)
+            .map(|entry| TrackedKey {
+                key: entry.key().clone(),
+                count: u64::from(entry.count),
+                idle: entry.last_updated.elapsed().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Drops keys without requests in the last `idle`, returning how many.
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        let before = self.requests.len();
+        self.requests.retain(|_, state| {
+            state
+                .last_updated
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed <= idle)
+        });
+        before.saturating_sub(self.requests.len())
+    }
 }
 
 #[derive(Clone)]
 pub struct LockFreeSlidingWindowRateLimiter {
     requests: Arc<DashMap<String, RequestState>>,
-    config: &'static RateLimitConfig,
+    config: Arc<RateLimitConfig>,
 }
 
 impl LockFreeSlidingWindowRateLimiter {
-    pub fn new(requests: Arc<DashMap<String, RequestState>>) -> Self {
-        Self {
-            requests,
-            config: &crate::config::RATE_LIMIT_CONFIG,
-        }
+    pub fn new(requests: Arc<DashMap<String, RequestState>>, config: Arc<RateLimitConfig>) -> Self {
+        Self { requests, config }
     }
 }
 
+#[async_trait]
 impl RateLimiter for LockFreeSlidingWindowRateLimiter {
-    async fn check_rate_limit(&self, ip: &str) -> Result<(), String> {
-        let now = Instant::now();
-        let window = Duration::from_secs(self.config.window_seconds);
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        let now = SystemTime::now();
+        let window = self.config.window;
+        let max_requests = u64::from(self.config.max_requests);
+        let mut count = 0;
 
         // Check request count while tolerating race conditions
         if let Some(mut entry) = self.requests.get_mut(ip) {
-            let duration_since_last = now.duration_since(entry.last_updated);
+            let duration_since_last = now.duration_since(entry.last_updated).unwrap_or_default();
 
             // Reset counter if window is exceeded
             if duration_since_last >= window {
@@ -51,18 +90,26 @@ impl RateLimiter for LockFreeSlidingWindowRateLimiter {
             }
 
             if entry.count >= self.config.max_requests {
-                return Err(format!(
-                    "Rate limit exceeded. Maximum {} requests per {} seconds.",
-                    self.config.max_requests, self.config.window_seconds
+                return Err(RateLimitError::rate_limit_exceeded(
+                    max_requests,
+                    window,
+                    window.saturating_sub(duration_since_last),
                 ));
             }
+            count = u64::from(entry.count);
         }
 
-        Ok(())
+        // Recording the request moves the window to start now.
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window,
+            remaining: max_requests - count - 1,
+            reset: window,
+        })
     }
 
     async fn record_request(&self, ip: &str) {
-        let now = Instant::now();
+        let now = SystemTime::now();
         self.requests
             .entry(ip.to_string())
             .and_modify(|state| {
@@ -73,5 +120,47 @@ impl RateLimiter for LockFreeSlidingWindowRateLimiter {
                 count: 1,
                 last_updated: now,
             });
+
+        if let Some(max) = *MAX_TRACKED_KEYS {
+            let evicted = least_recently_seen(
+                self.requests
+                    .iter()
+                    .map(|entry| (entry.key().clone(), entry.last_updated)),
+                self.requests.len(),
+                max,
+            );
+            for key in &evicted {
+                self.requests.remove(key);
+            }
+            metrics::record_eviction("capacity", evicted.len());
+        }
+    }
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let now = SystemTime::now();
+        let window = self.config.window;
+        let max_requests = u64::from(self.config.max_requests);
+        let (count, reset) = self
+            .requests
+            .get(ip)
+            .map(|entry| {
+                let elapsed = now.duration_since(entry.last_updated).unwrap_or_default();
+                (u64::from(entry.count), elapsed)
+            })
+            .filter(|&(_, elapsed)| elapsed < window)
+            .map_or((0, Duration::ZERO), |(count, elapsed)| {
+                (count, window - elapsed)
+            });
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window,
+            remaining: max_requests.saturating_sub(count),
+            reset,
+        })
+    }
+
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.requests.remove(ip);
+        Ok(())
     }
 }
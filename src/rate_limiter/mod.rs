@@ -1,40 +1,160 @@
-use std::time::Instant;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-#[derive(Debug, Clone)]
+use crate::config::{QuotaPeriod, RateLimitConfig};
+
+/// Wall-clock timestamps keep the state meaningful when it is snapshotted
+/// and restored by another process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestState {
     pub count: u32,
-    pub last_updated: Instant,
+    pub last_updated: SystemTime,
 }
 
-pub trait RateLimiter: Clone {
-    async fn check_rate_limit(&self, ip: &str) -> Result<(), String>;
-    async fn record_request(&self, ip: &str);
+/// Where a key stands against its limit after a request was checked, or
+/// when peeked at without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    /// Requests allowed per window.
+    pub limit: u64,
+    pub window: Duration,
+    /// Requests still allowed after this one, `0` if it was rejected. When
+    /// peeking, all requests still allowed.
+    pub remaining: u64,
+    /// Time until more requests are allowed again: until the oldest request
+    /// leaves the window, the window restarts, or a token is refilled.
+    pub reset: Duration,
 }
 
-mod lock_free;
-mod standard;
-
-pub use lock_free::*;
-pub use standard::*;
+/// Which limit a rejected request exceeded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    RateLimit,
+    Quota(QuotaPeriod),
+}
 
-#[derive(Clone)]
-pub enum RateLimiterEnum {
-    Standard(SlidingWindowRateLimiter),
-    LockFree(LockFreeSlidingWindowRateLimiter),
+/// Why a request was not admitted.
+#[derive(Debug, Clone)]
+pub enum RateLimitError {
+    /// The client used up its limit, `decision.reset` telling when it may
+    /// retry.
+    Exceeded {
+        reason: Reason,
+        decision: RateLimitDecision,
+    },
+    /// The backing store failed, so no decision could be made.
+    Unavailable(String),
 }
 
-impl RateLimiterEnum {
-    pub async fn check_rate_limit(&self, ip: &str) -> Result<(), String> {
-        match self {
-            Self::Standard(limiter) => limiter.check_rate_limit(ip).await,
-            Self::LockFree(limiter) => limiter.check_rate_limit(ip).await,
+impl RateLimitError {
+    /// The rate limit of `limit` requests per `window` is used up until
+    /// `reset`.
+    pub fn rate_limit_exceeded(limit: u64, window: Duration, reset: Duration) -> Self {
+        Self::Exceeded {
+            reason: Reason::RateLimit,
+            decision: RateLimitDecision {
+                limit,
+                window,
+                remaining: 0,
+                reset,
+            },
         }
     }
+}
+
+/// A limiter counting requests per key. Limiters are used as
+/// `Arc<dyn RateLimiter>`, so the middleware takes those of other crates
+/// alike, built by a [`LimiterFactory`]; implementations are written with
+/// `#[async_trait]`.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// Checks whether a request for `ip` is admitted, returning what is left
+    /// of its limit counting that request.
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError>;
+    async fn record_request(&self, ip: &str);
+    /// Where `ip` stands against its limit without counting a request, with
+    /// no reset time while it has used none of it.
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String>;
+    /// Forgets the requests counted for `ip`, so all of its limit is
+    /// available again.
+    async fn reset(&self, ip: &str) -> Result<(), String>;
+}
+
+/// Builds the limiter holding keys to a limit, the one of the route rule or
+/// tier a request falls under, over state the limiters it builds share.
+pub type LimiterFactory = Arc<dyn Fn(Arc<RateLimitConfig>) -> Arc<dyn RateLimiter> + Send + Sync>;
+
+/// Picks the keys to evict once more than `max` are tracked: the least
+/// recently seen ones, down to 90% of `max` so eviction is not needed again
+/// on the very next insert.
+pub fn least_recently_seen<K, T: Ord>(
+    entries: impl Iterator<Item = (K, T)>,
+    len: usize,
+    max: usize,
+) -> Vec<K> {
+    let excess = len.saturating_sub(max - max / 10);
+    if len <= max || excess == 0 {
+        return Vec::new();
+    }
+    let mut entries: Vec<(K, T)> = entries.collect();
+    let excess = excess.min(entries.len());
+    if excess < entries.len() {
+        entries.select_nth_unstable_by(excess, |a, b| a.1.cmp(&b.1));
+    }
+    entries.truncate(excess);
+    entries.into_iter().map(|(key, _)| key).collect()
+}
+
+/// A key a limiter holds state for, as listed by `GET /admin/keys`.
+pub struct TrackedKey {
+    pub key: String,
+    /// Requests counted in the key's window as of its last request; some
+    /// may have left the window since.
+    pub count: u64,
+    /// Time since the key's last request.
+    pub idle: Duration,
+}
+
+/// How much a limiter holds in memory, for capacity planning.
+pub struct MapHealth {
+    pub keys: usize,
+    /// Keys in each shard of the map, one for maps behind a single lock.
+    pub shards: Vec<usize>,
+    /// Estimate of the bytes the entries take: the entries themselves, their
+    /// keys and what their values point to, not the map's spare capacity.
+    pub bytes: usize,
+}
 
-    pub async fn record_request(&self, ip: &str) {
-        match self {
-            Self::Standard(limiter) => limiter.record_request(ip).await,
-            Self::LockFree(limiter) => limiter.record_request(ip).await,
+/// The health of `map`, whose values point to `heap_bytes` more each,
+/// read one shard at a time so requests are only held up on one of them.
+pub fn map_health<V>(map: &DashMap<String, V>, heap_bytes: impl Fn(&V) -> usize) -> MapHealth {
+    let mut shards = Vec::with_capacity(map.shards().len());
+    let mut bytes = 0;
+    for shard in map.shards() {
+        let shard = shard.read();
+        shards.push(shard.len());
+        for (key, value) in shard.iter() {
+            bytes += size_of::<(String, V)>() + key.capacity() + heap_bytes(value.get());
         }
     }
+    MapHealth {
+        keys: shards.iter().sum(),
+        shards,
+        bytes,
+    }
 }
+
+mod lock_free;
+mod quota;
+mod standard;
+mod store;
+
+pub use lock_free::*;
+pub use quota::*;
+pub use standard::*;
+pub use store::*;
@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use chrono::{Datelike, Months, NaiveDate, NaiveTime, Utc};
+
+use super::{RateLimitDecision, RateLimitError, RateLimiter, Reason};
+use crate::config::{QuotaConfig, QuotaPeriod};
+use crate::storage::PostgresQuotaStore;
+
+/// Enforces a daily or monthly quota per key, with periods following the UTC
+/// calendar.
+///
+/// Because the check already counts admitted requests, `record_request` is a
+/// no-op for this limiter.
+#[derive(Clone)]
+pub struct QuotaLimiter {
+    store: PostgresQuotaStore,
+    config: &'static QuotaConfig,
+}
+
+impl QuotaLimiter {
+    pub fn new(store: PostgresQuotaStore, config: &'static QuotaConfig) -> Self {
+        Self { store, config }
+    }
+
+    fn period_start(&self) -> NaiveDate {
+        let today = Utc::now().date_naive();
+        match self.config.period {
+            QuotaPeriod::Day => today,
+            QuotaPeriod::Month => today.with_day(1).unwrap_or(today),
+        }
+    }
+
+    fn period_end(&self, start: NaiveDate) -> NaiveDate {
+        match self.config.period {
+            QuotaPeriod::Day => start.succ_opt(),
+            QuotaPeriod::Month => start.checked_add_months(Months::new(1)),
+        }
+        .unwrap_or(start)
+    }
+
+    /// Where the current period stands, with `remaining` requests left.
+    fn decision(&self, start: NaiveDate, remaining: u64) -> RateLimitDecision {
+        let end = self.period_end(start);
+        RateLimitDecision {
+            limit: self.config.max_requests,
+            window: (end - start).to_std().unwrap_or_default(),
+            remaining,
+            reset: (end.and_time(NaiveTime::MIN).and_utc() - Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for QuotaLimiter {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        let start = self.period_start();
+        let decision = self.decision(start, 0);
+        match self
+            .store
+            .increment(ip, start, self.config.max_requests)
+            .await
+        {
+            Ok(Some(count)) => Ok(RateLimitDecision {
+                remaining: self.config.max_requests.saturating_sub(count),
+                ..decision
+            }),
+            Ok(None) => Err(RateLimitError::Exceeded {
+                reason: Reason::Quota(self.config.period),
+                decision,
+            }),
+            Err(e) => Err(RateLimitError::Unavailable(e.to_string())),
+        }
+    }
+
+    async fn record_request(&self, _ip: &str) {}
+
+    /// Reports the time until the period ends even before the key used any
+    /// of it, as the quota resets then regardless.
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let start = self.period_start();
+        let count = self
+            .store
+            .count(ip, start)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(self.decision(start, self.config.max_requests.saturating_sub(count)))
+    }
+
+    /// Forgets the requests of the current period only.
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.store
+            .reset(ip, self.period_start())
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
@@ -1,3 +1,4 @@
+use async_trait::async_trait;
 use std::{
     collections::HashMap,
     sync::Arc,
@@ -5,34 +6,86 @@ use std::{
 };
 use tokio::sync::RwLock;
 
-use super::RateLimiter;
-use crate::config::RateLimitConfig;
+use super::{
+    MapHealth, RateLimitDecision, RateLimitError, RateLimiter, TrackedKey, least_recently_seen,
+};
+use crate::config::{MAX_TRACKED_KEYS, RateLimitConfig};
+use crate::metrics;
 
 #[derive(Clone)]
 pub struct RateLimitState {
     pub requests: Arc<RwLock<HashMap<String, Vec<Instant>>>>,
 }
 
+impl RateLimitState {
+    pub async fn tracked_keys(&self) -> usize {
+        self.requests.read().await.len()
+    }
+
+    pub async fn health(&self) -> MapHealth {
+        let requests = self.requests.read().await;
+        let bytes = requests
+            .iter()
+            .map(|(key, timestamps)| {
+                size_of::<(String, Vec<Instant>)>()
+                    + key.capacity()
+                    + timestamps.capacity() * size_of::<Instant>()
+            })
+            .sum();
+        MapHealth {
+            keys: requests.len(),
+            shards: vec![requests.len()],
+            bytes,
+        }
+    }
+
+    /// Keys starting with `prefix` with at least `min_count` requests.
+    pub async fn keys(&self, prefix: &str, min_count: u64) -> Vec<TrackedKey> {
+        let requests = self.requests.read().await;
+        requests
+            .iter()
+            .filter(|(key, timestamps)| {
+                key.starts_with(prefix) && timestamps.len() as u64 >= min_count
+            })
+            .map(|(key, timestamps)| TrackedKey {
+                key: key.clone(),
+                count: timestamps.len() as u64,
+                idle: timestamps.last().map(Instant::elapsed).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Drops keys without requests in the last `idle`, returning how many.
+    pub async fn evict_idle(&self, idle: Duration) -> usize {
+        let mut requests = self.requests.write().await;
+        let before = requests.len();
+        requests
+            .retain(|_, timestamps| timestamps.last().is_some_and(|time| time.elapsed() <= idle));
+        before - requests.len()
+    }
+}
+
 #[derive(Clone)]
 pub struct SlidingWindowRateLimiter {
     requests: Arc<RwLock<HashMap<String, Vec<Instant>>>>,
-    config: &'static RateLimitConfig,
+    config: Arc<RateLimitConfig>,
 }
 
 impl SlidingWindowRateLimiter {
-    pub fn new(requests: Arc<RwLock<HashMap<String, Vec<Instant>>>>) -> Self {
-        Self {
-            requests,
-            config: &crate::config::RATE_LIMIT_CONFIG,
-        }
+    pub fn new(
+        requests: Arc<RwLock<HashMap<String, Vec<Instant>>>>,
+        config: Arc<RateLimitConfig>,
+    ) -> Self {
+        Self { requests, config }
     }
 }
 
+#[async_trait]
 impl RateLimiter for SlidingWindowRateLimiter {
-    async fn check_rate_limit(&self, ip: &str) -> Result<(), String> {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
         let mut requests = self.requests.write().await;
         let now = Instant::now();
-        let window = Duration::from_secs(self.config.window_seconds);
+        let window = self.config.window;
 
         // Remove old requests
         if let Some(timestamps) = requests.get_mut(ip) {
@@ -40,15 +93,26 @@ impl RateLimiter for SlidingWindowRateLimiter {
         }
 
         // Get current request count
-        let current_requests = requests.get(ip).map(|v| v.len()).unwrap_or(0);
+        let timestamps = requests.get(ip).map(Vec::as_slice).unwrap_or_default();
+        let current_requests = timestamps.len() as u64;
+        let max_requests = u64::from(self.config.max_requests);
+        // Without earlier requests, this one is the oldest.
+        let oldest = timestamps.first().copied().unwrap_or(now);
+        let reset = window.saturating_sub(now.duration_since(oldest));
 
-        if current_requests >= self.config.max_requests as usize {
-            Err(format!(
-                "Rate limit exceeded. Maximum {} requests per {} seconds.",
-                self.config.max_requests, self.config.window_seconds
+        if current_requests >= max_requests {
+            Err(RateLimitError::rate_limit_exceeded(
+                max_requests,
+                window,
+                reset,
             ))
         } else {
-            Ok(())
+            Ok(RateLimitDecision {
+                limit: max_requests,
+                window,
+                remaining: max_requests - current_requests - 1,
+                reset,
+            })
         }
     }
 
@@ -58,5 +122,47 @@ impl RateLimiter for SlidingWindowRateLimiter {
             .entry(ip.to_string())
             .or_insert_with(Vec::new)
             .push(Instant::now());
+
+        if let Some(max) = *MAX_TRACKED_KEYS {
+            let len = requests.len();
+            let evicted = least_recently_seen(
+                requests
+                    .iter()
+                    .map(|(key, timestamps)| (key.clone(), timestamps.last().copied())),
+                len,
+                max,
+            );
+            for key in &evicted {
+                requests.remove(key);
+            }
+            metrics::record_eviction("capacity", evicted.len());
+        }
+    }
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let requests = self.requests.read().await;
+        let now = Instant::now();
+        let window = self.config.window;
+        let mut timestamps = requests
+            .get(ip)
+            .into_iter()
+            .flatten()
+            .filter(|&&time| now.duration_since(time) <= window);
+        let oldest = timestamps.next();
+        let count = u64::from(oldest.is_some()) + timestamps.count() as u64;
+        let max_requests = u64::from(self.config.max_requests);
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window,
+            remaining: max_requests.saturating_sub(count),
+            reset: oldest.map_or(Duration::ZERO, |&oldest| {
+                window.saturating_sub(now.duration_since(oldest))
+            }),
+        })
+    }
+
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.requests.write().await.remove(ip);
+        Ok(())
     }
 }
@@ -0,0 +1,413 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{RateLimitDecision, RateLimitError, RateLimiter, Reason};
+use crate::config::{RateLimitAlgorithm, RateLimitConfig};
+use crate::storage::RateLimitStore;
+
+/// Timestamps of the requests admitted within the window.
+#[derive(Serialize, Deserialize, Default)]
+struct SlidingWindowLog {
+    timestamps: Vec<u64>,
+}
+
+/// Tokens left and when they were last refilled.
+#[derive(Serialize, Deserialize)]
+struct TokenBucket {
+    tokens: f64,
+    updated: u64,
+}
+
+impl TokenBucket {
+    /// Adds the tokens refilled since the last update, `capacity` per
+    /// `window` microseconds.
+    fn refill(&mut self, now: u64, capacity: f64, window: u64) {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.tokens = (self.tokens + elapsed * capacity / window as f64).min(capacity);
+        self.updated = now;
+    }
+
+    /// Time until the fraction of a token left grows to one.
+    fn next_token(&self, capacity: f64, window: u64) -> Duration {
+        let refill = (1.0 - self.tokens.fract()) * window as f64 / capacity;
+        Duration::from_micros(refill.ceil() as u64)
+    }
+}
+
+/// Runs a rate limit algorithm against any [`RateLimitStore`], checking and
+/// recording each request in one atomic update.
+///
+/// Because the check already records admitted requests, `record_request` is
+/// a no-op for this limiter. Timestamps are wall-clock microseconds, so
+/// replicas sharing a store should keep their clocks in sync.
+#[derive(Clone)]
+pub struct StoreRateLimiter<S> {
+    store: S,
+    algorithm: RateLimitAlgorithm,
+    config: Arc<RateLimitConfig>,
+}
+
+impl<S: RateLimitStore> StoreRateLimiter<S> {
+    pub fn new(store: S, algorithm: RateLimitAlgorithm, config: Arc<RateLimitConfig>) -> Self {
+        Self {
+            store,
+            algorithm,
+            config,
+        }
+    }
+
+    /// Returns whether the request was admitted (and recorded), and where the
+    /// key stands against its limit after it.
+    async fn admit(&self, ip: &str) -> Result<(bool, RateLimitDecision), String> {
+        let now = now_micros();
+        let window = self.config.window_micros();
+        let ttl = Duration::from_micros(window);
+        let max_requests = u64::from(self.config.max_requests);
+
+        match self.algorithm {
+            RateLimitAlgorithm::SlidingWindow => {
+                self.store
+                    .update(ip, ttl, |current| {
+                        let mut log: SlidingWindowLog = decode(current).unwrap_or_default();
+                        log.timestamps
+                            .retain(|&time| now.saturating_sub(time) < window);
+                        let count = log.timestamps.len() as u64;
+                        let oldest = log.timestamps.first().copied().unwrap_or(now);
+                        let mut decision = RateLimitDecision {
+                            limit: max_requests,
+                            window: self.config.window,
+                            remaining: 0,
+                            // Another node's clock may be ahead, leaving
+                            // timestamps in the future.
+                            reset: Duration::from_micros(
+                                window - now.saturating_sub(oldest).min(window),
+                            ),
+                        };
+                        if count >= max_requests {
+                            return (None, (false, decision));
+                        }
+                        log.timestamps.push(now);
+                        decision.remaining = max_requests - count - 1;
+                        (Some(encode(&log)), (true, decision))
+                    })
+                    .await
+            }
+            RateLimitAlgorithm::TokenBucket => {
+                self.store
+                    .update(ip, ttl, |current| {
+                        let capacity = max_requests as f64;
+                        let mut bucket = decode(current).unwrap_or(TokenBucket {
+                            tokens: capacity,
+                            updated: now,
+                        });
+                        bucket.refill(now, capacity, window);
+                        let allowed = bucket.tokens >= 1.0;
+                        if allowed {
+                            bucket.tokens -= 1.0;
+                        }
+                        let decision = RateLimitDecision {
+                            limit: max_requests,
+                            window: self.config.window,
+                            remaining: bucket.tokens as u64,
+                            reset: bucket.next_token(capacity, window),
+                        };
+                        (Some(encode(&bucket)), (allowed, decision))
+                    })
+                    .await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: RateLimitStore> RateLimiter for StoreRateLimiter<S> {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        match self.admit(ip).await {
+            Ok((true, decision)) => Ok(decision),
+            Ok((false, decision)) => Err(RateLimitError::Exceeded {
+                reason: Reason::RateLimit,
+                decision,
+            }),
+            Err(e) => Err(RateLimitError::Unavailable(e)),
+        }
+    }
+
+    async fn record_request(&self, _ip: &str) {}
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let now = now_micros();
+        let window = self.config.window_micros();
+        let max_requests = u64::from(self.config.max_requests);
+        let current = self.store.get(ip).await?;
+        let mut decision = RateLimitDecision {
+            limit: max_requests,
+            window: self.config.window,
+            remaining: max_requests,
+            reset: Duration::ZERO,
+        };
+        match self.algorithm {
+            RateLimitAlgorithm::SlidingWindow => {
+                let log: SlidingWindowLog = decode(current.as_deref()).unwrap_or_default();
+                let mut timestamps = log
+                    .timestamps
+                    .iter()
+                    .filter(|&&time| now.saturating_sub(time) < window);
+                if let Some(&oldest) = timestamps.next() {
+                    let count = 1 + timestamps.count() as u64;
+                    decision.remaining = max_requests.saturating_sub(count);
+                    decision.reset =
+                        Duration::from_micros(window - now.saturating_sub(oldest).min(window));
+                }
+            }
+            RateLimitAlgorithm::TokenBucket => {
+                let capacity = max_requests as f64;
+                if let Some(mut bucket) = decode::<TokenBucket>(current.as_deref()) {
+                    bucket.refill(now, capacity, window);
+                    decision.remaining = bucket.tokens as u64;
+                    if bucket.tokens < capacity {
+                        decision.reset = bucket.next_token(capacity, window);
+                    }
+                }
+            }
+        }
+        Ok(decision)
+    }
+
+    /// Writes the state of a key without requests over the key's, leaving
+    /// keys without state alone.
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        let now = now_micros();
+        let ttl = Duration::from_micros(self.config.window_micros());
+        let capacity = f64::from(self.config.max_requests);
+        self.store
+            .update(ip, ttl, |current| {
+                let reset = current.map(|_| match self.algorithm {
+                    RateLimitAlgorithm::SlidingWindow => encode(&SlidingWindowLog::default()),
+                    RateLimitAlgorithm::TokenBucket => encode(&TokenBucket {
+                        tokens: capacity,
+                        updated: now,
+                    }),
+                });
+                (reset, ())
+            })
+            .await
+    }
+}
+
+fn decode<T: DeserializeOwned>(value: Option<&[u8]>) -> Option<T> {
+    value.and_then(|value| serde_json::from_slice(value).ok())
+}
+
+fn encode<T: Serialize>(state: &T) -> Vec<u8> {
+    serde_json::to_vec(state).expect("rate limit state serializes")
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn limiter(algorithm: RateLimitAlgorithm, max_requests: u32) -> StoreRateLimiter<MemoryStore> {
+        StoreRateLimiter::new(
+            MemoryStore::new(),
+            algorithm,
+            Arc::new(RateLimitConfig {
+                max_requests,
+                window: Duration::from_secs(60),
+            }),
+        )
+    }
+
+    async fn write<T: Serialize>(limiter: &StoreRateLimiter<MemoryStore>, key: &str, state: &T) {
+        let stored = limiter
+            .store
+            .compare_and_swap(key, None, encode(state), Duration::from_secs(60))
+            .await;
+        assert_eq!(stored, Ok(true));
+    }
+
+    async fn admit(
+        limiter: &StoreRateLimiter<MemoryStore>,
+        key: &str,
+    ) -> (bool, RateLimitDecision) {
+        limiter
+            .admit(key)
+            .await
+            .expect("the memory store never fails")
+    }
+
+    #[tokio::test]
+    async fn sliding_window_admits_up_to_the_limit() {
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 3);
+        for remaining in [2, 1, 0] {
+            let (allowed, decision) = admit(&limiter, "k").await;
+            assert!(allowed);
+            assert_eq!(decision.remaining, remaining);
+            assert!(decision.reset <= Duration::from_secs(60));
+        }
+        let (allowed, decision) = admit(&limiter, "k").await;
+        assert!(!allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.reset > Duration::from_secs(59));
+        assert!(admit(&limiter, "other").await.0, "keys are counted apart");
+    }
+
+    #[tokio::test]
+    async fn sliding_window_forgets_requests_older_than_the_window() {
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 2);
+        let now = now_micros();
+        let log = SlidingWindowLog {
+            timestamps: vec![now - 61_000_000, now - 30_000_000],
+        };
+        write(&limiter, "k", &log).await;
+
+        let (allowed, decision) = admit(&limiter, "k").await;
+        assert!(allowed);
+        assert_eq!(decision.remaining, 0);
+        // The request of 30 seconds ago leaves the window first.
+        assert!(decision.reset <= Duration::from_secs(30));
+        assert!(decision.reset > Duration::from_secs(29));
+    }
+
+    #[tokio::test]
+    async fn sliding_window_peek_records_nothing() {
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 2);
+        let empty = limiter.peek("k").await.unwrap();
+        assert_eq!((empty.remaining, empty.reset), (2, Duration::ZERO));
+
+        admit(&limiter, "k").await;
+        for _ in 0..3 {
+            let decision = limiter.peek("k").await.unwrap();
+            assert_eq!(decision.remaining, 1);
+            assert!(decision.reset > Duration::ZERO);
+        }
+    }
+
+    #[tokio::test]
+    async fn token_bucket_empties_and_tells_when_a_token_is_back() {
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 3);
+        let full = limiter.peek("k").await.unwrap();
+        assert_eq!((full.remaining, full.reset), (3, Duration::ZERO));
+
+        for remaining in [2, 1, 0] {
+            let (allowed, decision) = admit(&limiter, "k").await;
+            assert!(allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let (allowed, decision) = admit(&limiter, "k").await;
+        assert!(!allowed);
+        // One token is refilled every 20 seconds.
+        assert!(decision.reset <= Duration::from_secs(20));
+        assert!(decision.reset > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_refills_with_time() {
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 4);
+        let bucket = TokenBucket {
+            tokens: 0.0,
+            updated: now_micros() - 30_000_000,
+        };
+        write(&limiter, "k", &bucket).await;
+
+        // Half a window refills half of the bucket, two tokens.
+        let (allowed, decision) = admit(&limiter, "k").await;
+        assert!(allowed);
+        assert_eq!(decision.remaining, 1);
+
+        let bucket = TokenBucket {
+            tokens: 1.0,
+            updated: now_micros() - 3_600_000_000,
+        };
+        write(&limiter, "full", &bucket).await;
+        assert_eq!(limiter.peek("full").await.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_tolerates_an_update_from_a_clock_ahead() {
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 3);
+        let bucket = TokenBucket {
+            tokens: 0.5,
+            updated: now_micros() + 10_000_000,
+        };
+        write(&limiter, "k", &bucket).await;
+
+        // The time ahead neither refills the bucket nor drains it.
+        let (allowed, decision) = admit(&limiter, "k").await;
+        assert!(!allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.reset <= Duration::from_secs(10));
+        let peeked = limiter.peek("k").await.unwrap();
+        assert_eq!(peeked.remaining, 0);
+        assert!(peeked.reset <= Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn unreadable_state_starts_over() {
+        for algorithm in [
+            RateLimitAlgorithm::SlidingWindow,
+            RateLimitAlgorithm::TokenBucket,
+        ] {
+            let limiter = limiter(algorithm, 2);
+            let stored = limiter
+                .store
+                .compare_and_swap("k", None, b"garbage".to_vec(), Duration::from_secs(60))
+                .await;
+            assert_eq!(stored, Ok(true));
+            let (allowed, decision) = admit(&limiter, "k").await;
+            assert!(allowed);
+            assert_eq!(decision.remaining, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_gives_the_whole_budget_back() {
+        for algorithm in [
+            RateLimitAlgorithm::SlidingWindow,
+            RateLimitAlgorithm::TokenBucket,
+        ] {
+            let limiter = limiter(algorithm, 1);
+            assert!(admit(&limiter, "k").await.0);
+            assert!(!admit(&limiter, "k").await.0);
+            limiter.reset("k").await.unwrap();
+            assert!(admit(&limiter, "k").await.0);
+
+            limiter.reset("unseen").await.unwrap();
+            assert_eq!(limiter.store.get("unseen").await, Ok(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn sliding_window_tolerates_timestamps_from_a_clock_ahead() {
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 1);
+        let ahead = SlidingWindowLog {
+            timestamps: vec![now_micros() + 10_000_000],
+        };
+        let stored = limiter
+            .store
+            .compare_and_swap("k", None, encode(&ahead), Duration::from_secs(60))
+            .await;
+        assert_eq!(stored, Ok(true));
+
+        let Err(RateLimitError::Exceeded { decision, .. }) = limiter.check_rate_limit("k").await
+        else {
+            panic!("the request from the future fills the limit");
+        };
+        assert_eq!(decision.reset, Duration::from_secs(60));
+        assert_eq!(
+            limiter.peek("k").await.unwrap().reset,
+            Duration::from_secs(60)
+        );
+    }
+}
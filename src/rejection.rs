@@ -0,0 +1,122 @@
+//! Responses to requests over their limit, as JSON clients can parse or as
+//! the plain message, depending on `RATE_LIMIT_ERROR_FORMAT`, or from an
+//! operator's template.
+
+use axum::{
+    Json,
+    body::Body,
+    http::{HeaderMap, Response, StatusCode, header},
+    response::IntoResponse,
+};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+use crate::config::{
+    ERROR_BODY_CONFIG, ErrorField, ErrorFormat, QuotaPeriod, REJECTION_CONFIG, RateLimitConfig,
+    RejectionConfig,
+};
+use crate::rate_limiter::{RateLimitDecision, Reason};
+use crate::request_id::request_id;
+
+/// Content type of templated bodies unless one is configured.
+const DEFAULT_TEMPLATE_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// A request rejected for exceeding a limit.
+pub struct Rejection {
+    pub reason: Reason,
+    pub decision: RateLimitDecision,
+}
+
+impl Rejection {
+    /// The response telling the client about it, `429 Too Many Requests`
+    /// unless the route `rule` or the global `REJECTION_CONFIG` say otherwise,
+    /// in that order. `headers` are the request's.
+    pub fn into_response(
+        self,
+        headers: &HeaderMap,
+        rule: Option<&RejectionConfig>,
+    ) -> Response<Body> {
+        let status = rule
+            .and_then(|rule| rule.status)
+            .or(REJECTION_CONFIG.status)
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+        let template = rule
+            .and_then(|rule| rule.template.as_deref())
+            .or(REJECTION_CONFIG.template.as_deref());
+        if let Some(template) = template {
+            let content_type = rule
+                .and_then(|rule| rule.content_type.as_deref())
+                .or(REJECTION_CONFIG.content_type.as_deref())
+                .unwrap_or(DEFAULT_TEMPLATE_CONTENT_TYPE);
+            let body = self.render(template, headers);
+            return (status, [(header::CONTENT_TYPE, content_type)], body).into_response();
+        }
+        if ERROR_BODY_CONFIG.format == ErrorFormat::Text {
+            return (status, self.message()).into_response();
+        }
+        let body: Map<String, Value> = ERROR_BODY_CONFIG
+            .fields
+            .iter()
+            .map(|(field, name)| (name.clone(), self.value(*field, headers)))
+            .collect();
+        (status, Json(body)).into_response()
+    }
+
+    /// `template` with each `{field}` placeholder replaced by the value of
+    /// that field of JSON bodies, strings without their quotes.
+    fn render(&self, template: &str, headers: &HeaderMap) -> String {
+        ErrorField::ALL
+            .into_iter()
+            .fold(template.to_string(), |body, field| {
+                let placeholder = format!("{{{}}}", field.name());
+                if !body.contains(&placeholder) {
+                    return body;
+                }
+                let value = match self.value(field, headers) {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                body.replace(&placeholder, &value)
+            })
+    }
+
+    fn value(&self, field: ErrorField, headers: &HeaderMap) -> Value {
+        let decision = &self.decision;
+        match field {
+            ErrorField::Error => Value::from(self.message()),
+            ErrorField::Limit => Value::from(decision.limit),
+            ErrorField::WindowSeconds => match decision.window.subsec_nanos() {
+                0 => Value::from(decision.window.as_secs()),
+                _ => Value::from(decision.window.as_secs_f64()),
+            },
+            ErrorField::RetryAfterSeconds => Value::from(seconds(decision.reset)),
+            ErrorField::RequestId => Value::from(request_id(headers)),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self.reason {
+            Reason::RateLimit => {
+                let limit = RateLimitConfig {
+                    max_requests: u32::try_from(self.decision.limit).unwrap_or(u32::MAX),
+                    window: self.decision.window,
+                };
+                format!("Rate limit exceeded. Maximum {}.", limit)
+            }
+            Reason::Quota(period) => format!(
+                "Quota exceeded. Maximum {} requests per {}.",
+                self.decision.limit,
+                match period {
+                    QuotaPeriod::Day => "day",
+                    QuotaPeriod::Month => "month",
+                }
+            ),
+        }
+    }
+}
+
+/// Whole seconds, rounded up so clients never retry too early.
+pub fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
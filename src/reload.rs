@@ -0,0 +1,164 @@
+use chrono::{Timelike, Utc};
+use std::{
+    future,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::config::{CONFIG_FILE, reload_limits, replace_limits, update_limits};
+use crate::config_file::FileConfig;
+use crate::metrics::{self, RuleDiff};
+
+/// Reloads the limits from the config file on SIGHUP and, unless
+/// `watch_seconds` is 0, whenever the file's modification time changes.
+///
+/// Only the limits, schedules, route rules, allowlist and exempt paths are
+/// swapped; backend and server settings and the named limiters keep their
+/// startup values. Limiter
+/// state is untouched, so clients keep their counts across a reload, and a
+/// file that fails to load leaves the current limits in force.
+pub fn spawn_reload(path: &'static str, watch_seconds: u64) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+
+    tokio::spawn(async move {
+        let mut ticker =
+            (watch_seconds > 0).then(|| tokio::time::interval(Duration::from_secs(watch_seconds)));
+        let mut last_modified = modified(path);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    tracing::info!("Received SIGHUP, reloading {}", path);
+                }
+                _ = async {
+                    match &mut ticker {
+                        Some(ticker) => ticker.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    let current = modified(path);
+                    if current == last_modified {
+                        continue;
+                    }
+                    last_modified = current;
+                    tracing::info!("{} changed, reloading", path);
+                }
+            }
+            reload(path);
+        }
+    });
+}
+
+/// Why the latest config load failed, until one succeeds, for readiness
+/// checks to flag instances left on other limits than they were given.
+static LOAD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn load_error() -> Option<String> {
+    LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Records a config that could not even be fetched.
+pub fn record_load_failure(error: String) {
+    *LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(error);
+}
+
+fn reload(path: &str) {
+    // Failures are logged and recorded by `apply`.
+    let _ = apply(FileConfig::load(path));
+}
+
+/// Swaps in the limits of a freshly loaded config, keeping the current ones
+/// if it failed to load or holds invalid limits, returning the problems
+/// found.
+pub fn apply(loaded: Result<FileConfig, String>) -> Result<(), Vec<String>> {
+    let limits = loaded
+        .and_then(startup_limiters)
+        .map_err(|e| vec![e])
+        .and_then(|file| reload_limits(&file));
+    match limits {
+        Ok(limits) => {
+            let rules = limits.rule_names();
+            let previous = replace_limits(limits);
+            metrics::record_config_reload(Ok(&RuleDiff::between(&previous.rule_names(), &rules)));
+            *LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            Ok(())
+        }
+        Err(errors) => {
+            let e = errors.join("; ");
+            metrics::record_config_reload(Err(&e));
+            #[cfg(feature = "sentry")]
+            crate::sentry::capture_error("config_reload_failure", &e);
+            *LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+            Err(errors)
+        }
+    }
+}
+
+/// Rejects route rules bound to limiters that did not exist at startup, as
+/// limiters are only created then.
+fn startup_limiters(file: FileConfig) -> Result<FileConfig, String> {
+    let missing = file
+        .routes
+        .iter()
+        .filter_map(|rule| rule.limiter.as_ref())
+        .find(|name| !CONFIG_FILE.limiters.contains_key(*name));
+    match missing {
+        Some(name) => Err(format!(
+            "limiter {:?} was not defined at startup, restart to add it",
+            name
+        )),
+        None => Ok(file),
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Swaps the limits of schedule blocks in and out as the blocks start and
+/// end, checking at the start of every minute since blocks are given to the
+/// minute.
+pub fn spawn_schedules() {
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now();
+            let elapsed = now.second() as u64 * 1000 + now.timestamp_subsec_millis() as u64;
+            tokio::time::sleep(Duration::from_millis(60_000 - elapsed)).await;
+
+            let now = Utc::now();
+            if let Some(limits) = update_limits(|limits| limits.reschedule(now)) {
+                metrics::record_schedule_switch(limits.schedule.as_deref());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(max_requests: u32) -> FileConfig {
+        // Built past the file's own checks, as the limits are checked again
+        // once environment overrides apply.
+        toml::from_str(&format!(
+            "[limits.default]\nmax_requests = {}\nwindow_seconds = 60\n",
+            max_requests
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_limits_are_returned_not_kept() {
+        let Err(errors) = reload_limits(&file(0)) else {
+            panic!("a limit of 0 requests was accepted");
+        };
+        assert_eq!(
+            errors,
+            [
+                "default limit: max_requests must be greater than 0",
+                "anonymous limit: max_requests must be greater than 0",
+            ]
+        );
+        assert!(reload_limits(&file(10)).is_ok());
+    }
+}
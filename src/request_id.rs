@@ -0,0 +1,83 @@
+//! Request IDs: every request carries one, taken from its `X-Request-Id`
+//! header or generated, so a client's complaint can be matched to the
+//! server's logs. The ID is attached to the request's tracing span, set on
+//! the request for the handlers behind, and echoed in the response.
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Request, Response},
+    middleware::Next,
+};
+use std::time::Instant;
+use tracing::{Instrument, field};
+
+use crate::access_log;
+use crate::telemetry;
+
+/// Header the request ID is read from and echoed in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest ID honored; longer ones are replaced rather than logged.
+const MAX_LENGTH: usize = 128;
+
+/// Runs the request with its ID, generating one when it has none.
+pub async fn propagate(mut req: Request<Body>, next: Next) -> Response<Body> {
+    let id = match incoming(req.headers()) {
+        Some(id) => id.to_string(),
+        None => {
+            let id = generate();
+            req.headers_mut().insert(
+                REQUEST_ID_HEADER,
+                HeaderValue::from_str(&id).expect("hex is a valid header value"),
+            );
+            id
+        }
+    };
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let started = Instant::now();
+    let entry = access_log::Entry::new(&req);
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        method = %method,
+        path = %path,
+        status = field::Empty,
+        otel.kind = "server",
+    );
+    telemetry::continue_trace(&span, req.headers());
+    let response = next.run(req).instrument(span.clone());
+    #[cfg(feature = "sentry")]
+    let response = crate::sentry::scope(id.clone(), response);
+    let mut response = response.await;
+    span.record("status", response.status().as_u16());
+    let latency = started.elapsed();
+    span.in_scope(|| telemetry::log_request(&method, &path, &response, latency));
+    if let Some(entry) = entry {
+        entry.write(&id, &response, latency);
+    }
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// The request's ID, or a new one for requests that did not pass through
+/// `propagate`.
+pub fn request_id(headers: &HeaderMap) -> String {
+    incoming(headers).map_or_else(generate, str::to_string)
+}
+
+/// The ID the request came with, if it is short printable ASCII.
+fn incoming(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+}
+
+fn generate() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
@@ -0,0 +1,50 @@
+//! Bounds on what a single request may cost the server: how long it may take
+//! and how large a body it may send. Both answer with their own status, so
+//! clients can tell them apart from rejections for exceeding a rate limit.
+
+use axum::{
+    body::Body,
+    http::{Request, Response, StatusCode, header},
+    middleware::Next,
+    response::IntoResponse,
+};
+use http_body_util::Limited;
+
+use crate::config::{MAX_BODY_BYTES, REQUEST_TIMEOUT};
+use crate::metrics;
+
+/// Answers `408 Request Timeout` for requests whose response has not started
+/// within `REQUEST_TIMEOUT`, dropping their handler.
+pub async fn timeout(req: Request<Body>, next: Next) -> Response<Body> {
+    let Some(timeout) = *REQUEST_TIMEOUT else {
+        return next.run(req).await;
+    };
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("Request timed out after {:?}", timeout);
+            metrics::record_refused("timeout");
+            (StatusCode::REQUEST_TIMEOUT, "Request timed out.").into_response()
+        }
+    }
+}
+
+/// Answers `413 Payload Too Large` for requests declaring a body over
+/// `MAX_BODY_BYTES`, and cuts off bodies that turn out larger while read.
+pub async fn limit_body(req: Request<Body>, next: Next) -> Response<Body> {
+    let Some(max_bytes) = *MAX_BODY_BYTES else {
+        return next.run(req).await;
+    };
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|length| length > max_bytes);
+    if too_large {
+        metrics::record_refused("body_too_large");
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large.").into_response();
+    }
+    next.run(req.map(|body| Body::new(Limited::new(body, max_bytes))))
+        .await
+}
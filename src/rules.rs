@@ -0,0 +1,372 @@
+//! Route rules managed at runtime through `/admin/rules`, next to those of
+//! the config file: a rule named like one of the file's takes its place, and
+//! the others apply ahead of the file's, newest first.
+//!
+//! Rules are kept in the backing store, as JSON under `config:rules`, so they
+//! survive restarts and every instance sharing the store applies them, each
+//! picking up changes made on another within `SYNC_INTERVAL`. Backends
+//! keeping their state in process memory keep rules on this instance only,
+//! until it restarts.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+use crate::config::{CONFIG_FILE, limits, update_limits};
+use crate::config_file::{RouteRule, validate_routes};
+use crate::middleware::{RateLimitStateEnum, SharedLimiter};
+
+const STORE_KEY: &str = "config:rules";
+/// Time between two reads of the rules in the store.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// How long stored rules last unless written again; memcached keeps nothing
+/// longer.
+const TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Age past which stored rules are written again, so they never expire.
+const REFRESH_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// The rules managed at runtime, as last stored or changed.
+static RULES: RwLock<Vec<RouteRule>> = RwLock::new(Vec::new());
+/// Held through a change, so two on this instance are not interleaved.
+static CHANGING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Default, Deserialize, Serialize)]
+struct Stored {
+    rules: Vec<RouteRule>,
+    /// Seconds since the epoch.
+    written_at: u64,
+}
+
+/// A change to the rules.
+pub enum Change {
+    /// Creates the rule, or replaces the one of the same name.
+    Put(Box<RouteRule>),
+    Delete(String),
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    /// The rules would not pass the checks of the config file's.
+    Invalid(String),
+    Unknown,
+    /// The rule is the config file's, so not deleted but by changing it.
+    InFile,
+    Store(String),
+}
+
+/// The route rules in force with those of the config file, `file_routes`.
+pub fn merged(file_routes: &[RouteRule]) -> Vec<RouteRule> {
+    merge(
+        &RULES.read().unwrap_or_else(|e| e.into_inner()),
+        file_routes,
+    )
+}
+
+/// Whether rule `name` is managed at runtime.
+pub fn is_runtime(name: &str) -> bool {
+    RULES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|rule| rule.name == name)
+}
+
+/// Makes `change`, in the store of `limiter` first if it keeps the rules,
+/// and puts the resulting rules in force. Returns the rule in force before
+/// and after the change, if any.
+pub async fn change(
+    limiter: &RateLimitStateEnum,
+    change: Change,
+) -> Result<(Option<RouteRule>, Option<RouteRule>), RuleError> {
+    let _changing = CHANGING.lock().await;
+    let file_routes = limits().file_routes().to_vec();
+    let stored = limiter
+        .update_shared(STORE_KEY, TTL, |current| {
+            let result = decode(current)
+                .map_err(RuleError::Store)
+                .and_then(|stored| apply(&stored.rules, &change, &file_routes));
+            match result {
+                Ok((rules, before)) => (Some(encode(&rules)), Ok((rules, before))),
+                Err(e) => (None, Err(e)),
+            }
+        })
+        .await;
+    let (rules, before) = match stored {
+        Some(result) => result.map_err(RuleError::Store)??,
+        None => apply(
+            &RULES.read().unwrap_or_else(|e| e.into_inner()),
+            &change,
+            &file_routes,
+        )?,
+    };
+    let after = match change {
+        Change::Put(rule) => Some(*rule),
+        // A rule taking a file rule's place leaves the file rule in force.
+        Change::Delete(name) => file_routes.into_iter().find(|rule| rule.name == name),
+    };
+    put_in_force(rules);
+    Ok((before, after))
+}
+
+/// Loads the rules kept in the store of `limiter`, then keeps reading them
+/// for changes made on other instances.
+pub async fn spawn_sync(limiter: SharedLimiter) {
+    if !limiter.get().is_shared() {
+        return;
+    }
+    sync(&limiter.get()).await;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            sync(&limiter.get()).await;
+        }
+    });
+}
+
+async fn sync(limiter: &RateLimitStateEnum) {
+    let now = now_seconds();
+    let stored = limiter
+        .update_shared(STORE_KEY, TTL, |current| match decode(current) {
+            Ok(stored) => {
+                let age = Duration::from_secs(now.saturating_sub(stored.written_at));
+                let refresh = current.is_some() && age > REFRESH_AFTER;
+                (refresh.then(|| encode(&stored.rules)), Ok(stored.rules))
+            }
+            Err(e) => (None, Err(e)),
+        })
+        .await;
+    let rules = match stored {
+        Some(Ok(Ok(rules))) => rules,
+        Some(Ok(Err(e)) | Err(e)) => {
+            tracing::error!("Failed to read the route rules from the store: {}", e);
+            return;
+        }
+        None => return,
+    };
+    let unchanged = {
+        let current = RULES.read().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_value(&*current).ok() == serde_json::to_value(&rules).ok()
+    };
+    if unchanged {
+        return;
+    }
+    // Another instance may know named limiters this one does not.
+    if let Err(e) = validate_routes(
+        &merge(&rules, limits().file_routes()),
+        &CONFIG_FILE.limiters,
+    ) {
+        tracing::error!("Ignoring the route rules in the store: {}", e);
+        return;
+    }
+    tracing::info!("Applying {} route rules from the store", rules.len());
+    put_in_force(rules);
+}
+
+/// The rules after `change` to `rules`, checked with those of the config
+/// file, and the rule in force before it.
+fn apply(
+    rules: &[RouteRule],
+    change: &Change,
+    file_routes: &[RouteRule],
+) -> Result<(Vec<RouteRule>, Option<RouteRule>), RuleError> {
+    let mut rules = rules.to_vec();
+    let file_rule = |name: &str| file_routes.iter().find(|rule| rule.name == name).cloned();
+    let before = match change {
+        Change::Put(rule) if !rule.path.starts_with('/') => {
+            return Err(RuleError::Invalid(format!(
+                "route rule {:?}: path must start with /",
+                rule.name
+            )));
+        }
+        Change::Put(rule) => match rules.iter_mut().find(|r| r.name == rule.name) {
+            Some(current) => Some(std::mem::replace(current, (**rule).clone())),
+            None => {
+                rules.insert(0, (**rule).clone());
+                file_rule(&rule.name)
+            }
+        },
+        Change::Delete(name) => match rules.iter().position(|rule| &rule.name == name) {
+            Some(index) => Some(rules.remove(index)),
+            None if file_rule(name).is_some() => return Err(RuleError::InFile),
+            None => return Err(RuleError::Unknown),
+        },
+    };
+    validate_routes(&merge(&rules, file_routes), &CONFIG_FILE.limiters)
+        .map_err(RuleError::Invalid)?;
+    Ok((rules, before))
+}
+
+fn merge(rules: &[RouteRule], file_routes: &[RouteRule]) -> Vec<RouteRule> {
+    let runtime = |name: &str| rules.iter().find(|rule| rule.name == name);
+    rules
+        .iter()
+        .filter(|rule| !file_routes.iter().any(|file| file.name == rule.name))
+        .chain(
+            file_routes
+                .iter()
+                .map(|file| runtime(&file.name).unwrap_or(file)),
+        )
+        .cloned()
+        .collect()
+}
+
+fn put_in_force(rules: Vec<RouteRule>) {
+    *RULES.write().unwrap_or_else(|e| e.into_inner()) = rules;
+    update_limits(|limits| Some(limits.with_runtime_rules()));
+}
+
+fn decode(value: Option<&[u8]>) -> Result<Stored, String> {
+    match value {
+        Some(value) => serde_json::from_slice(value).map_err(|e| format!("invalid rules: {}", e)),
+        None => Ok(Stored::default()),
+    }
+}
+
+fn encode(rules: &[RouteRule]) -> Vec<u8> {
+    serde_json::to_vec(&Stored {
+        rules: rules.to_vec(),
+        written_at: now_seconds(),
+    })
+    .unwrap_or_default()
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SqliteStore;
+
+    fn rule(name: &str, path: &str, max_requests: u32) -> RouteRule {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "path": path,
+            "max_requests": max_requests,
+            "window_seconds": 60,
+        }))
+        .unwrap()
+    }
+
+    fn put(rule: RouteRule) -> Change {
+        Change::Put(Box::new(rule))
+    }
+
+    fn names(rules: &[RouteRule]) -> Vec<&str> {
+        rules.iter().map(|rule| rule.name.as_str()).collect()
+    }
+
+    #[test]
+    fn runtime_rules_apply_ahead_of_the_files_newest_first() {
+        let file = [rule("auth", "/auth/*", 10), rule("api", "/api/*", 100)];
+        let (rules, before) = apply(&[], &put(rule("search", "/search", 5)), &file).unwrap();
+        assert!(before.is_none());
+        let (rules, _) = apply(&rules, &put(rule("export", "/export", 1)), &file).unwrap();
+        assert_eq!(names(&rules), ["export", "search"]);
+        assert_eq!(
+            names(&merge(&rules, &file)),
+            ["export", "search", "auth", "api"]
+        );
+    }
+
+    #[test]
+    fn runtime_rules_named_like_a_files_take_its_place() {
+        let file = [rule("auth", "/auth/*", 10), rule("api", "/api/*", 100)];
+        let (rules, before) = apply(&[], &put(rule("api", "/api/*", 50)), &file).unwrap();
+        assert_eq!(before.unwrap().limit.max_requests, 100);
+        let merged = merge(&rules, &file);
+        assert_eq!(names(&merged), ["auth", "api"]);
+        assert_eq!(merged[1].limit.max_requests, 50);
+
+        // Changing it again replaces the runtime rule.
+        let (rules, before) = apply(&rules, &put(rule("api", "/api/*", 20)), &file).unwrap();
+        assert_eq!(before.unwrap().limit.max_requests, 50);
+        assert_eq!(rules.len(), 1);
+
+        // Deleting it leaves the file's rule in force again.
+        let (rules, before) = apply(&rules, &Change::Delete("api".into()), &file).unwrap();
+        assert_eq!(before.unwrap().limit.max_requests, 20);
+        assert_eq!(merge(&rules, &file)[1].limit.max_requests, 100);
+    }
+
+    #[test]
+    fn only_runtime_rules_are_deleted() {
+        let file = [rule("auth", "/auth/*", 10)];
+        let rules = [rule("search", "/search", 5)];
+        assert!(matches!(
+            apply(&rules, &Change::Delete("auth".into()), &file),
+            Err(RuleError::InFile)
+        ));
+        assert!(matches!(
+            apply(&rules, &Change::Delete("nope".into()), &file),
+            Err(RuleError::Unknown)
+        ));
+        let (rules, _) = apply(&rules, &Change::Delete("search".into()), &file).unwrap();
+        assert!(rules.is_empty());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let file = [rule("auth", "/auth/*", 10)];
+        let invalid =
+            |rule: RouteRule| matches!(apply(&[], &put(rule), &file), Err(RuleError::Invalid(_)));
+        assert!(invalid(rule("search", "search", 5)));
+        assert!(invalid(rule("search", "/search", 0)));
+        assert!(invalid(rule("default", "/search", 5)));
+        let mut bound = rule("search", "/search", 5);
+        bound.limiter = Some("undefined-limiter".into());
+        assert!(invalid(bound));
+    }
+
+    #[tokio::test]
+    async fn rules_are_kept_in_shared_stores() {
+        let path = std::env::temp_dir().join(format!("rules-{:016x}.db", rand::random::<u64>()));
+        let store = SqliteStore::open(path.to_str().unwrap()).await.unwrap();
+        let limiter = RateLimitStateEnum::SqliteStore(store);
+        let stored = || async {
+            let stored = limiter
+                .update_shared(STORE_KEY, TTL, |current| (None, decode(current)))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            stored
+                .rules
+                .into_iter()
+                .map(|rule| rule.name)
+                .collect::<Vec<_>>()
+        };
+
+        let (before, after) = change(&limiter, put(rule("rules-test", "/rules-test", 5)))
+            .await
+            .unwrap();
+        assert!(before.is_none());
+        assert_eq!(after.unwrap().name, "rules-test");
+        assert_eq!(stored().await, ["rules-test"]);
+        assert!(is_runtime("rules-test"));
+        assert!(limits().routes.iter().any(|rule| rule.name == "rules-test"));
+
+        // An instance starting with the rules of the store puts them in force.
+        put_in_force(Vec::new());
+        assert!(!is_runtime("rules-test"));
+        sync(&limiter).await;
+        assert!(is_runtime("rules-test"));
+
+        change(&limiter, Change::Delete("rules-test".into()))
+            .await
+            .unwrap();
+        assert!(stored().await.is_empty());
+        assert!(!is_runtime("rules-test"));
+        assert!(!limits().routes.iter().any(|rule| rule.name == "rules-test"));
+        let _ = std::fs::remove_file(path);
+    }
+}
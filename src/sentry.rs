@@ -0,0 +1,79 @@
+//! Sentry error reporting, built with the `sentry` feature: panics, store
+//! failures and config reload failures are sent to the project of
+//! `SENTRY_DSN`, tagged with the ID of the request they happened in and the
+//! version of the limits in force.
+//!
+//! Events are queued and posted by the SDK's background transport, so
+//! reporting never holds up a request, and each kind of failure is reported
+//! at most once a minute, so an outage of the store does not become a flood
+//! of events.
+
+use dashmap::DashMap;
+use sentry::{ClientInitGuard, ClientOptions, Hub, Level, SentryFutureExt, protocol::Event};
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::{Arc, LazyLock, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::config::{SentryConfig, limits};
+
+/// Least time between two reports of the same kind of failure.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+const RELEASE: &str = concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION"));
+
+/// Kept for the life of the process, so the client is never shut down.
+static GUARD: OnceLock<ClientInitGuard> = OnceLock::new();
+/// When each kind of failure was last reported.
+static REPORTED: LazyLock<DashMap<&'static str, Instant>> = LazyLock::new(DashMap::new);
+
+/// Starts sending events to the project of `config`, and reporting panics.
+pub fn spawn(config: &'static SentryConfig) {
+    let guard = sentry::init(ClientOptions {
+        dsn: Some(config.dsn.clone()),
+        release: Some(RELEASE.into()),
+        environment: config.environment.clone().map(Cow::Owned),
+        // Runs in the panic hook too, so it only looks at the event.
+        before_send: Some(Arc::new(|mut event: Event<'static>| {
+            event
+                .tags
+                .entry("kind".to_string())
+                .or_insert_with(|| "panic".to_string());
+            Some(event)
+        })),
+        ..Default::default()
+    });
+    let _ = GUARD.set(guard);
+}
+
+/// Runs `future`, a request's, so what it reports, panics included, carries
+/// `request_id` and the version of the limits the request started with.
+pub async fn scope<F: Future>(request_id: String, future: F) -> F::Output {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("request_id", request_id);
+        scope.set_tag("config_version", &limits().version);
+    });
+    future.bind_hub(hub).await
+}
+
+/// Reports a failure of `kind`, like `store_failure`, unless one was reported
+/// in the last minute.
+pub fn capture_error(kind: &'static str, error: &str) {
+    if GUARD.get().is_none()
+        || REPORTED
+            .get(kind)
+            .is_some_and(|last| last.elapsed() < MIN_INTERVAL)
+    {
+        return;
+    }
+    REPORTED.insert(kind, Instant::now());
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("kind", kind);
+            scope.set_tag("config_version", &limits().version);
+        },
+        || sentry::capture_message(&format!("{}: {}", kind, error), Level::Error),
+    );
+}
@@ -0,0 +1,427 @@
+//! The rate limit server itself: what `main` runs. Programs embedding the
+//! limiter use the middleware and limiters instead.
+
+use axum::{
+    Router,
+    routing::{any, get},
+};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+
+#[cfg(feature = "bench")]
+use crate::bench;
+use crate::config::{
+    ACCESS_LOG_CONFIG, ADMIN_LISTENER, AUDIT_CONFIG, CLUSTER_CONFIG, CONFIG_FILE, CONFIG_KV,
+    CONFIG_PATH, CONFIG_WATCH_SECONDS, CORS_CONFIG, DYNAMODB_CONFIG, EVENTS_CONFIG,
+    EVICTION_CONFIG, GOSSIP_CONFIG, HYBRID_SYNC_MS, KEY_EXTRACTORS, KEY_HASH_SALT,
+    KeyExtractorKind, LISTEN_ADDR, LOG_SAMPLING_CONFIG, MEMCACHED_CONFIG, METRICS_TOP_KEYS,
+    QUOTA_CONFIG, RATE_LIMIT_ALGORITHM, RATE_LIMIT_MODE, RATE_LIMIT_PROFILE, RATE_LIMITER_BACKEND,
+    RATE_LIMITER_TYPE, REDIS_CONFIG, RateLimitMode, RateLimiterBackend, RateLimiterType,
+    SNAPSHOT_CONFIG, SQLITE_CONFIG, STATSD_CONFIG, STORE_FAILURE_POLICY, STORE_OVERRIDES_CONFIG,
+    StoreFailurePolicy, TIER_LOOKUP_CONFIG, TLS_CONFIG, TRUSTED_PROXIES, UPSTREAM, WEBHOOK_CONFIG,
+    limits,
+};
+use crate::events::EventPublisher;
+use crate::key_extractor::KeyExtractorChain;
+use crate::metrics::RuleDiff;
+use crate::middleware::{
+    MiddlewareState, NamedLimiter, RateLimitLayer, RateLimitStateEnum, SharedLimiter,
+};
+use crate::overrides::StoreOverrides;
+use crate::proxy::Proxy;
+use crate::rate_limiter::{LockFreeRateLimitState, RateLimitState};
+#[cfg(feature = "sentry")]
+use crate::sentry;
+use crate::storage::{
+    ClusterRateLimitState, DynamoDbStore, GossipRateLimitState, HybridRateLimitState,
+    MemcachedStore, MemoryStore, PostgresQuotaStore, RedisRateLimitState, RedisStore, SqliteStore,
+};
+use crate::throttle::Throttle;
+use crate::tier::TierResolver;
+use crate::{
+    access_log, admin, audit, bans, cli, config, cors, denylist, eviction, grpc, health, jwt,
+    kv_config, log_sampling, metrics, proxy, reload, request_id, request_limits, rules, snapshot,
+    stats, statsd, status, storage, store_health, telemetry, tls, top, webhooks,
+};
+
+async fn handler() -> &'static str {
+    "Hello, World!"
+}
+
+/// Runs the server: reads the command line and configuration, starts the
+/// limiter and its background tasks, and serves until the process ends.
+pub async fn run() {
+    // Parse the command line first, so `--help` and bad flags exit right away.
+    cli::parse();
+
+    #[cfg(feature = "bench")]
+    if let Some(cli::Command::Bench) = cli::ARGS.command {
+        bench::run().await;
+        return;
+    }
+
+    telemetry::init();
+
+    // Read every setting up front, so a bad value or config file fails
+    // startup right away instead of falling back to a default.
+    if let Err(errors) = config::validate() {
+        for error in errors {
+            tracing::error!("Invalid configuration: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if let Some(config) = &*ACCESS_LOG_CONFIG {
+        access_log::init(config).unwrap_or_else(|e| panic!("failed to open the access log: {}", e));
+        tracing::info!("Logging requests to {} ({:?})", config.path, config.format);
+    }
+    if let Some(path) = &*CONFIG_PATH {
+        tracing::info!(
+            "Loaded config file {} with {} route rules",
+            path,
+            CONFIG_FILE.routes.len()
+        );
+    }
+
+    // Select rate limiter implementation based on environment variable
+    let limiter = match (*RATE_LIMITER_BACKEND, *RATE_LIMITER_TYPE) {
+        (RateLimiterBackend::Redis, RateLimiterType::Store) => {
+            tracing::info!(
+                "Using {:?} rate limiter over the Redis store at {}",
+                *RATE_LIMIT_ALGORITHM,
+                REDIS_CONFIG.url
+            );
+            RateLimitStateEnum::RedisStore(RedisStore::new(
+                RedisRateLimitState::connect(&REDIS_CONFIG)
+                    .await
+                    .unwrap_or_else(|e| panic!("failed to connect to Redis: {}", e)),
+            ))
+        }
+        (RateLimiterBackend::Redis, _) => {
+            tracing::info!(
+                "Using Redis rate limiter at {} ({:?})",
+                REDIS_CONFIG.url,
+                REDIS_CONFIG.mode
+            );
+            RateLimitStateEnum::Redis(
+                RedisRateLimitState::connect(&REDIS_CONFIG)
+                    .await
+                    .unwrap_or_else(|e| panic!("failed to connect to Redis: {}", e)),
+            )
+        }
+        (RateLimiterBackend::Hybrid, _) => {
+            tracing::info!(
+                "Using hybrid rate limiter syncing with Redis at {} every {}ms",
+                REDIS_CONFIG.url,
+                *HYBRID_SYNC_MS
+            );
+            let state = HybridRateLimitState::new(
+                RedisRateLimitState::connect(&REDIS_CONFIG)
+                    .await
+                    .unwrap_or_else(|e| panic!("failed to connect to Redis: {}", e)),
+            );
+            state.spawn_sync(Duration::from_millis(*HYBRID_SYNC_MS));
+            RateLimitStateEnum::Hybrid(state)
+        }
+        (RateLimiterBackend::Memcached, _) => {
+            tracing::info!(
+                "Using {:?} rate limiter over memcached at {}",
+                *RATE_LIMIT_ALGORITHM,
+                MEMCACHED_CONFIG.servers.join(", ")
+            );
+            RateLimitStateEnum::MemcachedStore(MemcachedStore::new(&MEMCACHED_CONFIG))
+        }
+        (RateLimiterBackend::DynamoDb, _) => {
+            tracing::info!(
+                "Using {:?} rate limiter over DynamoDB table {} in {}",
+                *RATE_LIMIT_ALGORITHM,
+                DYNAMODB_CONFIG.table,
+                DYNAMODB_CONFIG
+                    .endpoint
+                    .as_deref()
+                    .unwrap_or(&DYNAMODB_CONFIG.region)
+            );
+            RateLimitStateEnum::DynamoDbStore(DynamoDbStore::new(&DYNAMODB_CONFIG).await)
+        }
+        (RateLimiterBackend::Sqlite, _) => {
+            tracing::info!(
+                "Using {:?} rate limiter persisted to SQLite at {}",
+                *RATE_LIMIT_ALGORITHM,
+                SQLITE_CONFIG.path
+            );
+            let store = SqliteStore::open(&SQLITE_CONFIG.path)
+                .await
+                .unwrap_or_else(|e| panic!("failed to open SQLite database: {}", e));
+            store.spawn_flush(Duration::from_millis(SQLITE_CONFIG.flush_ms));
+            RateLimitStateEnum::SqliteStore(store)
+        }
+        (RateLimiterBackend::Gossip, _) => {
+            tracing::info!(
+                "Using gossip rate limiter as node {} on {} with peers {:?}",
+                GOSSIP_CONFIG.node_id,
+                GOSSIP_CONFIG.bind,
+                GOSSIP_CONFIG.peers
+            );
+            let state = GossipRateLimitState::new(&GOSSIP_CONFIG.node_id);
+            state
+                .spawn_gossip(&GOSSIP_CONFIG)
+                .await
+                .unwrap_or_else(|e| panic!("failed to bind gossip socket: {}", e));
+            RateLimitStateEnum::Gossip(state)
+        }
+        (RateLimiterBackend::Cluster, _) => {
+            if !CLUSTER_CONFIG.peers.contains(&CLUSTER_CONFIG.self_url) {
+                panic!("RATE_LIMIT_CLUSTER_SELF must be one of RATE_LIMIT_CLUSTER_PEERS");
+            }
+            tracing::info!(
+                "Using cluster rate limiter as {} with peers {:?}",
+                CLUSTER_CONFIG.self_url,
+                CLUSTER_CONFIG.peers
+            );
+            RateLimitStateEnum::Cluster(ClusterRateLimitState::new(&CLUSTER_CONFIG))
+        }
+        (RateLimiterBackend::Memory, RateLimiterType::Standard) => {
+            tracing::info!("Using standard rate limiter");
+            RateLimitStateEnum::Standard(RateLimitState {
+                requests: Arc::new(RwLock::new(HashMap::new())),
+            })
+        }
+        (RateLimiterBackend::Memory, RateLimiterType::LockFree) => {
+            tracing::info!("Using lock-free rate limiter");
+            RateLimitStateEnum::LockFree(LockFreeRateLimitState::new())
+        }
+        (RateLimiterBackend::Memory, RateLimiterType::Store) => {
+            tracing::info!(
+                "Using {:?} rate limiter over the memory store",
+                *RATE_LIMIT_ALGORITHM
+            );
+            RateLimitStateEnum::MemoryStore(MemoryStore::new())
+        }
+    };
+
+    if let Some(config) = &*SNAPSHOT_CONFIG {
+        snapshot::restore(&limiter, config).await;
+    }
+    let limiter = SharedLimiter::new(limiter);
+    if let Some(config) = &*SNAPSHOT_CONFIG {
+        snapshot::spawn_snapshots(limiter.clone(), config);
+    }
+    eviction::spawn_eviction(limiter.clone(), &EVICTION_CONFIG);
+    let fallback_store = MemoryStore::new();
+    if *STORE_FAILURE_POLICY == StoreFailurePolicy::Local {
+        eviction::spawn_eviction(
+            SharedLimiter::new(RateLimitStateEnum::MemoryStore(fallback_store.clone())),
+            &EVICTION_CONFIG,
+        );
+    }
+
+    let limiters: HashMap<String, NamedLimiter> = CONFIG_FILE
+        .limiters
+        .iter()
+        .map(|(name, section)| {
+            tracing::info!("Using {:?} limiter {}", section.kind, name);
+            let limiter = NamedLimiter::new(section);
+            eviction::spawn_eviction(SharedLimiter::new(limiter.state.clone()), &EVICTION_CONFIG);
+            (name.clone(), limiter)
+        })
+        .collect();
+
+    log_sampling::spawn(&LOG_SAMPLING_CONFIG);
+    #[cfg(feature = "sentry")]
+    if let Some(config) = &*config::SENTRY_CONFIG {
+        sentry::spawn(config);
+        tracing::info!(
+            "Reporting errors to Sentry project {} at {}",
+            config.dsn.project_id(),
+            config.dsn.host()
+        );
+    }
+    if let Some(config) = &*AUDIT_CONFIG {
+        audit::spawn(config)
+            .await
+            .unwrap_or_else(|e| panic!("failed to open the audit log: {}", e));
+        tracing::info!("Auditing denials to {:?}", config.sink);
+    }
+    if let Some(config) = WEBHOOK_CONFIG.as_ref() {
+        webhooks::spawn(config);
+        tracing::info!("Sending webhooks for {:?}", config.events);
+    }
+    let named: Vec<(String, SharedLimiter)> =
+        std::iter::once(("default".to_string(), limiter.clone()))
+            .chain(
+                limiters.iter().map(|(name, limiter)| {
+                    (name.clone(), SharedLimiter::new(limiter.state.clone()))
+                }),
+            )
+            .collect();
+    store_health::spawn(named.clone());
+    rules::spawn_sync(limiter.clone()).await;
+    bans::spawn_sync(limiter.clone()).await;
+    if let Some(top_keys) = *METRICS_TOP_KEYS {
+        metrics::spawn_key_labels(top_keys);
+    }
+    if let Some(config) = &*STATSD_CONFIG {
+        statsd::spawn(config, named)
+            .unwrap_or_else(|e| panic!("failed to open StatsD socket: {}", e));
+        tracing::info!("Sending metrics to StatsD at {}", config.addr);
+    }
+    if KEY_EXTRACTORS.contains(&KeyExtractorKind::Jwt) || limits().tier_claim.is_some() {
+        jwt::spawn_jwks_refresh();
+    }
+
+    let quota_store = match &*QUOTA_CONFIG {
+        Some(config) => Some(
+            PostgresQuotaStore::connect(&config.database_url)
+                .await
+                .unwrap_or_else(|e| panic!("failed to connect to Postgres: {}", e)),
+        ),
+        None => None,
+    };
+
+    let state = MiddlewareState {
+        limiter,
+        limiters: Arc::new(limiters),
+        key_extractors: Arc::new(KeyExtractorChain::from_config(&KEY_EXTRACTORS)),
+        tier_resolver: TIER_LOOKUP_CONFIG
+            .as_ref()
+            .map(|config| Arc::new(TierResolver::new(config))),
+        store_overrides: STORE_OVERRIDES_CONFIG
+            .as_ref()
+            .map(|config| Arc::new(StoreOverrides::new(config))),
+        quota_store,
+        fallback_store,
+        events: EVENTS_CONFIG.as_ref().map(|config| {
+            tracing::info!("Publishing decisions to {:?} {}", config.sink, config.topic);
+            EventPublisher::spawn(config)
+        }),
+        throttle: Arc::new(Throttle::default()),
+    };
+
+    eviction::spawn_cache_eviction(&state, &EVICTION_CONFIG);
+    let middleware = RateLimitLayer::new(state.clone());
+    if let RateLimitStateEnum::Cluster(_) = state.limiter.get() {
+        storage::serve_cluster(&CLUSTER_CONFIG, state.clone()).await;
+    }
+
+    let app = Router::new().route("/metrics", get(metrics::metrics_handler));
+    // In proxy mode, every path not served here is forwarded upstream.
+    let app = if proxy::enabled() {
+        if let Some(upstream) = &*UPSTREAM {
+            tracing::info!("Proxying admitted requests to {:?}", upstream.targets);
+        }
+        for rule in limits().routes.iter() {
+            if let Some(upstream) = &rule.upstream {
+                tracing::info!("Proxying {} to {:?}", rule.path, upstream.pool().targets);
+            }
+        }
+        app.fallback_service(any(proxy::forward).with_state(Proxy::spawn()))
+    } else {
+        app.route("/", get(handler))
+    };
+    let mut app = app
+        .layer(middleware)
+        // Added after the layer so asking for the status costs clients
+        // nothing, and probes are always answered.
+        .route("/rate_limit", get(status::status_handler))
+        .route("/healthz", get(health::liveness_handler))
+        .route("/readyz", get(health::readiness_handler));
+    // Operators are not rate limited either. The admin API is only served on
+    // a listener of its own, so it is never reachable through this one.
+    if let Some(config) = ADMIN_LISTENER.as_ref().filter(|_| admin::enabled()) {
+        top::spawn();
+        stats::spawn();
+        let admin = admin::router(&state)
+            .layer(axum::middleware::from_fn(request_limits::limit_body))
+            .layer(axum::middleware::from_fn(grpc::translate))
+            .layer(axum::middleware::from_fn(request_id::propagate))
+            .with_state(state.clone());
+        admin::serve(config, admin).await;
+    }
+    // Around every route, inside the CORS layer so browsers can read these
+    // responses too.
+    app = app
+        .layer(axum::middleware::from_fn(request_limits::limit_body))
+        .layer(axum::middleware::from_fn(request_limits::timeout))
+        // Outside the limits, so the calls they refuse get a gRPC status too.
+        .layer(axum::middleware::from_fn(grpc::translate));
+    // Around the rate limit middleware, so preflights are answered before
+    // they reach it and rejections get the headers too.
+    if let Some(cors) = &*CORS_CONFIG {
+        app = app.layer(cors::layer(cors));
+    }
+    // Outermost, so every response, rejections included, carries the ID.
+    let app = app
+        .layer(axum::middleware::from_fn(request_id::propagate))
+        .with_state(state);
+
+    let addr = *LISTEN_ADDR;
+    tracing::info!("listening on {}", addr);
+    tracing::info!("rate limiter type: {:?}", *RATE_LIMITER_TYPE);
+    tracing::info!("key extractors: {:?}", *KEY_EXTRACTORS);
+    if KEY_HASH_SALT.is_some() && std::env::var("RATE_LIMIT_KEY_SALT").is_err() {
+        tracing::warn!("RATE_LIMIT_KEY_SALT is not set, using a random salt for key hashing");
+    }
+    if TRUSTED_PROXIES.is_empty() {
+        tracing::info!("TRUSTED_PROXIES is not set, client IP headers are ignored");
+    } else {
+        tracing::info!("trusted proxies: {:?}", *TRUSTED_PROXIES);
+    }
+    denylist::spawn_refresh().await;
+    if !limits().allowlist.is_empty() {
+        tracing::info!("allowlisted networks: {}", limits().allowlist.len());
+    }
+    if let Some(profile) = *RATE_LIMIT_PROFILE {
+        tracing::info!("Using the {:?} profile for unset settings", profile);
+    }
+    tracing::info!("rate limit config: {}", limits().default);
+    if *RATE_LIMIT_MODE == RateLimitMode::Shadow {
+        tracing::warn!("Shadow mode: rejections are logged but not enforced");
+    }
+    metrics::record_config_reload(Ok(&RuleDiff::between(&[], &limits().rule_names())));
+    if let Some(schedule) = &limits().schedule {
+        metrics::record_schedule_switch(Some(schedule));
+    }
+    // Limits pulled from a key-value store take the place of the file's, so
+    // the file is not watched then.
+    match (&*CONFIG_KV, &*CONFIG_PATH) {
+        (Some(kv), _) => {
+            tracing::info!(
+                "Loading the config from {:?} key {} at {}",
+                kv.store,
+                kv.key,
+                kv.url
+            );
+            kv_config::spawn_watch(kv).await;
+            reload::spawn_schedules();
+        }
+        (None, Some(path)) => {
+            reload::spawn_reload(path, *CONFIG_WATCH_SECONDS);
+            reload::spawn_schedules();
+        }
+        (None, None) => {}
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    match &*TLS_CONFIG {
+        Some(tls_config) => {
+            let server_config = tls::server_config(tls_config)
+                .unwrap_or_else(|e| panic!("invalid TLS configuration: {}", e));
+            tracing::info!(
+                "serving HTTPS, client certificates: {}",
+                match (&tls_config.client_ca_path, tls_config.client_cert_required) {
+                    (None, _) => "disabled",
+                    (Some(_), false) => "optional",
+                    (Some(_), true) => "required",
+                }
+            );
+            tls::serve(listener, app, server_config).await;
+        }
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::config::{SnapshotConfig, max_window};
+use crate::metrics;
+use crate::middleware::{RateLimitStateEnum, SharedLimiter};
+use crate::rate_limiter::RequestState;
+
+/// Portable form of the in-memory limiters' state, written to disk and
+/// exported through the admin API. Timestamps are wall-clock so they survive
+/// the process that took them.
+#[derive(Serialize, Deserialize)]
+pub enum Snapshot {
+    Standard(HashMap<String, Vec<SystemTime>>),
+    LockFree(HashMap<String, RequestState>),
+}
+
+impl Snapshot {
+    /// The state of `limiter`, none for limiters other than the standard and
+    /// lock-free ones.
+    pub async fn take(limiter: &RateLimitStateEnum) -> Option<Self> {
+        let snapshot = match limiter {
+            RateLimitStateEnum::Standard(state) => Self::Standard(
+                state
+                    .requests
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(key, timestamps)| {
+                        let timestamps = timestamps
+                            .iter()
+                            .map(|time| SystemTime::now() - time.elapsed())
+                            .collect();
+                        (key.clone(), timestamps)
+                    })
+                    .collect(),
+            ),
+            RateLimitStateEnum::LockFree(state) => Self::LockFree(
+                state
+                    .requests
+                    .iter()
+                    .map(|entry| (entry.key().clone(), entry.value().clone()))
+                    .collect(),
+            ),
+            _ => return None,
+        };
+        Some(snapshot)
+    }
+
+    /// Loads the snapshot into `limiter`, over the state it holds for the
+    /// same keys, returning how many keys were loaded. One taken with the
+    /// other limiter type is converted first. Limiters other than the
+    /// standard and lock-free ones load nothing.
+    pub async fn load(self, limiter: &RateLimitStateEnum) -> Option<usize> {
+        match limiter {
+            RateLimitStateEnum::Standard(state) => {
+                let mut current = state.requests.write().await;
+                let mut loaded = 0;
+                for (key, timestamps) in self.into_standard() {
+                    // Timestamps older than the process uptime cannot be
+                    // represented as an `Instant` and are long expired anyway.
+                    let timestamps: Vec<Instant> = timestamps
+                        .into_iter()
+                        .filter_map(|time| Instant::now().checked_sub(elapsed_since(time)))
+                        .collect();
+                    if !timestamps.is_empty() {
+                        current.insert(key, timestamps);
+                        loaded += 1;
+                    }
+                }
+                Some(loaded)
+            }
+            RateLimitStateEnum::LockFree(state) => {
+                let requests = self.into_lock_free();
+                let loaded = requests.len();
+                for (key, request_state) in requests {
+                    state.requests.insert(key, request_state);
+                }
+                Some(loaded)
+            }
+            _ => None,
+        }
+    }
+
+    /// The standard limiter's form. The lock-free limiter only keeps a count
+    /// and the time of the latest request, so each request counted is taken
+    /// as made then.
+    fn into_standard(self) -> HashMap<String, Vec<SystemTime>> {
+        match self {
+            Self::Standard(requests) => requests,
+            Self::LockFree(requests) => requests
+                .into_iter()
+                .filter(|(_, state)| state.count > 0)
+                .map(|(key, state)| (key, vec![state.last_updated; state.count as usize]))
+                .collect(),
+        }
+    }
+
+    /// The lock-free limiter's form, each key's requests within the longest
+    /// window counted as of its latest.
+    fn into_lock_free(self) -> HashMap<String, RequestState> {
+        match self {
+            Self::LockFree(requests) => requests,
+            Self::Standard(requests) => {
+                let window = max_window();
+                requests
+                    .into_iter()
+                    .filter_map(|(key, timestamps)| {
+                        let recent: Vec<SystemTime> = timestamps
+                            .into_iter()
+                            .filter(|&time| elapsed_since(time) < window)
+                            .collect();
+                        let last_updated = recent.iter().max().copied()?;
+                        let state = RequestState {
+                            count: u32::try_from(recent.len()).unwrap_or(u32::MAX),
+                            last_updated,
+                        };
+                        Some((key, state))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Loads the snapshot at the configured path into `limiter`, so clients keep
+/// their used budget across restarts. A missing file is not an error.
+pub async fn restore(limiter: &RateLimitStateEnum, config: &SnapshotConfig) {
+    let bytes = match tokio::fs::read(&config.path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::error!("Failed to read snapshot {}: {}", config.path, e);
+            return;
+        }
+    };
+    let snapshot: Snapshot = match serde_json::from_slice(&bytes) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!("Ignoring invalid snapshot {}: {}", config.path, e);
+            return;
+        }
+    };
+
+    match snapshot.load(limiter).await {
+        Some(restored) => {
+            tracing::info!("Restored {} keys from snapshot {}", restored, config.path)
+        }
+        None => tracing::warn!(
+            "Ignoring snapshot {}, the limiter in use keeps no state in memory",
+            config.path
+        ),
+    }
+}
+
+/// Writes a snapshot of `limiter` every configured interval. Only the
+/// standard and lock-free limiters are snapshotted; the other backends keep
+/// their state outside the process already, and the memory store's expires
+/// within a window. None is written while `limiter` is switched to it.
+pub fn spawn_snapshots(limiter: SharedLimiter, config: &'static SnapshotConfig) {
+    if !matches!(
+        limiter.get(),
+        RateLimitStateEnum::Standard(_) | RateLimitStateEnum::LockFree(_)
+    ) {
+        tracing::warn!("Snapshots are only supported by the standard and lock-free limiters");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+        // The first tick completes immediately; there is nothing to save yet.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let started = Instant::now();
+            let Some(snapshot) = Snapshot::take(&limiter.get()).await else {
+                continue;
+            };
+            let result = write(&snapshot, &config.path).await;
+            metrics::record_snapshot(started.elapsed(), result.is_ok());
+            if let Err(e) = result {
+                tracing::error!("Failed to write snapshot {}: {}", config.path, e);
+            }
+        }
+    });
+}
+
+async fn write(snapshot: &Snapshot, path: &str) -> std::io::Result<()> {
+    // Write to a temporary file first so a crash never leaves a truncated
+    // snapshot behind.
+    let bytes = serde_json::to_vec(snapshot)?;
+    let temporary = Path::new(path).with_extension("tmp");
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+fn elapsed_since(time: SystemTime) -> Duration {
+    SystemTime::now().duration_since(time).unwrap_or_default()
+}
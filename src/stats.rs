@@ -0,0 +1,201 @@
+//! Live stats: what the rate limiter did each second, the requests it
+//! allowed and denied, the keys it saw and how long its store took to
+//! answer, pushed to dashboards on `GET /admin/stats/stream` as server-sent
+//! events or WebSocket messages, and to the gRPC `StreamStats` call. Nothing
+//! is counted while no one listens.
+
+use axum::{
+    body::Body,
+    extract::{
+        WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::{Response, StatusCode},
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use chrono::Utc;
+use dashmap::DashSet;
+use serde::Serialize;
+use std::{
+    convert::Infallible,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Seconds of stats a slow listener may fall behind before missing some.
+const BUFFER: usize = 16;
+
+static STATS: OnceLock<Stats> = OnceLock::new();
+
+struct Stats {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    keys: DashSet<String>,
+    checks: AtomicU64,
+    check_micros: AtomicU64,
+    slowest_check_micros: AtomicU64,
+    /// Each second's stats.
+    seconds: broadcast::Sender<Arc<Second>>,
+}
+
+#[derive(Serialize)]
+pub struct Second {
+    pub time: String,
+    pub allowed: u64,
+    pub denied: u64,
+    /// Distinct keys decided on.
+    pub keys: usize,
+    /// Mean and longest store check, none without checks.
+    pub store_latency_ms: Option<Latency>,
+}
+
+#[derive(Serialize)]
+pub struct Latency {
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// Starts sending each second's stats to whoever listens.
+pub fn spawn() {
+    let (seconds, _) = broadcast::channel(BUFFER);
+    let _ = STATS.set(Stats {
+        allowed: AtomicU64::new(0),
+        denied: AtomicU64::new(0),
+        keys: DashSet::new(),
+        checks: AtomicU64::new(0),
+        check_micros: AtomicU64::new(0),
+        slowest_check_micros: AtomicU64::new(0),
+        seconds,
+    });
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(stats) = STATS.get() else { return };
+            let second = stats.take();
+            if stats.seconds.receiver_count() > 0 {
+                let _ = stats.seconds.send(Arc::new(second));
+            }
+        }
+    });
+}
+
+/// Counts a decision on `key`, denied unless `allowed`.
+pub fn record_decision(key: &str, allowed: bool) {
+    let Some(stats) = listened() else { return };
+    let count = if allowed {
+        &stats.allowed
+    } else {
+        &stats.denied
+    };
+    count.fetch_add(1, Ordering::Relaxed);
+    if !stats.keys.contains(key) {
+        stats.keys.insert(key.to_string());
+    }
+}
+
+/// Notes that the store took `latency` to check a rate limit.
+pub fn record_store_latency(latency: Duration) {
+    let Some(stats) = listened() else { return };
+    let micros = latency.as_micros() as u64;
+    stats.checks.fetch_add(1, Ordering::Relaxed);
+    stats.check_micros.fetch_add(micros, Ordering::Relaxed);
+    stats
+        .slowest_check_micros
+        .fetch_max(micros, Ordering::Relaxed);
+}
+
+/// The stats, if anyone is listening to them.
+fn listened() -> Option<&'static Stats> {
+    STATS
+        .get()
+        .filter(|stats| stats.seconds.receiver_count() > 0)
+}
+
+impl Stats {
+    /// The stats of the second that just ended, starting the next one.
+    fn take(&self) -> Second {
+        let keys = self.keys.len();
+        self.keys.clear();
+        let checks = self.checks.swap(0, Ordering::Relaxed);
+        let check_micros = self.check_micros.swap(0, Ordering::Relaxed);
+        let slowest = self.slowest_check_micros.swap(0, Ordering::Relaxed);
+        Second {
+            time: Utc::now().to_rfc3339(),
+            allowed: self.allowed.swap(0, Ordering::Relaxed),
+            denied: self.denied.swap(0, Ordering::Relaxed),
+            keys,
+            store_latency_ms: (checks > 0).then(|| Latency {
+                mean: (check_micros / checks) as f64 / 1000.0,
+                max: slowest as f64 / 1000.0,
+            }),
+        }
+    }
+}
+
+/// Each second's stats from now on, none if they are not kept.
+pub fn subscribe() -> Option<broadcast::Receiver<Arc<Second>>> {
+    STATS.get().map(|stats| stats.seconds.subscribe())
+}
+
+/// Streams each second's stats as a JSON WebSocket message to clients asking
+/// to upgrade, and as a server-sent event to others.
+pub async fn stream_handler(ws: Option<WebSocketUpgrade>) -> Response<Body> {
+    let Some(seconds) = subscribe() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match ws {
+        Some(ws) => ws.on_upgrade(move |socket| send(socket, seconds)),
+        None => {
+            let events = futures_util::stream::unfold(seconds, |mut seconds| async move {
+                let second = next(&mut seconds).await?;
+                let event = Event::default().data(serde_json::to_string(&*second).ok()?);
+                Some((Ok::<_, Infallible>(event), seconds))
+            });
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+    }
+}
+
+/// Sends the stats to `socket` until it is closed.
+async fn send(mut socket: WebSocket, mut seconds: broadcast::Receiver<Arc<Second>>) {
+    loop {
+        tokio::select! {
+            second = next(&mut seconds) => {
+                let Some(json) = second.and_then(|second| serde_json::to_string(&*second).ok()) else {
+                    return;
+                };
+                if socket.send(Message::Text(json)).await.is_err() {
+                    return;
+                }
+            }
+            // Anything the dashboard sends is ignored, until it closes.
+            message = socket.recv() => {
+                if matches!(message, None | Some(Err(_)) | Some(Ok(Message::Close(_)))) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The next second's stats, skipping those a slow listener missed.
+pub async fn next(seconds: &mut broadcast::Receiver<Arc<Second>>) -> Option<Arc<Second>> {
+    loop {
+        match seconds.recv().await {
+            Ok(second) => return Some(second),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! StatsD export, for setups that do not scrape Prometheus: every counter is
+//! also sent to a StatsD agent as a count, its labels as DogStatsD tags,
+//! along with gauges of the budget clients have left per tier and of the
+//! keys the in-memory limiters hold. Counts are summed in memory and sent
+//! each interval, batched into as few UDP packets as fit.
+
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::config::StatsdConfig;
+use crate::middleware::SharedLimiter;
+
+/// Largest payload of one packet, below the usual MTU once headers are added.
+const MAX_PACKET_BYTES: usize = 1432;
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+struct Sink {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+    /// Counts since the last flush, by metric name and tags.
+    counts: DashMap<(String, String), AtomicU64>,
+    /// Least budget a client of each tier had left since the last flush.
+    remaining: DashMap<String, u64>,
+}
+
+/// Sends metrics to the agent of `config` every interval, with the keys
+/// each of `limiters`, named, holds.
+pub fn spawn(config: &StatsdConfig, limiters: Vec<(String, SharedLimiter)>) -> io::Result<()> {
+    let socket = UdpSocket::bind(match config.addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V6(_)) => "[::]:0",
+        _ => "0.0.0.0:0",
+    })?;
+    socket.connect(&config.addr)?;
+    // Metrics are dropped rather than the server held up when the agent
+    // cannot keep up.
+    socket.set_nonblocking(true)?;
+    let sink = Sink {
+        socket,
+        prefix: config.prefix.clone(),
+        tags: config.tags.iter().map(|tag| sanitize(tag)).collect(),
+        counts: DashMap::new(),
+        remaining: DashMap::new(),
+    };
+    let _ = SINK.set(sink);
+    let interval = Duration::from_secs(config.interval_seconds);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(sink) = SINK.get() else { return };
+            let mut keys = Vec::new();
+            for (name, limiter) in &limiters {
+                if let Some(count) = limiter.get().tracked_keys().await {
+                    keys.push((name.as_str(), count));
+                }
+            }
+            sink.flush(&keys);
+        }
+    });
+    Ok(())
+}
+
+/// Adds `value` to the count of the counter `name` with `labels`.
+pub fn count(name: &str, labels: &[(&str, &str)], value: u64) {
+    let Some(sink) = SINK.get() else { return };
+    let key = (sink.metric(name), tags(labels));
+    if let Some(count) = sink.counts.get(&key) {
+        count.fetch_add(value, Ordering::Relaxed);
+        return;
+    }
+    sink.counts
+        .entry(key)
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(value, Ordering::Relaxed);
+}
+
+/// Notes that a client of `tier` has `remaining` requests of its limit left.
+pub fn observe_remaining(tier: &str, remaining: u64) {
+    let Some(sink) = SINK.get() else { return };
+    sink.remaining
+        .entry(tier.to_string())
+        .and_modify(|least| *least = (*least).min(remaining))
+        .or_insert(remaining);
+}
+
+impl Sink {
+    /// The StatsD name of a Prometheus counter, e.g. `rate_limit.decisions`
+    /// for `rate_limit_decisions_total`.
+    fn metric(&self, name: &str) -> String {
+        let name = name.strip_prefix("rate_limit_").unwrap_or(name);
+        let name = name.strip_suffix("_total").unwrap_or(name);
+        format!("{}.{}", self.prefix, name)
+    }
+
+    /// Sends the counts and gauges gathered since the last flush.
+    fn flush(&self, keys: &[(&str, usize)]) {
+        let mut lines = Vec::new();
+        for entry in self.counts.iter() {
+            let value = entry.value().swap(0, Ordering::Relaxed);
+            if value > 0 {
+                let (name, tags) = entry.key();
+                lines.push(self.line(name, value, "c", tags));
+            }
+        }
+        let remaining: Vec<(String, u64)> = self
+            .remaining
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.remaining.clear();
+        for (tier, least) in remaining {
+            let name = format!("{}.remaining", self.prefix);
+            lines.push(self.line(&name, least, "g", &tags(&[("tier", &tier)])));
+        }
+        for (limiter, count) in keys {
+            let name = format!("{}.keys", self.prefix);
+            let tags = tags(&[("limiter", limiter)]);
+            lines.push(self.line(&name, *count as u64, "g", &tags));
+        }
+        self.send(&lines);
+    }
+
+    fn line(&self, name: &str, value: u64, kind: &str, tags: &str) -> String {
+        let mut line = format!("{}:{}|{}", name, value, kind);
+        let constant = self.tags.join(",");
+        let tags: Vec<&str> = [tags, constant.as_str()]
+            .into_iter()
+            .filter(|tags| !tags.is_empty())
+            .collect();
+        if !tags.is_empty() {
+            let _ = write!(line, "|#{}", tags.join(","));
+        }
+        line
+    }
+
+    /// Sends `lines` in packets of up to `MAX_PACKET_BYTES`.
+    fn send(&self, lines: &[String]) {
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+                let _ = self.socket.send(packet.as_bytes());
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        if !packet.is_empty() {
+            let _ = self.socket.send(packet.as_bytes());
+        }
+    }
+}
+
+/// DogStatsD tags of `labels`, like `result:allow,tier:free`.
+fn tags(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}:{}", sanitize(key), sanitize(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Replaces the characters that delimit the parts of a StatsD line.
+fn sanitize(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n', '@'], "_")
+}
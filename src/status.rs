@@ -0,0 +1,109 @@
+//! `GET /rate_limit`: where the caller stands against its limits, read
+//! without counting a request, so clients can check before a burst of work.
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Query, State},
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::client_ip::{client_ip, is_allowlisted, is_denylisted};
+use crate::config::{ANONYMOUS_POLICY, AnonymousPolicy, QUOTA_CONFIG, limits};
+use crate::denylist::denylist;
+use crate::metrics;
+use crate::middleware::{Identity, MiddlewareState, identify, limiter, rule_key};
+use crate::rate_limiter::{QuotaLimiter, RateLimitDecision, RateLimiter};
+use crate::rejection::seconds;
+
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    /// Path whose route rule to report on, `/` by default.
+    path: Option<String>,
+}
+
+pub async fn status_handler(
+    State(state): State<MiddlewareState>,
+    Query(query): Query<StatusQuery>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let ip = client_ip(&req).unwrap_or_else(|| "unknown".to_string());
+    if is_denylisted(&denylist(), &ip) {
+        return (StatusCode::FORBIDDEN, "Forbidden.").into_response();
+    }
+    let limits = limits();
+    let path = query.path.as_deref().unwrap_or("/");
+    // Clients the limits do not apply to have nothing to report.
+    let unlimited = Json(json!({
+        "rule": null,
+        "tier": null,
+        "rate_limit": null,
+        "quota": null,
+    }));
+    if limits.is_exempt(path) || is_allowlisted(&limits.allowlist, &ip) {
+        return unlimited.into_response();
+    }
+
+    let identity = Identity::of(&state, &limits, &req, &ip);
+    let Some(client) = identify(&state, &limits, req.headers(), identity).await else {
+        return match *ANONYMOUS_POLICY {
+            AnonymousPolicy::Reject => {
+                (StatusCode::FORBIDDEN, "Unable to identify client.").into_response()
+            }
+            _ => unlimited.into_response(),
+        };
+    };
+    let tier = client.tier.clone();
+    let route_rule = limits.routes.iter().find(|rule| rule.matches(path));
+    let (key, config, rule) = rule_key(client, route_rule, path);
+
+    let rate_limit = match limiter(&state, route_rule, &config).peek(&key).await {
+        Ok(decision) => decision,
+        Err(error) => {
+            metrics::record_store_error("rate_limit");
+            tracing::error!("Rate limit store failed, cannot report status: {}", error);
+            return unavailable();
+        }
+    };
+    let quota = match (&state.quota_store, &*QUOTA_CONFIG) {
+        (Some(store), Some(quota)) => {
+            match QuotaLimiter::new(store.clone(), quota).peek(&key).await {
+                Ok(decision) => {
+                    let mut status = describe(&decision);
+                    status["period"] = json!(quota.period);
+                    Some(status)
+                }
+                Err(error) => {
+                    metrics::record_store_error("quota");
+                    tracing::error!("Quota store failed, cannot report status: {}", error);
+                    return unavailable();
+                }
+            }
+        }
+        _ => None,
+    };
+
+    Json(json!({
+        "rule": rule,
+        "tier": tier,
+        "rate_limit": describe(&rate_limit),
+        "quota": quota,
+    }))
+    .into_response()
+}
+
+pub fn describe(decision: &RateLimitDecision) -> Value {
+    json!({
+        "limit": decision.limit,
+        "window_seconds": seconds(decision.window),
+        "remaining": decision.remaining,
+        "reset_seconds": seconds(decision.reset),
+    })
+}
+
+pub fn unavailable() -> Response<Body> {
+    (StatusCode::SERVICE_UNAVAILABLE, "Rate limiter unavailable.").into_response()
+}
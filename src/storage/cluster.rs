@@ -0,0 +1,518 @@
+use async_trait::async_trait;
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{post, put},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+
+use super::MemoryStore;
+use crate::bans::{self, Replica};
+use crate::config::{ClusterConfig, RATE_LIMIT_ALGORITHM, RateLimitConfig};
+use crate::middleware::{MiddlewareState, RateLimitStateEnum};
+use crate::rate_limiter::{
+    MapHealth, RateLimitDecision, RateLimitError, RateLimiter, StoreRateLimiter,
+};
+
+/// Points per node on the ring, evening out how many keys each node owns.
+const VIRTUAL_NODES: usize = 100;
+/// Header carrying `RATE_LIMIT_CLUSTER_SECRET` on internal requests.
+const SECRET_HEADER: &str = "x-rate-limit-cluster-secret";
+/// Path nodes forward decisions to, on the cluster listener.
+const DECISION_PATH: &str = "/internal/rate_limit";
+/// Path nodes send their bans to, on the cluster listener.
+const BANS_PATH: &str = "/internal/bans";
+
+#[derive(Serialize, Deserialize)]
+pub struct DecisionRequest {
+    key: String,
+    max_requests: u32,
+    window_ms: u64,
+    /// Asks where the key stands without counting a request. Nodes predating
+    /// it count one.
+    #[serde(default)]
+    peek: bool,
+    /// Asks to forget the key's requests instead, for `DELETE
+    /// /admin/keys/{key}` on another node. Only answered on the cluster
+    /// listener with the secret, so clients cannot clear their own counts.
+    /// Nodes predating it count one.
+    #[serde(default)]
+    reset: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DecisionResponse {
+    allowed: bool,
+    /// Missing in answers from nodes predating them.
+    #[serde(default)]
+    remaining: u64,
+    #[serde(default)]
+    reset_ms: u64,
+}
+
+/// Consistent hash ring mapping each key to the node owning it, so adding or
+/// removing a node only moves the keys of its neighbours.
+struct HashRing {
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    fn new(nodes: &[String]) -> Self {
+        let points = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES).map(move |point| (hash(&format!("{}#{}", node, point)), index))
+            })
+            .collect();
+        Self { points }
+    }
+
+    fn owner(&self, key: &str) -> Option<usize> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, index)| *index)
+    }
+}
+
+/// Cluster membership plus the state of the keys this node owns.
+#[derive(Clone)]
+pub struct ClusterRateLimitState {
+    ring: Arc<HashRing>,
+    config: &'static ClusterConfig,
+    client: reqwest::Client,
+    local: MemoryStore,
+}
+
+impl ClusterRateLimitState {
+    pub fn new(config: &'static ClusterConfig) -> Self {
+        Self {
+            ring: Arc::new(HashRing::new(&config.peers)),
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.timeout_ms))
+                .build()
+                .expect("failed to build cluster HTTP client"),
+            local: MemoryStore::new(),
+        }
+    }
+
+    /// Checks and records a request for a key this node owns.
+    async fn decide_locally(
+        &self,
+        key: &str,
+        config: Arc<RateLimitConfig>,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        StoreRateLimiter::new(self.local.clone(), *RATE_LIMIT_ALGORITHM, config)
+            .check_rate_limit(key)
+            .await
+    }
+
+    /// Reads where a key this node owns stands, without counting a request.
+    async fn peek_locally(
+        &self,
+        key: &str,
+        config: Arc<RateLimitConfig>,
+    ) -> Result<RateLimitDecision, String> {
+        StoreRateLimiter::new(self.local.clone(), *RATE_LIMIT_ALGORITHM, config)
+            .peek(key)
+            .await
+    }
+
+    /// Forgets the requests of a key this node owns, or decided while its
+    /// owner was unreachable.
+    async fn reset_locally(&self, key: &str, config: Arc<RateLimitConfig>) -> Result<(), String> {
+        StoreRateLimiter::new(self.local.clone(), *RATE_LIMIT_ALGORITHM, config)
+            .reset(key)
+            .await
+    }
+
+    /// Drops expired state of the keys this node owns, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        self.local.evict_expired()
+    }
+
+    /// Keys of this node's share held here.
+    pub fn tracked_keys(&self) -> usize {
+        self.local.tracked_keys()
+    }
+
+    pub fn health(&self) -> MapHealth {
+        self.local.health()
+    }
+
+    async fn forward(
+        &self,
+        owner: &str,
+        key: &str,
+        config: &RateLimitConfig,
+        ask: Ask,
+    ) -> Result<DecisionResponse, reqwest::Error> {
+        let mut request = self
+            .client
+            .post(format!("{}{}", owner, DECISION_PATH))
+            .json(&DecisionRequest {
+                key: key.to_string(),
+                max_requests: config.max_requests,
+                window_ms: config.window.as_millis() as u64,
+                peek: ask == Ask::Peek,
+                reset: ask == Ask::Reset,
+            });
+        request = request.header(SECRET_HEADER, &self.config.secret);
+        request.send().await?.error_for_status()?.json().await
+    }
+
+    /// Sends the bans this node knows and those it lifted to the other
+    /// nodes, which have no store to read them from.
+    pub async fn send_bans(&self, replica: &Replica) {
+        let peers = self
+            .config
+            .peers
+            .iter()
+            .filter(|peer| **peer != self.config.self_url);
+        for peer in peers {
+            let sent = self
+                .client
+                .put(format!("{}{}", peer, BANS_PATH))
+                .header(SECRET_HEADER, &self.config.secret)
+                .json(replica)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                tracing::warn!("Failed to send the bans to {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+/// What a node is asked about a key it owns.
+#[derive(Clone, Copy, PartialEq)]
+enum Ask {
+    Decision,
+    Peek,
+    Reset,
+}
+
+/// Sends each key's decision to the node owning it, which checks and records
+/// the request in one step. Keys whose owner is unreachable are decided
+/// locally, so an outage only loosens limits instead of failing requests.
+#[derive(Clone)]
+pub struct ClusterRateLimiter {
+    state: ClusterRateLimitState,
+    config: Arc<RateLimitConfig>,
+}
+
+impl ClusterRateLimiter {
+    pub fn new(state: ClusterRateLimitState, config: Arc<RateLimitConfig>) -> Self {
+        Self { state, config }
+    }
+
+    /// The node owning `ip`, unless it is this one.
+    fn remote_owner(&self, ip: &str) -> Option<&'static str> {
+        let config = self.state.config;
+        self.state
+            .ring
+            .owner(ip)
+            .map(|index| config.peers[index].as_str())
+            .filter(|owner| *owner != config.self_url)
+    }
+}
+
+#[async_trait]
+impl RateLimiter for ClusterRateLimiter {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        let Some(owner) = self.remote_owner(ip) else {
+            return self.state.decide_locally(ip, self.config.clone()).await;
+        };
+
+        match self
+            .state
+            .forward(owner, ip, &self.config, Ask::Decision)
+            .await
+        {
+            Ok(response) if response.allowed => Ok(RateLimitDecision {
+                limit: u64::from(self.config.max_requests),
+                window: self.config.window,
+                remaining: response.remaining,
+                reset: Duration::from_millis(response.reset_ms),
+            }),
+            Ok(response) => Err(RateLimitError::rate_limit_exceeded(
+                u64::from(self.config.max_requests),
+                self.config.window,
+                Duration::from_millis(response.reset_ms),
+            )),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to forward decision to {}, deciding locally: {}",
+                    owner,
+                    e
+                );
+                self.state.decide_locally(ip, self.config.clone()).await
+            }
+        }
+    }
+
+    async fn record_request(&self, _ip: &str) {}
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let Some(owner) = self.remote_owner(ip) else {
+            return self.state.peek_locally(ip, self.config.clone()).await;
+        };
+
+        // Peeks are answered as allowed unless the owner could not read the
+        // key.
+        match self.state.forward(owner, ip, &self.config, Ask::Peek).await {
+            Ok(response) if response.allowed => Ok(RateLimitDecision {
+                limit: u64::from(self.config.max_requests),
+                window: self.config.window,
+                remaining: response.remaining,
+                reset: Duration::from_millis(response.reset_ms),
+            }),
+            Ok(_) => Err(format!("{} failed to read the key", owner)),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to forward peek to {}, peeking locally: {}",
+                    owner,
+                    e
+                );
+                self.state.peek_locally(ip, self.config.clone()).await
+            }
+        }
+    }
+
+    /// Resets the key here too, in case requests were decided here while
+    /// its owner was unreachable.
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.state.reset_locally(ip, self.config.clone()).await?;
+        let Some(owner) = self.remote_owner(ip) else {
+            return Ok(());
+        };
+        match self
+            .state
+            .forward(owner, ip, &self.config, Ask::Reset)
+            .await
+        {
+            Ok(response) if response.allowed => Ok(()),
+            Ok(_) => Err(format!("{} failed to reset the key", owner)),
+            Err(e) => Err(format!("failed to forward the reset to {}: {}", owner, e)),
+        }
+    }
+}
+
+/// Serves `POST /internal/rate_limit` and `PUT /internal/bans` to the other
+/// nodes on the cluster listener, so the public one never answers them.
+pub async fn serve_cluster(config: &ClusterConfig, state: MiddlewareState) {
+    let listener = TcpListener::bind(config.listen).await.unwrap_or_else(|e| {
+        panic!(
+            "failed to bind the cluster listener {}: {}",
+            config.listen, e
+        )
+    });
+    let app = Router::new()
+        .route(DECISION_PATH, post(decision_handler))
+        .route(BANS_PATH, put(bans_handler))
+        .with_state(state);
+    tracing::info!("Serving other nodes' decisions on {}", config.listen);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("Failed to serve the cluster listener: {}", e);
+        }
+    });
+}
+
+/// `POST /internal/rate_limit`: decides a request forwarded by another node
+/// for a key this node owns.
+async fn decision_handler(
+    State(state): State<MiddlewareState>,
+    headers: HeaderMap,
+    Json(request): Json<DecisionRequest>,
+) -> Response {
+    let RateLimitStateEnum::Cluster(cluster) = state.limiter.get() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !authorized(cluster.config, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    // The sender picked the limit, so nodes agree on it even while their
+    // configurations differ during a reload.
+    let config = Arc::new(RateLimitConfig {
+        max_requests: request.max_requests,
+        window: Duration::from_millis(request.window_ms),
+    });
+    if request.reset {
+        let reset = cluster.reset_locally(&request.key, config).await;
+        return Json(DecisionResponse {
+            allowed: reset.is_ok(),
+            remaining: u64::from(request.max_requests),
+            reset_ms: 0,
+        })
+        .into_response();
+    }
+    if request.peek {
+        let decision = cluster.peek_locally(&request.key, config).await;
+        return Json(DecisionResponse {
+            allowed: decision.is_ok(),
+            remaining: decision.as_ref().map_or(0, |decision| decision.remaining),
+            reset_ms: decision.map_or(0, |decision| decision.reset.as_millis() as u64),
+        })
+        .into_response();
+    }
+    let response = match cluster.decide_locally(&request.key, config).await {
+        Ok(decision) => DecisionResponse {
+            allowed: true,
+            remaining: decision.remaining,
+            reset_ms: decision.reset.as_millis() as u64,
+        },
+        Err(RateLimitError::Exceeded { decision, .. }) => DecisionResponse {
+            allowed: false,
+            remaining: 0,
+            reset_ms: decision.reset.as_millis() as u64,
+        },
+        Err(RateLimitError::Unavailable(_)) => DecisionResponse {
+            allowed: false,
+            remaining: 0,
+            reset_ms: 0,
+        },
+    };
+    Json(response).into_response()
+}
+
+/// `PUT /internal/bans`: puts in force the bans another node knows, and
+/// lifts those it lifted.
+async fn bans_handler(
+    State(state): State<MiddlewareState>,
+    headers: HeaderMap,
+    Json(replica): Json<Replica>,
+) -> StatusCode {
+    let RateLimitStateEnum::Cluster(cluster) = state.limiter.get() else {
+        return StatusCode::NOT_FOUND;
+    };
+    if !authorized(cluster.config, &headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    bans::merge(replica);
+    StatusCode::NO_CONTENT
+}
+
+/// Whether `headers` carry the cluster secret.
+fn authorized(config: &ClusterConfig, headers: &HeaderMap) -> bool {
+    // Compared in constant time, so the secret cannot be guessed by timing.
+    let sent = headers
+        .get(SECRET_HEADER)
+        .map_or(&[][..], |value| value.as_bytes());
+    !config.secret.is_empty() && bool::from(sent.ct_eq(config.secret.as_bytes()))
+}
+
+fn hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value);
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (1..=count)
+            .map(|node| format!("http://10.0.0.{}:3001", node))
+            .collect()
+    }
+
+    #[test]
+    fn every_key_has_one_owner_and_keys_are_spread() {
+        let ring = HashRing::new(&nodes(3));
+        let mut owned = [0; 3];
+        for key in 0..3000 {
+            let key = format!("ip:{}", key);
+            let owner = ring.owner(&key).unwrap();
+            assert_eq!(ring.owner(&key), Some(owner));
+            owned[owner] += 1;
+        }
+        assert!(owned.iter().all(|keys| *keys > 500), "{:?}", owned);
+
+        assert_eq!(HashRing::new(&nodes(1)).owner("ip:1"), Some(0));
+        assert_eq!(HashRing::new(&[]).owner("ip:1"), None);
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = HashRing::new(&nodes(3));
+        let after = HashRing::new(&nodes(4));
+        let mut moved = 0;
+        for key in 0..3000 {
+            let key = format!("ip:{}", key);
+            let (old, new) = (before.owner(&key).unwrap(), after.owner(&key).unwrap());
+            if old != new {
+                assert_eq!(new, 3);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 1500, "{} keys moved", moved);
+    }
+
+    fn cluster_state(secret: &str) -> MiddlewareState {
+        let config = Box::leak(Box::new(ClusterConfig {
+            peers: vec!["http://127.0.0.1:3101".to_string()],
+            self_url: "http://127.0.0.1:3101".to_string(),
+            listen: "127.0.0.1:3101".parse().unwrap(),
+            secret: secret.to_string(),
+            timeout_ms: 200,
+        }));
+        MiddlewareState::new(RateLimitStateEnum::Cluster(ClusterRateLimitState::new(
+            config,
+        )))
+    }
+
+    async fn decide(state: &MiddlewareState, secret: Option<&str>) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = secret {
+            headers.insert(SECRET_HEADER, secret.parse().unwrap());
+        }
+        let request = DecisionRequest {
+            key: "ip:203.0.113.7".to_string(),
+            max_requests: 10,
+            window_ms: 60_000,
+            peek: false,
+            reset: false,
+        };
+        decision_handler(State(state.clone()), headers, Json(request))
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn decisions_need_the_secret() {
+        let state = cluster_state("s3cret");
+        assert_eq!(decide(&state, Some("s3cret")).await, StatusCode::OK);
+        assert_eq!(decide(&state, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            decide(&state, Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            decide(&state, Some("s3cre")).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // An empty secret never matches, not even an empty header.
+        let state = cluster_state("");
+        assert_eq!(decide(&state, Some("")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(decide(&state, None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn other_backends_do_not_answer_decisions() {
+        let state = MiddlewareState::new(RateLimitStateEnum::MemoryStore(MemoryStore::new()));
+        assert_eq!(decide(&state, Some("s3cret")).await, StatusCode::NOT_FOUND);
+    }
+}
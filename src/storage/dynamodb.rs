@@ -0,0 +1,143 @@
+use aws_config::{BehaviorVersion, Region, timeout::TimeoutConfig};
+use aws_sdk_dynamodb::{
+    Client,
+    error::{DisplayErrorContext, SdkError},
+    operation::put_item::PutItemError,
+    primitives::Blob,
+    types::AttributeValue,
+};
+use aws_smithy_http_client::{
+    Builder,
+    tls::{Provider, rustls_provider::CryptoMode},
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::RateLimitStore;
+use crate::config::DynamoDbConfig;
+
+/// [`RateLimitStore`] over a DynamoDB table.
+///
+/// Each key is one item with its value in `value` and its expiry in
+/// `expires_at` (Unix seconds). Compare-and-swap is a conditional `PutItem`,
+/// so replicas never overwrite each other's updates. DynamoDB's TTL deletes
+/// expired items only eventually, so reads treat them as missing themselves.
+///
+/// Credentials come from the AWS SDK's default provider chain (environment,
+/// profile, SSO, web identity, ECS or instance metadata) and are refreshed
+/// before they expire.
+#[derive(Clone)]
+pub struct DynamoDbStore {
+    client: Client,
+    config: &'static DynamoDbConfig,
+}
+
+impl DynamoDbStore {
+    pub async fn new(config: &'static DynamoDbConfig) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let mut loader = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(config.region.clone()))
+            .timeout_config(TimeoutConfig::builder().operation_timeout(timeout).build())
+            .http_client(
+                Builder::new()
+                    .tls_provider(Provider::Rustls(CryptoMode::Ring))
+                    .build_https(),
+            );
+        if let Some(endpoint) = &config.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        Self {
+            client: Client::new(&loader.load().await),
+            config,
+        }
+    }
+
+    /// Checks that the table can be reached with the credentials.
+    pub async fn ping(&self) -> Result<(), String> {
+        self.client
+            .describe_table()
+            .table_name(&self.config.table)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("DynamoDB DescribeTable failed: {}", DisplayErrorContext(e)))
+    }
+
+    fn key(&self, key: &str) -> AttributeValue {
+        AttributeValue::S(format!("{}{}", self.config.key_prefix, key))
+    }
+}
+
+impl RateLimitStore for DynamoDbStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self
+            .client
+            .get_item()
+            .table_name(&self.config.table)
+            .key("pk", self.key(key))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| format!("DynamoDB GetItem failed: {}", DisplayErrorContext(e)))?;
+        let Some(item) = response.item else {
+            return Ok(None);
+        };
+        let expires_at: u64 = match item.get("expires_at") {
+            Some(AttributeValue::N(n)) => n.parse().unwrap_or(0),
+            _ => 0,
+        };
+        if expires_at <= now_seconds() {
+            return Ok(None);
+        }
+        match item.get("value") {
+            Some(AttributeValue::B(value)) => Ok(Some(value.clone().into_inner())),
+            _ => Ok(None),
+        }
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, String> {
+        let expires_at = now_seconds() + ttl.as_millis().div_ceil(1000).max(1) as u64;
+        let request = self
+            .client
+            .put_item()
+            .table_name(&self.config.table)
+            .item("pk", self.key(key))
+            .item("value", AttributeValue::B(Blob::new(new)))
+            .item("expires_at", AttributeValue::N(expires_at.to_string()));
+        let request = match current {
+            Some(current) => request
+                .condition_expression("#value = :current")
+                .expression_attribute_names("#value", "value")
+                .expression_attribute_values(":current", AttributeValue::B(Blob::new(current))),
+            // Items past their expiry may still exist, so they count as
+            // missing.
+            None => request
+                .condition_expression("attribute_not_exists(pk) OR expires_at <= :now")
+                .expression_attribute_values(":now", AttributeValue::N(now_seconds().to_string())),
+        };
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(e))
+                if matches!(e.err(), PutItemError::ConditionalCheckFailedException(_)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(format!(
+                "DynamoDB PutItem failed: {}",
+                DisplayErrorContext(e)
+            )),
+        }
+    }
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
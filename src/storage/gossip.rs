@@ -0,0 +1,550 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+
+use crate::bans::{self, Replica};
+use crate::config::{GossipConfig, RateLimitConfig};
+use crate::rate_limiter::{MapHealth, RateLimitDecision, RateLimitError, RateLimiter, map_health};
+
+/// Entries per datagram, keeping messages well below the UDP size limit.
+const ENTRIES_PER_MESSAGE: usize = 50;
+/// Every this many rounds all of a node's counts are resent, repairing
+/// counts lost with dropped datagrams.
+const FULL_SYNC_ROUNDS: u64 = 10;
+/// Length of the HMAC-SHA256 each datagram starts with.
+const TAG_LEN: usize = 32;
+/// Largest payload of a UDP datagram.
+const MAX_DATAGRAM: usize = 65_507;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// G-Counter of one key for one window: each node only ever increments its
+/// own component, and replicas merge by taking the maximum per node.
+struct KeyCounter {
+    /// Index of the window since the Unix epoch, so nodes agree on windows.
+    epoch: u64,
+    window_ms: u64,
+    counts: HashMap<String, u64>,
+    /// Requests of the window a reset on this node took back, as the counts
+    /// themselves only ever grow.
+    forgiven: u64,
+}
+
+impl KeyCounter {
+    fn new(epoch: u64, window_ms: u64) -> Self {
+        Self {
+            epoch,
+            window_ms,
+            counts: HashMap::new(),
+            forgiven: 0,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts
+            .values()
+            .sum::<u64>()
+            .saturating_sub(self.forgiven)
+    }
+
+    /// Moves the counter to the window `epoch`, dropping the counts of the
+    /// one before.
+    fn start(&mut self, epoch: u64) {
+        self.epoch = epoch;
+        self.counts.clear();
+        self.forgiven = 0;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct GossipEntry {
+    key: String,
+    epoch: u64,
+    window_ms: u64,
+    count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GossipMessage {
+    node: String,
+    entries: Vec<GossipEntry>,
+    /// The bans the node knows and those it lifted, sent in full rounds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bans: Option<Replica>,
+}
+
+/// Per-key counters of this node and what it heard from its peers.
+#[derive(Clone)]
+pub struct GossipRateLimitState {
+    node_id: Arc<str>,
+    counters: Arc<DashMap<String, KeyCounter>>,
+    dirty: Arc<Mutex<HashSet<String>>>,
+}
+
+impl GossipRateLimitState {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.into(),
+            counters: Arc::new(DashMap::new()),
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Binds the gossip socket, then sends this node's changed counts to
+    /// every peer each interval and merges the counts peers send back.
+    ///
+    /// Only datagrams from the addresses of the peers, signed with the shared
+    /// secret, are merged, so nobody else can make up counts.
+    pub async fn spawn_gossip(&self, config: &GossipConfig) -> std::io::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(config.bind).await?);
+        let secret: Arc<[u8]> = config.secret.as_bytes().into();
+
+        let state = self.clone();
+        let receiver = socket.clone();
+        let peers: HashSet<IpAddr> = config
+            .peers
+            .iter()
+            .map(|peer| peer.ip().to_canonical())
+            .collect();
+        let key = secret.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 65_536];
+            loop {
+                match receiver.recv_from(&mut buffer).await {
+                    Ok((len, from)) => {
+                        if let Some(message) = accept(&peers, &key, from, &buffer[..len]) {
+                            state.merge(message);
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to receive gossip: {}", e),
+                }
+            }
+        });
+
+        let state = self.clone();
+        let peers = config.peers.clone();
+        let interval = Duration::from_millis(config.interval_ms);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            for round in 1.. {
+                ticker.tick().await;
+                state
+                    .send(&socket, &peers, &secret, round % FULL_SYNC_ROUNDS == 0)
+                    .await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn send(&self, socket: &UdpSocket, peers: &[SocketAddr], secret: &[u8], full: bool) {
+        let keys: Vec<String> = if full {
+            self.counters
+                .iter()
+                .map(|entry| entry.key().clone())
+                .collect()
+        } else {
+            std::mem::take(&mut *self.dirty.lock().unwrap_or_else(|e| e.into_inner()))
+                .into_iter()
+                .collect()
+        };
+
+        let mut entries: Vec<GossipEntry> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let counter = self.counters.get(&key)?;
+                let count = *counter.counts.get(&*self.node_id)?;
+                Some(GossipEntry {
+                    epoch: counter.epoch,
+                    window_ms: counter.window_ms,
+                    count,
+                    key,
+                })
+            })
+            .collect();
+
+        while !entries.is_empty() {
+            let rest = entries.split_off(entries.len().min(ENTRIES_PER_MESSAGE));
+            let message = sign(
+                secret,
+                &GossipMessage {
+                    node: self.node_id.to_string(),
+                    entries,
+                    bans: None,
+                },
+            );
+            send_to_all(socket, peers, &message).await;
+            entries = rest;
+        }
+
+        let replica = bans::replica();
+        if !full || replica.is_empty() {
+            return;
+        }
+        let message = sign(
+            secret,
+            &GossipMessage {
+                node: self.node_id.to_string(),
+                entries: Vec::new(),
+                bans: Some(replica),
+            },
+        );
+        if message.len() > MAX_DATAGRAM {
+            tracing::warn!(
+                "Not gossiping the bans, {} bytes do not fit in a datagram",
+                message.len()
+            );
+            return;
+        }
+        send_to_all(socket, peers, &message).await;
+    }
+
+    fn merge(&self, message: GossipMessage) {
+        if *message.node == *self.node_id {
+            return;
+        }
+        if let Some(replica) = message.bans {
+            bans::merge(replica);
+        }
+        for entry in message.entries {
+            let current_epoch = epoch(entry.window_ms);
+            if entry.epoch != current_epoch {
+                continue;
+            }
+            let mut counter = self
+                .counters
+                .entry(entry.key)
+                .or_insert_with(|| KeyCounter::new(entry.epoch, entry.window_ms));
+            if counter.epoch < entry.epoch {
+                counter.start(entry.epoch);
+            }
+            if counter.epoch == entry.epoch {
+                let count = counter.counts.entry(message.node.clone()).or_default();
+                *count = (*count).max(entry.count);
+            }
+        }
+    }
+
+    pub fn tracked_keys(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn health(&self) -> MapHealth {
+        map_health(&self.counters, |counter| {
+            counter
+                .counts
+                .keys()
+                .map(|node| size_of::<(String, u64)>() + node.capacity())
+                .sum()
+        })
+    }
+
+    /// Drops counters of windows that have ended, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        let before = self.counters.len();
+        self.counters
+            .retain(|_, counter| counter.epoch >= epoch(counter.window_ms));
+        before.saturating_sub(self.counters.len())
+    }
+}
+
+/// Limits each key by the sum of the counts all nodes gossiped for the
+/// current fixed window.
+///
+/// Counts from peers arrive up to one gossip interval late, so the cluster
+/// may over-admit by what the other nodes admit in that time.
+#[derive(Clone)]
+pub struct GossipRateLimiter {
+    state: GossipRateLimitState,
+    config: Arc<RateLimitConfig>,
+}
+
+impl GossipRateLimiter {
+    pub fn new(state: GossipRateLimitState, config: Arc<RateLimitConfig>) -> Self {
+        Self { state, config }
+    }
+
+    /// Requests all nodes counted for the key in the current window, and the
+    /// time until the window ends.
+    fn total(&self, ip: &str) -> (u64, Duration) {
+        let window_ms = window_ms(&self.config);
+        let total = self
+            .state
+            .counters
+            .get(ip)
+            .filter(|counter| counter.epoch == epoch(window_ms))
+            .map(|counter| counter.total())
+            .unwrap_or(0);
+        let reset = Duration::from_millis(window_ms.max(1) - now_ms() % window_ms.max(1));
+        (total, reset)
+    }
+}
+
+#[async_trait]
+impl RateLimiter for GossipRateLimiter {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        let (total, reset) = self.total(ip);
+        let max_requests = u64::from(self.config.max_requests);
+        if total >= max_requests {
+            return Err(RateLimitError::rate_limit_exceeded(
+                max_requests,
+                self.config.window,
+                reset,
+            ));
+        }
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window: self.config.window,
+            remaining: max_requests - total - 1,
+            reset,
+        })
+    }
+
+    async fn record_request(&self, ip: &str) {
+        let current_epoch = epoch(window_ms(&self.config));
+        {
+            let mut counter = self
+                .state
+                .counters
+                .entry(ip.to_string())
+                .or_insert_with(|| KeyCounter::new(current_epoch, window_ms(&self.config)));
+            if counter.epoch != current_epoch {
+                counter.start(current_epoch);
+                counter.window_ms = window_ms(&self.config);
+            }
+            *counter
+                .counts
+                .entry(self.state.node_id.to_string())
+                .or_default() += 1;
+        }
+        self.state
+            .dirty
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(ip.to_string());
+    }
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let (total, reset) = self.total(ip);
+        let max_requests = u64::from(self.config.max_requests);
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window: self.config.window,
+            remaining: max_requests.saturating_sub(total),
+            reset: if total > 0 { reset } else { Duration::ZERO },
+        })
+    }
+
+    /// Takes back what all nodes counted so far in the window, on this node
+    /// only: the other nodes keep counting the key's requests until the
+    /// window ends.
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        if let Some(mut counter) = self.state.counters.get_mut(ip) {
+            counter.forgiven = counter.counts.values().sum();
+        }
+        Ok(())
+    }
+}
+
+async fn send_to_all(socket: &UdpSocket, peers: &[SocketAddr], datagram: &[u8]) {
+    for peer in peers {
+        if let Err(e) = socket.send_to(datagram, peer).await {
+            tracing::warn!("Failed to send gossip to {}: {}", peer, e);
+        }
+    }
+}
+
+/// `message` as sent: its HMAC under `secret`, then its JSON.
+fn sign(secret: &[u8], message: &GossipMessage) -> Vec<u8> {
+    let body = serde_json::to_vec(message).expect("gossip message serializes");
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&body);
+    let mut datagram = mac.finalize().into_bytes().to_vec();
+    datagram.extend_from_slice(&body);
+    datagram
+}
+
+/// The message in `datagram` received `from`, if it comes from a peer and is
+/// signed with `secret`.
+fn accept(
+    peers: &HashSet<IpAddr>,
+    secret: &[u8],
+    from: SocketAddr,
+    datagram: &[u8],
+) -> Option<GossipMessage> {
+    if !peers.contains(&from.ip().to_canonical()) {
+        tracing::debug!("Ignoring gossip from {}, which is not a peer", from);
+        return None;
+    }
+    let Some((tag, body)) = datagram.split_at_checked(TAG_LEN) else {
+        tracing::warn!("Ignoring truncated gossip from {}", from);
+        return None;
+    };
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    // Compared in constant time, so the signature cannot be guessed by timing.
+    if mac.verify_slice(tag).is_err() {
+        tracing::warn!("Ignoring gossip from {} with a wrong signature", from);
+        return None;
+    }
+    match serde_json::from_slice(body) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            tracing::warn!("Ignoring invalid gossip from {}: {}", from, e);
+            None
+        }
+    }
+}
+
+fn epoch(window_ms: u64) -> u64 {
+    now_ms() / window_ms.max(1)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn window_ms(config: &RateLimitConfig) -> u64 {
+    config.window.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW_MS: u64 = 60_000;
+
+    fn limiter(
+        state: &GossipRateLimitState,
+        max_requests: u32,
+        window_ms: u64,
+    ) -> GossipRateLimiter {
+        GossipRateLimiter::new(
+            state.clone(),
+            Arc::new(RateLimitConfig {
+                max_requests,
+                window: Duration::from_millis(window_ms),
+            }),
+        )
+    }
+
+    fn message(node: &str, key: &str, epoch: u64, count: u64) -> GossipMessage {
+        GossipMessage {
+            node: node.to_string(),
+            entries: vec![GossipEntry {
+                key: key.to_string(),
+                epoch,
+                window_ms: WINDOW_MS,
+                count,
+            }],
+            bans: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_keeps_the_highest_count_of_each_node() {
+        let state = GossipRateLimitState::new("a");
+        let limiter = limiter(&state, 10, WINDOW_MS);
+        limiter.record_request("k").await;
+        let current = epoch(WINDOW_MS);
+
+        state.merge(message("b", "k", current, 3));
+        state.merge(message("b", "k", current, 2));
+        state.merge(message("c", "k", current, 4));
+        assert_eq!(limiter.total("k").0, 8);
+        // Counts of this node only ever come from itself.
+        state.merge(message("a", "k", current, 9));
+        assert_eq!(limiter.total("k").0, 8);
+    }
+
+    #[tokio::test]
+    async fn merge_ignores_other_windows() {
+        let state = GossipRateLimitState::new("a");
+        let limiter = limiter(&state, 10, WINDOW_MS);
+        let current = epoch(WINDOW_MS);
+
+        state.merge(message("b", "k", current - 1, 5));
+        state.merge(message("b", "k", current + 1, 5));
+        assert_eq!(limiter.total("k").0, 0);
+        assert_eq!(state.tracked_keys(), 0);
+    }
+
+    #[tokio::test]
+    async fn counts_start_over_in_the_next_window() {
+        let state = GossipRateLimitState::new("a");
+        let limiter = limiter(&state, 2, 50);
+        limiter.record_request("k").await;
+        limiter.record_request("k").await;
+        assert!(limiter.check_rate_limit("k").await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(limiter.check_rate_limit("k").await.is_ok());
+        limiter.record_request("k").await;
+        assert_eq!(limiter.total("k").0, 1);
+        assert_eq!(state.evict_expired(), 0);
+    }
+
+    #[tokio::test]
+    async fn reset_forgives_what_was_counted_so_far() {
+        let state = GossipRateLimitState::new("a");
+        let limiter = limiter(&state, 5, WINDOW_MS);
+        limiter.record_request("k").await;
+        state.merge(message("b", "k", epoch(WINDOW_MS), 4));
+        assert!(limiter.check_rate_limit("k").await.is_err());
+
+        limiter.reset("k").await.unwrap();
+        assert_eq!(limiter.total("k").0, 0);
+        limiter.record_request("k").await;
+        state.merge(message("b", "k", epoch(WINDOW_MS), 5));
+        assert_eq!(limiter.total("k").0, 2);
+    }
+
+    #[test]
+    fn only_signed_datagrams_from_peers_are_accepted() {
+        let peers: HashSet<IpAddr> = ["10.0.0.2".parse().unwrap()].into();
+        let peer: SocketAddr = "10.0.0.2:7946".parse().unwrap();
+        let datagram = sign(b"secret", &message("b", "k", 1, 3));
+
+        let accepted = accept(&peers, b"secret", peer, &datagram).unwrap();
+        assert_eq!(accepted.node, "b");
+        assert_eq!(accepted.entries[0].count, 3);
+        // Peers are matched by address, whatever port they send from.
+        assert!(
+            accept(
+                &peers,
+                b"secret",
+                "10.0.0.2:40000".parse().unwrap(),
+                &datagram
+            )
+            .is_some()
+        );
+
+        assert!(
+            accept(
+                &peers,
+                b"secret",
+                "10.0.0.9:7946".parse().unwrap(),
+                &datagram
+            )
+            .is_none()
+        );
+        assert!(accept(&peers, b"other", peer, &datagram).is_none());
+        let mut tampered = datagram.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(accept(&peers, b"secret", peer, &tampered).is_none());
+        assert!(accept(&peers, b"secret", peer, &datagram[..TAG_LEN - 1]).is_none());
+        let unsigned = serde_json::to_vec(&message("b", "k", 1, 3)).unwrap();
+        assert!(accept(&peers, b"secret", peer, &unsigned).is_none());
+    }
+}
@@ -0,0 +1,250 @@
+use ::redis::{RedisResult, Script};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use super::{RedisConnection, RedisRateLimitState};
+use crate::config::RateLimitConfig;
+use crate::rate_limiter::{MapHealth, RateLimitDecision, RateLimitError, RateLimiter, map_health};
+
+/// Local view of one key's usage between two synchronizations with Redis.
+struct LocalAllowance {
+    /// Requests Redis counted across all replicas at the last sync.
+    global_count: u32,
+    /// Requests admitted by this replica and not yet pushed to Redis.
+    pending: u32,
+    config: Arc<RateLimitConfig>,
+}
+
+/// Redis connection plus the local allowances checked on the request path.
+#[derive(Clone)]
+pub struct HybridRateLimitState {
+    redis: RedisRateLimitState,
+    local: Arc<DashMap<String, LocalAllowance>>,
+}
+
+impl HybridRateLimitState {
+    pub fn new(redis: RedisRateLimitState) -> Self {
+        Self {
+            redis,
+            local: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// The Redis connection behind the local allowances.
+    pub fn redis(&self) -> &RedisRateLimitState {
+        &self.redis
+    }
+
+    pub async fn ping(&self) -> Result<(), String> {
+        self.redis.ping().await
+    }
+
+    /// Keys with a local allowance, the rest being only in Redis.
+    pub fn tracked_keys(&self) -> usize {
+        self.local.len()
+    }
+
+    pub fn health(&self) -> MapHealth {
+        map_health(&self.local, |_| 0)
+    }
+
+    /// Pushes locally admitted requests to Redis every `interval` and pulls
+    /// back the global counts.
+    pub fn spawn_sync(&self, interval: Duration) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                state.sync().await;
+            }
+        });
+    }
+
+    async fn sync(&self) {
+        let keys: Vec<(String, u32, Arc<RateLimitConfig>)> = self
+            .local
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.pending, entry.config.clone()))
+            .collect();
+        let mut connection = self.redis.connection.clone();
+
+        for (key, pending, config) in keys {
+            let global_count = match self.push(&mut connection, &key, pending, &config).await {
+                Ok(count) => count,
+                Err(e) => {
+                    // The pending requests stay local and are pushed with the
+                    // next sync.
+                    tracing::error!("Redis sync failed for key {}: {}", key, e);
+                    return;
+                }
+            };
+
+            if let Some(mut allowance) = self.local.get_mut(&key) {
+                allowance.pending -= pending;
+                allowance.global_count = global_count;
+            }
+            // Keys whose window emptied out are dropped so the map does not
+            // grow without bound.
+            self.local.remove_if(&key, |_, allowance| {
+                allowance.pending == 0 && allowance.global_count == 0
+            });
+        }
+    }
+
+    async fn push(
+        &self,
+        connection: &mut RedisConnection,
+        key: &str,
+        pending: u32,
+        config: &RateLimitConfig,
+    ) -> RedisResult<u32> {
+        SYNC_SCRIPT
+            .key(self.redis.key(key))
+            .arg(config.window_micros())
+            .arg(pending)
+            .arg(rand::random::<u32>())
+            .invoke_async(connection)
+            .await
+    }
+}
+
+/// Admits requests against the last known global count plus this replica's
+/// unsynced requests, so the request path never waits on Redis.
+///
+/// Until the next sync, every replica may admit requests the others already
+/// used up, so the limit can be overshot by up to what all replicas admit
+/// within one sync interval.
+#[derive(Clone)]
+pub struct HybridRateLimiter {
+    state: HybridRateLimitState,
+    config: Arc<RateLimitConfig>,
+}
+
+impl HybridRateLimiter {
+    pub fn new(state: HybridRateLimitState, config: Arc<RateLimitConfig>) -> Self {
+        Self { state, config }
+    }
+
+    /// Requests in the global window as of the last sync, plus the ones
+    /// admitted here since.
+    fn count(&self, ip: &str) -> u64 {
+        self.state
+            .local
+            .get(ip)
+            .map(|allowance| u64::from(allowance.global_count + allowance.pending))
+            .unwrap_or(0)
+    }
+}
+
+#[async_trait]
+impl RateLimiter for HybridRateLimiter {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        let max_requests = u64::from(self.config.max_requests);
+        let count = self.count(ip);
+        // When the oldest request leaves the global window is not known
+        // locally, so the whole window is reported.
+        if count >= max_requests {
+            return Err(RateLimitError::rate_limit_exceeded(
+                max_requests,
+                self.config.window,
+                self.config.window,
+            ));
+        }
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window: self.config.window,
+            remaining: max_requests - count - 1,
+            reset: self.config.window,
+        })
+    }
+
+    async fn record_request(&self, ip: &str) {
+        self.state
+            .local
+            .entry(ip.to_string())
+            .or_insert_with(|| LocalAllowance {
+                global_count: 0,
+                pending: 0,
+                config: self.config.clone(),
+            })
+            .pending += 1;
+    }
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        let max_requests = u64::from(self.config.max_requests);
+        let count = self.count(ip);
+        Ok(RateLimitDecision {
+            limit: max_requests,
+            window: self.config.window,
+            remaining: max_requests.saturating_sub(count),
+            reset: if count > 0 {
+                self.config.window
+            } else {
+                Duration::ZERO
+            },
+        })
+    }
+
+    /// Drops the local allowance, requests not yet pushed with it, before
+    /// deleting the global count.
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.state.local.remove(ip);
+        self.state.redis.delete(ip).await
+    }
+}
+
+/// Adds a batch of requests to the same sorted-set sliding window the Redis
+/// limiter uses and returns the number of requests in the window.
+///
+/// KEYS[1]: key, ARGV[1]: window in microseconds, ARGV[2]: requests to add,
+/// ARGV[3]: random suffix keeping members unique.
+static SYNC_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        local window = tonumber(ARGV[1])
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+        for i = 1, tonumber(ARGV[2]) do
+            redis.call('ZADD', KEYS[1], now, now .. '-' .. ARGV[3] .. '-' .. i)
+        end
+        local count = redis.call('ZCARD', KEYS[1])
+        if count > 0 then
+            redis.call('PEXPIRE', KEYS[1], math.ceil(window / 1000))
+        end
+        return count
+        ",
+    )
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitAlgorithm;
+    use crate::storage::redis::tests::state;
+
+    #[tokio::test]
+    async fn sync_adds_the_pending_requests_to_the_window() {
+        let Some(redis) = state(RateLimitAlgorithm::SlidingWindow).await else {
+            return;
+        };
+        let state = HybridRateLimitState::new(redis);
+        let config = RateLimitConfig {
+            max_requests: 10,
+            window: Duration::from_secs(60),
+        };
+        let mut connection = state.redis.connection.clone();
+        assert_eq!(state.push(&mut connection, "k", 3, &config).await, Ok(3));
+        assert_eq!(state.push(&mut connection, "k", 2, &config).await, Ok(5));
+        assert_eq!(state.push(&mut connection, "k", 0, &config).await, Ok(5));
+        assert_eq!(
+            state.push(&mut connection, "other", 1, &config).await,
+            Ok(1)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use super::RateLimitStore;
+use crate::config::MemcachedConfig;
+
+/// Longest key memcached accepts.
+const MAX_KEY_LENGTH: usize = 250;
+/// Expiration times above 30 days are read by memcached as Unix timestamps.
+const MAX_RELATIVE_EXPIRATION: u64 = 60 * 60 * 24 * 30;
+
+type Connection = BufStream<TcpStream>;
+
+/// One memcached server with a pool of idle connections.
+struct Server {
+    addr: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+/// [`RateLimitStore`] over a memcached cluster, speaking the text protocol.
+///
+/// Keys are spread across the servers by hash and compare-and-swap maps onto
+/// `gets`/`cas`, with `add` creating missing keys. Values expire after the
+/// window they were written for.
+#[derive(Clone)]
+pub struct MemcachedStore {
+    servers: Arc<Vec<Server>>,
+    key_prefix: String,
+    timeout: Duration,
+}
+
+impl MemcachedStore {
+    pub fn new(config: &MemcachedConfig) -> Self {
+        Self {
+            servers: Arc::new(
+                config
+                    .servers
+                    .iter()
+                    .map(|addr| Server {
+                        addr: addr.clone(),
+                        idle: Mutex::new(Vec::new()),
+                    })
+                    .collect(),
+            ),
+            key_prefix: config.key_prefix.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+
+    /// Prefixes the key, replacing keys memcached would reject (too long, or
+    /// containing whitespace or control characters) by their SHA-256 digest.
+    fn key(&self, key: &str) -> String {
+        let key = format!("{}{}", self.key_prefix, key);
+        if key.len() <= MAX_KEY_LENGTH && key.bytes().all(|b| b.is_ascii_graphic()) {
+            return key;
+        }
+        format!(
+            "{}sha256:{}",
+            self.key_prefix,
+            hex::encode(Sha256::digest(key))
+        )
+    }
+
+    fn server(&self, key: &str) -> Result<&Server, String> {
+        if self.servers.is_empty() {
+            return Err("no memcached servers configured".to_string());
+        }
+        let digest = Sha256::digest(key);
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+        Ok(&self.servers[(hash % self.servers.len() as u64) as usize])
+    }
+
+    /// Runs `command` on a pooled connection to the key's server.
+    async fn with_connection<T>(
+        &self,
+        key: &str,
+        command: impl AsyncFnOnce(&mut Connection) -> std::io::Result<T>,
+    ) -> Result<T, String> {
+        self.exchange(self.server(key)?, command).await
+    }
+
+    /// Runs `command` on a pooled connection to `server`. Connections are
+    /// only returned to the pool after a successful exchange, since a failed
+    /// one may have left unread data behind.
+    async fn exchange<T>(
+        &self,
+        server: &Server,
+        command: impl AsyncFnOnce(&mut Connection) -> std::io::Result<T>,
+    ) -> Result<T, String> {
+        let pooled = server.idle.lock().await.pop();
+        let exchange = async {
+            let mut connection = match pooled {
+                Some(connection) => connection,
+                None => BufStream::new(TcpStream::connect(&server.addr).await?),
+            };
+            let result = command(&mut connection).await?;
+            Ok::<_, std::io::Error>((connection, result))
+        };
+
+        match tokio::time::timeout(self.timeout, exchange).await {
+            Ok(Ok((connection, result))) => {
+                server.idle.lock().await.push(connection);
+                Ok(result)
+            }
+            Ok(Err(e)) => Err(format!("memcached {}: {}", server.addr, e)),
+            Err(_) => Err(format!("memcached {}: timed out", server.addr)),
+        }
+    }
+
+    /// Checks that every server answers.
+    pub async fn ping(&self) -> Result<(), String> {
+        for server in self.servers.iter() {
+            self.exchange(server, async |connection| {
+                connection.write_all(b"version\r\n").await?;
+                connection.flush().await?;
+                let line = read_line(connection).await?;
+                if !line.starts_with("VERSION") {
+                    return Err(protocol_error(&line));
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn gets(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, String> {
+        self.with_connection(key, async |connection| {
+            connection
+                .write_all(format!("gets {}\r\n", key).as_bytes())
+                .await?;
+            connection.flush().await?;
+
+            let line = read_line(connection).await?;
+            if line == "END" {
+                return Ok(None);
+            }
+            // VALUE <key> <flags> <bytes> <cas unique>
+            let fields: Vec<&str> = line.split(' ').collect();
+            let (Some(length), Some(cas)) = (
+                fields.get(3).and_then(|v| v.parse::<usize>().ok()),
+                fields.get(4).and_then(|v| v.parse::<u64>().ok()),
+            ) else {
+                return Err(protocol_error(&line));
+            };
+            let mut value = vec![0; length + 2];
+            connection.read_exact(&mut value).await?;
+            value.truncate(length);
+
+            let end = read_line(connection).await?;
+            if end != "END" {
+                return Err(protocol_error(&end));
+            }
+            Ok(Some((value, cas)))
+        })
+        .await
+    }
+
+    /// Stores `value` with `add` when `cas` is `None`, otherwise with `cas`,
+    /// returning whether it was stored.
+    async fn store(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: Duration,
+        cas: Option<u64>,
+    ) -> Result<bool, String> {
+        let expiration = ttl
+            .as_secs_f64()
+            .ceil()
+            .clamp(1.0, MAX_RELATIVE_EXPIRATION as f64) as u64;
+        let command = match cas {
+            Some(cas) => format!("cas {} 0 {} {} {}\r\n", key, expiration, value.len(), cas),
+            None => format!("add {} 0 {} {}\r\n", key, expiration, value.len()),
+        };
+
+        self.with_connection(key, async |connection| {
+            connection.write_all(command.as_bytes()).await?;
+            connection.write_all(value).await?;
+            connection.write_all(b"\r\n").await?;
+            connection.flush().await?;
+
+            match read_line(connection).await?.as_str() {
+                "STORED" => Ok(true),
+                "EXISTS" | "NOT_FOUND" | "NOT_STORED" => Ok(false),
+                other => Err(protocol_error(other)),
+            }
+        })
+        .await
+    }
+}
+
+impl RateLimitStore for MemcachedStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.gets(&self.key(key)).await?.map(|(value, _)| value))
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, String> {
+        let key = self.key(key);
+        let stored = self.gets(&key).await?;
+        match (current, stored) {
+            (None, None) => self.store(&key, &new, ttl, None).await,
+            (Some(current), Some((value, cas))) if value == current => {
+                self.store(&key, &new, ttl, Some(cas)).await
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Uses the CAS token of the read directly, saving the second `gets` the
+    // default implementation would make through `compare_and_swap`.
+    async fn update<R: Send>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        mut f: impl FnMut(Option<&[u8]>) -> (Option<Vec<u8>>, R) + Send,
+    ) -> Result<R, String> {
+        let key = self.key(key);
+        loop {
+            let stored = self.gets(&key).await?;
+            let (new, result) = f(stored.as_ref().map(|(value, _)| value.as_slice()));
+            let Some(new) = new else {
+                return Ok(result);
+            };
+            let cas = stored.map(|(_, cas)| cas);
+            if self.store(&key, &new, ttl, cas).await? {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+async fn read_line(connection: &mut Connection) -> std::io::Result<String> {
+    let mut line = String::new();
+    if connection.read_line(&mut line).await? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn protocol_error(line: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("unexpected response: {}", line),
+    )
+}
@@ -0,0 +1,138 @@
+use dashmap::{DashMap, mapref::entry::Entry};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::RateLimitStore;
+use crate::config::MAX_TRACKED_KEYS;
+use crate::metrics;
+use crate::rate_limiter::{MapHealth, least_recently_seen, map_health};
+
+struct StoredValue {
+    value: Vec<u8>,
+    expires_at: Instant,
+}
+
+/// In-process store; expired values are treated as absent.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    entries: Arc<DashMap<String, StoredValue>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Evicts the values written longest ago once more than
+    /// `RATE_LIMIT_MAX_TRACKED_KEYS` keys are stored.
+    fn enforce_capacity(&self) {
+        let Some(max) = *MAX_TRACKED_KEYS else {
+            return;
+        };
+        // Values share the TTL of their limit, so the earliest expiry
+        // approximates the least recently written key.
+        let evicted = least_recently_seen(
+            self.entries
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.expires_at)),
+            self.entries.len(),
+            max,
+        );
+        for key in &evicted {
+            self.entries.remove(key);
+        }
+        metrics::record_eviction("capacity", evicted.len());
+    }
+
+    /// Keys stored, expired ones included until evicted.
+    pub fn tracked_keys(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn health(&self) -> MapHealth {
+        map_health(&self.entries, |stored| stored.value.capacity())
+    }
+
+    /// Drops expired values, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        let before = self.entries.len();
+        let now = Instant::now();
+        self.entries.retain(|_, stored| stored.expires_at > now);
+        before.saturating_sub(self.entries.len())
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self
+            .entries
+            .get(key)
+            .filter(|stored| stored.expires_at > Instant::now())
+            .map(|stored| stored.value.clone()))
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, String> {
+        let now = Instant::now();
+        let new = StoredValue {
+            value: new,
+            expires_at: now + ttl,
+        };
+        match self.entries.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let stored = entry.get();
+                let stored = (stored.expires_at > now).then_some(stored.value.as_slice());
+                if stored != current {
+                    return Ok(false);
+                }
+                entry.insert(new);
+            }
+            Entry::Vacant(entry) => {
+                if current.is_some() {
+                    return Ok(false);
+                }
+                entry.insert(new);
+            }
+        }
+        self.enforce_capacity();
+        Ok(true)
+    }
+
+    // The entry lock makes the update atomic without a compare-and-swap loop.
+    async fn update<R: Send>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        mut f: impl FnMut(Option<&[u8]>) -> (Option<Vec<u8>>, R) + Send,
+    ) -> Result<R, String> {
+        let now = Instant::now();
+        let (new, result, entry) = match self.entries.entry(key.to_string()) {
+            Entry::Occupied(entry) => {
+                let stored = entry.get();
+                let (new, result) = f((stored.expires_at > now).then_some(stored.value.as_slice()));
+                (new, result, Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => {
+                let (new, result) = f(None);
+                (new, result, Entry::Vacant(entry))
+            }
+        };
+        if let Some(value) = new {
+            entry.insert(StoredValue {
+                value,
+                expires_at: now + ttl,
+            });
+            self.enforce_capacity();
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+mod cluster;
+mod dynamodb;
+mod gossip;
+mod hybrid;
+mod memcached;
+mod memory;
+mod postgres;
+mod redis;
+mod sqlite;
+
+pub use self::cluster::*;
+pub use self::dynamodb::*;
+pub use self::gossip::*;
+pub use self::hybrid::*;
+pub use self::memcached::*;
+pub use self::memory::*;
+pub use self::postgres::*;
+pub use self::redis::*;
+pub use self::sqlite::*;
+
+/// Key-value storage rate limit algorithms keep their per-key state in.
+///
+/// Values are opaque bytes that expire after the TTL they were last written
+/// with. Stores only need to provide reads and an atomic compare-and-swap;
+/// `update` builds a read-modify-write loop on top of them, which stores with
+/// cheaper atomic updates may override.
+///
+/// Methods return `Send` futures, so stores can be used from any task;
+/// implementations may still write them as `async fn`.
+pub trait RateLimitStore: Clone + Send + Sync + 'static {
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, String>> + Send;
+
+    /// Writes `new` if the key currently holds `current` (`None` meaning
+    /// absent or expired), returning whether the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, String>> + Send;
+
+    /// Atomically applies `f` to the key's value. `f` returns the value to
+    /// store, or `None` to leave the key untouched, and a result handed back
+    /// to the caller. It may be called several times under contention.
+    fn update<R: Send>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        mut f: impl FnMut(Option<&[u8]>) -> (Option<Vec<u8>>, R) + Send,
+    ) -> impl Future<Output = Result<R, String>> + Send {
+        async move {
+            loop {
+                let current = self.get(key).await?;
+                let (new, result) = f(current.as_deref());
+                let Some(new) = new else {
+                    return Ok(result);
+                };
+                if self
+                    .compare_and_swap(key, current.as_deref(), new, ttl)
+                    .await?
+                {
+                    return Ok(result);
+                }
+            }
+        }
+    }
+}
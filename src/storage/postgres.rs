@@ -0,0 +1,81 @@
+use chrono::NaiveDate;
+use sqlx::{PgPool, postgres::PgPoolOptions};
+
+/// Durable per-period request counters in Postgres, for quotas spanning days
+/// or months that must survive restarts.
+#[derive(Clone)]
+pub struct PostgresQuotaStore {
+    pool: PgPool,
+}
+
+impl PostgresQuotaStore {
+    /// Connects and applies the migrations embedded in the binary.
+    pub async fn connect(database_url: &str) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new().connect(database_url).await?;
+        sqlx::migrate!("migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    /// Checks that the database answers.
+    pub async fn ping(&self) -> Result<(), String> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Counts a request against the key's quota for the period starting at
+    /// `period_start`, unless `max_requests` were already counted. Returns
+    /// the count including the request if it was counted.
+    ///
+    /// The check and the increment are a single upsert, so concurrent
+    /// replicas cannot overshoot the quota.
+    pub async fn increment(
+        &self,
+        key: &str,
+        period_start: NaiveDate,
+        max_requests: u64,
+    ) -> Result<Option<u64>, sqlx::Error> {
+        if max_requests == 0 {
+            return Ok(None);
+        }
+        let count: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO rate_limit_quotas (key, period_start, count) VALUES ($1, $2, 1)
+             ON CONFLICT (key, period_start) DO UPDATE
+             SET count = rate_limit_quotas.count + 1
+             WHERE rate_limit_quotas.count < $3
+             RETURNING count",
+        )
+        .bind(key)
+        .bind(period_start)
+        .bind(i64::try_from(max_requests).unwrap_or(i64::MAX))
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(count.map(|count| count as u64))
+    }
+
+    /// Requests counted against the key's quota for the period starting at
+    /// `period_start`.
+    pub async fn count(&self, key: &str, period_start: NaiveDate) -> Result<u64, sqlx::Error> {
+        let count: Option<i64> = sqlx::query_scalar(
+            "SELECT count FROM rate_limit_quotas WHERE key = $1 AND period_start = $2",
+        )
+        .bind(key)
+        .bind(period_start)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(count.map_or(0, |count| count as u64))
+    }
+
+    /// Forgets the requests counted against the key's quota for the period
+    /// starting at `period_start`.
+    pub async fn reset(&self, key: &str, period_start: NaiveDate) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM rate_limit_quotas WHERE key = $1 AND period_start = $2")
+            .bind(key)
+            .bind(period_start)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,627 @@
+use ::redis::{
+    AsyncCommands, Client, Cmd, Pipeline, RedisError, RedisFuture, RedisResult, Script, Value,
+    aio::{ConnectionLike, ConnectionManager, MultiplexedConnection},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::{SentinelClient, SentinelServerType},
+};
+use async_trait::async_trait;
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+use super::RateLimitStore;
+use crate::config::{RateLimitAlgorithm, RateLimitConfig, RedisConfig, RedisMode};
+use crate::rate_limiter::{RateLimitDecision, RateLimitError, RateLimiter, Reason};
+
+/// Connection to a single server, a Redis Cluster or a Sentinel-managed
+/// master. Each variant follows failovers on its own: the connection manager
+/// reconnects, the cluster connection follows slot moves, and the sentinel
+/// connection asks the sentinels for the new master.
+#[derive(Clone)]
+pub enum RedisConnection {
+    Standalone(Box<ConnectionManager>),
+    Cluster(ClusterConnection),
+    Sentinel(SentinelConnection),
+}
+
+impl RedisConnection {
+    async fn connect(config: &RedisConfig) -> RedisResult<Self> {
+        Ok(match config.mode {
+            RedisMode::Standalone => Self::Standalone(Box::new(
+                Client::open(config.url.as_str())?
+                    .get_connection_manager()
+                    .await?,
+            )),
+            RedisMode::Cluster => Self::Cluster(
+                ClusterClient::new(config.urls())?
+                    .get_async_connection()
+                    .await?,
+            ),
+            RedisMode::Sentinel => {
+                let client = SentinelClient::build(
+                    config.urls(),
+                    config.sentinel_master.clone(),
+                    None,
+                    SentinelServerType::Master,
+                )?;
+                let connection = SentinelConnection {
+                    client: Arc::new(Mutex::new(client)),
+                    master: Arc::new(Mutex::new(None)),
+                };
+                // Fail at startup rather than on the first request if no
+                // sentinel knows the master.
+                connection.master().await?;
+                Self::Sentinel(connection)
+            }
+        })
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Standalone(connection) => connection.req_packed_command(cmd),
+            Self::Cluster(connection) => connection.req_packed_command(cmd),
+            Self::Sentinel(sentinel) => Box::pin(async move {
+                let mut master = sentinel.master().await?;
+                let result = master.req_packed_command(cmd).await;
+                sentinel.check(result).await
+            }),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Standalone(connection) => connection.req_packed_commands(cmd, offset, count),
+            Self::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+            Self::Sentinel(sentinel) => Box::pin(async move {
+                let mut master = sentinel.master().await?;
+                let result = master.req_packed_commands(cmd, offset, count).await;
+                sentinel.check(result).await
+            }),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Standalone(connection) => connection.get_db(),
+            Self::Cluster(_) | Self::Sentinel(_) => 0,
+        }
+    }
+}
+
+/// Connection to the master the sentinels currently report.
+#[derive(Clone)]
+pub struct SentinelConnection {
+    client: Arc<Mutex<SentinelClient>>,
+    master: Arc<Mutex<Option<MultiplexedConnection>>>,
+}
+
+impl SentinelConnection {
+    async fn master(&self) -> RedisResult<MultiplexedConnection> {
+        let mut master = self.master.lock().await;
+        if let Some(connection) = &*master {
+            return Ok(connection.clone());
+        }
+        let connection = self.client.lock().await.get_async_connection().await?;
+        *master = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Forgets the master after errors suggesting it went away or was
+    /// demoted, so the next command asks the sentinels again.
+    async fn check<T>(&self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(e) = &result
+            && is_failover(e)
+        {
+            tracing::warn!("Lost the Redis master, asking the sentinels again: {}", e);
+            *self.master.lock().await = None;
+        }
+        result
+    }
+}
+
+fn is_failover(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.kind() == ::redis::ErrorKind::ReadOnly
+}
+
+/// Connection shared by all Redis limiters, so every replica counts against
+/// the same keys.
+#[derive(Clone)]
+pub struct RedisRateLimitState {
+    pub connection: RedisConnection,
+    pub key_prefix: String,
+    pub algorithm: RateLimitAlgorithm,
+    hash_tags: bool,
+}
+
+impl RedisRateLimitState {
+    pub async fn connect(config: &RedisConfig) -> RedisResult<Self> {
+        Ok(Self {
+            connection: RedisConnection::connect(config).await?,
+            key_prefix: config.key_prefix.clone(),
+            algorithm: config.algorithm,
+            hash_tags: config.hash_tags,
+        })
+    }
+
+    /// Checks that Redis answers.
+    pub async fn ping(&self) -> Result<(), String> {
+        let mut connection = self.connection.clone();
+        ::redis::cmd("PING")
+            .query_async::<()>(&mut connection)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Deletes the state of a rate limit key.
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        let mut connection = self.connection.clone();
+        connection
+            .del(self.key(key))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Redis key of a rate limit key. With hash tags, the client part of the
+    /// key (before any `|route` scope) is wrapped in `{}` so all of a
+    /// client's keys land on the same cluster slot.
+    pub fn key(&self, key: &str) -> String {
+        if !self.hash_tags {
+            return format!("{}{}", self.key_prefix, key);
+        }
+        match key.split_once('|') {
+            Some((client, rest)) => format!("{}{{{}}}|{}", self.key_prefix, client, rest),
+            None => format!("{}{{{}}}", self.key_prefix, key),
+        }
+    }
+}
+
+/// Keeps each key's state in Redis, checking and recording a request in one
+/// atomic Lua script so concurrent replicas cannot over-admit.
+///
+/// Because the check already records admitted requests, `record_request` is
+/// a no-op for this limiter.
+#[derive(Clone)]
+pub struct RedisRateLimiter {
+    state: RedisRateLimitState,
+    config: Arc<RateLimitConfig>,
+}
+
+impl RedisRateLimiter {
+    pub fn new(state: RedisRateLimitState, config: Arc<RateLimitConfig>) -> Self {
+        Self { state, config }
+    }
+
+    /// Returns whether the request was admitted (and recorded), and where the
+    /// key stands against its limit after it.
+    async fn admit(&self, ip: &str) -> RedisResult<(bool, RateLimitDecision)> {
+        let key = self.state.key(ip);
+        let window_micros = self.config.window_micros();
+        let mut connection = self.state.connection.clone();
+
+        // Script::invoke_async uses EVALSHA and only sends the script body
+        // when Redis does not have it cached yet.
+        let (allowed, remaining, reset): (i64, u64, u64) = match self.state.algorithm {
+            RateLimitAlgorithm::SlidingWindow => {
+                SLIDING_WINDOW_SCRIPT
+                    .key(key)
+                    .arg(window_micros)
+                    .arg(self.config.max_requests)
+                    .arg(rand::random::<u32>())
+                    .invoke_async(&mut connection)
+                    .await?
+            }
+            RateLimitAlgorithm::TokenBucket => {
+                TOKEN_BUCKET_SCRIPT
+                    .key(key)
+                    .arg(window_micros)
+                    .arg(self.config.max_requests)
+                    .invoke_async(&mut connection)
+                    .await?
+            }
+        };
+        let decision = RateLimitDecision {
+            limit: u64::from(self.config.max_requests),
+            window: self.config.window,
+            remaining,
+            reset: Duration::from_micros(reset),
+        };
+        Ok((allowed == 1, decision))
+    }
+
+    /// Where the key stands against its limit, read without recording a
+    /// request.
+    async fn read(&self, ip: &str) -> RedisResult<RateLimitDecision> {
+        let script = match self.state.algorithm {
+            RateLimitAlgorithm::SlidingWindow => &SLIDING_WINDOW_PEEK_SCRIPT,
+            RateLimitAlgorithm::TokenBucket => &TOKEN_BUCKET_PEEK_SCRIPT,
+        };
+        let mut connection = self.state.connection.clone();
+        let (remaining, reset): (u64, u64) = script
+            .key(self.state.key(ip))
+            .arg(self.config.window_micros())
+            .arg(self.config.max_requests)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(RateLimitDecision {
+            limit: u64::from(self.config.max_requests),
+            window: self.config.window,
+            remaining,
+            reset: Duration::from_micros(reset),
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimiter for RedisRateLimiter {
+    async fn check_rate_limit(&self, ip: &str) -> Result<RateLimitDecision, RateLimitError> {
+        match self.admit(ip).await {
+            Ok((true, decision)) => Ok(decision),
+            Ok((false, decision)) => Err(RateLimitError::Exceeded {
+                reason: Reason::RateLimit,
+                decision,
+            }),
+            Err(e) => Err(RateLimitError::Unavailable(e.to_string())),
+        }
+    }
+
+    async fn record_request(&self, _ip: &str) {}
+
+    async fn peek(&self, ip: &str) -> Result<RateLimitDecision, String> {
+        self.read(ip).await.map_err(|e| e.to_string())
+    }
+
+    async fn reset(&self, ip: &str) -> Result<(), String> {
+        self.state.delete(ip).await
+    }
+}
+
+/// [`RateLimitStore`] over plain Redis strings, for algorithms without a
+/// dedicated Lua script.
+#[derive(Clone)]
+pub struct RedisStore {
+    state: RedisRateLimitState,
+}
+
+impl RedisStore {
+    pub fn new(state: RedisRateLimitState) -> Self {
+        Self { state }
+    }
+
+    pub async fn ping(&self) -> Result<(), String> {
+        self.state.ping().await
+    }
+}
+
+impl RateLimitStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let mut connection = self.state.connection.clone();
+        connection
+            .get(self.state.key(key))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, String> {
+        let mut connection = self.state.connection.clone();
+        let swapped: i64 = COMPARE_AND_SWAP_SCRIPT
+            .key(self.state.key(key))
+            .arg(current.is_some() as u8)
+            .arg(current.unwrap_or_default())
+            .arg(new)
+            .arg(ttl.as_millis().max(1) as u64)
+            .invoke_async(&mut connection)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(swapped == 1)
+    }
+}
+
+/// KEYS[1]: key, ARGV[1]: whether a current value is expected, ARGV[2]: the
+/// expected value, ARGV[3]: the new value, ARGV[4]: TTL in milliseconds.
+static COMPARE_AND_SWAP_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local current = redis.call('GET', KEYS[1])
+        local matches
+        if ARGV[1] == '1' then
+            matches = current == ARGV[2]
+        else
+            matches = current == false
+        end
+        if not matches then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[3], 'PX', ARGV[4])
+        return 1
+        ",
+    )
+});
+
+/// Sliding window log in a sorted set scored by request time.
+///
+/// KEYS[1]: key, ARGV[1]: window in microseconds, ARGV[2]: maximum requests,
+/// ARGV[3]: random suffix keeping members unique.
+///
+/// Returns whether the request was allowed, the requests remaining and the
+/// microseconds until the oldest request leaves the window.
+static SLIDING_WINDOW_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        local window = tonumber(ARGV[1])
+        local max_requests = tonumber(ARGV[2])
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+        local count = redis.call('ZCARD', KEYS[1])
+        local allowed = 0
+        local remaining = 0
+        if count < max_requests then
+            redis.call('ZADD', KEYS[1], now, now .. '-' .. ARGV[3])
+            redis.call('PEXPIRE', KEYS[1], math.ceil(window / 1000))
+            allowed = 1
+            remaining = max_requests - count - 1
+        end
+        local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+        local reset = window
+        if oldest[2] then
+            reset = tonumber(oldest[2]) + window - now
+        end
+        return {allowed, remaining, reset}
+        ",
+    )
+});
+
+/// Token bucket holding up to the maximum requests, refilled completely over
+/// one window.
+///
+/// KEYS[1]: key, ARGV[1]: window in microseconds, ARGV[2]: bucket capacity.
+///
+/// Returns whether the request was allowed, the whole tokens left and the
+/// microseconds until the next token is refilled.
+static TOKEN_BUCKET_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        local window = tonumber(ARGV[1])
+        local capacity = tonumber(ARGV[2])
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + (now - updated) * capacity / window)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tokens, 'updated', now)
+        redis.call('PEXPIRE', KEYS[1], math.ceil(window / 1000))
+        local whole = math.floor(tokens)
+        return {allowed, whole, math.ceil((1 - (tokens - whole)) * window / capacity)}
+        ",
+    )
+});
+
+/// Reads a sliding window without changing it, for `peek`.
+///
+/// KEYS[1]: key, ARGV[1]: window in microseconds, ARGV[2]: maximum requests.
+///
+/// Returns the requests remaining and the microseconds until the oldest
+/// request leaves the window, `0` if it is empty.
+static SLIDING_WINDOW_PEEK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        local window = tonumber(ARGV[1])
+        local max_requests = tonumber(ARGV[2])
+        local from = string.format('(%.0f', now - window)
+        local count = redis.call('ZCOUNT', KEYS[1], from, '+inf')
+        local oldest = redis.call('ZRANGEBYSCORE', KEYS[1], from, '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+        local reset = 0
+        if oldest[2] then
+            reset = tonumber(oldest[2]) + window - now
+        end
+        return {math.max(max_requests - count, 0), reset}
+        ",
+    )
+});
+
+/// Reads a token bucket without taking a token, for `peek`.
+///
+/// KEYS[1]: key, ARGV[1]: window in microseconds, ARGV[2]: bucket capacity.
+///
+/// Returns the whole tokens left and the microseconds until the next token
+/// is refilled, `0` if the bucket is full.
+static TOKEN_BUCKET_PEEK_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        local window = tonumber(ARGV[1])
+        local capacity = tonumber(ARGV[2])
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + (now - updated) * capacity / window)
+        local whole = math.floor(tokens)
+        local reset = 0
+        if tokens < capacity then
+            reset = math.ceil((1 - (tokens - whole)) * window / capacity)
+        end
+        return {whole, reset}
+        ",
+    )
+});
+
+/// These run the Lua scripts on the Redis server at `TEST_REDIS_URL`, and
+/// are skipped without one.
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    pub(in crate::storage) async fn state(
+        algorithm: RateLimitAlgorithm,
+    ) -> Option<RedisRateLimitState> {
+        let url = std::env::var("TEST_REDIS_URL").ok()?;
+        let config = RedisConfig {
+            url,
+            key_prefix: format!("rate_limit_test:{:016x}:", rand::random::<u64>()),
+            algorithm,
+            mode: RedisMode::Standalone,
+            sentinel_master: String::new(),
+            hash_tags: false,
+        };
+        Some(
+            RedisRateLimitState::connect(&config)
+                .await
+                .expect("TEST_REDIS_URL is reachable"),
+        )
+    }
+
+    async fn limiter(algorithm: RateLimitAlgorithm, max_requests: u32) -> Option<RedisRateLimiter> {
+        Some(RedisRateLimiter::new(
+            state(algorithm).await?,
+            Arc::new(RateLimitConfig {
+                max_requests,
+                window: WINDOW,
+            }),
+        ))
+    }
+
+    #[tokio::test]
+    async fn sliding_window_admits_up_to_the_limit() {
+        let Some(limiter) = limiter(RateLimitAlgorithm::SlidingWindow, 3).await else {
+            return;
+        };
+        for remaining in [2, 1, 0] {
+            let (allowed, decision) = limiter.admit("k").await.unwrap();
+            assert!(allowed);
+            assert_eq!(decision.remaining, remaining);
+            assert!(decision.reset <= WINDOW && decision.reset > WINDOW - Duration::from_secs(5));
+        }
+        let (allowed, decision) = limiter.admit("k").await.unwrap();
+        assert!(!allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.reset <= WINDOW);
+
+        let (allowed, _) = limiter.admit("other").await.unwrap();
+        assert!(allowed, "keys are counted apart");
+    }
+
+    #[tokio::test]
+    async fn sliding_window_peek_records_nothing() {
+        let Some(limiter) = limiter(RateLimitAlgorithm::SlidingWindow, 2).await else {
+            return;
+        };
+        let empty = limiter.read("k").await.unwrap();
+        assert_eq!((empty.remaining, empty.reset), (2, Duration::ZERO));
+
+        limiter.admit("k").await.unwrap();
+        for _ in 0..3 {
+            let decision = limiter.read("k").await.unwrap();
+            assert_eq!(decision.remaining, 1);
+            assert!(decision.reset <= WINDOW && decision.reset > Duration::ZERO);
+        }
+    }
+
+    #[tokio::test]
+    async fn token_bucket_empties_and_tells_when_a_token_is_back() {
+        let Some(limiter) = limiter(RateLimitAlgorithm::TokenBucket, 3).await else {
+            return;
+        };
+        let full = limiter.read("k").await.unwrap();
+        assert_eq!((full.remaining, full.reset), (3, Duration::ZERO));
+
+        for remaining in [2, 1, 0] {
+            let (allowed, decision) = limiter.admit("k").await.unwrap();
+            assert!(allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let (allowed, decision) = limiter.admit("k").await.unwrap();
+        assert!(!allowed);
+        assert_eq!(decision.remaining, 0);
+        // One token is refilled every third of the window.
+        assert!(decision.reset <= WINDOW / 3 && decision.reset > Duration::ZERO);
+
+        let peeked = limiter.read("k").await.unwrap();
+        assert_eq!(peeked.remaining, 0);
+        assert!(peeked.reset <= WINDOW / 3);
+    }
+
+    #[tokio::test]
+    async fn reset_gives_the_whole_budget_back() {
+        for algorithm in [
+            RateLimitAlgorithm::SlidingWindow,
+            RateLimitAlgorithm::TokenBucket,
+        ] {
+            let Some(limiter) = limiter(algorithm, 1).await else {
+                return;
+            };
+            assert!(limiter.admit("k").await.unwrap().0);
+            assert!(!limiter.admit("k").await.unwrap().0);
+            limiter.reset("k").await.unwrap();
+            assert!(limiter.admit("k").await.unwrap().0);
+        }
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_only_replaces_the_expected_value() {
+        let Some(state) = state(RateLimitAlgorithm::SlidingWindow).await else {
+            return;
+        };
+        let store = RedisStore::new(state);
+        let ttl = Duration::from_secs(10);
+
+        assert_eq!(
+            store
+                .compare_and_swap("k", Some(b"a"), b"b".to_vec(), ttl)
+                .await,
+            Ok(false)
+        );
+        assert_eq!(
+            store.compare_and_swap("k", None, b"a".to_vec(), ttl).await,
+            Ok(true)
+        );
+        assert_eq!(
+            store.compare_and_swap("k", None, b"b".to_vec(), ttl).await,
+            Ok(false)
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("k", Some(b"x"), b"b".to_vec(), ttl)
+                .await,
+            Ok(false)
+        );
+        assert_eq!(store.get("k").await, Ok(Some(b"a".to_vec())));
+        assert_eq!(
+            store
+                .compare_and_swap("k", Some(b"a"), b"b".to_vec(), ttl)
+                .await,
+            Ok(true)
+        );
+        assert_eq!(store.get("k").await, Ok(Some(b"b".to_vec())));
+
+        let mut connection = store.state.connection.clone();
+        let ttl: i64 = connection.pttl(store.state.key("k")).await.unwrap();
+        assert!(ttl > 0 && ttl <= 10_000);
+    }
+}
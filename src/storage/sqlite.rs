@@ -0,0 +1,177 @@
+use sqlx::{
+    Row, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{MemoryStore, RateLimitStore};
+
+/// Value waiting to be written, with its expiry in Unix milliseconds.
+type PendingWrite = (Vec<u8>, i64);
+
+/// [`RateLimitStore`] for single-node deployments that keeps state in memory
+/// and persists it to SQLite, so it survives restarts without Redis.
+///
+/// Requests only touch memory; changed values are written to the database in
+/// batches by a background task, so up to one flush interval of updates is
+/// lost when the process dies.
+#[derive(Clone)]
+pub struct SqliteStore {
+    memory: MemoryStore,
+    pool: SqlitePool,
+    pending: Arc<Mutex<HashMap<String, PendingWrite>>>,
+}
+
+impl SqliteStore {
+    /// Opens (creating if needed) the database at `path`, applies the
+    /// embedded migrations and loads the values that have not expired yet.
+    pub async fn open(path: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::migrate!("migrations/sqlite").run(&pool).await?;
+
+        let store = Self {
+            memory: MemoryStore::new(),
+            pool,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let now = now_millis();
+        let rows =
+            sqlx::query("SELECT key, value, expires_at FROM rate_limit_state WHERE expires_at > ?")
+                .bind(now)
+                .fetch_all(&store.pool)
+                .await?;
+        for row in &rows {
+            let ttl = Duration::from_millis((row.get::<i64, _>("expires_at") - now) as u64);
+            // Cannot fail: the memory store is empty and never errors.
+            let _ = store
+                .memory
+                .compare_and_swap(row.get("key"), None, row.get("value"), ttl)
+                .await;
+        }
+        tracing::info!("Restored {} keys from SQLite", rows.len());
+        Ok(store)
+    }
+
+    /// Checks that the database answers.
+    pub async fn ping(&self) -> Result<(), String> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Writes the pending values to SQLite every `interval`, dropping expired
+    /// rows along the way.
+    pub fn spawn_flush(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                store.flush().await;
+            }
+        });
+    }
+
+    async fn flush(&self) {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        if let Err(e) = self.write(&batch).await {
+            tracing::error!("Failed to persist {} keys to SQLite: {}", batch.len(), e);
+            // Keep the batch for the next flush unless newer values replaced it.
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            for (key, write) in batch {
+                pending.entry(key).or_insert(write);
+            }
+        }
+    }
+
+    async fn write(&self, batch: &HashMap<String, PendingWrite>) -> Result<(), sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        for (key, (value, expires_at)) in batch {
+            sqlx::query(
+                "INSERT INTO rate_limit_state (key, value, expires_at) VALUES (?, ?, ?)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+            )
+            .bind(key)
+            .bind(value)
+            .bind(expires_at)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        sqlx::query("DELETE FROM rate_limit_state WHERE expires_at <= ?")
+            .bind(now_millis())
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+
+    /// Drops expired values from memory; the database is pruned on flush.
+    pub fn evict_expired(&self) -> usize {
+        self.memory.evict_expired()
+    }
+
+    fn enqueue(&self, key: &str, value: Vec<u8>, ttl: Duration) {
+        let expires_at = now_millis() + ttl.as_millis() as i64;
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (value, expires_at));
+    }
+}
+
+impl RateLimitStore for SqliteStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        self.memory.get(key).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &str,
+        current: Option<&[u8]>,
+        new: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<bool, String> {
+        let swapped = self
+            .memory
+            .compare_and_swap(key, current, new.clone(), ttl)
+            .await?;
+        if swapped {
+            self.enqueue(key, new, ttl);
+        }
+        Ok(swapped)
+    }
+
+    async fn update<R: Send>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        mut f: impl FnMut(Option<&[u8]>) -> (Option<Vec<u8>>, R) + Send,
+    ) -> Result<R, String> {
+        // Enqueueing inside the update runs under the memory store's entry
+        // lock, so concurrent writes to a key are persisted in order.
+        self.memory
+            .update(key, ttl, |current| {
+                let (new, result) = f(current);
+                if let Some(value) = &new {
+                    self.enqueue(key, value.clone(), ttl);
+                }
+                (new, result)
+            })
+            .await
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
@@ -0,0 +1,27 @@
+//! Health of the limiters keeping their keys in memory: the keys each holds,
+//! in total and per shard of its map, and an estimate of the memory they
+//! take, refreshed as gauges for capacity planning.
+
+use std::time::Duration;
+
+use crate::metrics;
+use crate::middleware::SharedLimiter;
+
+/// Time between two readings. Each walks every key, so not on every scrape.
+const INTERVAL: Duration = Duration::from_secs(15);
+
+/// Starts reading the health of each of `limiters`, named, that keeps its
+/// keys in memory.
+pub fn spawn(limiters: Vec<(String, SharedLimiter)>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(INTERVAL);
+        loop {
+            ticker.tick().await;
+            for (name, limiter) in &limiters {
+                if let Some(health) = limiter.get().health().await {
+                    metrics::record_store_health(name, &health);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,73 @@
+//! Switching the main limiter between the in-memory types at runtime, by
+//! `POST /admin/limiter`, rather than restarting with another
+//! `RATE_LIMITER_TYPE`.
+//!
+//! Clients' counts are carried over between the standard and lock-free
+//! limiters, as each counts requests per key. The memory store keeps
+//! entries encoded for its algorithm and each key's limit, so switching to
+//! or from it starts every client over.
+
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::RateLimiterType;
+use crate::middleware::{RateLimitStateEnum, SharedLimiter};
+use crate::rate_limiter::{LockFreeRateLimitState, RateLimitState};
+use crate::snapshot::Snapshot;
+use crate::storage::MemoryStore;
+
+/// Held through a switch, so two at once do not both migrate the same state.
+static SWITCHING: Mutex<()> = Mutex::const_new(());
+
+/// A switch of the main limiter that went through.
+pub struct Switch {
+    pub previous: RateLimiterType,
+    /// Keys whose counts were carried over, none if every client started
+    /// over.
+    pub migrated: Option<usize>,
+}
+
+/// Switches `limiter` to a limiter of type `to`, carrying clients' counts
+/// over where the types allow. Only the in-memory limiters can be switched.
+pub async fn switch(limiter: &SharedLimiter, to: RateLimiterType) -> Result<Switch, String> {
+    let _switching = SWITCHING.lock().await;
+    let current = limiter.get();
+    let Some(previous) = current.memory_type() else {
+        return Err("Only the limiters of the memory backend can be switched.".to_string());
+    };
+    if previous == to {
+        return Ok(Switch {
+            previous,
+            migrated: current.tracked_keys().await,
+        });
+    }
+
+    // Carried over like a snapshot restored on startup. Requests recorded
+    // between taking it and the swap are not.
+    let switched = empty(to);
+    let migrated = match Snapshot::take(&current).await {
+        Some(snapshot) => snapshot.load(&switched).await,
+        None => None,
+    };
+    limiter.set(switched);
+    tracing::info!(
+        "Switched the limiter from {:?} to {:?}, {}",
+        previous,
+        to,
+        migrated.map_or("starting every client over".to_string(), |keys| format!(
+            "carrying over {} keys",
+            keys
+        ))
+    );
+    Ok(Switch { previous, migrated })
+}
+
+fn empty(kind: RateLimiterType) -> RateLimitStateEnum {
+    match kind {
+        RateLimiterType::Standard => RateLimitStateEnum::Standard(RateLimitState {
+            requests: Arc::new(RwLock::new(HashMap::new())),
+        }),
+        RateLimiterType::LockFree => RateLimitStateEnum::LockFree(LockFreeRateLimitState::new()),
+        RateLimiterType::Store => RateLimitStateEnum::MemoryStore(MemoryStore::new()),
+    }
+}
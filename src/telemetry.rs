@@ -0,0 +1,159 @@
+//! Tracing: every request runs in a span, with a child span per rate limit
+//! decision and per upstream request, logged as they close. With an OTLP
+//! endpoint configured the spans are exported to a collector too, continuing
+//! the trace of the request's `traceparent` and passing it on upstream.
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, Method, Response},
+};
+use opentelemetry::{
+    Key, KeyValue, Value, global,
+    trace::{TraceError, TracerProvider as _},
+};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{
+    Resource, propagation::TraceContextPropagator, runtime, trace::TracerProvider,
+};
+use std::{sync::OnceLock, time::Duration};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    EnvFilter, Layer as _, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+use crate::config::{LOG_FORMAT, LOG_LEVEL, LogFormat, OTLP_TRACING};
+use crate::log_sampling;
+use crate::middleware::Checked;
+
+/// Name spans are reported under unless `OTEL_SERVICE_NAME` gives another.
+const SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+const SERVICE_NAME_KEY: &str = "service.name";
+
+/// Service name of resources that were not given one.
+const UNKNOWN_SERVICE: &str = "unknown_service";
+
+/// Swaps the filter of what is logged and exported while running.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Logs to stdout, as `LOG_FORMAT` says, and, when enabled, exports spans
+/// over OTLP, both filtered by `LOG_LEVEL`.
+pub fn init() {
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_thread_ids(true)
+        .with_file(true)
+        .with_line_number(true);
+    let fmt = match *LOG_FORMAT {
+        LogFormat::Text => fmt.boxed(),
+        // Fields at the top level, under the same names in every line, with
+        // the spans, and so the request's ID, alongside.
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let provider = if *OTLP_TRACING {
+        Some(provider())
+    } else {
+        None
+    };
+    let otel = match &provider {
+        Some(Ok(provider)) => {
+            Some(tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)))
+        }
+        _ => None,
+    };
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&*LOG_LEVEL));
+    let _ = FILTER.set(handle);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(otel)
+        .init();
+    match provider {
+        Some(Ok(provider)) => {
+            tracing::info!("Exporting traces over OTLP");
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            global::set_tracer_provider(provider);
+        }
+        Some(Err(error)) => tracing::error!("Failed to set up trace export: {}", error),
+        None => {}
+    }
+}
+
+/// The filter directives in force.
+pub fn log_level() -> Option<String> {
+    FILTER.get()?.with_current(ToString::to_string).ok()
+}
+
+/// Filters what is logged and exported by `directives` from now on, e.g.
+/// `debug` during an incident, without a restart losing the limiters' state.
+pub fn set_log_level(directives: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    let handle = FILTER.get().ok_or("logging is not set up")?;
+    tracing::info!("Setting log level to {}", directives);
+    handle.reload(filter).map_err(|e| e.to_string())
+}
+
+/// A provider batching spans to the OTLP exporter, which, like the sampler,
+/// takes its settings from the standard `OTEL_*` variables.
+fn provider() -> Result<TracerProvider, TraceError> {
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let mut resource = Resource::default();
+    if resource.get(Key::from_static_str(SERVICE_NAME_KEY)) == Some(Value::from(UNKNOWN_SERVICE)) {
+        resource = resource.merge(&Resource::new([KeyValue::new(
+            SERVICE_NAME_KEY,
+            SERVICE_NAME,
+        )]));
+    }
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(resource)
+        .build())
+}
+
+/// Logs a request once its response started, with what the rate limiter
+/// decided on it if it was checked, under field names that stay the same
+/// across releases. Allowed requests are sampled, and clients are held to
+/// their lines per second.
+pub fn log_request(method: &Method, path: &str, response: &Response<Body>, latency: Duration) {
+    let checked = response.extensions().get::<Checked>();
+    if !log_sampling::sampled(checked.is_none_or(|checked| checked.allowed))
+        || checked.is_some_and(|checked| !log_sampling::for_key(&checked.key))
+    {
+        return;
+    }
+    tracing::info!(
+        method = %method,
+        path,
+        status = response.status().as_u16(),
+        ip = checked.map(|checked| checked.ip.as_str()),
+        key = checked.map(|checked| checked.key.as_str()),
+        decision = checked.map(|checked| if checked.allowed { "allow" } else { "deny" }),
+        remaining = checked.and_then(|checked| checked.remaining),
+        latency_ms = latency.as_micros() as f64 / 1000.0,
+        "Request completed"
+    );
+}
+
+/// Makes `span` part of the trace the request's `traceparent` belongs to, if
+/// it has one.
+pub fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+/// Sets the `traceparent` of a request made within `span`, so the receiver's
+/// spans join its trace.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
@@ -0,0 +1,280 @@
+//! Throttling: requests over their limit wait until the limiter admits them
+//! instead of being rejected at once, for callers that would rather be slowed
+//! down than fail.
+//!
+//! Waiting requests retry whenever their last rejection said capacity frees
+//! up, so they are admitted roughly, but not strictly, in arrival order.
+
+use dashmap::DashMap;
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+use crate::config::ThrottleConfig;
+use crate::metrics;
+use crate::rate_limiter::{RateLimitDecision, RateLimitError, RateLimiter};
+
+/// Shortest pause between retries, so limiters reporting no reset time are
+/// not retried in a busy loop.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Requests waiting per key.
+pub struct Throttle {
+    waiting: DashMap<String, usize>,
+    /// Counts how waits end.
+    record: fn(&str),
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(metrics::record_throttled)
+    }
+}
+
+impl Throttle {
+    pub fn new(record: fn(&str)) -> Self {
+        Self {
+            waiting: DashMap::new(),
+            record,
+        }
+    }
+
+    /// Retries a request `limiter` rejected with `error` until it is admitted
+    /// or `config.max_wait` has passed, returning the last rejection then.
+    /// Requests finding `config.max_queue` others of their key waiting are
+    /// rejected right away.
+    pub async fn wait(
+        &self,
+        limiter: &dyn RateLimiter,
+        key: &str,
+        config: &ThrottleConfig,
+        mut error: RateLimitError,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let Some(_slot) = self.enter(key, config.max_queue) else {
+            (self.record)("queue_full");
+            return Err(error);
+        };
+        let deadline = Instant::now() + config.max_wait();
+        loop {
+            let RateLimitError::Exceeded { decision, .. } = &error else {
+                return Err(error);
+            };
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                (self.record)("timed_out");
+                return Err(error);
+            }
+            sleep(decision.reset.max(MIN_RETRY_DELAY).min(left)).await;
+            match limiter.check_rate_limit(key).await {
+                Ok(decision) => {
+                    (self.record)("admitted");
+                    return Ok(decision);
+                }
+                Err(e) => error = e,
+            }
+        }
+    }
+
+    /// Takes a place in `key`'s queue if fewer than `max_queue` requests wait.
+    fn enter(&self, key: &str, max_queue: usize) -> Option<Slot<'_>> {
+        let mut waiting = self.waiting.entry(key.to_string()).or_insert(0);
+        if *waiting >= max_queue {
+            return None;
+        }
+        *waiting += 1;
+        Some(Slot {
+            throttle: self,
+            key: key.to_string(),
+        })
+    }
+}
+
+/// A place in a key's queue, given up when dropped, including when the
+/// client disconnects while waiting.
+struct Slot<'a> {
+    throttle: &'a Throttle,
+    key: String,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(mut waiting) = self.throttle.waiting.get_mut(&self.key) {
+            *waiting -= 1;
+        }
+        self.throttle
+            .waiting
+            .remove_if(&self.key, |_, waiting| *waiting == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    thread_local! {
+        static OUTCOMES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records how waits end on this thread, which each test runs on.
+    fn record(outcome: &str) {
+        OUTCOMES.with_borrow_mut(|outcomes| outcomes.push(outcome.to_string()));
+    }
+
+    fn outcomes() -> Vec<String> {
+        OUTCOMES.take()
+    }
+
+    /// Rejects requests until `opens_at`, saying capacity frees up then.
+    struct Reopens {
+        opens_at: Instant,
+        checks: AtomicUsize,
+    }
+
+    impl Reopens {
+        fn after(delay: Duration) -> Self {
+            Self {
+                opens_at: Instant::now() + delay,
+                checks: AtomicUsize::new(0),
+            }
+        }
+
+        fn rejection(&self) -> RateLimitError {
+            let reset = self.opens_at.saturating_duration_since(Instant::now());
+            RateLimitError::rate_limit_exceeded(1, Duration::from_secs(1), reset)
+        }
+    }
+
+    #[async_trait]
+    impl RateLimiter for Reopens {
+        async fn check_rate_limit(&self, _: &str) -> Result<RateLimitDecision, RateLimitError> {
+            self.checks.fetch_add(1, Ordering::Relaxed);
+            if Instant::now() < self.opens_at {
+                return Err(self.rejection());
+            }
+            Ok(RateLimitDecision {
+                limit: 1,
+                window: Duration::from_secs(1),
+                remaining: 0,
+                reset: Duration::from_secs(1),
+            })
+        }
+
+        async fn record_request(&self, _: &str) {}
+
+        async fn peek(&self, _: &str) -> Result<RateLimitDecision, String> {
+            Err("not peeked in these tests".to_string())
+        }
+
+        async fn reset(&self, _: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn config(max_wait_ms: u64, max_queue: usize) -> ThrottleConfig {
+        ThrottleConfig {
+            max_wait_ms,
+            max_queue,
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_admitted_once_capacity_frees_up() {
+        let throttle = Throttle::new(record);
+        let limiter = Reopens::after(Duration::from_millis(30));
+        let started = Instant::now();
+
+        let decision = throttle
+            .wait(&limiter, "k", &config(1000, 10), limiter.rejection())
+            .await;
+        assert!(decision.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        // The retry waited for the reset rather than polling.
+        assert_eq!(limiter.checks.load(Ordering::Relaxed), 1);
+        assert_eq!(outcomes(), ["admitted"]);
+        assert!(throttle.waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_give_up_after_max_wait() {
+        let throttle = Throttle::new(record);
+        let limiter = Reopens::after(Duration::from_secs(60));
+        let started = Instant::now();
+
+        let result = throttle
+            .wait(&limiter, "k", &config(30, 10), limiter.rejection())
+            .await;
+        assert!(matches!(result, Err(RateLimitError::Exceeded { .. })));
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(30) && waited < Duration::from_secs(1));
+        assert_eq!(outcomes(), ["timed_out"]);
+        assert!(throttle.waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_over_max_queue_are_rejected_at_once() {
+        let throttle = Arc::new(Throttle::new(record));
+        let limiter = Arc::new(Reopens::after(Duration::from_millis(50)));
+        let waiting = tokio::spawn({
+            let (throttle, limiter) = (throttle.clone(), limiter.clone());
+            async move {
+                throttle
+                    .wait(&*limiter, "k", &config(1000, 1), limiter.rejection())
+                    .await
+                    .is_ok()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let started = Instant::now();
+        let result = throttle
+            .wait(&*limiter, "k", &config(1000, 1), limiter.rejection())
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_millis(10));
+        // Other keys have queues of their own.
+        let other = throttle
+            .wait(&*limiter, "other", &config(1000, 1), limiter.rejection())
+            .await;
+        assert!(other.is_ok());
+
+        assert!(waiting.await.unwrap());
+        assert_eq!(outcomes(), ["queue_full", "admitted", "admitted"]);
+        assert!(throttle.waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unavailable_limiters_are_not_waited_for() {
+        let throttle = Throttle::new(record);
+        let limiter = Reopens::after(Duration::ZERO);
+
+        let result = throttle
+            .wait(
+                &limiter,
+                "k",
+                &config(1000, 10),
+                RateLimitError::Unavailable("store down".to_string()),
+            )
+            .await;
+        assert!(matches!(result, Err(RateLimitError::Unavailable(_))));
+        assert_eq!(limiter.checks.load(Ordering::Relaxed), 0);
+        assert!(outcomes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_waits_give_up_their_place() {
+        let throttle = Throttle::new(record);
+        let limiter = Reopens::after(Duration::from_secs(60));
+        let config = config(60_000, 1);
+        let wait = throttle.wait(&limiter, "k", &config, limiter.rejection());
+        // The client disconnecting drops the request's future mid-wait.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), wait)
+                .await
+                .is_err()
+        );
+        assert!(throttle.waiting.is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::{API_KEY_HEADER, Limits, RateLimitConfig, TierLookupConfig, limits};
+use crate::jwt::JWT_VALIDATOR;
+use crate::lookup_cache::LookupCache;
+
+/// Longest API key sent to the lookup service.
+const MAX_API_KEY_LENGTH: usize = 256;
+
+#[derive(Serialize)]
+struct TierLookupRequest<'a> {
+    api_key: &'a str,
+}
+
+#[derive(Deserialize)]
+struct TierLookupResponse {
+    tier: Option<String>,
+}
+
+/// A tier a client was assigned to, with its limit.
+pub struct Tier {
+    pub name: String,
+    pub limit: Arc<RateLimitConfig>,
+}
+
+/// Resolves API keys to tiers through an external HTTP endpoint, e.g. a
+/// billing system, caching answers for a TTL.
+///
+/// The endpoint receives `POST {"api_key": "..."}` and answers
+/// `{"tier": "pro"}`; the tier's limit from `RATE_LIMIT_TIERS` is then applied.
+///
+/// Keys are whatever clients send, so malformed ones are never looked up and
+/// lookups are capped per second, leaving keys beyond without a tier until
+/// asked for again, so made-up keys cannot flood the service.
+pub struct TierResolver {
+    client: reqwest::Client,
+    url: String,
+    cache: LookupCache<String>,
+    max_lookups_per_second: u32,
+    /// Start of the current second and the lookups sent in it.
+    lookups: Mutex<(Instant, u32)>,
+}
+
+impl TierResolver {
+    pub fn new(config: &TierLookupConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(config.timeout_ms))
+                .build()
+                .expect("failed to build tier lookup HTTP client"),
+            url: config.url.clone(),
+            cache: LookupCache::new(
+                Duration::from_secs(config.cache_ttl_seconds),
+                config.cache_max_keys,
+            ),
+            max_lookups_per_second: config.max_lookups_per_second,
+            lookups: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Drops the lookup results older than the cache TTL, returning how many.
+    pub fn evict_expired(&self) -> usize {
+        self.cache.evict_expired()
+    }
+
+    /// Returns the tier the request's API key belongs to, if any.
+    pub async fn tier(&self, headers: &HeaderMap) -> Option<Tier> {
+        let name = self.resolve(api_key(headers)?).await?;
+        known_tier(&limits(), name)
+    }
+
+    async fn resolve(&self, api_key: &str) -> Option<String> {
+        if let Some(tier) = self.cache.get(api_key) {
+            return tier;
+        }
+        if !is_well_formed(api_key) || !self.take_lookup() {
+            return None;
+        }
+
+        // Failures are cached like answers so an outage of the lookup service
+        // does not add its timeout to every request.
+        let tier = match self.lookup(api_key).await {
+            Ok(tier) => tier,
+            Err(e) => {
+                tracing::error!("Tier lookup failed: {}", e);
+                None
+            }
+        };
+        self.cache.insert(api_key, tier.clone());
+        tier
+    }
+
+    /// Counts a lookup against the ones allowed this second, if any is left.
+    fn take_lookup(&self) -> bool {
+        let mut lookups = self.lookups.lock().unwrap_or_else(|e| e.into_inner());
+        let (started_at, sent) = &mut *lookups;
+        if started_at.elapsed() >= Duration::from_secs(1) {
+            *started_at = Instant::now();
+            *sent = 0;
+        }
+        if *sent >= self.max_lookups_per_second {
+            if *sent == self.max_lookups_per_second {
+                tracing::warn!(
+                    "Tier lookups are over {} a second, leaving keys without a tier",
+                    self.max_lookups_per_second
+                );
+                *sent += 1;
+            }
+            return false;
+        }
+        *sent += 1;
+        true
+    }
+
+    async fn lookup(&self, api_key: &str) -> Result<Option<String>, reqwest::Error> {
+        let response: TierLookupResponse = self
+            .client
+            .post(&self.url)
+            .json(&TierLookupRequest { api_key })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.tier)
+    }
+}
+
+/// Returns the tier the configuration assigns the request to, by its API key
+/// in `api_key_tiers` or else by the tier claim of its bearer token. This is
+/// checked before any tier lookup.
+pub fn configured_tier(limits: &Limits, req: &Request<Body>) -> Option<Tier> {
+    let name = api_key(req.headers())
+        .and_then(|key| limits.api_key_tiers.get(key).cloned())
+        .or_else(|| JWT_VALIDATOR.claim_from_request(req, limits.tier_claim.as_deref()?))?;
+    known_tier(limits, name)
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(&*API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Whether `api_key` could be a key at all: not overly long and printable
+/// ASCII without spaces.
+fn is_well_formed(api_key: &str) -> bool {
+    api_key.len() <= MAX_API_KEY_LENGTH && api_key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn known_tier(limits: &Limits, name: String) -> Option<Tier> {
+    let Some(limit) = limits.tiers.get(&name).cloned() else {
+        tracing::warn!("Request was assigned unknown tier: {}", name);
+        return None;
+    };
+    Some(Tier { name, limit })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, routing::post};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A lookup service answering `pro` for every key, counting lookups.
+    async fn service() -> (String, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counted = lookups.clone();
+        let app = Router::new().route(
+            "/",
+            post(move || async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Json(serde_json::json!({ "tier": "pro" }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, lookups)
+    }
+
+    fn resolver(url: String, cache_max_keys: usize, max_lookups_per_second: u32) -> TierResolver {
+        TierResolver::new(&TierLookupConfig {
+            url,
+            timeout_ms: 1_000,
+            cache_ttl_seconds: 60,
+            cache_max_keys,
+            max_lookups_per_second,
+        })
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached() {
+        let (url, lookups) = service().await;
+        let resolver = resolver(url, 10, 10);
+        assert_eq!(resolver.resolve("k-1234").await.as_deref(), Some("pro"));
+        assert_eq!(resolver.resolve("k-1234").await.as_deref(), Some("pro"));
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_keys_are_not_looked_up() {
+        let (url, lookups) = service().await;
+        let resolver = resolver(url, 10, 10);
+        let long = "k".repeat(MAX_API_KEY_LENGTH + 1);
+        for api_key in [long.as_str(), "k 1234", "k-ü"] {
+            assert_eq!(resolver.resolve(api_key).await, None);
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 0);
+        assert_eq!(resolver.cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn lookups_are_capped_per_second() {
+        let (url, lookups) = service().await;
+        let resolver = resolver(url, 100, 3);
+        let mut tiers = 0;
+        for i in 0..10 {
+            if resolver.resolve(&format!("k-{}", i)).await.is_some() {
+                tiers += 1;
+            }
+        }
+        assert_eq!(tiers, 3);
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+        assert_eq!(resolver.cache.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cache_is_bounded() {
+        let (url, _) = service().await;
+        let resolver = resolver(url, 5, 100);
+        for i in 0..20 {
+            resolver.resolve(&format!("k-{}", i)).await;
+        }
+        assert!(resolver.cache.len() <= 5);
+    }
+}
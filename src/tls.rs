@@ -0,0 +1,99 @@
+use axum::{Router, extract::ConnectInfo, http::Request};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use rustls::{RootCertStore, ServerConfig, crypto::ring, server::WebPkiClientVerifier};
+use sha2::{Digest, Sha256};
+use std::{error::Error, fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::config::TlsConfig;
+
+/// SHA-256 fingerprint (lowercase hex) of the certificate a client presented
+/// during the TLS handshake, attached to each request of the connection.
+#[derive(Clone, Debug)]
+pub struct ClientCertFingerprint(pub String);
+
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, Box<dyn Error>> {
+    let provider = Arc::new(ring::default_provider());
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.key_path)?))?
+        .ok_or_else(|| format!("no private key found in {}", config.key_path))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(cert?)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_cert_required {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+/// Serves `app` over TLS, exposing the peer address as `ConnectInfo` and the
+/// client certificate, if any, as [`ClientCertFingerprint`].
+pub async fn serve(listener: TcpListener, app: Router, config: ServerConfig) {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let fingerprint = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| ClientCertFingerprint(hex::encode(Sha256::digest(cert))));
+
+            let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo::<SocketAddr>(peer));
+                if let Some(fingerprint) = fingerprint.clone() {
+                    req.extensions_mut().insert(fingerprint);
+                }
+                app.clone().oneshot(req)
+            });
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection from {} closed with error: {}", peer, e);
+            }
+        });
+    }
+}
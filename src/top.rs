@@ -0,0 +1,151 @@
+//! Top offenders: the requests and denials of each key in the last minute,
+//! so operators can find the clients hammering the server with
+//! `GET /admin/top`. Counts are kept per minute, the previous minute's
+//! weighted by how much of it is still within the last 60 seconds, like the
+//! sliding window counter of a limiter.
+
+use dashmap::DashMap;
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+/// How far back requests are counted.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+static TRACKER: OnceLock<Tracker> = OnceLock::new();
+
+struct Tracker {
+    started: Instant,
+    keys: DashMap<String, Counts>,
+}
+
+/// Requests and denials of a key in the minute it was last seen and the one
+/// before, oldest first.
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    minute: u64,
+    requests: [u64; 2],
+    denials: [u64; 2],
+}
+
+impl Counts {
+    /// Moves the counts along to `minute`, dropping those now too old.
+    fn advance(&mut self, minute: u64) {
+        match minute.saturating_sub(self.minute) {
+            0 => return,
+            1 => {
+                self.requests = [self.requests[1], 0];
+                self.denials = [self.denials[1], 0];
+            }
+            _ => {
+                self.requests = [0, 0];
+                self.denials = [0, 0];
+            }
+        }
+        self.minute = minute;
+    }
+}
+
+/// A key with what it did in the last minute.
+#[derive(Clone, Serialize)]
+pub struct Offender {
+    pub key: String,
+    pub requests: u64,
+    pub denials: u64,
+}
+
+/// Starts counting the requests of each key, dropping the keys idle for a
+/// minute as they go.
+pub fn spawn() {
+    let _ = TRACKER.set(Tracker {
+        started: Instant::now(),
+        keys: DashMap::new(),
+    });
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(WINDOW);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(tracker) = TRACKER.get() else { return };
+            let minute = tracker.minute();
+            tracker
+                .keys
+                .retain(|_, counts| counts.minute.saturating_add(1) >= minute);
+        }
+    });
+}
+
+/// Counts a request of `key`, denied unless `allowed`.
+pub fn record(key: &str, allowed: bool) {
+    let Some(tracker) = TRACKER.get() else { return };
+    let minute = tracker.minute();
+    let count = |counts: &mut Counts| {
+        counts.advance(minute);
+        counts.requests[1] += 1;
+        if !allowed {
+            counts.denials[1] += 1;
+        }
+    };
+    if let Some(mut counts) = tracker.keys.get_mut(key) {
+        count(&mut counts);
+        return;
+    }
+    count(&mut tracker.keys.entry(key.to_string()).or_insert(Counts {
+        minute,
+        ..Counts::default()
+    }));
+}
+
+/// The `n` keys with the most requests and the `n` with the most denials in
+/// the last minute, most first; none if requests are not counted.
+pub fn top(n: usize) -> (Vec<Offender>, Vec<Offender>) {
+    let Some(tracker) = TRACKER.get() else {
+        return (Vec::new(), Vec::new());
+    };
+    let minute = tracker.minute();
+    // Share of the previous minute still within the window.
+    let previous = 1.0 - tracker.minute_elapsed().as_secs_f64() / WINDOW.as_secs_f64();
+    let recent = |[before, now]: [u64; 2]| now + (before as f64 * previous).round() as u64;
+    let offenders: Vec<Offender> = tracker
+        .keys
+        .iter()
+        .map(|entry| {
+            let mut counts = *entry.value();
+            counts.advance(minute);
+            Offender {
+                key: entry.key().clone(),
+                requests: recent(counts.requests),
+                denials: recent(counts.denials),
+            }
+        })
+        .filter(|offender| offender.requests > 0)
+        .collect();
+    let ranked = |by: fn(&Offender) -> u64| {
+        let mut ranked: Vec<&Offender> = offenders
+            .iter()
+            .filter(|offender| by(offender) > 0)
+            .collect();
+        ranked.sort_by_key(|offender| Reverse(by(offender)));
+        ranked.truncate(n);
+        ranked.into_iter().cloned().collect()
+    };
+    (
+        ranked(|offender| offender.requests),
+        ranked(|offender| offender.denials),
+    )
+}
+
+impl Tracker {
+    /// Index of the current minute since the tracker started.
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / WINDOW.as_secs()
+    }
+
+    /// Time since the current minute started.
+    fn minute_elapsed(&self) -> Duration {
+        Duration::from_secs_f64(self.started.elapsed().as_secs_f64() % WINDOW.as_secs_f64())
+    }
+}
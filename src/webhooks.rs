@@ -0,0 +1,248 @@
+//! Webhooks: operators' endpoints, like a Slack incoming webhook, are told
+//! when a client gets rate limited for the first time in a while, and when
+//! traffic across all clients goes over a threshold.
+//!
+//! The request path only queues events; a background task sends them in
+//! batches each second, retrying endpoints that fail with backoff, so a
+//! slow endpoint costs late or dropped alerts rather than request latency.
+
+use axum::{
+    body::Bytes,
+    http::{StatusCode, header},
+};
+use chrono::Utc;
+use dashmap::DashMap;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::config::{WebhookConfig, WebhookEvent, WebhookFormat};
+use crate::metrics;
+
+/// Events waiting to be sent, past which new ones are dropped.
+const QUEUE: usize = 1000;
+/// Most events sent in one request.
+const BATCH_SIZE: usize = 100;
+/// Tries to send a batch before giving up on it.
+const ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled before each next one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static WEBHOOKS: OnceLock<Webhooks> = OnceLock::new();
+
+struct Webhooks {
+    config: &'static WebhookConfig,
+    sender: mpsc::Sender<Event>,
+    /// When each key was last rate limited.
+    limited: DashMap<String, Instant>,
+    /// Requests decided on in the current second.
+    requests: AtomicU64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    RateLimited {
+        key: String,
+        rule: String,
+        ip: String,
+        time: String,
+    },
+    Traffic {
+        requests_per_second: u64,
+        threshold: u64,
+        time: String,
+    },
+}
+
+impl Event {
+    /// The event as a line of a chat message.
+    fn summary(&self) -> String {
+        match self {
+            Self::RateLimited { key, rule, ip, .. } => {
+                format!("Rate limited `{}` under rule `{}` (IP {})", key, rule, ip)
+            }
+            Self::Traffic {
+                requests_per_second,
+                threshold,
+                ..
+            } => format!(
+                "Traffic at {} requests per second, over the threshold of {}",
+                requests_per_second, threshold
+            ),
+        }
+    }
+}
+
+/// Starts the task sending the events of `config` to its endpoints.
+pub fn spawn(config: &'static WebhookConfig) {
+    let (sender, receiver) = mpsc::channel(QUEUE);
+    let _ = WEBHOOKS.set(Webhooks {
+        config,
+        sender,
+        limited: DashMap::new(),
+        requests: AtomicU64::new(0),
+    });
+    tokio::spawn(run(receiver, config));
+}
+
+/// Counts a decision on `key`, queueing an event if it was the key's first
+/// denial in the cooldown.
+pub fn record(key: &str, rule: &str, ip: &str, allowed: bool) {
+    let Some(webhooks) = WEBHOOKS.get() else {
+        return;
+    };
+    webhooks.requests.fetch_add(1, Ordering::Relaxed);
+    if allowed || !webhooks.config.events.contains(&WebhookEvent::RateLimited) {
+        return;
+    }
+    let now = Instant::now();
+    let cooldown = Duration::from_secs(webhooks.config.cooldown_seconds);
+    // Denied all along, a key fires once, not each cooldown.
+    let first = match webhooks.limited.get_mut(key) {
+        Some(mut last) => {
+            let quiet = now.duration_since(*last) >= cooldown;
+            *last = now;
+            quiet
+        }
+        None => webhooks
+            .limited
+            .insert(key.to_string(), now)
+            .is_none_or(|last| now.duration_since(last) >= cooldown),
+    };
+    if first {
+        webhooks.queue(Event::RateLimited {
+            key: key.to_string(),
+            rule: rule.to_string(),
+            ip: ip.to_string(),
+            time: Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+impl Webhooks {
+    /// Queues `event` without waiting; it is dropped if the queue is full.
+    fn queue(&self, event: Event) {
+        if self.sender.try_send(event).is_err() {
+            metrics::record_webhook_events("dropped", 1);
+        }
+    }
+}
+
+async fn run(mut receiver: mpsc::Receiver<Event>, config: &'static WebhookConfig) {
+    let client = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .expect("failed to build webhook HTTP client");
+    let cooldown = Duration::from_secs(config.cooldown_seconds);
+    let mut over_threshold: Option<Instant> = None;
+    let mut pruned = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let Some(webhooks) = WEBHOOKS.get() else {
+            return;
+        };
+        let mut batch = Vec::new();
+
+        let requests = webhooks.requests.swap(0, Ordering::Relaxed);
+        if let Some(threshold) = config.traffic_threshold
+            && config.events.contains(&WebhookEvent::Traffic)
+            && requests > threshold
+        {
+            if over_threshold.is_none_or(|last| last.elapsed() >= cooldown) {
+                batch.push(Event::Traffic {
+                    requests_per_second: requests,
+                    threshold,
+                    time: Utc::now().to_rfc3339(),
+                });
+            }
+            over_threshold = Some(Instant::now());
+        }
+
+        while batch.len() < BATCH_SIZE
+            && let Ok(event) = receiver.try_recv()
+        {
+            batch.push(event);
+        }
+
+        if pruned.elapsed() >= cooldown {
+            webhooks.limited.retain(|_, last| last.elapsed() < cooldown);
+            pruned = Instant::now();
+        }
+
+        if batch.is_empty() {
+            continue;
+        }
+        let body = match config.format {
+            WebhookFormat::Json => json!({ "events": batch }),
+            WebhookFormat::Slack => json!({
+                "text": batch.iter().map(Event::summary).collect::<Vec<_>>().join("\n"),
+            }),
+        };
+        let body = Bytes::from(body.to_string());
+        for url in &config.urls {
+            tokio::spawn(send(client.clone(), url, body.clone(), batch.len()));
+        }
+    }
+}
+
+/// Posts `body`, carrying `events`, to `url`, retrying failures that may
+/// pass: errors reaching it, `429 Too Many Requests` and server errors.
+async fn send(client: reqwest::Client, url: &'static str, body: Bytes, events: usize) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=ATTEMPTS {
+        let error = match client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                metrics::record_webhook_events("sent", events);
+                return;
+            }
+            Ok(response) => {
+                let status = response.status();
+                if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                    give_up(url, events, status);
+                    return;
+                }
+                status.to_string()
+            }
+            Err(error) => error.to_string(),
+        };
+        if attempt == ATTEMPTS {
+            give_up(url, events, error);
+            return;
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// Gives up on events, naming only the endpoint's host, since webhook URLs
+/// often carry their secret in the path.
+fn give_up(url: &str, events: usize, error: impl std::fmt::Display) {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    tracing::error!(
+        "Failed to send {} webhook events to {}: {}",
+        events,
+        host,
+        error
+    );
+    metrics::record_webhook_events("failed", events);
+}